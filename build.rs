@@ -0,0 +1,88 @@
+//! Packs the `initrd` directory into the USTAR archive embedded in the kernel image, see
+//! `fs::initrd`.
+
+use std::{
+    env, fs,
+    io::Result,
+    path::{Path, PathBuf},
+};
+
+const BLOCK: usize = 512;
+
+fn main() -> Result<()> {
+    println!("cargo:rerun-if-changed=initrd");
+
+    let mut archive = Vec::new();
+    let mut entries = Vec::new();
+    collect(Path::new("initrd"), &mut entries)?;
+    for (path, name) in entries {
+        if path.is_dir() {
+            append(&mut archive, &format!("{name}/"), b'5', 0o755, &[]);
+        } else {
+            append(&mut archive, &name, b'0', 0o644, &fs::read(&path)?);
+        }
+    }
+    // The archive ends with two zero blocks
+    archive.resize(archive.len() + 2 * BLOCK, 0);
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR not set"));
+    fs::write(out.join("initrd.tar"), archive)
+}
+
+/// Lists everything under `dir` in name order, each directory before its contents, along with
+/// its path relative to the `initrd` directory
+fn collect(dir: &Path, entries: &mut Vec<(PathBuf, String)>) -> Result<()> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>>>()?;
+    paths.sort();
+    for path in paths {
+        let name = path
+            .strip_prefix("initrd")
+            .unwrap()
+            .to_str()
+            .expect("initrd paths must be UTF-8")
+            .replace('\\', "/");
+        let is_dir = path.is_dir();
+        entries.push((path.clone(), name));
+        if is_dir {
+            collect(&path, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends an entry owned by root, dated at the epoch so the archive only changes along with
+/// the files
+fn append(archive: &mut Vec<u8>, name: &str, kind: u8, mode: u32, data: &[u8]) {
+    assert!(name.len() <= 100, "{name}: name too long for a USTAR header");
+
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], mode.into());
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], data.len() as u64);
+    octal(&mut header[136..148], 0);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[265..269].copy_from_slice(b"root");
+    header[297..301].copy_from_slice(b"root");
+
+    // The checksum is computed with its own field set to spaces
+    header[148..156].fill(b' ');
+    let sum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+    octal(&mut header[148..155], sum);
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(BLOCK), 0);
+}
+
+/// Writes `value` as zero-padded octal digits followed by a null byte, filling `field`
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}\0", width = field.len() - 1);
+    assert_eq!(digits.len(), field.len(), "{value} doesn't fit its field");
+    field.copy_from_slice(digits.as_bytes());
+}
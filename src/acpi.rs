@@ -0,0 +1,137 @@
+use core::{mem, ptr, slice};
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+/// The header shared by every ACPI System Description Table
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Only valid if `revision >= 2`
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// A discovered ACPI table, located in physical memory
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub phys: PhysAddr,
+    pub header: SdtHeader,
+}
+
+impl Table {
+    /// The complete table, including its header
+    pub fn bytes(&self) -> &'static [u8] {
+        let virt = memory::phys_to_virt(self.phys).expect("memory not initialized");
+        unsafe { slice::from_raw_parts(virt.as_ptr(), self.header.length as usize) }
+    }
+
+    /// Reads a value of type `T` at `offset` bytes from the start of the table
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        let bytes = self.bytes();
+        assert!(offset + mem::size_of::<T>() <= bytes.len());
+        unsafe { ptr::read_unaligned(bytes.as_ptr().add(offset).cast()) }
+    }
+}
+
+/// The physical address of the root table (RSDT or XSDT), along with the size of its entries
+static ROOT: Once<Option<(PhysAddr, usize)>> = Once::new();
+
+/// Reads a value of type `T` from physical memory
+///
+/// Panics if the memory module has not been initialized
+unsafe fn read_phys<T: Copy>(phys: PhysAddr) -> T {
+    let virt = memory::phys_to_virt(phys).expect("memory not initialized");
+    unsafe { ptr::read_unaligned(virt.as_ptr()) }
+}
+
+fn checksum_ok(phys: PhysAddr, len: usize) -> bool {
+    let virt = memory::phys_to_virt(phys).expect("memory not initialized");
+    let bytes = unsafe { slice::from_raw_parts(virt.as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) == 0
+}
+
+/// Searches the EBDA and the BIOS read-only area for the RSDP
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = (unsafe { read_phys::<u16>(PhysAddr::new(0x40e)) } as u64) << 4;
+    let candidates = (ebda..ebda + 1024)
+        .step_by(16)
+        .chain((0xe0000..0x100000).step_by(16));
+
+    for addr in candidates {
+        let phys = PhysAddr::new(addr);
+        let rsdp: Rsdp = unsafe { read_phys(phys) };
+        if &rsdp.signature == b"RSD PTR " && checksum_ok(phys, 20) {
+            return Some(rsdp);
+        }
+    }
+    None
+}
+
+fn root() -> Option<(PhysAddr, usize)> {
+    // Don't cache a failed search just because physical memory isn't accessible yet
+    memory::phys_to_virt(PhysAddr::new(0))?;
+    *ROOT.call_once(|| {
+        let rsdp = find_rsdp()?;
+        if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+            Some((PhysAddr::new(rsdp.xsdt_address), 8))
+        } else {
+            Some((PhysAddr::new(rsdp.rsdt_address as u64), 4))
+        }
+    })
+}
+
+/// Iterates over every table referenced by the RSDT/XSDT
+///
+/// Yields nothing if no ACPI tables were found, or if the memory module has not been initialized
+pub fn tables() -> impl Iterator<Item = Table> {
+    let (root_phys, entry_size, entries) = match root() {
+        Some((phys, entry_size)) => {
+            let header: SdtHeader = unsafe { read_phys(phys) };
+            let entries =
+                (header.length as usize).saturating_sub(mem::size_of::<SdtHeader>()) / entry_size;
+            (phys, entry_size, entries)
+        }
+        None => (PhysAddr::new(0), 4, 0),
+    };
+
+    (0..entries).filter_map(move |i| {
+        let entry = root_phys + (mem::size_of::<SdtHeader>() + i * entry_size) as u64;
+        let phys = match entry_size {
+            8 => unsafe { read_phys::<u64>(entry) },
+            _ => u64::from(unsafe { read_phys::<u32>(entry) }),
+        };
+        let phys = PhysAddr::new(phys);
+        let header: SdtHeader = unsafe { read_phys(phys) };
+        checksum_ok(phys, header.length as usize).then_some(Table { phys, header })
+    })
+}
+
+/// Finds the first table with the given signature, for example `b"HPET"`
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    tables().find(|t| &t.header.signature == signature)
+}
@@ -1,22 +1,34 @@
 use core::{
     alloc::GlobalAlloc,
     ptr::{self},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
-use talc::{ClaimOnOom, Span, Talc, Talck};
+use lock_api::RawMutex;
+use talc::{ErrOnOom, Span, Talc, Talck};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
+use crate::{
+    config,
+    sync::{LockClass, RawIrqSpinLock},
+};
+
 pub mod bump;
 pub mod linked_list;
 
 /// The virtual address of the heap
 const HEAP_START: usize = 0x_4444_4444_0000;
+/// The size of the heap, unless the boot configuration sets another
 pub const HEAP_SIZE: usize = 100 * 1024;
+/// The largest heap the virtual address space reserved for it can hold
+pub const MAX_HEAP_SIZE: usize = 1 << 30;
+
+static MAPPED_HEAP_SIZE: AtomicUsize = AtomicUsize::new(0);
 
 /// Align the given address `addr` upwards to alignment `align`.
 ///
@@ -25,13 +37,18 @@ fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
+/// Maps the heap and hands it to the allocator. Its size comes from the boot configuration,
+/// capped to `MAX_HEAP_SIZE`
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    let heap_size = config::get()
+        .heap_size
+        .clamp(Size4KiB::SIZE as usize, MAX_HEAP_SIZE);
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE as u64 - 1;
+        let heap_end = heap_start + heap_size as u64 - 1;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -45,13 +62,47 @@ pub fn init_heap(
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
 
+    let heap = Span::from_base_size(HEAP_START as *mut u8, heap_size);
+    unsafe { ALLOCATOR.lock().claim(heap) }.expect("the heap is too small to claim");
+    MAPPED_HEAP_SIZE.store(heap_size, Ordering::Relaxed);
+
     Ok(())
 }
 
+/// The size of the heap, or zero before `init_heap`
+pub fn heap_size() -> usize {
+    MAPPED_HEAP_SIZE.load(Ordering::Relaxed)
+}
+
+/// The lock of the kernel heap: a `RawIrqSpinLock` in its own lock class, since `Talck`
+/// always creates its lock through `RawMutex::INIT`
+pub struct RawHeapLock(RawIrqSpinLock);
+
+unsafe impl RawMutex for RawHeapLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self(RawIrqSpinLock::with_class(LockClass::new("ALLOCATOR")));
+
+    type GuardMarker = <RawIrqSpinLock as RawMutex>::GuardMarker;
+
+    fn lock(&self) {
+        self.0.lock()
+    }
+
+    fn try_lock(&self) -> bool {
+        self.0.try_lock()
+    }
+
+    unsafe fn unlock(&self) {
+        unsafe { self.0.unlock() }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: Talck<spin::Mutex<()>, ClaimOnOom> =
-    Talc::new(unsafe { ClaimOnOom::new(Span::from_base_size(HEAP_START as *mut _, HEAP_SIZE)) })
-        .lock();
+static ALLOCATOR: Talck<RawHeapLock, ErrOnOom> = Talc::new(ErrOnOom).lock();
 
 pub struct DummyAlloc;
 
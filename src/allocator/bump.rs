@@ -1,8 +1,7 @@
 use core::{alloc::GlobalAlloc, ops::DerefMut, ptr};
 
-use spin::Mutex;
-
 use super::align_up;
+use crate::sync::IrqSpinLock;
 
 struct BumpAllocInner {
     heap_start: usize,
@@ -28,13 +27,13 @@ impl BumpAllocInner {
 }
 
 pub struct BumpAlloc {
-    inner: Mutex<BumpAllocInner>,
+    inner: IrqSpinLock<BumpAllocInner>,
 }
 
 impl BumpAlloc {
     pub const fn new() -> Self {
         Self {
-            inner: Mutex::new(BumpAllocInner::new()),
+            inner: IrqSpinLock::new(BumpAllocInner::new()),
         }
     }
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
@@ -5,9 +5,7 @@ use core::{
     ptr,
 };
 
-use spin::Mutex;
-
-use crate::allocator::align_up;
+use crate::{allocator::align_up, sync::IrqSpinLock};
 
 struct Node {
     size: usize,
@@ -125,13 +123,13 @@ impl LinkedListAllocInner {
 }
 
 pub struct LinkedListAlloc {
-    inner: Mutex<LinkedListAllocInner>,
+    inner: IrqSpinLock<LinkedListAllocInner>,
 }
 
 impl LinkedListAlloc {
     pub const fn new() -> Self {
         Self {
-            inner: Mutex::new(LinkedListAllocInner::new()),
+            inner: IrqSpinLock::new(LinkedListAllocInner::new()),
         }
     }
 
@@ -0,0 +1,95 @@
+use core::{arch::asm, fmt};
+
+/// Maximum number of frames recorded in a `Backtrace`
+const MAX_FRAMES: usize = 32;
+/// The stack bounds aren't known, so frames are only followed this far above the first one
+const MAX_STACK_SPAN: u64 = 1 << 20;
+
+/// The return addresses of the current call stack, found by following the frame pointer chain.
+///
+/// The target spec forces frame pointers, so every kernel function pushes `rbp` on entry
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Captures the call stack of the caller
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        unsafe { Self::from_frame_pointer(rbp) }
+    }
+
+    /// Walks the frame pointer chain starting at `rbp`.
+    ///
+    /// # Safety
+    /// `rbp` must be the frame pointer of a live stack frame
+    pub unsafe fn from_frame_pointer(rbp: u64) -> Self {
+        let mut backtrace = Self {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+
+        let start = rbp;
+        let mut rbp = rbp;
+        while backtrace.len < MAX_FRAMES
+            && rbp != 0
+            && rbp.is_multiple_of(8)
+            && rbp.wrapping_sub(start) < MAX_STACK_SPAN
+        {
+            // A frame starts with the caller's frame pointer, followed by the return address
+            let frame = rbp as *const u64;
+            let (next, return_address) = unsafe { (frame.read(), frame.add(1).read()) };
+            if return_address == 0 {
+                break;
+            }
+            backtrace.frames[backtrace.len] = return_address;
+            backtrace.len += 1;
+
+            // The stack grows down, so callers' frames are always at higher addresses
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+
+        backtrace
+    }
+
+    /// Return addresses, innermost first
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Debug for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.frames()).finish()
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, address) in self.frames().iter().enumerate() {
+            writeln!(f, "  #{i:<2} {address:#018x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_backtrace_has_frames() {
+    #[inline(never)]
+    fn inner() -> Backtrace {
+        Backtrace::capture()
+    }
+
+    let outer = Backtrace::capture();
+    let inner = inner();
+    assert!(!outer.frames().is_empty());
+    // `inner` adds at least its own frame on top of this function's
+    assert!(inner.frames().len() > outer.frames().len().min(MAX_FRAMES - 1));
+}
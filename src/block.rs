@@ -0,0 +1,199 @@
+//! Block devices, and the registry drivers add the devices they find to.
+//!
+//! A block device is an array of fixed size blocks, which are read and written whole.
+//! Drivers register each device under a name such as `ata0`, through which filesystems
+//! find it.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+
+use crate::sync::{LockClass, Mutex};
+
+pub mod ahci;
+pub mod ata;
+pub mod cache;
+pub mod nvme;
+pub mod partition;
+pub mod ramdisk;
+#[cfg(any(test, feature = "block-testing"))]
+pub mod testing;
+pub mod virtio;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The blocks are past the end of the device
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks
+    BadBufferSize,
+    /// The device can't be written to
+    ReadOnly,
+    /// There is no medium in the drive
+    NoMedium,
+    /// There was no memory left for the buffers of the transfer
+    NoMemory,
+    /// The device didn't complete the command in time
+    Timeout,
+    /// The device reported an error, with its device specific error code
+    Device(u8),
+    /// The device needs something the driver doesn't support, such as a block size
+    Unsupported,
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// The size of a block in bytes, usually 512
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Reads the blocks starting at `lba` into `buf`, which must be a whole number of blocks
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf`, which must be a whole number of blocks, to the blocks starting at `lba`
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes previous writes persistent, if the device caches them
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// The size of the device in bytes
+    fn size(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
+
+/// Checks that an access of `len` bytes at `lba` is within `device`, returning the number of
+/// blocks it covers
+pub fn check_access(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    if !len.is_multiple_of(device.block_size()) {
+        return Err(BlockError::BadBufferSize);
+    }
+    let count = (len / device.block_size()) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Reads `count` blocks from `lba` into a new buffer
+pub fn read_to_vec(
+    device: &dyn BlockDevice,
+    lba: u64,
+    count: usize,
+) -> Result<Vec<u8>, BlockError> {
+    let mut buf = vec![0; count * device.block_size()];
+    device.read_blocks(lba, &mut buf)?;
+    Ok(buf)
+}
+
+/// Reads `buf` from `device` at the byte `offset`, which doesn't need to be block aligned
+pub fn read_bytes(device: &dyn BlockDevice, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+    let block_size = device.block_size();
+    let mut block = Vec::new();
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let lba = pos / block_size as u64;
+        let start = (pos % block_size as u64) as usize;
+        let rest = buf.len() - done;
+        if start == 0 && rest >= block_size {
+            // Whole blocks are read in place
+            let len = rest - rest % block_size;
+            device.read_blocks(lba, &mut buf[done..done + len])?;
+            done += len;
+        } else {
+            block.resize(block_size, 0);
+            device.read_blocks(lba, &mut block)?;
+            let len = rest.min(block_size - start);
+            buf[done..done + len].copy_from_slice(&block[start..start + len]);
+            done += len;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `buf` to `device` at the byte `offset`, reading the blocks it only partly covers
+/// first
+pub fn write_bytes(device: &dyn BlockDevice, offset: u64, buf: &[u8]) -> Result<(), BlockError> {
+    let block_size = device.block_size();
+    let mut block = Vec::new();
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let lba = pos / block_size as u64;
+        let start = (pos % block_size as u64) as usize;
+        let rest = buf.len() - done;
+        if start == 0 && rest >= block_size {
+            let len = rest - rest % block_size;
+            device.write_blocks(lba, &buf[done..done + len])?;
+            done += len;
+        } else {
+            block.resize(block_size, 0);
+            device.read_blocks(lba, &mut block)?;
+            let len = rest.min(block_size - start);
+            block[start..start + len].copy_from_slice(&buf[done..done + len]);
+            device.write_blocks(lba, &block)?;
+            done += len;
+        }
+    }
+    Ok(())
+}
+
+static DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> =
+    Mutex::with_class(LockClass::new("BLOCK_DEVICES"), Vec::new());
+
+/// Adds `device` to the registry under `name`, replacing any device with the same name
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) {
+    log::info!(
+        "block: {name}: {} blocks of {} bytes{}",
+        device.block_count(),
+        device.block_size(),
+        if device.is_read_only() {
+            ", read-only"
+        } else {
+            ""
+        }
+    );
+    let mut devices = DEVICES.lock();
+    devices.retain(|(other, _)| other != name);
+    devices.push((name.to_string(), device));
+}
+
+/// The name of disk `index` of a driver naming them `prefix` followed by letters, as Linux
+/// does: `a` to `z`, then `aa`, `ab` and so on
+pub fn disk_name(prefix: &str, mut index: usize) -> String {
+    let mut letters = Vec::new();
+    loop {
+        letters.push(b'a' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    letters.reverse();
+    let mut name = prefix.to_string();
+    name.extend(letters.into_iter().map(char::from));
+    name
+}
+
+/// The device registered under `name`
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(other, _)| other == name)
+        .map(|(_, device)| device.clone())
+}
+
+/// Every registered device, in the order they were registered
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES.lock().clone()
+}
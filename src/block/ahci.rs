@@ -0,0 +1,513 @@
+//! SATA drives on AHCI controllers, such as the one of QEMU's q35 machine.
+//!
+//! The controller's registers are in the memory BAR 5, the ABAR, with a block of registers
+//! per port. Each port gets a command list and a FIS receive area in DMA memory, and commands
+//! are built as a register FIS in a command table, with a PRDT entry pointing at the data.
+//! Only the first command slot is used, so commands on a port run one at a time, and wait
+//! for the controller's interrupt before checking the task file of the port.
+//!
+//! Drives are registered as `sda`, `sdb` and so on, in the order they are found. They use
+//! LBA48 commands, or LBA28 ones if IDENTIFY says they lack LBA48. ATAPI devices on SATA ports
+//! aren't supported.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    ptr,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use x86_64::VirtAddr;
+
+use super::{ata::Identify, BlockDevice, BlockError};
+use crate::{
+    dma::DmaBuffer,
+    irq::{self, IrqReturn},
+    memory,
+    pci::{self, Bar, DeviceId, PciDevice},
+    sync::{IrqSpinLock, LockClass, Mutex, RawIrqSpinLock, WaitQueue},
+    time::Deadline,
+};
+
+const ABAR: usize = 5;
+
+// Generic host control registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0c;
+
+/// Supports staggered spin-up, so ports must be spun up by software
+const CAP_SSS: u32 = 1 << 27;
+const GHC_AE: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+
+const PORTS: usize = 32;
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+
+// Port registers, as offsets from the port's block
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_POD: u32 = 1 << 2;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+/// Device to host register FIS received, which ends most commands
+const IS_DHRS: u32 = 1 << 0;
+/// PIO setup FIS received
+const IS_PSS: u32 = 1 << 1;
+/// Task file error
+const IS_TFES: u32 = 1 << 30;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+/// A device is present and communication is established, in the detection field of SSTS
+const SSTS_DET_PRESENT: u32 = 3;
+/// The interface is active, in the power management field of SSTS
+const SSTS_IPM_ACTIVE: u32 = 1;
+
+const SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// Marks a register FIS as a command, rather than a control register update
+const FIS_COMMAND: u8 = 1 << 7;
+/// The length of a register FIS, in dwords
+const FIS_REG_H2D_DWORDS: u32 = 5;
+
+const ATA_READ_DMA: u8 = 0xc8;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA: u8 = 0xca;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE: u8 = 0xe7;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_IDENTIFY: u8 = 0xec;
+
+// The layout of a port's DMA memory: the command list, with 32 headers of 32 bytes, the FIS
+// receive area and the command table of the first slot, with its single PRDT entry
+const COMMAND_LIST: usize = 0;
+const FIS_AREA: usize = 0x400;
+const COMMAND_TABLE: usize = 0x800;
+const PORT_MEMORY: usize = COMMAND_TABLE + PRDT + 16;
+/// Where the PRDT starts in the command table
+const PRDT: usize = 0x80;
+
+/// Writes the data, in the command header
+const HEADER_WRITE: u32 = 1 << 6;
+/// Interrupts when the PRDT entry is done, in its byte count
+const PRDT_INTERRUPT: u32 = 1 << 31;
+
+const SECTOR_SIZE: usize = 512;
+/// The most sectors of a single command, whose data the single PRDT entry describes and so
+/// has to be one contiguous DMA buffer
+const MAX_SECTORS: usize = 128;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a port may take to establish communication once its device is spun up
+const SPIN_UP_TIMEOUT: Duration = Duration::from_millis(10);
+
+static DRIVER: pci::Driver = pci::Driver {
+    name: "ahci",
+    ids: &[DeviceId::class(0x01, 0x06).prog_if(0x01)],
+    probe,
+};
+
+/// The controllers found, whose port interrupts the handler acknowledges
+static CONTROLLERS: IrqSpinLock<Vec<Arc<Hba>>> = IrqSpinLock::from_raw(
+    RawIrqSpinLock::with_class(LockClass::new("AHCI_CONTROLLERS")),
+    Vec::new(),
+);
+
+static NEXT_NAME: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers the driver for AHCI controllers
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+fn interrupt() -> IrqReturn {
+    let mut result = IrqReturn::NotMine;
+    for hba in CONTROLLERS.lock().iter() {
+        if hba.interrupt() {
+            result = IrqReturn::Handled;
+        }
+    }
+    result
+}
+
+/// The registers of a controller
+struct Hba {
+    base: VirtAddr,
+    /// Notified on each interrupt of the controller
+    completed: WaitQueue,
+    /// The ports which reported a task file error since their last command was issued
+    errors: AtomicU32,
+}
+
+impl Hba {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + register as u64).as_ptr()) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + register as u64).as_mut_ptr(), value) }
+    }
+
+    fn port_register(port: usize, register: usize) -> usize {
+        PORT_BASE + port * PORT_SIZE + register
+    }
+
+    /// Acknowledges the interrupts of every port, returning whether there were any
+    fn interrupt(&self) -> bool {
+        let pending = self.read(HBA_IS);
+        if pending == 0 {
+            return false;
+        }
+        for port in (0..PORTS).filter(|port| pending & (1 << port) != 0) {
+            let register = Self::port_register(port, PX_IS);
+            let status = self.read(register);
+            if status & IS_TFES != 0 {
+                self.errors.fetch_or(1 << port, Ordering::Relaxed);
+            }
+            self.write(register, status);
+        }
+        // The port interrupts must be cleared first, or the controller raises them again
+        self.write(HBA_IS, pending);
+        self.completed.notify_all();
+        true
+    }
+}
+
+fn probe(device: &PciDevice) -> bool {
+    let Some(Bar::Memory { address, .. }) = device.bars[ABAR] else {
+        return false;
+    };
+    let Some(base) = memory::phys_to_virt(address) else {
+        return false;
+    };
+    device.enable();
+    device.set_command(device.command() & !pci::COMMAND_INTX_DISABLE);
+
+    let hba = Arc::new(Hba {
+        base,
+        completed: WaitQueue::new(),
+        errors: AtomicU32::new(0),
+    });
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_AE);
+    CONTROLLERS.lock().push(hba.clone());
+    let line = device.interrupt_line;
+    match irq::register(line, interrupt) {
+        Ok(()) | Err(irq::IrqError::AlreadyRegistered) => {}
+        Err(err) => log::warn!("ahci: can't use IRQ {line}: {err:?}"),
+    }
+    hba.write(HBA_IS, u32::MAX);
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_IE);
+
+    let implemented = hba.read(HBA_PI);
+    let staggered_spin_up = hba.read(HBA_CAP) & CAP_SSS != 0;
+    for number in (0..PORTS).filter(|port| implemented & (1 << port) != 0) {
+        let port = match Port::new(hba.clone(), number, staggered_spin_up) {
+            Ok(Some(port)) => port,
+            Ok(None) => continue,
+            Err(err) => {
+                log::warn!("ahci: port {number}: {err:?}");
+                continue;
+            }
+        };
+        let identify = match port.identify() {
+            Ok(identify) => identify,
+            Err(err) => {
+                log::warn!("ahci: port {number}: IDENTIFY failed: {err:?}");
+                continue;
+            }
+        };
+        log::info!("ahci: drive {:?} on port {number}", identify.model());
+
+        let index = NEXT_NAME.fetch_add(1, Ordering::Relaxed);
+        let name = super::disk_name("sd", index);
+        super::register(&name, Arc::new(AhciDrive { port, identify }));
+    }
+    true
+}
+
+/// A port with a drive
+struct Port {
+    hba: Arc<Hba>,
+    number: usize,
+    /// The command list, FIS receive area and command table, locked for a whole command
+    memory: Mutex<DmaBuffer>,
+}
+
+impl Port {
+    /// Sets up port `number`, returning `None` if it has no ATA drive
+    fn new(
+        hba: Arc<Hba>,
+        number: usize,
+        staggered_spin_up: bool,
+    ) -> Result<Option<Self>, BlockError> {
+        let port = Self {
+            hba,
+            number,
+            memory: Mutex::new(DmaBuffer::new(PORT_MEMORY).map_err(|_| BlockError::NoMemory)?),
+        };
+        if staggered_spin_up {
+            port.write(PX_CMD, port.read(PX_CMD) | CMD_SUD | CMD_POD);
+            let deadline = Deadline::after(SPIN_UP_TIMEOUT);
+            while port.read(PX_SSTS) & 0xf != SSTS_DET_PRESENT && !deadline.has_passed() {
+                core::hint::spin_loop();
+            }
+        }
+
+        let status = port.read(PX_SSTS);
+        if status & 0xf != SSTS_DET_PRESENT || (status >> 8) & 0xf != SSTS_IPM_ACTIVE {
+            return Ok(None);
+        }
+        let signature = port.read(PX_SIG);
+        if signature != SIG_ATA {
+            log::debug!("ahci: port {number}: unsupported signature {signature:#010x}");
+            return Ok(None);
+        }
+
+        port.stop()?;
+        let memory = port.memory.lock();
+        let command_list = memory.phys_at(COMMAND_LIST).as_u64();
+        let fis_area = memory.phys_at(FIS_AREA).as_u64();
+        drop(memory);
+        port.write(PX_CLB, command_list as u32);
+        port.write(PX_CLBU, (command_list >> 32) as u32);
+        port.write(PX_FB, fis_area as u32);
+        port.write(PX_FBU, (fis_area >> 32) as u32);
+        port.write(PX_SERR, u32::MAX);
+        port.write(PX_IS, u32::MAX);
+        port.write(PX_IE, IS_DHRS | IS_PSS | IS_TFES);
+        port.start()?;
+        Ok(Some(port))
+    }
+
+    fn read(&self, register: usize) -> u32 {
+        self.hba.read(Hba::port_register(self.number, register))
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        self.hba
+            .write(Hba::port_register(self.number, register), value)
+    }
+
+    /// Polls until the bits of `mask` are clear in `register`
+    fn wait_clear(&self, register: usize, mask: u32) -> Result<(), BlockError> {
+        let deadline = Deadline::after(TIMEOUT);
+        while self.read(register) & mask != 0 {
+            if deadline.has_passed() {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Stops processing the command list and receiving FISes
+    fn stop(&self) -> Result<(), BlockError> {
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_ST);
+        self.wait_clear(PX_CMD, CMD_CR)?;
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_FRE);
+        self.wait_clear(PX_CMD, CMD_FR)
+    }
+
+    /// Starts receiving FISes and processing the command list, once the drive is idle
+    fn start(&self) -> Result<(), BlockError> {
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_FRE);
+        self.wait_clear(PX_TFD, TFD_BSY | TFD_DRQ)?;
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_ST);
+        Ok(())
+    }
+
+    /// Restarts the port after a failed command, which stops it
+    fn recover(&self) {
+        let result = self.stop().and_then(|()| {
+            self.write(PX_SERR, u32::MAX);
+            self.write(PX_IS, u32::MAX);
+            self.start()
+        });
+        if let Err(err) = result {
+            log::warn!("ahci: port {}: can't restart: {err:?}", self.number);
+        }
+    }
+
+    /// Runs `command` on `count` sectors at `lba`, transferring `data`, whose first `len`
+    /// bytes are used, to or from the drive
+    fn issue(
+        &self,
+        command: u8,
+        lba: u64,
+        count: u16,
+        data: Option<(&DmaBuffer, usize)>,
+        write: bool,
+    ) -> Result<(), BlockError> {
+        let mut memory = self.memory.lock();
+        self.wait_clear(PX_TFD, TFD_BSY | TFD_DRQ)?;
+
+        let table = memory.phys_at(COMMAND_TABLE).as_u64();
+        let mut flags = FIS_REG_H2D_DWORDS;
+        if write {
+            flags |= HEADER_WRITE;
+        }
+        if data.is_some() {
+            flags |= 1 << 16;
+        }
+        let header = &mut memory[COMMAND_LIST..COMMAND_LIST + 32];
+        header.fill(0);
+        header[0..4].copy_from_slice(&flags.to_le_bytes());
+        header[8..16].copy_from_slice(&table.to_le_bytes());
+
+        let table = &mut memory[COMMAND_TABLE..PORT_MEMORY];
+        table.fill(0);
+        let lba = lba.to_le_bytes();
+        // LBA28 commands take the top bits of the address in the device register
+        let device = match command {
+            ATA_READ_DMA | ATA_WRITE_DMA => (1 << 6) | (lba[3] & 0xf),
+            _ => 1 << 6,
+        };
+        let fis = [
+            FIS_TYPE_REG_H2D,
+            FIS_COMMAND,
+            command,
+            0,
+            lba[0],
+            lba[1],
+            lba[2],
+            device,
+            lba[3],
+            lba[4],
+            lba[5],
+            0,
+            count as u8,
+            (count >> 8) as u8,
+        ];
+        table[..fis.len()].copy_from_slice(&fis);
+        if let Some((data, len)) = data {
+            let entry = &mut table[PRDT..PRDT + 16];
+            entry[0..8].copy_from_slice(&data.phys().as_u64().to_le_bytes());
+            let byte_count = (len as u32 - 1) | PRDT_INTERRUPT;
+            entry[12..16].copy_from_slice(&byte_count.to_le_bytes());
+        }
+
+        let bit = 1 << self.number;
+        self.write(PX_IS, u32::MAX);
+        self.hba.errors.fetch_and(!bit, Ordering::Relaxed);
+        self.write(PX_CI, 1);
+        let status = self
+            .hba
+            .completed
+            .wait_until_deadline(Deadline::after(TIMEOUT), || {
+                let failed = self.hba.errors.load(Ordering::Relaxed) & bit != 0
+                    || self.read(PX_IS) & IS_TFES != 0;
+                (failed || self.read(PX_CI) & 1 == 0).then(|| self.read(PX_TFD))
+            });
+        match status {
+            Some(tfd) if tfd & TFD_ERR == 0 => Ok(()),
+            Some(tfd) => {
+                self.recover();
+                Err(BlockError::Device((tfd >> 8) as u8))
+            }
+            None => {
+                self.recover();
+                Err(BlockError::Timeout)
+            }
+        }
+    }
+
+    fn identify(&self) -> Result<Identify, BlockError> {
+        let buffer = DmaBuffer::new(SECTOR_SIZE).map_err(|_| BlockError::NoMemory)?;
+        self.issue(ATA_IDENTIFY, 0, 0, Some((&buffer, SECTOR_SIZE)), false)?;
+        let data = buffer[..SECTOR_SIZE].try_into().unwrap();
+        Ok(Identify::parse(data))
+    }
+}
+
+/// A SATA drive on an AHCI port
+pub struct AhciDrive {
+    port: Port,
+    identify: Identify,
+}
+
+impl AhciDrive {
+    pub fn identify(&self) -> &Identify {
+        &self.identify
+    }
+
+    /// Picks the LBA48 form of a command if the drive supports it
+    fn command(&self, lba28: u8, lba48: u8) -> u8 {
+        if self.identify.lba48 {
+            lba48
+        } else {
+            lba28
+        }
+    }
+}
+
+impl BlockDevice for AhciDrive {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.identify.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_access(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            let data = DmaBuffer::new(chunk.len()).map_err(|_| BlockError::NoMemory)?;
+            let count = (chunk.len() / SECTOR_SIZE) as u16;
+            self.port.issue(
+                self.command(ATA_READ_DMA, ATA_READ_DMA_EXT),
+                lba,
+                count,
+                Some((&data, chunk.len())),
+                false,
+            )?;
+            chunk.copy_from_slice(&data[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        super::check_access(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            let mut data = DmaBuffer::new(chunk.len()).map_err(|_| BlockError::NoMemory)?;
+            data[..chunk.len()].copy_from_slice(chunk);
+            let count = (chunk.len() / SECTOR_SIZE) as u16;
+            self.port.issue(
+                self.command(ATA_WRITE_DMA, ATA_WRITE_DMA_EXT),
+                lba,
+                count,
+                Some((&data, chunk.len())),
+                true,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let command = self.command(ATA_FLUSH_CACHE, ATA_FLUSH_CACHE_EXT);
+        self.port.issue(command, 0, 0, None, false)
+    }
+}
@@ -0,0 +1,680 @@
+//! ATA and ATAPI drives on the IDE controller, driven with PIO.
+//!
+//! Each of the two channels has a master and a slave drive, which share the channel's
+//! registers. Drives are found with IDENTIFY (or IDENTIFY PACKET for ATAPI) while the
+//! channel's interrupt is masked, after which commands wait for the channel's IRQ, 14 and 15
+//! in compatibility mode, before each block of data and at the end.
+//!
+//! ATA drives of the first controller are registered as `ata0` to `ata3`, in the order primary
+//! master, primary slave, secondary master and secondary slave, and those of the next ones
+//! from `ata4` on. They use LBA28 commands when they can and LBA48
+//! past the first 128GiB. ATAPI drives are read-only, with 2048 byte blocks read through
+//! SCSI commands sent in packets.
+
+use alloc::{format, sync::Arc};
+use core::{
+    sync::atomic::{AtomicU16, AtomicU8, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use x86_64::instructions::port::Port;
+
+use super::{BlockDevice, BlockError};
+use crate::{
+    irq::{self, IrqReturn},
+    pci::{self, Bar, DeviceId, PciDevice},
+    sync::{Mutex, Semaphore},
+    time::Deadline,
+};
+
+const PRIMARY_IO: u16 = 0x1f0;
+const PRIMARY_CONTROL: u16 = 0x3f6;
+const PRIMARY_IRQ: u8 = 14;
+const SECONDARY_IO: u16 = 0x170;
+const SECONDARY_CONTROL: u16 = 0x376;
+const SECONDARY_IRQ: u8 = 15;
+
+// Registers, as offsets from the I/O base
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_FEATURES: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+/// Masks the channel's interrupt, in the device control register
+const CONTROL_NIEN: u8 = 1 << 1;
+
+/// The bus master status register of a channel, as an offset from its bus master registers
+const BM_STATUS: u16 = 2;
+/// Set when the channel raised its interrupt, and cleared by writing it back
+const BM_STATUS_INTERRUPT: u8 = 1 << 2;
+
+const DRIVE_LBA: u8 = 1 << 6;
+/// Bits which must always be set in the drive register
+const DRIVE_OBSOLETE: u8 = 0b1010_0000;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_PACKET: u8 = 0xa0;
+const CMD_IDENTIFY_PACKET: u8 = 0xa1;
+const CMD_FLUSH_CACHE: u8 = 0xe7;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xea;
+const CMD_IDENTIFY: u8 = 0xec;
+
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_12: u8 = 0xa8;
+/// The sense key, in the upper half of the error register, when no medium is inserted
+const SENSE_NOT_READY: u8 = 0x2;
+/// The sense key of the first command after a disc was inserted or the drive reset, which
+/// fails it
+const SENSE_UNIT_ATTENTION: u8 = 0x6;
+
+/// The signature left in the LBA mid and high registers by ATAPI drives
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xeb);
+
+const SECTOR_SIZE: usize = 512;
+const ATAPI_SECTOR_SIZE: usize = 2048;
+/// The most sectors transferred by a single command
+const MAX_SECTORS: usize = 256;
+const MAX_ATAPI_SECTORS: usize = 16;
+/// Sectors past this one need LBA48
+const LBA28_LIMIT: u64 = 1 << 28;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The most controllers driven, each with two channels
+const MAX_CONTROLLERS: usize = 4;
+const MAX_CHANNELS: usize = 2 * MAX_CONTROLLERS;
+
+/// The state shared with a channel's interrupt handler
+struct Completion {
+    /// The I/O base of the channel, 0 until it is set up
+    io_base: AtomicU16,
+    /// The bus master registers of the channel, 0 if the controller has none
+    bus_master: AtomicU16,
+    status: AtomicU8,
+    done: Semaphore,
+}
+
+impl Completion {
+    const fn new() -> Self {
+        Self {
+            io_base: AtomicU16::new(0),
+            bus_master: AtomicU16::new(0),
+            status: AtomicU8::new(0),
+            done: Semaphore::new(0),
+        }
+    }
+
+    fn interrupt(&self) -> IrqReturn {
+        let io_base = self.io_base.load(Ordering::Relaxed);
+        if io_base == 0 {
+            return IrqReturn::NotMine;
+        }
+        // Native mode channels may share their line with other devices, and only the bus
+        // master status tells whether the channel raised the interrupt. Compatibility mode
+        // channels have an IRQ of their own
+        let bus_master = self.bus_master.load(Ordering::Relaxed);
+        if bus_master != 0 {
+            let mut bm_status = Port::<u8>::new(bus_master + BM_STATUS);
+            let status = unsafe { bm_status.read() };
+            if status & BM_STATUS_INTERRUPT == 0 {
+                return IrqReturn::NotMine;
+            }
+            unsafe { bm_status.write(status) };
+        }
+        // Reading the status register acknowledges the interrupt
+        let status = unsafe { Port::<u8>::new(io_base + REG_STATUS).read() };
+        self.status.store(status, Ordering::Relaxed);
+        self.done.release();
+        IrqReturn::Handled
+    }
+}
+
+/// Every channel's state, two for each controller in the order they were found
+static COMPLETIONS: [Completion; MAX_CHANNELS] = [const { Completion::new() }; MAX_CHANNELS];
+/// The number of controllers found
+static CONTROLLERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The interrupt handler of the channel whose state is `COMPLETIONS[N]`
+fn interrupt<const N: usize>() -> IrqReturn {
+    COMPLETIONS[N].interrupt()
+}
+
+const HANDLERS: [irq::Handler; MAX_CHANNELS] = [
+    interrupt::<0>,
+    interrupt::<1>,
+    interrupt::<2>,
+    interrupt::<3>,
+    interrupt::<4>,
+    interrupt::<5>,
+    interrupt::<6>,
+    interrupt::<7>,
+];
+
+struct Channel {
+    io: u16,
+    control: u16,
+    completion: &'static Completion,
+    /// Held for a whole command, since both drives use the same registers
+    lock: Mutex<()>,
+}
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.io + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.io + register).write(value) }
+    }
+
+    /// Reads the status without acknowledging an interrupt
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    fn set_control(&self, value: u8) {
+        unsafe { Port::new(self.control).write(value) }
+    }
+
+    /// Waits the 400ns the drive needs to update its status after a command or selection
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn select(&self, slave: bool, bits: u8) {
+        self.write(REG_DRIVE, DRIVE_OBSOLETE | u8::from(slave) << 4 | bits);
+        self.delay();
+    }
+
+    fn command(&self, command: u8) {
+        self.write(REG_COMMAND, command);
+        self.delay();
+    }
+
+    /// Polls the status until the drive isn't busy
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        let deadline = Deadline::after(TIMEOUT);
+        loop {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            if deadline.has_passed() {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Fails with the error register if `status` reports an error
+    fn check(&self, status: u8) -> Result<u8, BlockError> {
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            Err(BlockError::Device(self.read(REG_ERROR)))
+        } else {
+            Ok(status)
+        }
+    }
+
+    /// Polls until the drive is ready to transfer data
+    fn wait_drq(&self) -> Result<(), BlockError> {
+        let status = self.check(self.wait_not_busy()?)?;
+        if status & STATUS_DRQ == 0 {
+            return Err(BlockError::Device(self.read(REG_ERROR)));
+        }
+        Ok(())
+    }
+
+    /// Forgets interrupts left over from previous commands
+    fn clear_interrupts(&self) {
+        while self.completion.done.try_acquire() {}
+    }
+
+    /// Waits for the channel's interrupt, returning the status read by the handler
+    fn wait_interrupt(&self) -> Result<u8, BlockError> {
+        let status = if self.completion.done.acquire_timeout(TIMEOUT) {
+            self.completion.status.load(Ordering::Relaxed)
+        } else {
+            // The interrupt may have been lost, which is fine if the drive is ready
+            let status = self.alt_status();
+            if status & STATUS_BSY != 0 {
+                return Err(BlockError::Timeout);
+            }
+            status
+        };
+        self.check(status)
+    }
+
+    fn read_data(&self, buf: &mut [u8]) {
+        let mut data = Port::<u16>::new(self.io + REG_DATA);
+        for word in buf.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, buf: &[u8]) {
+        let mut data = Port::<u16>::new(self.io + REG_DATA);
+        for word in buf.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) }
+        }
+    }
+
+    /// Identifies the drive, polling since the interrupt is masked while probing
+    fn identify(&self, slave: bool) -> Option<(DriveKind, Identify)> {
+        self.select(slave, 0);
+        for register in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+            self.write(register, 0);
+        }
+        self.command(CMD_IDENTIFY);
+        // A floating bus reads as all ones, and a missing drive as zero
+        if matches!(self.alt_status(), 0 | 0xff) {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+
+        let kind = match (self.read(REG_LBA_MID), self.read(REG_LBA_HIGH)) {
+            (0, 0) => DriveKind::Ata,
+            ATAPI_SIGNATURE => {
+                self.command(CMD_IDENTIFY_PACKET);
+                DriveKind::Atapi
+            }
+            // SATA drives behind a legacy interface, among others
+            _ => return None,
+        };
+        self.wait_drq().ok()?;
+
+        let mut identify = [0; SECTOR_SIZE];
+        self.read_data(&mut identify);
+        Some((kind, Identify::parse(&identify)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriveKind {
+    Ata,
+    Atapi,
+}
+
+/// The interesting parts of the IDENTIFY data
+#[derive(Debug, Clone)]
+pub struct Identify {
+    model: [u8; 40],
+    serial: [u8; 20],
+    /// Whether the drive supports LBA48 commands
+    pub lba48: bool,
+    /// The number of sectors of ATA drives
+    pub sectors: u64,
+}
+
+impl Identify {
+    pub(super) fn parse(data: &[u8; SECTOR_SIZE]) -> Self {
+        let word = |i: usize| u16::from_le_bytes([data[2 * i], data[2 * i + 1]]);
+        // Strings hold two characters per word, the first one in the high byte
+        let string = |words: core::ops::Range<usize>, out: &mut [u8]| {
+            for (chars, i) in out.chunks_exact_mut(2).zip(words) {
+                chars.copy_from_slice(&word(i).to_be_bytes());
+            }
+        };
+
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (100..104)
+                .rev()
+                .fold(0, |acc, i| acc << 16 | u64::from(word(i)))
+        } else {
+            u64::from(word(61)) << 16 | u64::from(word(60))
+        };
+        let mut identify = Self {
+            model: [0; 40],
+            serial: [0; 20],
+            lba48,
+            sectors,
+        };
+        string(27..47, &mut identify.model);
+        string(10..20, &mut identify.serial);
+        identify
+    }
+
+    pub fn model(&self) -> &str {
+        core::str::from_utf8(&self.model).unwrap_or("").trim()
+    }
+
+    pub fn serial(&self) -> &str {
+        core::str::from_utf8(&self.serial).unwrap_or("").trim()
+    }
+}
+
+/// An ATA or ATAPI drive
+pub struct AtaDrive {
+    channel: Arc<Channel>,
+    slave: bool,
+    kind: DriveKind,
+    identify: Identify,
+    block_count: u64,
+}
+
+impl AtaDrive {
+    pub fn kind(&self) -> DriveKind {
+        self.kind
+    }
+
+    pub fn identify(&self) -> &Identify {
+        &self.identify
+    }
+
+    /// Selects the drive and programs the address and count of an ATA command, returning
+    /// whether it needs the LBA48 variant
+    fn setup(&self, lba: u64, count: usize) -> bool {
+        let channel = &self.channel;
+        let lba48 = lba + count as u64 > LBA28_LIMIT;
+        let bytes = lba.to_le_bytes();
+        if lba48 {
+            channel.select(self.slave, DRIVE_LBA);
+            // The high bytes go first, each register keeping the last two values written
+            channel.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            channel.write(REG_LBA_LOW, bytes[3]);
+            channel.write(REG_LBA_MID, bytes[4]);
+            channel.write(REG_LBA_HIGH, bytes[5]);
+        } else {
+            channel.select(self.slave, DRIVE_LBA | (bytes[3] & 0xf));
+        }
+        // A count of 0 means 256 sectors for LBA28, which is never exceeded
+        channel.write(REG_SECTOR_COUNT, count as u8);
+        channel.write(REG_LBA_LOW, bytes[0]);
+        channel.write(REG_LBA_MID, bytes[1]);
+        channel.write(REG_LBA_HIGH, bytes[2]);
+        lba48
+    }
+
+    fn read_ata(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let channel = &self.channel;
+        let _guard = channel.lock.lock();
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            channel.clear_interrupts();
+            let lba48 = self.setup(lba, chunk.len() / SECTOR_SIZE);
+            channel.command(if lba48 {
+                CMD_READ_SECTORS_EXT
+            } else {
+                CMD_READ_SECTORS
+            });
+
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                if channel.wait_interrupt()? & STATUS_DRQ == 0 {
+                    return Err(BlockError::Device(channel.read(REG_ERROR)));
+                }
+                channel.read_data(sector);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_ata(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let channel = &self.channel;
+        let _guard = channel.lock.lock();
+        for (i, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            channel.clear_interrupts();
+            let lba48 = self.setup(lba, chunk.len() / SECTOR_SIZE);
+            channel.command(if lba48 {
+                CMD_WRITE_SECTORS_EXT
+            } else {
+                CMD_WRITE_SECTORS
+            });
+
+            // The drive interrupts once it is ready for the next sector, and at the end
+            channel.wait_drq()?;
+            for (i, sector) in chunk.chunks_exact(SECTOR_SIZE).enumerate() {
+                if i > 0 && channel.wait_interrupt()? & STATUS_DRQ == 0 {
+                    return Err(BlockError::Device(channel.read(REG_ERROR)));
+                }
+                channel.write_data(sector);
+            }
+            channel.wait_interrupt()?;
+        }
+        Ok(())
+    }
+
+    /// Sends a SCSI command in a packet, reading the data it returns into `buf`. Returns the
+    /// number of bytes read
+    fn packet(&self, packet: [u8; 12], buf: &mut [u8]) -> Result<usize, BlockError> {
+        let result = match self.send_packet(packet, buf) {
+            Err(BlockError::Device(error)) if error >> 4 == SENSE_UNIT_ATTENTION => {
+                self.send_packet(packet, buf)
+            }
+            result => result,
+        };
+        result.map_err(|err| match err {
+            BlockError::Device(error) if error >> 4 == SENSE_NOT_READY => BlockError::NoMedium,
+            err => err,
+        })
+    }
+
+    fn send_packet(&self, packet: [u8; 12], buf: &mut [u8]) -> Result<usize, BlockError> {
+        let channel = &self.channel;
+        let _guard = channel.lock.lock();
+        channel.clear_interrupts();
+        channel.select(self.slave, 0);
+        // PIO, with at most this many bytes per data block
+        let limit = buf.len().min(0xfffe) as u16;
+        channel.write(REG_FEATURES, 0);
+        channel.write(REG_LBA_MID, limit as u8);
+        channel.write(REG_LBA_HIGH, (limit >> 8) as u8);
+        channel.command(CMD_PACKET);
+        channel.wait_drq()?;
+        channel.write_data(&packet);
+
+        let mut done = 0;
+        loop {
+            let status = channel.wait_interrupt()?;
+            if status & STATUS_DRQ == 0 {
+                return Ok(done);
+            }
+
+            let bytes = usize::from(channel.read(REG_LBA_MID))
+                | usize::from(channel.read(REG_LBA_HIGH)) << 8;
+            let len = bytes.min(buf.len() - done);
+            channel.read_data(&mut buf[done..done + len]);
+            // Drop whatever doesn't fit
+            for _ in (len..bytes).step_by(2) {
+                channel.read_data(&mut [0; 2]);
+            }
+            done += len;
+        }
+    }
+
+    /// The number of blocks of the medium in an ATAPI drive
+    fn read_capacity(&self) -> Result<u64, BlockError> {
+        let mut packet = [0; 12];
+        packet[0] = SCSI_READ_CAPACITY;
+        let mut capacity = [0; 8];
+        if self.packet(packet, &mut capacity)? < capacity.len() {
+            return Err(BlockError::Device(0));
+        }
+        let last_lba = u32::from_be_bytes([capacity[0], capacity[1], capacity[2], capacity[3]]);
+        Ok(u64::from(last_lba) + 1)
+    }
+
+    fn read_atapi(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        for (i, chunk) in buf
+            .chunks_mut(MAX_ATAPI_SECTORS * ATAPI_SECTOR_SIZE)
+            .enumerate()
+        {
+            let lba = lba + (i * MAX_ATAPI_SECTORS) as u64;
+            let count = (chunk.len() / ATAPI_SECTOR_SIZE) as u32;
+            let mut packet = [0; 12];
+            packet[0] = SCSI_READ_12;
+            packet[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+            packet[6..10].copy_from_slice(&count.to_be_bytes());
+            if self.packet(packet, chunk)? < chunk.len() {
+                return Err(BlockError::Device(0));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        match self.kind {
+            DriveKind::Ata => SECTOR_SIZE,
+            DriveKind::Atapi => ATAPI_SECTOR_SIZE,
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        if self.block_count == 0 && self.kind == DriveKind::Atapi {
+            return Err(BlockError::NoMedium);
+        }
+        super::check_access(self, lba, buf.len())?;
+        match self.kind {
+            DriveKind::Ata => self.read_ata(lba, buf),
+            DriveKind::Atapi => self.read_atapi(lba, buf),
+        }
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.kind == DriveKind::Atapi {
+            return Err(BlockError::ReadOnly);
+        }
+        super::check_access(self, lba, buf.len())?;
+        self.write_ata(lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.kind == DriveKind::Atapi {
+            return Ok(());
+        }
+        let channel = &self.channel;
+        let _guard = channel.lock.lock();
+        channel.clear_interrupts();
+        channel.select(self.slave, 0);
+        channel.command(if self.identify.lba48 {
+            CMD_FLUSH_CACHE_EXT
+        } else {
+            CMD_FLUSH_CACHE
+        });
+        channel.wait_interrupt().map(drop)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.kind == DriveKind::Atapi
+    }
+}
+
+static DRIVER: pci::Driver = pci::Driver {
+    name: "ata",
+    ids: &[DeviceId::class(0x01, 0x01)],
+    probe,
+};
+
+/// Registers the driver for IDE controllers
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+fn probe(device: &PciDevice) -> bool {
+    let controller = CONTROLLERS.load(Ordering::Relaxed);
+    if controller == MAX_CONTROLLERS {
+        log::warn!("ata: ignoring controller {}", device.address);
+        return false;
+    }
+    device.set_command(device.command() | pci::COMMAND_IO_SPACE);
+    let bus_master = match device.bars[4] {
+        Some(Bar::Io { port, .. }) => port,
+        _ => 0,
+    };
+
+    let mut found = false;
+    for index in 0..2 {
+        // Each channel is either in native mode, with its ports in BARs and the interrupt
+        // line of the controller, or at the legacy ports and IRQ
+        let native = device.prog_if & (1 << (2 * index)) != 0;
+        let (io, control, line) = match (native, index) {
+            (true, _) => match (device.bars[2 * index], device.bars[2 * index + 1]) {
+                (Some(Bar::Io { port: io, .. }), Some(Bar::Io { port: control, .. })) => {
+                    (io, control + 2, device.interrupt_line)
+                }
+                _ => continue,
+            },
+            (false, 0) => (PRIMARY_IO, PRIMARY_CONTROL, PRIMARY_IRQ),
+            (false, _) => (SECONDARY_IO, SECONDARY_CONTROL, SECONDARY_IRQ),
+        };
+        let bus_master = if bus_master != 0 {
+            bus_master + 8 * index as u16
+        } else {
+            0
+        };
+        found |= probe_channel(2 * controller + index, io, control, bus_master, line);
+    }
+    if found {
+        CONTROLLERS.store(controller + 1, Ordering::Relaxed);
+    }
+    found
+}
+
+/// Sets up the channel whose state is `COMPLETIONS[index]`
+fn probe_channel(index: usize, io: u16, control: u16, bus_master: u16, line: u8) -> bool {
+    let channel = Arc::new(Channel {
+        io,
+        control,
+        completion: &COMPLETIONS[index],
+        lock: Mutex::new(()),
+    });
+
+    channel.set_control(CONTROL_NIEN);
+    let drives = [false, true].map(|slave| channel.identify(slave).map(|d| (slave, d)));
+    if drives.iter().all(Option::is_none) {
+        return false;
+    }
+
+    channel
+        .completion
+        .bus_master
+        .store(bus_master, Ordering::Relaxed);
+    channel.completion.io_base.store(io, Ordering::Relaxed);
+    if let Err(err) = irq::register(line, HANDLERS[index]) {
+        log::warn!("ata: can't use IRQ {line}: {err:?}");
+    }
+    channel.set_control(0);
+
+    for (slave, (kind, identify)) in drives.into_iter().flatten() {
+        let mut drive = AtaDrive {
+            channel: channel.clone(),
+            slave,
+            kind,
+            block_count: identify.sectors,
+            identify,
+        };
+        if kind == DriveKind::Atapi {
+            drive.block_count = drive.read_capacity().unwrap_or(0);
+        }
+        log::info!(
+            "ata: {:?} drive {:?} on channel {index}",
+            kind,
+            drive.identify.model()
+        );
+        let name = format!("ata{}", 2 * index + usize::from(slave));
+        super::register(&name, Arc::new(drive));
+    }
+    true
+}
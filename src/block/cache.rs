@@ -0,0 +1,210 @@
+//! A write-back cache of the blocks of a device.
+//!
+//! `BlockCache` is itself a `BlockDevice`, so filesystems use it in place of the device it
+//! wraps. It keeps up to `capacity` blocks, evicting the least recently used one to make
+//! room. Writes only go to the cache and mark the blocks dirty: they reach the device when
+//! the block is evicted, on `flush`, or when the cache is dropped.
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+
+use super::{BlockDevice, BlockError};
+use crate::sync::{LockClass, Mutex};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Blocks found in the cache
+    pub hits: u64,
+    /// Blocks which had to be read from the device
+    pub misses: u64,
+    /// Dirty blocks written back to the device
+    pub writebacks: u64,
+}
+
+struct Entry {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// When the block was last used, which is its key in `Inner::lru`
+    used: u64,
+}
+
+struct Inner {
+    entries: BTreeMap<u64, Entry>,
+    /// The cached blocks, by when they were last used
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Inner {
+    /// Marks `lba`, which must be cached, as just used
+    fn touch(&mut self, lba: u64) -> &mut Entry {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&lba).unwrap();
+        self.lru.remove(&entry.used);
+        entry.used = self.clock;
+        self.lru.insert(self.clock, lba);
+        entry
+    }
+}
+
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl BlockCache {
+    /// Caches up to `capacity` blocks of `device`
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        Self {
+            device,
+            capacity: capacity.max(1),
+            inner: Mutex::with_class(
+                LockClass::new("BLOCK_CACHE"),
+                Inner {
+                    entries: BTreeMap::new(),
+                    lru: BTreeMap::new(),
+                    clock: 0,
+                    stats: CacheStats::default(),
+                },
+            ),
+        }
+    }
+
+    /// The device under the cache
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().stats
+    }
+
+    /// The number of blocks written to the cache but not to the device yet
+    pub fn dirty_count(&self) -> usize {
+        self.inner
+            .lock()
+            .entries
+            .values()
+            .filter(|entry| entry.dirty)
+            .count()
+    }
+
+    /// Evicts blocks until there is room for one more, writing them back if they are dirty
+    fn make_room(&self, inner: &mut Inner) -> Result<(), BlockError> {
+        while inner.entries.len() >= self.capacity {
+            let Some((&used, &lba)) = inner.lru.first_key_value() else {
+                break;
+            };
+            let entry = &inner.entries[&lba];
+            if entry.dirty {
+                self.device.write_blocks(lba, &entry.data)?;
+                inner.stats.writebacks += 1;
+            }
+            inner.lru.remove(&used);
+            inner.entries.remove(&lba);
+        }
+        Ok(())
+    }
+
+    /// Caches `data` as the contents of `lba`, which isn't cached yet
+    fn insert(
+        &self,
+        inner: &mut Inner,
+        lba: u64,
+        data: Box<[u8]>,
+        dirty: bool,
+    ) -> Result<(), BlockError> {
+        self.make_room(inner)?;
+        inner.clock += 1;
+        let used = inner.clock;
+        inner.entries.insert(lba, Entry { data, dirty, used });
+        inner.lru.insert(used, lba);
+        Ok(())
+    }
+
+    /// Writes every dirty block back to the device, in order, without flushing the device
+    pub fn sync(&self) -> Result<(), BlockError> {
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        for (&lba, entry) in inner.entries.iter_mut().filter(|(_, entry)| entry.dirty) {
+            self.device.write_blocks(lba, &entry.data)?;
+            entry.dirty = false;
+            inner.stats.writebacks += 1;
+        }
+        Ok(())
+    }
+
+    /// Drops every cached block, after writing back the dirty ones
+    pub fn invalidate(&self) -> Result<(), BlockError> {
+        self.sync()?;
+        let mut inner = self.inner.lock();
+        inner.entries.clear();
+        inner.lru.clear();
+        Ok(())
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_access(self, lba, buf.len())?;
+        let block_size = self.block_size();
+        let mut inner = self.inner.lock();
+        for (lba, block) in (lba..).zip(buf.chunks_mut(block_size)) {
+            if inner.entries.contains_key(&lba) {
+                inner.stats.hits += 1;
+                block.copy_from_slice(&inner.touch(lba).data);
+            } else {
+                inner.stats.misses += 1;
+                self.device.read_blocks(lba, block)?;
+                self.insert(&mut inner, lba, block.into(), false)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        super::check_access(self, lba, buf.len())?;
+        let block_size = self.block_size();
+        let mut inner = self.inner.lock();
+        for (lba, block) in (lba..).zip(buf.chunks(block_size)) {
+            if inner.entries.contains_key(&lba) {
+                let entry = inner.touch(lba);
+                entry.data.copy_from_slice(block);
+                entry.dirty = true;
+            } else {
+                // Whole blocks are written, so there's nothing to read first
+                self.insert(&mut inner, lba, block.into(), true)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.sync()?;
+        self.device.flush()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::warn!("block cache: dirty blocks lost: {err:?}");
+        }
+    }
+}
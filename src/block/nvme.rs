@@ -0,0 +1,656 @@
+//! NVMe controllers, and their namespaces as block devices.
+//!
+//! Commands go through pairs of queues in DMA memory: the driver writes 64 byte commands to a
+//! submission queue and rings its doorbell, and the controller posts 16 byte completions to
+//! the completion queue, flipping a phase bit on each pass so new entries can be told from
+//! old ones. Each controller gets the admin queue pair, used to identify it and its
+//! namespaces and to create the second pair, which carries reads and writes.
+//!
+//! Several commands can be in flight on a pair. Completions are picked up by the interrupt
+//! handler, which wakes whoever waits on them, and data is described to the controller with
+//! PRP entries, one per page, through a PRP list for transfers of more than two pages.
+//!
+//! Namespaces are registered as `nvme<controller>n<namespace>`, such as `nvme0n1`.
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use x86_64::VirtAddr;
+
+use super::{BlockDevice, BlockError};
+use crate::{
+    dma::{DmaBuffer, PAGE_SIZE},
+    irq::{self, IrqReturn},
+    memory,
+    pci::{self, Bar, DeviceId, PciDevice},
+    sync::{IrqSpinLock, LockClass, Mutex, RawIrqSpinLock, WaitQueue},
+    time::Deadline,
+};
+
+// Controller registers
+const REG_CAP: usize = 0x00;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1c;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+/// Where CC holds the memory page size, as a power of 2 of 4KiB
+const CC_MPS_SHIFT: u32 = 7;
+/// The size of submission queue entries, as a power of 2
+const CC_IOSQES: u32 = 6 << 16;
+/// The size of completion queue entries, as a power of 2
+const CC_IOCQES: u32 = 4 << 20;
+const CSTS_RDY: u32 = 1 << 0;
+/// Controller fatal status
+const CSTS_CFS: u32 = 1 << 1;
+
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+// What Identify returns, in CDW10
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+const CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+
+/// The queue is physically contiguous, in CDW11 of the create commands
+const QUEUE_CONTIGUOUS: u32 = 1 << 0;
+/// The completion queue raises interrupts, in CDW11 of Create I/O Completion Queue
+const QUEUE_INTERRUPTS: u32 = 1 << 1;
+
+const SUBMISSION_ENTRY_SIZE: usize = 64;
+const COMPLETION_ENTRY_SIZE: usize = 16;
+const ADMIN_QUEUE_SIZE: u16 = 16;
+/// At most 64, the size of the bitmap of command identifiers
+const IO_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_ID: u16 = 1;
+
+/// Bounds transfers, which the controller may bound further. 64KiB takes a PRP list of 15
+/// entries at most, well within a page
+const MAX_TRANSFER: usize = 64 * 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static DRIVER: pci::Driver = pci::Driver {
+    name: "nvme",
+    ids: &[DeviceId::class(0x01, 0x08).prog_if(0x02)],
+    probe,
+};
+
+/// The queue pairs of every controller, added before the first command goes through them so
+/// the interrupt handler picks up every completion
+static QUEUES: IrqSpinLock<Vec<Arc<QueuePair>>> = IrqSpinLock::from_raw(
+    RawIrqSpinLock::with_class(LockClass::new("NVME_QUEUES")),
+    Vec::new(),
+);
+
+static CONTROLLERS: Mutex<Vec<Arc<Controller>>> =
+    Mutex::with_class(LockClass::new("NVME_CONTROLLERS"), Vec::new());
+
+static NEXT_CONTROLLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers the driver for NVMe controllers
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+/// Every NVMe controller found, with the identify data its namespaces don't show
+pub fn controllers() -> Vec<Arc<Controller>> {
+    CONTROLLERS.lock().clone()
+}
+
+fn interrupt() -> IrqReturn {
+    let mut result = IrqReturn::NotMine;
+    // Every pair is checked, since there's no telling which one interrupted
+    for queue in QUEUES.lock().iter() {
+        if queue.process() {
+            result = IrqReturn::Handled;
+        }
+    }
+    result
+}
+
+/// A command for a submission queue, without its identifier
+#[derive(Debug, Clone, Copy, Default)]
+struct Command {
+    opcode: u8,
+    namespace: u32,
+    prp1: u64,
+    prp2: u64,
+    /// Command dwords 10 to 15
+    cdw: [u32; 6],
+}
+
+/// Set in the completion slots once the command completed, above its status
+const COMPLETED: u32 = 1 << 31;
+
+struct QueueState {
+    submissions: DmaBuffer,
+    completions: DmaBuffer,
+    tail: u16,
+    head: u16,
+    /// The phase of new completions, which flips each time the head wraps around
+    phase: bool,
+    /// The command identifiers in use
+    busy: u64,
+}
+
+/// A submission queue and its completion queue
+struct QueuePair {
+    size: u16,
+    state: IrqSpinLock<QueueState>,
+    /// The completion of each command identifier, `COMPLETED` and the status once it is done
+    results: Box<[AtomicU32]>,
+    /// Notified as commands complete, and as their identifiers are freed
+    completed: WaitQueue,
+    submission_doorbell: VirtAddr,
+    completion_doorbell: VirtAddr,
+}
+
+impl QueuePair {
+    fn new(registers: VirtAddr, stride: usize, id: u16, size: u16) -> Result<Self, BlockError> {
+        let queue = |entry_size: usize| {
+            DmaBuffer::new(usize::from(size) * entry_size).map_err(|_| BlockError::NoMemory)
+        };
+        let doorbell = |index: usize| registers + (DOORBELLS + index * stride) as u64;
+        Ok(Self {
+            size,
+            state: IrqSpinLock::from_raw(
+                RawIrqSpinLock::with_class(LockClass::new("NVME_QUEUE")),
+                QueueState {
+                    submissions: queue(SUBMISSION_ENTRY_SIZE)?,
+                    completions: queue(COMPLETION_ENTRY_SIZE)?,
+                    tail: 0,
+                    head: 0,
+                    phase: true,
+                    busy: 0,
+                },
+            ),
+            results: (0..size).map(|_| AtomicU32::new(0)).collect(),
+            completed: WaitQueue::new(),
+            submission_doorbell: doorbell(2 * usize::from(id)),
+            completion_doorbell: doorbell(2 * usize::from(id) + 1),
+        })
+    }
+
+    fn addresses(&self) -> (u64, u64) {
+        let state = self.state.lock();
+        (
+            state.submissions.phys().as_u64(),
+            state.completions.phys().as_u64(),
+        )
+    }
+
+    /// Queues `command`, waiting for room if the queue is full, and returns its identifier
+    fn submit(&self, command: &Command) -> Result<u16, BlockError> {
+        self.completed
+            .wait_until_deadline(Deadline::after(TIMEOUT), || {
+                let mut state = self.state.lock();
+                // One entry stays empty, since a full queue would look like an empty one
+                let in_flight = state.busy.count_ones() as u16;
+                let id = (0..self.size).find(|&id| state.busy & (1 << id) == 0)?;
+                if in_flight + 1 >= self.size {
+                    return None;
+                }
+                state.busy |= 1 << id;
+                self.results[usize::from(id)].store(0, Ordering::Relaxed);
+
+                let mut entry = [0u8; SUBMISSION_ENTRY_SIZE];
+                entry[0] = command.opcode;
+                entry[2..4].copy_from_slice(&id.to_le_bytes());
+                entry[4..8].copy_from_slice(&command.namespace.to_le_bytes());
+                entry[24..32].copy_from_slice(&command.prp1.to_le_bytes());
+                entry[32..40].copy_from_slice(&command.prp2.to_le_bytes());
+                for (i, dword) in command.cdw.iter().enumerate() {
+                    entry[40 + 4 * i..44 + 4 * i].copy_from_slice(&dword.to_le_bytes());
+                }
+                let offset = usize::from(state.tail) * SUBMISSION_ENTRY_SIZE;
+                state.submissions[offset..offset + SUBMISSION_ENTRY_SIZE].copy_from_slice(&entry);
+
+                state.tail = (state.tail + 1) % self.size;
+                unsafe {
+                    ptr::write_volatile(
+                        self.submission_doorbell.as_mut_ptr(),
+                        u32::from(state.tail),
+                    )
+                };
+                Some(id)
+            })
+            .ok_or(BlockError::Timeout)
+    }
+
+    /// Records the completions the controller posted, returning whether there were any
+    fn process(&self) -> bool {
+        let mut state = self.state.lock();
+        let mut found = false;
+        loop {
+            let offset = usize::from(state.head) * COMPLETION_ENTRY_SIZE;
+            let entry = unsafe {
+                ptr::read_volatile(state.completions[offset + 12..].as_ptr() as *const u32)
+            };
+            if (entry & (1 << 16) != 0) != state.phase {
+                break;
+            }
+            let id = (entry & 0xffff) as u16;
+            let status = (entry >> 17) & 0x7fff;
+            if let Some(result) = self.results.get(usize::from(id)) {
+                result.store(COMPLETED | status, Ordering::Release);
+            }
+            found = true;
+
+            state.head += 1;
+            if state.head == self.size {
+                state.head = 0;
+                state.phase = !state.phase;
+            }
+        }
+        if found {
+            unsafe {
+                ptr::write_volatile(self.completion_doorbell.as_mut_ptr(), u32::from(state.head))
+            };
+            self.completed.notify_all();
+        }
+        found
+    }
+
+    /// Waits for the command with identifier `id` to complete, and frees the identifier
+    fn wait(&self, id: u16) -> Result<(), BlockError> {
+        let result = &self.results[usize::from(id)];
+        let status = self
+            .completed
+            .wait_until_deadline(Deadline::after(TIMEOUT), || {
+                self.process();
+                let result = result.load(Ordering::Acquire);
+                (result & COMPLETED != 0).then_some(result & !COMPLETED)
+            });
+        // A command which timed out keeps its identifier, since it may still complete
+        let status = status.ok_or(BlockError::Timeout)?;
+        self.state.lock().busy &= !(1 << id);
+        self.completed.notify_all();
+        match status {
+            0 => Ok(()),
+            // The status code, without its type
+            status => Err(BlockError::Device(status as u8)),
+        }
+    }
+
+    /// Submits `command` and waits for it to complete
+    fn run(&self, command: &Command) -> Result<(), BlockError> {
+        let id = self.submit(command)?;
+        self.wait(id)
+    }
+
+    /// Runs `command`, which points the controller at `buffers`, and gives the buffers back.
+    ///
+    /// The buffers of a command which timed out are leaked instead, since the controller may
+    /// still access them
+    fn run_with<B>(&self, command: &Command, buffers: B) -> Result<B, BlockError> {
+        match self.run(command) {
+            Ok(()) => Ok(buffers),
+            Err(BlockError::Timeout) => {
+                mem::forget(buffers);
+                Err(BlockError::Timeout)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Creates the queue pair, and adds it to the pairs the interrupt handler checks
+    fn register(
+        registers: VirtAddr,
+        stride: usize,
+        id: u16,
+        size: u16,
+    ) -> Result<Arc<Self>, BlockError> {
+        let queue = Arc::new(Self::new(registers, stride, id, size)?);
+        QUEUES.lock().push(queue.clone());
+        Ok(queue)
+    }
+}
+
+/// An NVMe controller, whose namespaces are registered as block devices
+pub struct Controller {
+    registers: VirtAddr,
+    admin: Arc<QueuePair>,
+    io: Option<Arc<QueuePair>>,
+    /// The largest transfer of a single command, in bytes
+    max_transfer: usize,
+    model: String,
+    /// The number of namespaces the controller supports
+    namespace_count: u32,
+}
+
+impl Controller {
+    fn read32(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.registers + register as u64).as_ptr()) }
+    }
+
+    fn write32(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.registers + register as u64).as_mut_ptr(), value) }
+    }
+
+    fn write64(&self, register: usize, value: u64) {
+        self.write32(register, value as u32);
+        self.write32(register + 4, (value >> 32) as u32);
+    }
+
+    /// Polls until the ready bit of the status is `ready`
+    fn wait_ready(&self, ready: bool, timeout: Duration) -> Result<(), BlockError> {
+        let deadline = Deadline::after(timeout);
+        loop {
+            let status = self.read32(REG_CSTS);
+            if status & CSTS_CFS != 0 {
+                return Err(BlockError::Device(0xff));
+            }
+            if (status & CSTS_RDY != 0) == ready {
+                return Ok(());
+            }
+            if deadline.has_passed() {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Resets the controller and sets up the admin queues
+    fn new(registers: VirtAddr) -> Result<Self, BlockError> {
+        let capabilities =
+            unsafe { ptr::read_volatile((registers + REG_CAP as u64).as_ptr::<u64>()) };
+        let max_entries = (capabilities & 0xffff) as u16 + 1;
+        let stride = 4 << ((capabilities >> 32) & 0xf);
+        // In units of 500ms
+        let timeout = Duration::from_millis(500 * ((capabilities >> 24) & 0xff).max(1));
+        // Page sizes are powers of 2 of 4KiB, and PRP entries use the DMA pool's pages
+        let page_shift = PAGE_SIZE.trailing_zeros() - 12;
+        let min_page_shift = ((capabilities >> 48) & 0xf) as u32;
+        let max_page_shift = ((capabilities >> 52) & 0xf) as u32;
+        if !(min_page_shift..=max_page_shift).contains(&page_shift) {
+            return Err(BlockError::Unsupported);
+        }
+
+        let admin_size = ADMIN_QUEUE_SIZE.min(max_entries);
+        let mut controller = Self {
+            registers,
+            admin: QueuePair::register(registers, stride, 0, admin_size)?,
+            io: None,
+            max_transfer: MAX_TRANSFER,
+            model: String::new(),
+            namespace_count: 0,
+        };
+
+        controller.write32(REG_CC, controller.read32(REG_CC) & !CC_EN);
+        controller.wait_ready(false, timeout)?;
+        let size = u32::from(admin_size) - 1;
+        controller.write32(REG_AQA, size << 16 | size);
+        let (submissions, completions) = controller.admin.addresses();
+        controller.write64(REG_ASQ, submissions);
+        controller.write64(REG_ACQ, completions);
+        // The NVM command set is the default, left as 0
+        let page_size = page_shift << CC_MPS_SHIFT;
+        controller.write32(REG_CC, page_size | CC_IOSQES | CC_IOCQES | CC_EN);
+        controller.wait_ready(true, timeout)?;
+
+        let identify = controller.identify(CNS_CONTROLLER, 0)?;
+        // The limit is a power of 2 of the minimum page size, 0 meaning none
+        let min_page_size = 1usize << (12 + min_page_shift);
+        if identify[77] != 0 {
+            let max_transfer = min_page_size
+                .checked_shl(identify[77].into())
+                .filter(|&max| max >> identify[77] == min_page_size)
+                .ok_or(BlockError::Unsupported)?;
+            controller.max_transfer = MAX_TRANSFER.min(max_transfer);
+        }
+        controller.model = String::from_utf8_lossy(&identify[24..64])
+            .trim()
+            .to_string();
+        controller.namespace_count = u32::from_le_bytes(identify[516..520].try_into().unwrap());
+
+        let io = QueuePair::register(
+            registers,
+            stride,
+            IO_QUEUE_ID,
+            IO_QUEUE_SIZE.min(max_entries),
+        )?;
+        let (submissions, completions) = io.addresses();
+        let size = u32::from(io.size - 1) << 16 | u32::from(IO_QUEUE_ID);
+        controller.admin.run(&Command {
+            opcode: ADMIN_CREATE_IO_CQ,
+            prp1: completions,
+            cdw: [size, QUEUE_CONTIGUOUS | QUEUE_INTERRUPTS, 0, 0, 0, 0],
+            ..Command::default()
+        })?;
+        controller.admin.run(&Command {
+            opcode: ADMIN_CREATE_IO_SQ,
+            prp1: submissions,
+            cdw: [
+                size,
+                u32::from(IO_QUEUE_ID) << 16 | QUEUE_CONTIGUOUS,
+                0,
+                0,
+                0,
+                0,
+            ],
+            ..Command::default()
+        })?;
+        controller.io = Some(io);
+        Ok(controller)
+    }
+
+    /// Runs Identify for `cns` and `namespace`, returning the 4KiB it returns
+    fn identify(&self, cns: u32, namespace: u32) -> Result<DmaBuffer, BlockError> {
+        let buffer = DmaBuffer::new(PAGE_SIZE).map_err(|_| BlockError::NoMemory)?;
+        let command = Command {
+            opcode: ADMIN_IDENTIFY,
+            namespace,
+            prp1: buffer.phys().as_u64(),
+            cdw: [cns, 0, 0, 0, 0, 0],
+            ..Command::default()
+        };
+        self.admin.run_with(&command, buffer)
+    }
+
+    /// The model number the controller reports
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// The identifiers of the active namespaces, asked for from the controller each time
+    pub fn namespaces(&self) -> Vec<u32> {
+        match self.identify(CNS_ACTIVE_NAMESPACES, 0) {
+            Ok(list) => list
+                .chunks_exact(4)
+                .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+                .take_while(|&id| id != 0)
+                .collect(),
+            // Controllers before NVMe 1.1 don't have the list, so every namespace is tried
+            Err(_) => (1..=self.namespace_count).collect(),
+        }
+    }
+
+    /// Runs the I/O command `opcode` on `count` blocks at `lba`, with the first `len` bytes of
+    /// `data` as the data, and gives `data` back
+    fn transfer(
+        &self,
+        opcode: u8,
+        namespace: u32,
+        lba: u64,
+        count: u32,
+        data: DmaBuffer,
+        len: usize,
+    ) -> Result<DmaBuffer, BlockError> {
+        let io = self.io.as_ref().ok_or(BlockError::NoMedium)?;
+        // The first page goes in PRP1 and the second in PRP2, or, past two pages, the rest of
+        // them go in a list PRP2 points to
+        let pages = len.div_ceil(PAGE_SIZE);
+        let mut list = None;
+        let prp2 = match pages {
+            0 | 1 => 0,
+            2 => data.phys_at(PAGE_SIZE).as_u64(),
+            _ => {
+                let mut entries =
+                    DmaBuffer::new(8 * (pages - 1)).map_err(|_| BlockError::NoMemory)?;
+                for (i, entry) in entries.chunks_exact_mut(8).take(pages - 1).enumerate() {
+                    let page = data.phys_at((i + 1) * PAGE_SIZE).as_u64();
+                    entry.copy_from_slice(&page.to_le_bytes());
+                }
+                list.insert(entries).phys().as_u64()
+            }
+        };
+        let command = Command {
+            opcode,
+            namespace,
+            prp1: data.phys().as_u64(),
+            prp2,
+            // The count is 0 based
+            cdw: [lba as u32, (lba >> 32) as u32, count - 1, 0, 0, 0],
+        };
+        io.run_with(&command, (data, list)).map(|(data, _)| data)
+    }
+}
+
+fn probe(device: &PciDevice) -> bool {
+    let Some(Bar::Memory { address, .. }) = device.bars[0] else {
+        return false;
+    };
+    let Some(registers) = memory::phys_to_virt(address) else {
+        return false;
+    };
+    device.enable();
+    device.set_command(device.command() & !pci::COMMAND_INTX_DISABLE);
+
+    // The interrupt handler has to be in place before the admin commands, and the controller
+    // adds its queue pairs to the ones it checks
+    let line = device.interrupt_line;
+    match irq::register(line, interrupt) {
+        Ok(()) | Err(irq::IrqError::AlreadyRegistered) => {}
+        Err(err) => log::warn!("nvme: can't use IRQ {line}: {err:?}"),
+    }
+    let controller = match Controller::new(registers) {
+        Ok(controller) => Arc::new(controller),
+        Err(err) => {
+            log::warn!("nvme: can't use {}: {err:?}", device.address);
+            return false;
+        }
+    };
+    CONTROLLERS.lock().push(controller.clone());
+
+    let index = NEXT_CONTROLLER.fetch_add(1, Ordering::Relaxed);
+    log::info!("nvme: controller {index}: {:?}", controller.model);
+    for id in controller.namespaces() {
+        match Namespace::new(controller.clone(), id) {
+            Ok(Some(namespace)) => {
+                super::register(&format!("nvme{index}n{id}"), Arc::new(namespace))
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("nvme: namespace {id}: {err:?}"),
+        }
+    }
+    true
+}
+
+/// A namespace of a controller, which is what holds the blocks
+pub struct Namespace {
+    controller: Arc<Controller>,
+    id: u32,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl Namespace {
+    /// Identifies namespace `id`, returning `None` if it is inactive
+    fn new(controller: Arc<Controller>, id: u32) -> Result<Option<Self>, BlockError> {
+        let identify = controller.identify(CNS_NAMESPACE, id)?;
+        let block_count = u64::from_le_bytes(identify[0..8].try_into().unwrap());
+        if block_count == 0 {
+            return Ok(None);
+        }
+        // The format in use, and its block size as a power of 2
+        let format = usize::from(identify[26] & 0xf);
+        let block_shift = identify[128 + 4 * format + 2];
+        // Blocks are at least 512 bytes
+        let block_size = 1usize
+            .checked_shl(block_shift.into())
+            .filter(|&size| size >= 512)
+            .ok_or(BlockError::Unsupported)?;
+        Ok(Some(Self {
+            controller,
+            id,
+            block_size,
+            block_count,
+        }))
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The most blocks transferred by a single command
+    fn max_blocks(&self) -> usize {
+        (self.controller.max_transfer / self.block_size).max(1)
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_access(self, lba, buf.len())?;
+        let max_blocks = self.max_blocks();
+        for (i, chunk) in buf.chunks_mut(max_blocks * self.block_size).enumerate() {
+            let lba = lba + (i * max_blocks) as u64;
+            let count = (chunk.len() / self.block_size) as u32;
+            let data = DmaBuffer::new(chunk.len()).map_err(|_| BlockError::NoMemory)?;
+            let data = self
+                .controller
+                .transfer(IO_READ, self.id, lba, count, data, chunk.len())?;
+            chunk.copy_from_slice(&data[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        super::check_access(self, lba, buf.len())?;
+        let max_blocks = self.max_blocks();
+        for (i, chunk) in buf.chunks(max_blocks * self.block_size).enumerate() {
+            let lba = lba + (i * max_blocks) as u64;
+            let count = (chunk.len() / self.block_size) as u32;
+            let mut data = DmaBuffer::new(chunk.len()).map_err(|_| BlockError::NoMemory)?;
+            data[..chunk.len()].copy_from_slice(chunk);
+            self.controller
+                .transfer(IO_WRITE, self.id, lba, count, data, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let io = self.controller.io.as_ref().ok_or(BlockError::NoMedium)?;
+        io.run(&Command {
+            opcode: IO_FLUSH,
+            namespace: self.id,
+            ..Command::default()
+        })
+    }
+}
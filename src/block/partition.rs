@@ -0,0 +1,377 @@
+//! MBR and GPT partition tables, and partitions as block devices of their own.
+//!
+//! An MBR has four primary entries in its first block, one of which may be an extended
+//! partition holding a chain of EBRs, each describing a logical partition. A GPT is found
+//! through the protective MBR entry covering the disk: its header is in block 1 and points
+//! at an array of entries, both checked against their CRC32.
+//!
+//! `scan` registers the partitions of a disk under the disk's name followed by their number,
+//! such as `vda1`, with a `p` in between for names ending in a digit, such as `nvme0n1p1`.
+//! Like Linux, logical partitions are numbered from 5.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::fmt;
+
+use super::{BlockDevice, BlockError};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const TYPE_EMPTY: u8 = 0x00;
+const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const EXTENDED_TYPES: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Bounds the walk of the EBR chain, in case it loops
+const MAX_LOGICAL: usize = 128;
+const FIRST_LOGICAL: usize = 5;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_LBA: u64 = 1;
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// Bounds the size of the entry array, which is usually 128 entries
+const GPT_MAX_ENTRIES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    /// Reading the disk failed
+    Block(BlockError),
+    /// The disk has no MBR, so no partitions
+    NoTable,
+    /// The GPT header is invalid, or doesn't match its CRC
+    BadGptHeader,
+    /// The GPT entries don't match their CRC
+    BadGptEntries,
+    /// A partition goes past the end of the disk
+    OutOfRange,
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(err: BlockError) -> Self {
+        PartitionError::Block(err)
+    }
+}
+
+/// A GUID, in its mixed-endian on-disk form
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid([0; 16]);
+    /// EFI system partition, C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+    pub const EFI_SYSTEM: Guid = Guid([
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ]);
+    /// Linux filesystem data, 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+    pub const LINUX_DATA: Guid = Guid([
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ]);
+    /// Microsoft basic data, used for FAT, EBD0A0A2-B9E5-4433-87C0-68B6B72699C7
+    pub const BASIC_DATA: Guid = Guid([
+        0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99,
+        0xc7,
+    ]);
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        b[10..].iter().try_for_each(|byte| write!(f, "{byte:02X}"))
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// An MBR partition, with its type byte
+    Mbr { kind: u8, bootable: bool },
+    Gpt {
+        kind: Guid,
+        guid: Guid,
+        name: String,
+    },
+}
+
+/// A range of the blocks of a disk
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    /// The partition's number, from 1
+    pub number: usize,
+    pub start: u64,
+    pub kind: PartitionKind,
+    block_count: u64,
+}
+
+impl Partition {
+    /// The disk the partition is on
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+}
+
+impl fmt::Debug for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Partition")
+            .field("number", &self.number)
+            .field("start", &self.start)
+            .field("block_count", &self.block_count)
+            .field("kind", &self.kind)
+            .finish()
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_access(self, lba, buf.len())?;
+        self.device.read_blocks(self.start + lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        super::check_access(self, lba, buf.len())?;
+        self.device.write_blocks(self.start + lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+}
+
+/// An entry of an MBR or EBR
+struct MbrEntry {
+    bootable: bool,
+    kind: u8,
+    start: u64,
+    count: u64,
+}
+
+fn mbr_entries(block: &[u8]) -> impl Iterator<Item = MbrEntry> + '_ {
+    block[MBR_ENTRIES..MBR_ENTRIES + 4 * MBR_ENTRY_SIZE]
+        .chunks_exact(MBR_ENTRY_SIZE)
+        .map(|entry| MbrEntry {
+            bootable: entry[0] & 0x80 != 0,
+            kind: entry[4],
+            start: u32::from_le_bytes(entry[8..12].try_into().unwrap()).into(),
+            count: u32::from_le_bytes(entry[12..16].try_into().unwrap()).into(),
+        })
+}
+
+/// Reads the partition table of `device`, MBR or GPT, returning its partitions ordered by
+/// number. Empty entries are skipped
+pub fn parse(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, PartitionError> {
+    let mbr = super::read_to_vec(device.as_ref(), 0, 1)?;
+    if mbr.len() < 512 || mbr[510..512] != MBR_SIGNATURE {
+        return Err(PartitionError::NoTable);
+    }
+    // Boot sectors of unpartitioned volumes, such as FAT ones, have the signature too, but
+    // code instead of entries, which rarely gets the status bytes right
+    let status = |i: usize| mbr[MBR_ENTRIES + i * MBR_ENTRY_SIZE];
+    if (0..4).any(|i| status(i) & 0x7f != 0) {
+        return Err(PartitionError::NoTable);
+    }
+    if mbr_entries(&mbr).any(|entry| entry.kind == TYPE_GPT_PROTECTIVE) {
+        return parse_gpt(device);
+    }
+
+    let mut partitions = Vec::new();
+    let new = |number, entry: &MbrEntry, start: u64| -> Result<Partition, PartitionError> {
+        match start.checked_add(entry.count) {
+            Some(end) if end <= device.block_count() => Ok(Partition {
+                device: device.clone(),
+                number,
+                start,
+                kind: PartitionKind::Mbr {
+                    kind: entry.kind,
+                    bootable: entry.bootable,
+                },
+                block_count: entry.count,
+            }),
+            _ => Err(PartitionError::OutOfRange),
+        }
+    };
+
+    let mut extended = None;
+    for (i, entry) in mbr_entries(&mbr).enumerate() {
+        if entry.kind == TYPE_EMPTY || entry.count == 0 {
+            continue;
+        }
+        if EXTENDED_TYPES.contains(&entry.kind) {
+            extended = Some(entry.start);
+        } else {
+            partitions.push(new(i + 1, &entry, entry.start)?);
+        }
+    }
+
+    // Each EBR has the logical partition, relative to the EBR, and a link to the next EBR,
+    // relative to the start of the extended partition
+    if let Some(base) = extended {
+        let mut ebr = base;
+        for number in FIRST_LOGICAL..FIRST_LOGICAL + MAX_LOGICAL {
+            let block = super::read_to_vec(device.as_ref(), ebr, 1)?;
+            if block.len() < 512 || block[510..512] != MBR_SIGNATURE {
+                break;
+            }
+            let mut entries = mbr_entries(&block);
+            let logical = entries.next().unwrap();
+            if logical.kind != TYPE_EMPTY && logical.count != 0 {
+                partitions.push(new(number, &logical, ebr + logical.start)?);
+            }
+            match entries.next() {
+                Some(next) if next.kind != TYPE_EMPTY && next.start != 0 => {
+                    ebr = base + next.start;
+                }
+                _ => break,
+            }
+        }
+    }
+    Ok(partitions)
+}
+
+fn parse_gpt(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, PartitionError> {
+    let block_size = device.block_size();
+    let mut header = super::read_to_vec(device.as_ref(), GPT_HEADER_LBA, 1)?;
+    let u32_at = |data: &[u8], offset: usize| {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    };
+    let u64_at = |data: &[u8], offset: usize| {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    };
+
+    let header_size = u32_at(&header, 12) as usize;
+    if &header[0..8] != GPT_SIGNATURE || !(GPT_MIN_HEADER_SIZE..=block_size).contains(&header_size)
+    {
+        return Err(PartitionError::BadGptHeader);
+    }
+    // The CRC is computed with its own field zeroed
+    let header_crc = u32_at(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != header_crc {
+        return Err(PartitionError::BadGptHeader);
+    }
+
+    let entries_lba = u64_at(&header, 72);
+    let entry_count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    // Entries are 128 bytes times a power of 2, and never span blocks
+    if entry_count > GPT_MAX_ENTRIES
+        || !entry_size.is_power_of_two()
+        || !(GPT_MIN_ENTRY_SIZE..=block_size).contains(&entry_size)
+    {
+        return Err(PartitionError::BadGptHeader);
+    }
+    let len = entry_count * entry_size;
+    let blocks = len.div_ceil(block_size);
+    let entries = super::read_to_vec(device.as_ref(), entries_lba, blocks)?;
+    if crc32(&entries[..len]) != u32_at(&header, 88) {
+        return Err(PartitionError::BadGptEntries);
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries[..len].chunks_exact(entry_size).enumerate() {
+        let kind = Guid(entry[0..16].try_into().unwrap());
+        if kind == Guid::UNUSED {
+            continue;
+        }
+        let start = u64_at(entry, 32);
+        // The last block is inclusive
+        let end = u64_at(entry, 40);
+        if end < start || end >= device.block_count() {
+            return Err(PartitionError::OutOfRange);
+        }
+        let name = char::decode_utf16(
+            entry[56..128]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|&c| c != 0),
+        )
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+        partitions.push(Partition {
+            device: device.clone(),
+            number: i + 1,
+            start,
+            kind: PartitionKind::Gpt {
+                kind,
+                guid: Guid(entry[16..32].try_into().unwrap()),
+                name,
+            },
+            block_count: end - start + 1,
+        });
+    }
+    Ok(partitions)
+}
+
+/// The CRC32 used by GPT, and by zip and Ethernet
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// The name of partition `number` of the disk `disk`
+pub fn partition_name(disk: &str, number: usize) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{disk}p{number}")
+    } else {
+        format!("{disk}{number}")
+    }
+}
+
+/// Registers the partitions of the disk registered as `disk`, returning how many there were
+pub fn scan(disk: &str) -> Result<usize, PartitionError> {
+    let device = super::get(disk).ok_or(PartitionError::Block(BlockError::NoMedium))?;
+    let partitions = parse(&device)?;
+    let count = partitions.len();
+    for partition in partitions {
+        log::debug!(
+            "partition: {} {:?}",
+            partition_name(disk, partition.number),
+            partition.kind
+        );
+        super::register(&partition_name(disk, partition.number), Arc::new(partition));
+    }
+    Ok(count)
+}
+
+/// Registers the partitions of every registered disk. Disks without a partition table are
+/// left alone
+pub fn scan_all() {
+    // Partitions registered on the way don't have tables of their own
+    let disks: Vec<String> = super::devices().into_iter().map(|(name, _)| name).collect();
+    for disk in disks {
+        match scan(&disk) {
+            Ok(_) | Err(PartitionError::NoTable) => {}
+            // Drives without media, such as empty CD-ROM drives
+            Err(PartitionError::Block(BlockError::NoMedium)) => {}
+            Err(err) => log::warn!("partition: {disk}: {err:?}"),
+        }
+    }
+}
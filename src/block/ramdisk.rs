@@ -0,0 +1,71 @@
+//! Block devices backed by memory, for filesystems without a disk and for tests.
+
+use alloc::{vec, vec::Vec};
+
+use super::{BlockDevice, BlockError};
+use crate::sync::{LockClass, Mutex};
+
+pub struct RamDisk {
+    block_size: usize,
+    data: Mutex<Vec<u8>>,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// A zeroed disk of `block_count` blocks of `block_size` bytes
+    pub fn new(block_size: usize, block_count: u64) -> Self {
+        Self::from_vec(block_size, vec![0; block_size * block_count as usize])
+    }
+
+    /// A disk holding `data`, which is padded with zeroes to a whole number of blocks
+    pub fn from_vec(block_size: usize, mut data: Vec<u8>) -> Self {
+        data.resize(data.len().next_multiple_of(block_size), 0);
+        Self {
+            block_size,
+            data: Mutex::with_class(LockClass::new("RAMDISK"), data),
+            read_only: false,
+        }
+    }
+
+    /// Makes the disk reject writes
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// A copy of the whole disk
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.lock().clone()
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_access(self, lba, buf.len())?;
+        let start = lba as usize * self.block_size;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        super::check_access(self, lba, buf.len())?;
+        let start = lba as usize * self.block_size;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
@@ -0,0 +1,91 @@
+//! Checks every block driver's tests run against the test disk, tests/data/ata.img.
+//!
+//! The image has `SECTORS` sectors of 512 bytes, each starting with `ros sector <n>` followed
+//! by bytes counting up from `n + 16`. The checks leave it as they found it, so the tests of
+//! a driver can go on reading it afterwards.
+
+use alloc::{format, sync::Arc, vec, vec::Vec};
+
+use super::{BlockDevice, BlockError};
+
+pub const SECTORS: u64 = 256;
+pub const SECTOR_SIZE: usize = 512;
+
+/// Panics unless `sector` holds what the test disk has in sector `lba`
+pub fn check_sector(lba: u64, sector: &[u8]) {
+    let header = format!("ros sector {lba:05}");
+    assert_eq!(&sector[..header.len()], header.as_bytes());
+    for (j, &byte) in sector.iter().enumerate().skip(16) {
+        assert_eq!(byte, (lba as usize + j) as u8, "sector {lba} byte {j}");
+    }
+}
+
+/// Runs every check on the device registered as `name`, which must hold the test disk
+pub fn check_test_disk(name: &str) {
+    let disk = super::get(name).unwrap_or_else(|| panic!("{name} missing"));
+    assert_eq!(disk.block_size(), SECTOR_SIZE);
+    assert_eq!(disk.block_count(), SECTORS);
+    assert!(!disk.is_read_only());
+
+    check_reads(&disk);
+    check_writes(&disk);
+    check_bad_accesses(&disk);
+}
+
+fn check_reads(disk: &Arc<dyn BlockDevice>) {
+    let mut buf = vec![0; SECTOR_SIZE];
+    for lba in [0, 7, SECTORS - 1] {
+        disk.read_blocks(lba, &mut buf).unwrap();
+        check_sector(lba, &buf);
+    }
+
+    // The whole disk at once, which takes several commands with every driver
+    let mut buf = vec![0; SECTORS as usize * SECTOR_SIZE];
+    disk.read_blocks(0, &mut buf).unwrap();
+    for (lba, sector) in buf.chunks(SECTOR_SIZE).enumerate() {
+        check_sector(lba as u64, sector);
+    }
+}
+
+fn check_writes(disk: &Arc<dyn BlockDevice>) {
+    const LBA: u64 = 100;
+    const LEN: usize = 4 * SECTOR_SIZE;
+    let original = super::read_to_vec(&**disk, LBA, LEN / SECTOR_SIZE).unwrap();
+
+    let data: Vec<u8> = (0..LEN).map(|i| (i * 7 + 3) as u8).collect();
+    disk.write_blocks(LBA, &data).unwrap();
+    disk.flush().unwrap();
+    let mut buf = vec![0; LEN];
+    disk.read_blocks(LBA, &mut buf).unwrap();
+    assert_eq!(buf, data);
+    // The neighbours are untouched
+    disk.read_blocks(LBA - 1, &mut buf[..SECTOR_SIZE]).unwrap();
+    check_sector(LBA - 1, &buf[..SECTOR_SIZE]);
+    disk.read_blocks(LBA + 4, &mut buf[..SECTOR_SIZE]).unwrap();
+    check_sector(LBA + 4, &buf[..SECTOR_SIZE]);
+
+    disk.write_blocks(LBA, &original).unwrap();
+    disk.flush().unwrap();
+    disk.read_blocks(LBA, &mut buf).unwrap();
+    assert_eq!(buf, original);
+}
+
+fn check_bad_accesses(disk: &Arc<dyn BlockDevice>) {
+    let mut buf = vec![0; 2 * SECTOR_SIZE];
+    assert_eq!(
+        disk.read_blocks(SECTORS - 1, &mut buf),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        disk.write_blocks(SECTORS, &buf[..SECTOR_SIZE]),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        disk.read_blocks(0, &mut buf[..100]),
+        Err(BlockError::BadBufferSize)
+    );
+    assert_eq!(
+        disk.write_blocks(0, &buf[..100]),
+        Err(BlockError::BadBufferSize)
+    );
+}
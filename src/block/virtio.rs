@@ -0,0 +1,341 @@
+//! Virtio block devices.
+//!
+//! Each device has a single request queue. A request is a chain of three buffers: a header
+//! with the command and sector, the data, and a status byte the device writes last. Requests
+//! complete asynchronously: `start_read` and `start_write` return as soon as the device is
+//! notified, and the interrupt handler marks requests done as the device returns them, waking
+//! whoever waits on them.
+//!
+//! Devices are registered as `vda`, `vdb` and so on, in the order they are found. Data goes
+//! through DMA buffers, so the `BlockDevice` methods copy through bounce buffers.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use super::{BlockDevice, BlockError};
+use crate::{
+    dma::DmaBuffer,
+    irq::{self, IrqReturn},
+    pci::{self, PciDevice},
+    sync::{IrqSpinLock, LockClass, RawIrqSpinLock, WaitQueue},
+    time::Deadline,
+    virtio::{self, Buffer, Transport, VirtQueue},
+};
+
+const SECTOR_SIZE: usize = 512;
+
+/// The device is read-only
+const F_RO: u64 = 1 << 5;
+/// The device supports the flush command
+const F_FLUSH: u64 = 1 << 9;
+
+/// The capacity in sectors, in the device configuration
+const CONFIG_CAPACITY: usize = 0;
+
+const TYPE_IN: u32 = 0;
+const TYPE_OUT: u32 = 1;
+const TYPE_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+/// Where the device writes the status, in the buffer holding the 16 byte header
+const STATUS_OFFSET: usize = 16;
+
+/// Bounds the number of entries of the queue, and so the number of requests in flight
+const QUEUE_SIZE: u16 = 128;
+/// The most sectors of a single request, whose data goes in a single descriptor and so has to
+/// be one contiguous DMA buffer
+const MAX_SECTORS: usize = 128;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-blk",
+    ids: &virtio::device_ids(0x1001, 2),
+    probe,
+};
+
+/// The devices found, whose ISR status the interrupt handler reads
+static DEVICES: IrqSpinLock<Vec<Arc<VirtioBlk>>> = IrqSpinLock::from_raw(
+    RawIrqSpinLock::with_class(LockClass::new("VIRTIO_BLK_DEVICES")),
+    Vec::new(),
+);
+
+static NEXT_NAME: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers the driver for virtio block devices
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+/// Every virtio block device found, for the requests `BlockDevice` doesn't offer
+pub fn devices() -> Vec<Arc<VirtioBlk>> {
+    DEVICES.lock().clone()
+}
+
+fn interrupt() -> IrqReturn {
+    let mut result = IrqReturn::NotMine;
+    for device in DEVICES.lock().iter() {
+        // Reading the ISR status acknowledges the interrupt
+        if device.transport.isr_status() & virtio::ISR_QUEUE != 0 {
+            device.complete();
+            result = IrqReturn::Handled;
+        }
+    }
+    result
+}
+
+fn probe(device: &PciDevice) -> bool {
+    match VirtioBlk::new(device) {
+        Ok(disk) => {
+            let disk = Arc::new(disk);
+            DEVICES.lock().push(disk.clone());
+
+            let line = device.interrupt_line;
+            match irq::register(line, interrupt) {
+                Ok(()) | Err(irq::IrqError::AlreadyRegistered) => {}
+                Err(err) => log::warn!("virtio-blk: can't use IRQ {line}: {err:?}"),
+            }
+            disk.transport.driver_ok();
+
+            let index = NEXT_NAME.fetch_add(1, Ordering::Relaxed);
+            let name = super::disk_name("vd", index);
+            log::info!(
+                "virtio-blk: {name} at {} through the {} interface",
+                device.address,
+                if disk.transport.is_legacy() {
+                    "legacy"
+                } else {
+                    "modern"
+                }
+            );
+            super::register(&name, disk);
+            true
+        }
+        Err(err) => {
+            log::warn!("virtio-blk: can't use {}: {err:?}", device.address);
+            false
+        }
+    }
+}
+
+pub struct VirtioBlk {
+    transport: Transport,
+    queue: IrqSpinLock<VirtQueue>,
+    /// Whether the request whose chain starts at each descriptor is done
+    done: Box<[AtomicBool]>,
+    /// Notified as requests complete, and as descriptors are freed
+    completed: WaitQueue,
+    sectors: u64,
+    features: u64,
+}
+
+impl VirtioBlk {
+    fn new(device: &PciDevice) -> Result<Self, virtio::VirtioError> {
+        let transport = Transport::new(device)?;
+        transport.reset();
+        let features = transport.negotiate(F_RO | F_FLUSH)?;
+        let queue = transport.setup_queue(0, QUEUE_SIZE)?;
+        let sectors = transport.read_config(CONFIG_CAPACITY)?;
+        Ok(Self {
+            done: (0..queue.size()).map(|_| AtomicBool::new(false)).collect(),
+            queue: IrqSpinLock::from_raw(
+                RawIrqSpinLock::with_class(LockClass::new("VIRTIO_BLK_QUEUE")),
+                queue,
+            ),
+            transport,
+            completed: WaitQueue::new(),
+            sectors,
+            features,
+        })
+    }
+
+    /// Marks the requests the device returned as done
+    fn complete(&self) {
+        let mut completed = false;
+        while let Some((head, _)) = self.queue.lock().pop_used() {
+            self.done[usize::from(head)].store(true, Ordering::Release);
+            completed = true;
+        }
+        if completed {
+            self.completed.notify_all();
+        }
+    }
+
+    /// Queues a request with the header for `kind` and `sector`, and `data` if the request
+    /// has data, waiting for free descriptors if the queue is full
+    fn submit(
+        &self,
+        kind: u32,
+        sector: u64,
+        data: Option<(DmaBuffer, usize)>,
+    ) -> Result<PendingRequest<'_>, BlockError> {
+        let mut header = DmaBuffer::new(STATUS_OFFSET + 1).map_err(|_| BlockError::NoMemory)?;
+        header[0..4].copy_from_slice(&kind.to_le_bytes());
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+        // Left in place if the device never writes the status
+        header[STATUS_OFFSET] = 0xff;
+
+        let mut buffers = Vec::with_capacity(3);
+        buffers.push(Buffer::readable(header.phys(), 16));
+        if let Some((data, len)) = &data {
+            let address = data.phys();
+            buffers.push(match kind {
+                TYPE_IN => Buffer::writable(address, *len as u32),
+                _ => Buffer::readable(address, *len as u32),
+            });
+        }
+        buffers.push(Buffer::writable(header.phys_at(STATUS_OFFSET), 1));
+
+        let head = self
+            .completed
+            .wait_until_deadline(Deadline::after(TIMEOUT), || {
+                let mut queue = self.queue.lock();
+                let head = queue.add(&buffers)?;
+                self.done[usize::from(head)].store(false, Ordering::Relaxed);
+                queue.notify();
+                Some(head)
+            })
+            .ok_or(BlockError::Timeout)?;
+
+        Ok(PendingRequest {
+            device: self,
+            head,
+            header: Some(header),
+            data: data.map(|(data, _)| data),
+            finished: false,
+        })
+    }
+
+    /// Starts reading `count` sectors from `lba`, into a buffer the request returns
+    pub fn start_read(&self, lba: u64, count: usize) -> Result<PendingRequest<'_>, BlockError> {
+        let len = count * SECTOR_SIZE;
+        super::check_access(self, lba, len)?;
+        let data = DmaBuffer::new(len).map_err(|_| BlockError::NoMemory)?;
+        self.submit(TYPE_IN, lba, Some((data, len)))
+    }
+
+    /// Starts writing the first `count` sectors of `data` to `lba`
+    pub fn start_write(
+        &self,
+        lba: u64,
+        data: DmaBuffer,
+        count: usize,
+    ) -> Result<PendingRequest<'_>, BlockError> {
+        let len = count * SECTOR_SIZE;
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        if len > data.len() {
+            return Err(BlockError::BadBufferSize);
+        }
+        super::check_access(self, lba, len)?;
+        self.submit(TYPE_OUT, lba, Some((data, len)))
+    }
+
+    /// Whether the device was found through the legacy interface
+    pub fn is_legacy(&self) -> bool {
+        self.transport.is_legacy()
+    }
+}
+
+/// A request the device is working on.
+///
+/// Dropping it waits for the device to finish, since the device is still using its buffers
+pub struct PendingRequest<'a> {
+    device: &'a VirtioBlk,
+    head: u16,
+    header: Option<DmaBuffer>,
+    data: Option<DmaBuffer>,
+    finished: bool,
+}
+
+impl PendingRequest<'_> {
+    pub fn is_complete(&self) -> bool {
+        self.device.done[usize::from(self.head)].load(Ordering::Acquire)
+    }
+
+    fn wait_completion(&mut self) -> Result<(), BlockError> {
+        let device = self.device;
+        let done = &device.done[usize::from(self.head)];
+        device
+            .completed
+            .wait_until_deadline(Deadline::after(TIMEOUT), || {
+                device.complete();
+                done.load(Ordering::Acquire).then_some(())
+            })
+            .ok_or(BlockError::Timeout)?;
+
+        device.queue.lock().free(self.head);
+        device.completed.notify_all();
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Waits for the request to complete, returning its data buffer, if it has one
+    pub fn wait(mut self) -> Result<Option<DmaBuffer>, BlockError> {
+        self.wait_completion()?;
+        let status = self.header.as_ref().map_or(0xff, |h| h[STATUS_OFFSET]);
+        match status {
+            STATUS_OK => Ok(self.data.take()),
+            status => Err(BlockError::Device(status)),
+        }
+    }
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        if !self.finished && self.wait_completion().is_err() {
+            // The device may still write to the buffers, so they can't be reused
+            core::mem::forget(self.header.take());
+            core::mem::forget(self.data.take());
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        super::check_access(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            let data = self.start_read(lba, chunk.len() / SECTOR_SIZE)?.wait()?;
+            if let Some(data) = data {
+                chunk.copy_from_slice(&data[..chunk.len()]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        super::check_access(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            let mut data = DmaBuffer::new(chunk.len()).map_err(|_| BlockError::NoMemory)?;
+            data[..chunk.len()].copy_from_slice(chunk);
+            self.start_write(lba, data, chunk.len() / SECTOR_SIZE)?
+                .wait()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.features & F_FLUSH == 0 {
+            return Ok(());
+        }
+        self.submit(TYPE_FLUSH, 0, None)?.wait().map(|_| ())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.features & F_RO != 0
+    }
+}
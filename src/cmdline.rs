@@ -0,0 +1,36 @@
+//! The raw kernel command line, parsed by `config`.
+//!
+//! The bootloader can't pass a command line, so it is taken from the `opt/ros/cmdline` fw_cfg
+//! file when the host provides one (`-fw_cfg name=opt/ros/cmdline,string=...`). Otherwise it
+//! is embedded at build time: from the `ROS_CMDLINE` environment variable if set, and
+//! `DEFAULT` otherwise.
+
+use spin::Once;
+
+use crate::fw_cfg;
+
+/// The command line used when none is given by the host or at build time
+pub const DEFAULT: &str = "";
+
+/// The fw_cfg file the host can pass the command line in
+pub const FW_CFG_FILE: &str = "opt/ros/cmdline";
+
+/// Longer command lines from the host are truncated
+const MAX_LEN: usize = 1024;
+
+pub fn get() -> &'static str {
+    // Read without the heap, since the command line configures it
+    static FROM_HOST: Once<Option<([u8; MAX_LEN], usize)>> = Once::new();
+    let from_host = FROM_HOST.call_once(|| {
+        let file = fw_cfg::find(FW_CFG_FILE)?;
+        let mut buf = [0; MAX_LEN];
+        let len = fw_cfg::read_into(&file, &mut buf);
+        Some((buf, len))
+    });
+
+    from_host
+        .as_ref()
+        .and_then(|(buf, len)| core::str::from_utf8(&buf[..*len]).ok())
+        .or(option_env!("ROS_CMDLINE"))
+        .unwrap_or(DEFAULT)
+}
@@ -0,0 +1,205 @@
+//! The boot configuration, parsed from the kernel command line.
+//!
+//! Recognized arguments:
+//! - `heap_size=<size>`: size of the kernel heap, with an optional `K`, `M` or `G` suffix
+//! - `log=<off|error|warn|info|debug|trace>`: the maximum log level
+//! - `console=<vga|serial|both>`: where log records are printed
+//! - `panic=<halt|reboot|exit>`: what happens after a kernel panic
+//! - `test.filter=<pattern>`, `test.skip=<pattern>`, `test.ignored` and
+//!   `test.format=<pretty|tap|junit>`: see `testing`
+//!
+//! Arguments which aren't recognized or have an invalid value are kept, so they can be
+//! reported once logging is up.
+
+use log::LevelFilter;
+use spin::Once;
+
+use crate::{
+    allocator, cmdline, logger::Console, panic::PanicPolicy, testing::report::Format, QemuExitCode,
+};
+
+/// Maximum number of values kept for a repeatable argument
+const MAX_VALUES: usize = 8;
+
+/// The values of a repeatable argument, in order. Values past `MAX_VALUES` are dropped
+#[derive(Debug, Clone, Copy)]
+pub struct Values {
+    values: [&'static str; MAX_VALUES],
+    len: usize,
+}
+
+impl Values {
+    const fn new() -> Self {
+        Self {
+            values: [""; MAX_VALUES],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, value: &'static str) {
+        if self.len < MAX_VALUES {
+            self.values[self.len] = value;
+            self.len += 1;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.values[..self.len].iter().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for Values {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TestConfig {
+    /// Only tests whose name contains one of these run, if there are any
+    pub filters: Values,
+    /// Tests whose name contains one of these are skipped
+    pub skip: Values,
+    /// Whether tests marked as ignored run too
+    pub run_ignored: bool,
+    pub format: Format,
+}
+
+impl TestConfig {
+    pub fn is_selected(&self, name: &str) -> bool {
+        let filtered_in = self.filters.is_empty() || self.filters.iter().any(|f| name.contains(f));
+        filtered_in && !self.skip.iter().any(|s| name.contains(s))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootConfig {
+    pub heap_size: usize,
+    pub log_level: LevelFilter,
+    pub console: Console,
+    pub panic_policy: PanicPolicy,
+    pub test: TestConfig,
+    /// Arguments which weren't recognized or had an invalid value
+    pub invalid: Values,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            heap_size: allocator::HEAP_SIZE,
+            log_level: LevelFilter::Info,
+            console: Console::Both,
+            panic_policy: PanicPolicy::Halt,
+            test: TestConfig {
+                filters: Values::new(),
+                skip: Values::new(),
+                run_ignored: false,
+                format: Format::Pretty,
+            },
+            invalid: Values::new(),
+        }
+    }
+}
+
+impl BootConfig {
+    pub fn parse(cmdline: &'static str) -> Self {
+        let mut config = Self::default();
+        for arg in cmdline.split_whitespace() {
+            let (key, value) = match arg.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (arg, None),
+            };
+            if config.apply(key, value).is_none() {
+                config.invalid.push(arg);
+            }
+        }
+        config
+    }
+
+    /// Applies a single argument, returning `None` if it isn't valid
+    fn apply(&mut self, key: &str, value: Option<&'static str>) -> Option<()> {
+        match (key, value) {
+            ("heap_size", Some(size)) => self.heap_size = parse_size(size)?,
+            ("log", Some(level)) => self.log_level = level.parse().ok()?,
+            ("console", Some(console)) => {
+                self.console = match console {
+                    "vga" => Console::Vga,
+                    "serial" => Console::Serial,
+                    "both" => Console::Both,
+                    _ => return None,
+                }
+            }
+            ("panic", Some(policy)) => {
+                self.panic_policy = match policy {
+                    "halt" => PanicPolicy::Halt,
+                    "reboot" => PanicPolicy::Reboot,
+                    "exit" => PanicPolicy::ExitQemu(QemuExitCode::Failed),
+                    _ => return None,
+                }
+            }
+            ("test.filter", Some(pattern)) => self.test.filters.push(pattern),
+            ("test.skip", Some(pattern)) => self.test.skip.push(pattern),
+            ("test.ignored", None) => self.test.run_ignored = true,
+            ("test.format", Some(format)) => self.test.format = Format::parse(format)?,
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// Parses a size in bytes, with an optional `K`, `M` or `G` suffix
+fn parse_size(size: &str) -> Option<usize> {
+    let (digits, shift) = match size.as_bytes().last()? {
+        b'k' | b'K' => (&size[..size.len() - 1], 10),
+        b'm' | b'M' => (&size[..size.len() - 1], 20),
+        b'g' | b'G' => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+static CONFIG: Once<BootConfig> = Once::new();
+
+/// The boot configuration, parsed from the command line on first use
+pub fn get() -> &'static BootConfig {
+    CONFIG.call_once(|| BootConfig::parse(cmdline::get()))
+}
+
+#[test_case]
+fn test_parse_boot_config() {
+    let config = BootConfig::parse(
+        "heap_size=2M log=debug console=serial panic=exit test.filter=vga \
+         test.filter=time test.ignored bogus log=loud",
+    );
+    assert_eq!(config.heap_size, 2 * 1024 * 1024);
+    assert_eq!(config.log_level, LevelFilter::Debug);
+    assert_eq!(config.console, Console::Serial);
+    assert_eq!(
+        config.panic_policy,
+        PanicPolicy::ExitQemu(QemuExitCode::Failed)
+    );
+    assert!(config.test.run_ignored);
+    assert!(config.test.is_selected("ros::vga_buffer::test_vga_colors"));
+    assert!(config.test.is_selected("ros::time::test_instant_monotonic"));
+    assert!(!config
+        .test
+        .is_selected("ros::rtc::test_rtc_update_interrupt"));
+
+    let mut invalid = config.invalid.iter();
+    assert_eq!(invalid.next(), Some("bogus"));
+    assert_eq!(invalid.next(), Some("log=loud"));
+    assert_eq!(invalid.next(), None);
+}
+
+#[test_case]
+fn test_parse_size() {
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("64K"), Some(64 * 1024));
+    assert_eq!(parse_size("1g"), Some(1 << 30));
+    assert_eq!(parse_size("M"), None);
+    assert_eq!(parse_size(""), None);
+}
@@ -0,0 +1,244 @@
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+/// Number of work items a queue can hold
+const CAPACITY: usize = 256;
+
+/// A small unit of work deferred out of an interrupt handler
+#[derive(Debug, Clone, Copy)]
+pub struct Work {
+    f: fn(usize),
+    data: usize,
+}
+
+impl Work {
+    pub const fn new(f: fn(usize), data: usize) -> Self {
+        Self { f, data }
+    }
+
+    pub fn run(self) {
+        (self.f)(self.data)
+    }
+}
+
+/// The queue was full, so the work item was dropped
+#[derive(Debug, Clone, Copy)]
+pub struct QueueFull(pub Work);
+
+struct Slot {
+    sequence: AtomicUsize,
+    work: UnsafeCell<MaybeUninit<Work>>,
+}
+
+/// A bounded lock-free multi-producer multi-consumer queue of work items.
+///
+/// This is Dmitry Vyukov's bounded queue: each slot carries a sequence number telling
+/// producers and consumers whether it is free for the current lap around the ring, so a
+/// producer interrupted halfway through an enqueue never blocks an interrupt handler
+/// enqueueing on top of it
+pub struct WorkQueue {
+    slots: [Slot; CAPACITY],
+    enqueue_pos: AtomicUsize,
+    dequeue_pos: AtomicUsize,
+}
+
+unsafe impl Sync for WorkQueue {}
+
+impl WorkQueue {
+    pub const fn new() -> Self {
+        let mut slots = [const {
+            Slot {
+                sequence: AtomicUsize::new(0),
+                work: UnsafeCell::new(MaybeUninit::uninit()),
+            }
+        }; CAPACITY];
+        let mut i = 0;
+        while i < CAPACITY {
+            slots[i].sequence = AtomicUsize::new(i);
+            i += 1;
+        }
+
+        Self {
+            slots,
+            enqueue_pos: AtomicUsize::new(0),
+            dequeue_pos: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn push(&self, work: Work) -> Result<(), QueueFull> {
+        let mut pos = self.enqueue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % CAPACITY];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(pos as isize) {
+                0 => match self.enqueue_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.work.get()).write(work) };
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                },
+                diff if diff < 0 => return Err(QueueFull(work)),
+                _ => pos = self.enqueue_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<Work> {
+        let mut pos = self.dequeue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % CAPACITY];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(pos.wrapping_add(1) as isize) {
+                0 => match self.dequeue_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let work = unsafe { (*slot.work.get()).assume_init_read() };
+                        slot.sequence
+                            .store(pos.wrapping_add(CAPACITY), Ordering::Release);
+                        return Some(work);
+                    }
+                    Err(current) => pos = current,
+                },
+                diff if diff < 0 => return None,
+                _ => pos = self.dequeue_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dequeue_pos.load(Ordering::Relaxed) == self.enqueue_pos.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for WorkQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Only the bootstrap processor runs the kernel for now, so there is a single queue
+static QUEUE: WorkQueue = WorkQueue::new();
+/// Whether the local queue is currently being processed
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// The work queue of the current CPU
+fn local_queue() -> &'static WorkQueue {
+    &QUEUE
+}
+
+/// Defers `f(data)` until after the current interrupt has been acknowledged.
+///
+/// Safe to call from interrupt handlers. The work runs with interrupts enabled, either at the
+/// end of the interrupt handler or from `worker_loop`
+pub fn defer(f: fn(usize), data: usize) -> Result<(), QueueFull> {
+    local_queue().push(Work::new(f, data)).inspect_err(|_| {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    })
+}
+
+/// Number of work items dropped because the queue was full
+pub fn dropped_count() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Runs every pending work item of the current CPU, returning how many ran.
+///
+/// Does nothing if the queue is already being processed further up the stack
+pub fn run_pending() -> usize {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+
+    let mut ran = 0;
+    while let Some(work) = local_queue().pop() {
+        work.run();
+        ran += 1;
+    }
+
+    RUNNING.store(false, Ordering::Release);
+    ran
+}
+
+/// Processes pending work with interrupts enabled, at the end of an interrupt handler
+/// (after the EOI has been sent)
+pub(crate) fn run_after_interrupt() {
+    if local_queue().is_empty() || RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    interrupts::enable();
+    run_pending();
+    interrupts::disable();
+}
+
+/// Processes deferred work whenever the CPU has nothing else to do
+pub fn worker_loop() -> ! {
+    loop {
+        run_pending();
+        // Anything deferred from here on is processed by the interrupt handler that deferred it
+        interrupts::enable_and_hlt();
+    }
+}
+
+#[test_case]
+fn test_work_queue_fifo_and_capacity() {
+    static LOCAL: WorkQueue = WorkQueue::new();
+
+    fn nothing(_: usize) {}
+
+    for i in 0..CAPACITY {
+        LOCAL.push(Work::new(nothing, i)).unwrap();
+    }
+    assert!(LOCAL.push(Work::new(nothing, CAPACITY)).is_err());
+    for i in 0..CAPACITY {
+        assert_eq!(LOCAL.pop().map(|w| w.data), Some(i));
+    }
+    assert!(LOCAL.pop().is_none());
+    assert!(LOCAL.is_empty());
+}
+
+#[test_case]
+fn test_work_deferred_from_timer_runs_once() {
+    use crate::irq::{self, IrqReturn};
+
+    static ARMED: AtomicBool = AtomicBool::new(true);
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    static RAN_WITH_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+    fn work(data: usize) {
+        assert_eq!(data, 42);
+        RUNS.fetch_add(1, Ordering::Relaxed);
+        RAN_WITH_INTERRUPTS.store(interrupts::are_enabled(), Ordering::Relaxed);
+    }
+    fn on_timer() -> IrqReturn {
+        if ARMED.swap(false, Ordering::Relaxed) {
+            defer(work, 42).unwrap();
+        }
+        IrqReturn::NotMine
+    }
+
+    irq::register(irq::TIMER_IRQ, on_timer).unwrap();
+    crate::time::busy_wait(core::time::Duration::from_millis(20));
+    irq::unregister(irq::TIMER_IRQ, on_timer).unwrap();
+
+    assert!(!ARMED.load(Ordering::Relaxed));
+    assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+    assert!(RAN_WITH_INTERRUPTS.load(Ordering::Relaxed));
+}
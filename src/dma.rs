@@ -0,0 +1,178 @@
+//! Physically contiguous memory for devices doing DMA.
+//!
+//! Heap memory is only contiguous in virtual memory, so `init` sets aside a pool of
+//! contiguous frames at boot, from which `DmaBuffer`s are allocated in whole pages. Buffers
+//! are accessed through the bootloader's mapping of physical memory.
+
+use core::{
+    ops::{Deref, DerefMut},
+    slice,
+};
+
+use x86_64::{
+    structures::paging::{FrameAllocator, Size4KiB},
+    PhysAddr,
+};
+
+use crate::{
+    memory,
+    sync::{IrqSpinLock, LockClass, RawIrqSpinLock},
+};
+
+pub const PAGE_SIZE: usize = 4096;
+/// The size of the pool, in pages
+const POOL_PAGES: usize = 512;
+/// How many runs of frames `init` tries before giving up on finding a contiguous one
+const MAX_ATTEMPTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// `init` wasn't called, or failed
+    NotInitialized,
+    /// No large enough run of free pages is left in the pool
+    OutOfMemory,
+}
+
+struct Pool {
+    base: PhysAddr,
+    /// One bit per page, set while it is allocated
+    used: [u64; POOL_PAGES / 64],
+}
+
+impl Pool {
+    fn is_used(&self, page: usize) -> bool {
+        self.used[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn set_used(&mut self, pages: core::ops::Range<usize>, used: bool) {
+        for page in pages {
+            if used {
+                self.used[page / 64] |= 1 << (page % 64);
+            } else {
+                self.used[page / 64] &= !(1 << (page % 64));
+            }
+        }
+    }
+
+    /// Finds the first run of `count` free pages
+    fn allocate(&mut self, count: usize) -> Option<usize> {
+        let mut start = 0;
+        while start + count <= POOL_PAGES {
+            match (start..start + count).find(|&page| self.is_used(page)) {
+                Some(used) => start = used + 1,
+                None => {
+                    self.set_used(start..start + count, true);
+                    return Some(start);
+                }
+            }
+        }
+        None
+    }
+}
+
+static POOL: IrqSpinLock<Option<Pool>> =
+    IrqSpinLock::from_raw(RawIrqSpinLock::with_class(LockClass::new("DMA_POOL")), None);
+
+/// Sets aside the pool, taking frames from `frame_allocator` until enough of them are
+/// contiguous. Frames from runs which turn out too short are lost.
+///
+/// Needs the memory module, to access the pool
+pub fn init(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(), DmaError> {
+    for _ in 0..MAX_ATTEMPTS {
+        let first = frame_allocator
+            .allocate_frame()
+            .ok_or(DmaError::OutOfMemory)?;
+        let mut last = first;
+        let mut count = 1;
+        while count < POOL_PAGES {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(DmaError::OutOfMemory)?;
+            if frame != last + 1 {
+                break;
+            }
+            last = frame;
+            count += 1;
+        }
+
+        if count == POOL_PAGES {
+            *POOL.lock() = Some(Pool {
+                base: first.start_address(),
+                used: [0; POOL_PAGES / 64],
+            });
+            return Ok(());
+        }
+    }
+    Err(DmaError::OutOfMemory)
+}
+
+/// A zeroed, physically contiguous buffer of whole pages, returned to the pool on drop
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates a buffer of `len` bytes, rounded up to whole pages
+    pub fn new(len: usize) -> Result<Self, DmaError> {
+        let pages = len.max(1).div_ceil(PAGE_SIZE);
+        let phys = {
+            let mut pool = POOL.lock();
+            let pool = pool.as_mut().ok_or(DmaError::NotInitialized)?;
+            let page = pool.allocate(pages).ok_or(DmaError::OutOfMemory)?;
+            pool.base + (page * PAGE_SIZE) as u64
+        };
+        let mut buffer = Self {
+            phys,
+            len: pages * PAGE_SIZE,
+        };
+        buffer.fill(0);
+        Ok(buffer)
+    }
+
+    /// The physical address of the start of the buffer, for the device
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// The physical address of `offset`, which must be in the buffer
+    pub fn phys_at(&self, offset: usize) -> PhysAddr {
+        assert!(offset < self.len);
+        self.phys + offset as u64
+    }
+
+    /// A pointer to the start of the buffer, for memory the device accesses while the driver
+    /// does, which must only be accessed with volatile reads and writes
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        memory::phys_to_virt(self.phys)
+            .expect("memory not initialized")
+            .as_mut_ptr()
+    }
+}
+
+impl Deref for DmaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_mut_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for DmaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let mut pool = POOL.lock();
+        if let Some(pool) = pool.as_mut() {
+            let first = ((self.phys - pool.base) as usize) / PAGE_SIZE;
+            pool.set_used(first..first + self.len / PAGE_SIZE, false);
+        }
+    }
+}
+
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
@@ -0,0 +1,553 @@
+//! The virtual filesystem: a single tree of files, made of the filesystems mounted in it.
+//!
+//! Filesystems expose their files and directories as `Inode`s. Paths are absolute and are
+//! resolved lexically, so `.` and `..` never leave the root: the mount with the longest
+//! matching prefix is picked, and the remaining components are looked up one by one from the
+//! root of its filesystem. A symbolic link met on the way is replaced by its target, relative
+//! to the directory containing it unless absolute, and the resulting path resolved again. The
+//! last component of a path is followed too, except by `remove`, `read_link` and
+//! `symlink_metadata`. Opened files are `File`s, referred to by the descriptors in a single,
+//! kernel wide table.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{
+    block::BlockError,
+    sync::{Mutex, RwLock},
+};
+
+pub mod ext2;
+pub mod fat;
+pub mod initrd;
+pub mod iso9660;
+pub mod ramfs;
+
+pub type Result<T> = core::result::Result<T, FsError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// No file or directory exists at the path
+    NotFound,
+    /// A file was used where a directory is expected
+    NotADirectory,
+    /// A directory was used where a file is expected
+    IsADirectory,
+    AlreadyExists,
+    /// Only empty directories can be removed
+    DirectoryNotEmpty,
+    /// The path isn't absolute, or names the root where a child is expected
+    InvalidPath,
+    /// The file or filesystem can't be modified
+    ReadOnly,
+    /// The file wasn't opened with the access needed
+    PermissionDenied,
+    /// The file descriptor isn't open
+    BadDescriptor,
+    /// All `MAX_OPEN_FILES` descriptors are in use
+    TooManyOpenFiles,
+    /// A filesystem is mounted on or beneath the path
+    Busy,
+    /// The file can't grow any further
+    NoSpace,
+    /// An argument, such as a seek position, is out of range
+    InvalidArgument,
+    /// The device backing the filesystem failed
+    Io,
+    /// More than `MAX_SYMLINKS` symbolic links were followed, which usually means they loop
+    SymlinkLoop,
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> Self {
+        match err {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            _ => FsError::Io,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    /// The size in bytes, or the number of entries of a directory
+    pub size: u64,
+    /// Identifies the file within its filesystem
+    pub inode: u64,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+/// A file or directory of a filesystem.
+///
+/// The methods only valid for one kind of inode fail with `NotADirectory` or `IsADirectory`
+/// for the other. The ones modifying the filesystem fail with `ReadOnly` unless implemented
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// The type of the inode, which path resolution asks for every component. Worth
+    /// implementing when `metadata` does more than that
+    fn file_type(&self) -> FileType {
+        self.metadata().file_type
+    }
+
+    /// Reads from `offset`, returning the number of bytes read, which is 0 at the end
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+
+    /// Writes at `offset`, growing the file if needed. A gap past the previous end reads as
+    /// zeroes
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Shrinks or grows the file to `size` bytes
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// The entry of this directory called `name`
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>>;
+
+    /// Adds an empty file or directory called `name` to this directory
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Removes the entry called `name` from this directory, which must be empty if it is a
+    /// directory itself
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// The entries of this directory, without `.` and `..`
+    fn read_dir(&self) -> Result<Vec<DirEntry>>;
+
+    /// The target of this symbolic link. Fails with `InvalidArgument` for other inodes
+    fn read_link(&self) -> Result<String> {
+        Err(FsError::InvalidArgument)
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// An opened file, with its own position
+pub trait File: Send + Sync {
+    /// Reads from the current position, advancing it
+    fn read(&self, buf: &mut [u8]) -> Result<usize>;
+    /// Writes at the current position, or at the end when appending, advancing it
+    fn write(&self, buf: &[u8]) -> Result<usize>;
+    /// Moves the position, returning the new one
+    fn seek(&self, pos: SeekFrom) -> Result<u64>;
+    fn metadata(&self) -> Metadata;
+}
+
+/// How a file is opened, by default for reading only
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    create: bool,
+    truncate: bool,
+}
+
+impl OpenOptions {
+    pub const fn new() -> Self {
+        Self {
+            read: true,
+            write: false,
+            append: false,
+            create: false,
+            truncate: false,
+        }
+    }
+
+    pub const fn read(self, read: bool) -> Self {
+        Self { read, ..self }
+    }
+
+    pub const fn write(self, write: bool) -> Self {
+        Self { write, ..self }
+    }
+
+    /// Every write goes to the end of the file. Implies `write`
+    pub const fn append(self, append: bool) -> Self {
+        Self {
+            append,
+            write: self.write || append,
+            ..self
+        }
+    }
+
+    /// Creates the file if it doesn't exist yet
+    pub const fn create(self, create: bool) -> Self {
+        Self { create, ..self }
+    }
+
+    /// Empties the file when opening it for writing
+    pub const fn truncate(self, truncate: bool) -> Self {
+        Self { truncate, ..self }
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A `File` reading and writing an inode
+struct InodeFile {
+    inode: Arc<dyn Inode>,
+    options: OpenOptions,
+    position: Mutex<u64>,
+}
+
+impl File for InodeFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        if !self.options.read {
+            return Err(FsError::PermissionDenied);
+        }
+        let mut position = self.position.lock();
+        let read = self.inode.read_at(*position, buf)?;
+        *position += read as u64;
+        Ok(read)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        if !self.options.write {
+            return Err(FsError::PermissionDenied);
+        }
+        let mut position = self.position.lock();
+        if self.options.append {
+            *position = self.inode.metadata().size;
+        }
+        let written = self.inode.write_at(*position, buf)?;
+        *position += written as u64;
+        Ok(written)
+    }
+
+    fn seek(&self, pos: SeekFrom) -> Result<u64> {
+        let mut position = self.position.lock();
+        let new = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.inode.metadata().size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+        };
+        *position = new.ok_or(FsError::InvalidArgument)?;
+        Ok(*position)
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+}
+
+/// A file descriptor, returned by `open`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fd(pub usize);
+
+/// Maximum number of files open at the same time
+pub const MAX_OPEN_FILES: usize = 64;
+
+/// Maximum number of symbolic links followed while resolving a path
+pub const MAX_SYMLINKS: usize = 40;
+
+struct Mount {
+    /// The normalized components of the mount point
+    path: Vec<String>,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+static FILES: Mutex<Vec<Option<Arc<dyn File>>>> = Mutex::new(Vec::new());
+
+/// Mounts an empty ramfs as the root, unless a filesystem is already mounted there
+pub fn init() {
+    match mount("/", Arc::new(ramfs::RamFs::new())) {
+        Ok(()) | Err(FsError::Busy) => {}
+        Err(err) => panic!("failed to mount the root filesystem: {err:?}"),
+    }
+}
+
+/// The components of the absolute `path`, with `.` and `..` applied
+fn normalize(path: &str) -> Result<Vec<&str>> {
+    let relative = path.strip_prefix('/').ok_or(FsError::InvalidPath)?;
+    let mut components = Vec::new();
+    for component in relative.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+/// Resolves `components`, following symbolic links, the last one only if `follow_last`
+fn resolve_components(components: &[&str], follow_last: bool) -> Result<Arc<dyn Inode>> {
+    let mut path: Vec<String> = components.iter().map(|&c| String::from(c)).collect();
+    let mut followed = 0;
+    'resolve: loop {
+        let (mut inode, start) = {
+            let mounts = MOUNTS.read();
+            let mount = mounts
+                .iter()
+                .filter(|mount| {
+                    mount.path.len() <= path.len()
+                        && mount.path.iter().zip(&path).all(|(a, b)| a == b)
+                })
+                .max_by_key(|mount| mount.path.len())
+                .ok_or(FsError::NotFound)?;
+            (mount.fs.root(), mount.path.len())
+        };
+
+        for (i, name) in path.iter().enumerate().skip(start) {
+            inode = inode.lookup(name)?;
+            if inode.file_type() != FileType::Symlink || (!follow_last && i == path.len() - 1) {
+                continue;
+            }
+            followed += 1;
+            if followed > MAX_SYMLINKS {
+                return Err(FsError::SymlinkLoop);
+            }
+            let target = inode.read_link()?;
+            let mut resolved = String::new();
+            if !target.starts_with('/') {
+                for component in &path[..i] {
+                    resolved.push('/');
+                    resolved.push_str(component);
+                }
+                resolved.push('/');
+            }
+            resolved.push_str(&target);
+            for component in &path[i + 1..] {
+                resolved.push('/');
+                resolved.push_str(component);
+            }
+            path = normalize(&resolved)?
+                .into_iter()
+                .map(String::from)
+                .collect();
+            continue 'resolve;
+        }
+        return Ok(inode);
+    }
+}
+
+fn resolve(path: &str) -> Result<Arc<dyn Inode>> {
+    resolve_components(&normalize(path)?, true)
+}
+
+/// The directory containing `path`, and the name of `path` in it
+fn resolve_parent(path: &str) -> Result<(Arc<dyn Inode>, String)> {
+    let mut components = normalize(path)?;
+    let name = components.pop().ok_or(FsError::InvalidPath)?;
+    Ok((resolve_components(&components, true)?, name.to_string()))
+}
+
+/// Resolves `path` without following it if it is a symbolic link itself
+fn resolve_no_follow(path: &str) -> Result<Arc<dyn Inode>> {
+    resolve_components(&normalize(path)?, false)
+}
+
+/// Whether a filesystem is mounted on or beneath `components`
+fn is_mount_point(components: &[&str]) -> bool {
+    MOUNTS.read().iter().any(|mount| {
+        mount.path.len() >= components.len()
+            && mount.path.iter().zip(components).all(|(a, b)| a == b)
+    })
+}
+
+/// Mounts `fs` on the directory at `path`, hiding its contents until `fs` is unmounted
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+    let components = normalize(path)?;
+    // The root doesn't need to exist before something is mounted on it
+    if !components.is_empty() && !resolve_components(&components, true)?.metadata().is_dir() {
+        return Err(FsError::NotADirectory);
+    }
+
+    let mut mounts = MOUNTS.write();
+    if mounts
+        .iter()
+        .any(|mount| mount.path.iter().eq(components.iter()))
+    {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Mount {
+        path: components.into_iter().map(String::from).collect(),
+        fs,
+    });
+    Ok(())
+}
+
+/// Unmounts the filesystem mounted on `path`, which must not have others mounted beneath it
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>> {
+    let components = normalize(path)?;
+    let mut mounts = MOUNTS.write();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path.iter().eq(components.iter()))
+        .ok_or(FsError::NotFound)?;
+    let nested = mounts.iter().any(|mount| {
+        mount.path.len() > components.len() && mount.path.starts_with(&mounts[index].path)
+    });
+    if nested {
+        return Err(FsError::Busy);
+    }
+    Ok(mounts.remove(index).fs)
+}
+
+pub fn metadata(path: &str) -> Result<Metadata> {
+    Ok(resolve(path)?.metadata())
+}
+
+/// The metadata of `path`, or of the link itself if it is a symbolic link
+pub fn symlink_metadata(path: &str) -> Result<Metadata> {
+    Ok(resolve_no_follow(path)?.metadata())
+}
+
+/// The target of the symbolic link at `path`
+pub fn read_link(path: &str) -> Result<String> {
+    resolve_no_follow(path)?.read_link()
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>> {
+    resolve(path)?.read_dir()
+}
+
+pub fn create_dir(path: &str) -> Result<()> {
+    let (parent, name) = resolve_parent(path)?;
+    parent.create(&name, FileType::Directory).map(drop)
+}
+
+/// Removes the file or empty directory at `path`
+pub fn remove(path: &str) -> Result<()> {
+    if is_mount_point(&normalize(path)?) {
+        return Err(FsError::Busy);
+    }
+    let (parent, name) = resolve_parent(path)?;
+    parent.unlink(&name)
+}
+
+pub fn open(path: &str, options: OpenOptions) -> Result<Fd> {
+    let inode = match resolve(path) {
+        Err(FsError::NotFound) if options.create => {
+            let (parent, name) = resolve_parent(path)?;
+            parent.create(&name, FileType::Regular)?
+        }
+        result => result?,
+    };
+    if inode.metadata().is_dir() && options.write {
+        return Err(FsError::IsADirectory);
+    }
+    if options.truncate && options.write {
+        inode.truncate(0)?;
+    }
+
+    let file = Arc::new(InodeFile {
+        inode,
+        options,
+        position: Mutex::new(0),
+    });
+
+    let mut files = FILES.lock();
+    let fd = match files.iter().position(Option::is_none) {
+        Some(free) => free,
+        None if files.len() < MAX_OPEN_FILES => {
+            files.push(None);
+            files.len() - 1
+        }
+        None => return Err(FsError::TooManyOpenFiles),
+    };
+    files[fd] = Some(file);
+    Ok(Fd(fd))
+}
+
+/// The file open as `fd`
+pub fn file(fd: Fd) -> Result<Arc<dyn File>> {
+    FILES
+        .lock()
+        .get(fd.0)
+        .cloned()
+        .flatten()
+        .ok_or(FsError::BadDescriptor)
+}
+
+pub fn close(fd: Fd) -> Result<()> {
+    FILES
+        .lock()
+        .get_mut(fd.0)
+        .and_then(Option::take)
+        .map(drop)
+        .ok_or(FsError::BadDescriptor)
+}
+
+pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize> {
+    file(fd)?.read(buf)
+}
+
+pub fn write(fd: Fd, buf: &[u8]) -> Result<usize> {
+    file(fd)?.write(buf)
+}
+
+pub fn seek(fd: Fd, pos: SeekFrom) -> Result<u64> {
+    file(fd)?.seek(pos)
+}
+
+/// Reads the whole file at `path`
+pub fn read_to_vec(path: &str) -> Result<Vec<u8>> {
+    let inode = resolve(path)?;
+    let mut contents = alloc::vec![0; inode.metadata().size as usize];
+    let mut len = 0;
+    while len < contents.len() {
+        match inode.read_at(len as u64, &mut contents[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    contents.truncate(len);
+    Ok(contents)
+}
+
+/// Replaces the contents of the file at `path`, creating it if needed
+pub fn write_file(path: &str, contents: &[u8]) -> Result<()> {
+    let fd = open(
+        path,
+        OpenOptions::new().write(true).create(true).truncate(true),
+    )?;
+    let result = write(fd, contents).map(drop);
+    close(fd)?;
+    result
+}
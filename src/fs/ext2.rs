@@ -0,0 +1,461 @@
+//! Read-only ext2 filesystems.
+//!
+//! An ext2 volume is split into block groups, each with a bitmap of its blocks, a bitmap of
+//! its inodes and a table of inodes. The superblock, 1024 bytes into the volume, gives their
+//! sizes, and the group descriptors in the blocks after it where each table is. An inode lists
+//! the blocks of its file: twelve directly, then through blocks of block numbers one, two and
+//! three levels deep. A block number of 0 is a hole, read as zeroes.
+//!
+//! Directories are files of variable length entries. Symbolic links keep their target in the
+//! block list of their inode when it is short enough, and in a data block otherwise.
+//!
+//! Volumes with features changing how files are found, such as extents, are refused. The
+//! others are read whatever the features they set, since nothing is written back. Inodes other
+//! than directories and symbolic links, such as devices, are seen as regular files.
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata, Result};
+use crate::block::{self, BlockDevice, BlockError};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const GROUP_DESC_SIZE: usize = 32;
+/// The size of the inodes of revision 0 volumes, and the part of larger ones read here
+const OLD_INODE_SIZE: usize = 128;
+const ROOT_INODE: u32 = 2;
+
+/// Directory entries record the type of their inode
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Group bitmaps and tables may be anywhere, which the group descriptors give anyway
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+const MODE_TYPE_MASK: u16 = 0xf000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_SYMLINK: u16 = 0xa000;
+
+const DIRECT_BLOCKS: u64 = 12;
+/// The block list of an inode: the direct blocks, then the single, double and triple
+/// indirect ones
+const BLOCK_POINTERS: usize = 15;
+const DIR_ENTRY_HEADER: usize = 8;
+
+/// The file types of directory entries
+const DIR_TYPE_DIRECTORY: u8 = 2;
+const DIR_TYPE_SYMLINK: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext2Error {
+    /// Reading the device failed
+    Block(BlockError),
+    /// The superblock doesn't describe a valid ext2 volume
+    BadSuperblock,
+    /// The volume sets incompatible features which aren't supported, given here
+    Unsupported(u32),
+}
+
+impl From<BlockError> for Ext2Error {
+    fn from(err: BlockError) -> Self {
+        Ext2Error::Block(err)
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    filetype: bool,
+    /// The first block of the inode table of each group
+    inode_tables: Vec<u32>,
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> core::result::Result<(Self, String), Ext2Error> {
+        let mut sb = [0; SUPERBLOCK_SIZE];
+        if device.size() < SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE as u64 {
+            return Err(Ext2Error::BadSuperblock);
+        }
+        block::read_bytes(device.as_ref(), SUPERBLOCK_OFFSET, &mut sb)?;
+        if le16(&sb, 56) != MAGIC {
+            return Err(Ext2Error::BadSuperblock);
+        }
+
+        let inode_count = le32(&sb, 0);
+        let block_count = le32(&sb, 4);
+        let first_data_block = le32(&sb, 20);
+        let log_block_size = le32(&sb, 24);
+        let blocks_per_group = le32(&sb, 32);
+        let inodes_per_group = le32(&sb, 40);
+        let revision = le32(&sb, 76);
+        let (inode_size, incompat) = match revision {
+            0 => (OLD_INODE_SIZE, 0),
+            _ => (usize::from(le16(&sb, 88)), le32(&sb, 96)),
+        };
+        // Blocks are 1KiB to 64KiB
+        if log_block_size > 6 {
+            return Err(Ext2Error::BadSuperblock);
+        }
+        let block_size = 1024 << log_block_size;
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || first_data_block >= block_count
+            || inode_size < OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+            || inode_size > block_size
+            || u64::from(block_count) * block_size as u64 > device.size()
+        {
+            return Err(Ext2Error::BadSuperblock);
+        }
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(Ext2Error::Unsupported(incompat & !SUPPORTED_INCOMPAT));
+        }
+
+        let group_count = (block_count - first_data_block).div_ceil(blocks_per_group);
+        if u64::from(group_count) * u64::from(inodes_per_group) < u64::from(inode_count) {
+            return Err(Ext2Error::BadSuperblock);
+        }
+        let mut descriptors = vec![0; group_count as usize * GROUP_DESC_SIZE];
+        let table_offset = u64::from(first_data_block + 1) * block_size as u64;
+        block::read_bytes(device.as_ref(), table_offset, &mut descriptors)?;
+        let inode_tables = descriptors
+            .chunks_exact(GROUP_DESC_SIZE)
+            .map(|descriptor| le32(descriptor, 8))
+            .collect();
+
+        let name = &sb[120..136];
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        let volume_name = String::from_utf8_lossy(&name[..len]).into_owned();
+
+        let volume = Self {
+            device,
+            block_size,
+            inode_count,
+            inodes_per_group,
+            inode_size,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            inode_tables,
+        };
+        Ok((volume, volume_name))
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        Ok(block::read_bytes(self.device.as_ref(), offset, buf)?)
+    }
+
+    /// Reads `buf.len()` bytes at `offset` in block `block`, which is a hole if it is 0
+    fn read_block(&self, block: u32, offset: usize, buf: &mut [u8]) -> Result<()> {
+        if block == 0 {
+            buf.fill(0);
+            return Ok(());
+        }
+        let position = u64::from(block) * self.block_size as u64 + offset as u64;
+        if position + buf.len() as u64 > self.device.size() {
+            return Err(FsError::Io);
+        }
+        self.read(position, buf)
+    }
+
+    /// The entry at `index` of the block of block numbers `block`
+    fn indirect(&self, block: u32, index: u64) -> Result<u32> {
+        let mut entry = [0; 4];
+        self.read_block(block, index as usize * 4, &mut entry)?;
+        Ok(u32::from_le_bytes(entry))
+    }
+
+    /// The block holding block `index` of a file, 0 for a hole
+    fn map(&self, blocks: &[u32; BLOCK_POINTERS], index: u64) -> Result<u32> {
+        let per_block = self.block_size as u64 / 4;
+        if index < DIRECT_BLOCKS {
+            return Ok(blocks[index as usize]);
+        }
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = per_block;
+        for (level, &top) in blocks[DIRECT_BLOCKS as usize..].iter().enumerate() {
+            if index >= span {
+                index -= span;
+                span *= per_block;
+                continue;
+            }
+            // Walk down from the top block, each level dividing the span by `per_block`
+            let mut block = top;
+            for _ in 0..=level {
+                span /= per_block;
+                if block == 0 {
+                    break;
+                }
+                block = self.indirect(block, index / span)?;
+                index %= span;
+            }
+            return Ok(block);
+        }
+        Err(FsError::Io)
+    }
+
+    /// Where `inode` is on the device, if it is a valid inode number
+    fn inode_offset(&self, inode: u32) -> Option<u64> {
+        if inode == 0 || inode > self.inode_count {
+            return None;
+        }
+        let group = (inode - 1) / self.inodes_per_group;
+        let index = (inode - 1) % self.inodes_per_group;
+        let table = *self.inode_tables.get(group as usize)?;
+        let offset =
+            u64::from(table) * self.block_size as u64 + u64::from(index) * self.inode_size as u64;
+        (table != 0 && offset + OLD_INODE_SIZE as u64 <= self.device.size()).then_some(offset)
+    }
+
+    fn read_inode(self: &Arc<Self>, inode: u32) -> Result<Ext2Inode> {
+        let offset = self.inode_offset(inode).ok_or(FsError::Io)?;
+        let mut raw = [0; OLD_INODE_SIZE];
+        self.read(offset, &mut raw)?;
+        Ok(self.parse_inode(inode, &raw))
+    }
+
+    fn parse_inode(self: &Arc<Self>, inode: u32, raw: &[u8; OLD_INODE_SIZE]) -> Ext2Inode {
+        let mode = le16(raw, 0);
+        let file_type = match mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            _ => FileType::Regular,
+        };
+        // The upper half of the size is only kept for regular files, directories using the
+        // field for something else
+        let size = match file_type {
+            FileType::Regular => u64::from(le32(raw, 108)) << 32 | u64::from(le32(raw, 4)),
+            _ => u64::from(le32(raw, 4)),
+        };
+        let mut blocks = [0; BLOCK_POINTERS];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = le32(raw, 40 + 4 * i);
+        }
+        // Symbolic links with no data blocks, apart from one for extended attributes, keep
+        // their target in the block list
+        let sectors = u64::from(le32(raw, 28));
+        let attribute_sectors = match le32(raw, 104) {
+            0 => 0,
+            _ => self.block_size as u64 / 512,
+        };
+        let inline = file_type == FileType::Symlink && sectors == attribute_sectors;
+
+        Ext2Inode {
+            volume: self.clone(),
+            inode,
+            file_type,
+            size,
+            allocated: sectors * 512,
+            blocks,
+            inline,
+        }
+    }
+}
+
+struct Ext2Inode {
+    volume: Arc<Volume>,
+    inode: u32,
+    file_type: FileType,
+    size: u64,
+    /// The bytes in the blocks of the inode, including indirect and extended attribute blocks
+    allocated: u64,
+    blocks: [u32; BLOCK_POINTERS],
+    /// Whether this is a symbolic link with its target in `blocks`
+    inline: bool,
+}
+
+impl Ext2Inode {
+    /// Reads the data of the inode, whatever its type
+    fn read_data(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((self.size - offset) as usize);
+        let block_size = self.volume.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let start = (position % block_size) as usize;
+            let chunk = (len - done).min(block_size as usize - start);
+            let block = self.volume.map(&self.blocks, position / block_size)?;
+            self.volume
+                .read_block(block, start, &mut buf[done..done + chunk])?;
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// The entries of this directory, with `.` and `..`, as names, inodes and types
+    fn entries(&self) -> Result<Vec<(String, u32, u8)>> {
+        if self.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        // Directories have no holes, so a size past the blocks they have is corrupt
+        if self.size > self.allocated {
+            return Err(FsError::Io);
+        }
+
+        // Entries never cross blocks, which are read one at a time
+        let block_size = self.volume.block_size;
+        let mut block = vec![0; block_size];
+        let mut entries = Vec::new();
+        for start in (0..self.size).step_by(block_size) {
+            let len = self.read_data(start, &mut block)?;
+            let data = &block[..len];
+            let mut offset = 0;
+            while offset + DIR_ENTRY_HEADER <= data.len() {
+                let inode = le32(data, offset);
+                let rec_len = usize::from(le16(data, offset + 4));
+                let name_len = usize::from(data[offset + 6]);
+                if rec_len < DIR_ENTRY_HEADER
+                    || offset + rec_len > data.len()
+                    || DIR_ENTRY_HEADER + name_len > rec_len
+                {
+                    return Err(FsError::Io);
+                }
+                // Removed entries have no inode
+                if inode != 0 {
+                    let name =
+                        &data[offset + DIR_ENTRY_HEADER..offset + DIR_ENTRY_HEADER + name_len];
+                    entries.push((
+                        String::from_utf8_lossy(name).into_owned(),
+                        inode,
+                        data[offset + 7],
+                    ));
+                }
+                offset += rec_len;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        let size = match self.file_type {
+            FileType::Directory => self.read_dir().map_or(0, |entries| entries.len() as u64),
+            _ => self.size,
+        };
+        Metadata {
+            file_type: self.file_type,
+            size,
+            inode: self.inode.into(),
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        match self.file_type {
+            FileType::Regular => self.read_data(offset, buf),
+            FileType::Directory => Err(FsError::IsADirectory),
+            FileType::Symlink => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let (_, inode, _) = self
+            .entries()?
+            .into_iter()
+            .find(|(entry, _, _)| entry == name)
+            .ok_or(FsError::NotFound)?;
+        Ok(Arc::new(self.volume.read_inode(inode)?))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        self.entries()?
+            .into_iter()
+            .filter(|(name, _, _)| name != "." && name != "..")
+            .map(|(name, inode, file_type)| {
+                let file_type = match file_type {
+                    _ if !self.volume.filetype => self.volume.read_inode(inode)?.file_type,
+                    DIR_TYPE_DIRECTORY => FileType::Directory,
+                    DIR_TYPE_SYMLINK => FileType::Symlink,
+                    _ => FileType::Regular,
+                };
+                Ok(DirEntry { name, file_type })
+            })
+            .collect()
+    }
+
+    fn read_link(&self) -> Result<String> {
+        if self.file_type != FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        let target = if self.inline {
+            let len = self.size as usize;
+            if len > BLOCK_POINTERS * 4 {
+                return Err(FsError::Io);
+            }
+            self.blocks
+                .iter()
+                .flat_map(|block| block.to_le_bytes())
+                .take(len)
+                .collect()
+        } else {
+            // Targets fit in a block
+            if self.size > self.volume.block_size as u64 {
+                return Err(FsError::Io);
+            }
+            let mut target = vec![0; self.size as usize];
+            self.read_data(0, &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| FsError::Io)
+    }
+}
+
+/// An ext2 volume, mounted read-only
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    root: Arc<Ext2Inode>,
+    volume_name: String,
+}
+
+impl Ext2Fs {
+    /// Reads the superblock and group descriptors of the volume on `device`
+    pub fn new(device: Arc<dyn BlockDevice>) -> core::result::Result<Self, Ext2Error> {
+        let (volume, volume_name) = Volume::new(device)?;
+        let volume = Arc::new(volume);
+        let offset = volume
+            .inode_offset(ROOT_INODE)
+            .ok_or(Ext2Error::BadSuperblock)?;
+        let mut raw = [0; OLD_INODE_SIZE];
+        block::read_bytes(volume.device.as_ref(), offset, &mut raw)?;
+        let root = Arc::new(volume.parse_inode(ROOT_INODE, &raw));
+        if root.file_type != FileType::Directory {
+            return Err(Ext2Error::BadSuperblock);
+        }
+        Ok(Self {
+            volume,
+            root,
+            volume_name,
+        })
+    }
+
+    /// The name given to the volume when it was created, empty if it has none
+    pub fn volume_name(&self) -> &str {
+        &self.volume_name
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.volume.block_size
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
@@ -0,0 +1,1255 @@
+//! FAT12, FAT16 and FAT32 filesystems, with long file names.
+//!
+//! A FAT volume starts with a boot sector describing its layout in the BIOS parameter block:
+//! reserved sectors, one or more copies of the file allocation table, the fixed size root
+//! directory of FAT12 and FAT16, and then the clusters holding files and directories. The
+//! table links each cluster of a file to the next one. As in Linux, a volume is FAT32 when
+//! its boot sector gives the size of the table in the FAT32 field, whatever its number of
+//! clusters, and otherwise FAT12 or FAT16 depending on its number of clusters.
+//!
+//! Long names are kept in extra directory entries before the 8.3 one. Names which are valid
+//! 8.3 names, in a single case per part, are created without a long name. Names are looked up
+//! ignoring ASCII case, like on other systems.
+//!
+//! Every operation holds the lock of the volume. Blocks aren't cached: mount the volume on a
+//! `BlockCache` for that.
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata, Result};
+use crate::{
+    block::{self, BlockDevice, BlockError},
+    rtc::SystemTime,
+    sync::{LockClass, Mutex},
+};
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// The attributes of long name entries, which no short entry has
+const ATTR_LONG_NAME: u8 = 0x0f;
+const ATTR_LONG_NAME_MASK: u8 = 0x3f;
+
+/// The first byte of the slot after the last entry of a directory
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+/// Stands for 0xe5 as the first byte of a short name
+const ENTRY_E5: u8 = 0x05;
+
+/// Flags the last part of a long name, which comes first
+const LFN_LAST: u8 = 0x40;
+const LFN_SEQUENCE_MASK: u8 = 0x1f;
+/// The offsets of the 13 UCS-2 characters of a long name entry
+const LFN_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LEN: usize = 255;
+
+/// Flags in the reserved byte of short entries, for parts of the name in lower case
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+/// Characters allowed in short names, besides ASCII letters and digits
+const SHORT_NAME_SPECIAL: &[u8] = b"!#$%&'()-@^_`{}~";
+/// Characters no name can contain
+const INVALID_CHARS: &str = "\"*/:<>?\\|";
+const DOT: [u8; 11] = *b".          ";
+const DOT_DOT: [u8; 11] = *b"..         ";
+
+/// Volumes with fewer clusters than this are FAT12
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT32_MASK: u32 = 0x0fff_ffff;
+const FREE: u32 = 0;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: u64 = 488;
+/// Stands for a free count or next free cluster which isn't known
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// No entry is at offset 1, since they are aligned to their size
+const ROOT_INODE: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// The smallest table entry which ends a chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xff8,
+            FatType::Fat16 => 0xfff8,
+            FatType::Fat32 => 0x0fff_fff8,
+        }
+    }
+
+    /// The value written to end a chain
+    fn end_marker(self) -> u32 {
+        self.end_of_chain() | 7
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    /// Reading the device failed
+    Block(BlockError),
+    /// The boot sector doesn't describe a valid FAT volume
+    BadBootSector,
+}
+
+impl From<BlockError> for FatError {
+    fn from(err: BlockError) -> Self {
+        FatError::Block(err)
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// The state of a volume changed by its operations
+struct State {
+    /// Where to start looking for a free cluster
+    next_free: u32,
+    /// The number of free clusters, if it is known
+    free_count: Option<u32>,
+    /// Whether the FSInfo sector needs to be written
+    fs_info_dirty: bool,
+    /// The inodes in use, so each file has a single one
+    inodes: BTreeMap<u64, Weak<FatInode>>,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    read_only: bool,
+    cluster_size: usize,
+    cluster_count: u32,
+    /// The offset of the first copy of the table
+    fat_start: u64,
+    /// The size of each copy of the table
+    fat_size: u64,
+    fat_count: u32,
+    /// The offset and number of entries of the FAT12 and FAT16 root directory
+    root_dir: (u64, usize),
+    /// The first cluster of the FAT32 root directory
+    root_cluster: u32,
+    data_start: u64,
+    /// The offset of the FAT32 FSInfo sector
+    fs_info: Option<u64>,
+    state: Mutex<State>,
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> core::result::Result<Self, FatError> {
+        let mut boot = [0; 512];
+        block::read_bytes(device.as_ref(), 0, &mut boot)?;
+
+        let sector_size = u64::from(le16(&boot, 11));
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved = u64::from(le16(&boot, 14));
+        let fat_count = u32::from(boot[16]);
+        let root_entries = usize::from(le16(&boot, 17));
+        let total_sectors = match le16(&boot, 19) {
+            0 => u64::from(le32(&boot, 32)),
+            total => u64::from(total),
+        };
+        let fat32 = le16(&boot, 22) == 0;
+        let fat_sectors = if fat32 {
+            u64::from(le32(&boot, 36))
+        } else {
+            u64::from(le16(&boot, 22))
+        };
+        if !(512..=4096).contains(&sector_size)
+            || !sector_size.is_power_of_two()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_sectors == 0
+            || (fat32 && root_entries != 0)
+            || total_sectors * sector_size > device.size()
+        {
+            return Err(FatError::BadBootSector);
+        }
+
+        let root_dir_sectors = (root_entries * DIR_ENTRY_SIZE).div_ceil(sector_size as usize);
+        let data_start = reserved + u64::from(fat_count) * fat_sectors + root_dir_sectors as u64;
+        if data_start >= total_sectors {
+            return Err(FatError::BadBootSector);
+        }
+        let clusters = (total_sectors - data_start) / sectors_per_cluster;
+        let fat_type = match clusters {
+            _ if fat32 => FatType::Fat32,
+            clusters if clusters < u64::from(FAT12_MAX_CLUSTERS) => FatType::Fat12,
+            _ => FatType::Fat16,
+        };
+        // Clusters past the end of the table can't be used, and the first two entries are
+        // reserved
+        let entry_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        let entries = fat_sectors * sector_size * 8 / entry_bits;
+        let cluster_count = clusters
+            .min(entries.saturating_sub(2))
+            .min(u64::from(FAT32_MASK - 0xf)) as u32;
+        if cluster_count == 0 {
+            return Err(FatError::BadBootSector);
+        }
+
+        let root_cluster = if fat32 { le32(&boot, 44) } else { 0 };
+        let fs_info = match le16(&boot, 48) {
+            sector if fat32 && sector != 0 && u64::from(sector) < reserved => {
+                Some(u64::from(sector) * sector_size)
+            }
+            _ => None,
+        };
+
+        let mut volume = Self {
+            read_only: device.is_read_only(),
+            device,
+            fat_type,
+            cluster_size: (sectors_per_cluster * sector_size) as usize,
+            cluster_count,
+            fat_start: reserved * sector_size,
+            fat_size: fat_sectors * sector_size,
+            fat_count,
+            root_dir: (
+                (reserved + u64::from(fat_count) * fat_sectors) * sector_size,
+                root_entries,
+            ),
+            root_cluster,
+            data_start: data_start * sector_size,
+            fs_info,
+            state: Mutex::with_class(
+                LockClass::new("FAT"),
+                State {
+                    next_free: 2,
+                    free_count: None,
+                    fs_info_dirty: false,
+                    inodes: BTreeMap::new(),
+                },
+            ),
+        };
+        if fat32 && !volume.is_cluster(root_cluster) {
+            return Err(FatError::BadBootSector);
+        }
+        if let Some(offset) = volume.fs_info {
+            let mut info = [0; 512];
+            block::read_bytes(volume.device.as_ref(), offset, &mut info)?;
+            if le32(&info, 0) == FSINFO_LEAD_SIGNATURE
+                && le32(&info, 484) == FSINFO_STRUCT_SIGNATURE
+            {
+                let free_count = le32(&info, FSINFO_FREE_COUNT as usize);
+                let next_free = le32(&info, FSINFO_FREE_COUNT as usize + 4);
+                let next_free_valid = volume.is_cluster(next_free);
+                let state = volume.state.get_mut();
+                state.free_count = (free_count <= cluster_count).then_some(free_count);
+                if next_free_valid {
+                    state.next_free = next_free;
+                }
+            }
+        }
+        Ok(volume)
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - 2) * self.cluster_size as u64
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        Ok(block::read_bytes(self.device.as_ref(), offset, buf)?)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        Ok(block::write_bytes(self.device.as_ref(), offset, buf)?)
+    }
+
+    /// The offset of the entry of `cluster` in the first copy of the table
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        let cluster = u64::from(cluster);
+        self.fat_start
+            + match self.fat_type {
+                FatType::Fat12 => cluster + cluster / 2,
+                FatType::Fat16 => 2 * cluster,
+                FatType::Fat32 => 4 * cluster,
+            }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32> {
+        let offset = self.fat_offset(cluster);
+        let mut bytes = [0; 4];
+        match self.fat_type {
+            FatType::Fat12 => {
+                self.read(offset, &mut bytes[..2])?;
+                // Entries are 12 bits wide, the odd ones in the upper bits of their 16
+                let value = le16(&bytes, 0);
+                Ok(u32::from(if cluster & 1 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                }))
+            }
+            FatType::Fat16 => {
+                self.read(offset, &mut bytes[..2])?;
+                Ok(u32::from(le16(&bytes, 0)))
+            }
+            FatType::Fat32 => {
+                self.read(offset, &mut bytes)?;
+                Ok(u32::from_le_bytes(bytes) & FAT32_MASK)
+            }
+        }
+    }
+
+    /// Sets the entry of `cluster` in every copy of the table
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<()> {
+        for copy in 0..self.fat_count {
+            let offset = self.fat_offset(cluster) + u64::from(copy) * self.fat_size;
+            let mut bytes = [0; 4];
+            match self.fat_type {
+                FatType::Fat12 => {
+                    self.read(offset, &mut bytes[..2])?;
+                    let old = le16(&bytes, 0);
+                    let value = value as u16 & 0xfff;
+                    let new = if cluster & 1 == 1 {
+                        old & 0x000f | value << 4
+                    } else {
+                        old & 0xf000 | value
+                    };
+                    self.write(offset, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.write(offset, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // The upper 4 bits are reserved, and kept
+                    self.read(offset, &mut bytes)?;
+                    let old = u32::from_le_bytes(bytes);
+                    let new = old & !FAT32_MASK | value & FAT32_MASK;
+                    self.write(offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain starting at `first`, which is empty for `FREE`
+    fn chain(&self, first: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut next = first;
+        while next != FREE {
+            // Free and bad clusters, and loops, are never part of a valid chain
+            if !self.is_cluster(next) || chain.len() >= self.cluster_count as usize {
+                return Err(FsError::Io);
+            }
+            chain.push(next);
+            next = match self.fat_entry(next)? {
+                value if value >= self.fat_type.end_of_chain() => FREE,
+                FREE => return Err(FsError::Io),
+                value => value,
+            };
+        }
+        Ok(chain)
+    }
+
+    /// Allocates a zeroed cluster, linked after `previous` if there is one
+    fn allocate(&self, state: &mut State, previous: Option<u32>) -> Result<u32> {
+        let count = self.cluster_count;
+        let start = state.next_free.clamp(2, count + 1) - 2;
+        let mut found = None;
+        for i in 0..count {
+            let cluster = (start + i) % count + 2;
+            if self.fat_entry(cluster)? == FREE {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+
+        self.write(self.cluster_offset(cluster), &vec![0; self.cluster_size])?;
+        self.set_fat_entry(cluster, self.fat_type.end_marker())?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        state.next_free = (cluster - 2 + 1) % count + 2;
+        state.free_count = state.free_count.map(|free| free.saturating_sub(1));
+        state.fs_info_dirty = true;
+        Ok(cluster)
+    }
+
+    /// Frees the chain starting at `first`
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<()> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, FREE)?;
+            state.free_count = state.free_count.map(|free| free + 1);
+        }
+        state.fs_info_dirty = true;
+        Ok(())
+    }
+
+    /// Reads the bytes at `offset` of the file made of `chain`
+    fn read_chain(&self, chain: &[u32], offset: u64, buf: &mut [u8]) -> Result<()> {
+        let cluster_size = self.cluster_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let cluster = *chain
+                .get((pos / cluster_size) as usize)
+                .ok_or(FsError::Io)?;
+            let within = pos % cluster_size;
+            let len = (buf.len() - done).min((cluster_size - within) as usize);
+            self.read(
+                self.cluster_offset(cluster) + within,
+                &mut buf[done..done + len],
+            )?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes the bytes at `offset` of the file made of `chain`, which must be long enough
+    fn write_chain(&self, chain: &[u32], offset: u64, buf: &[u8]) -> Result<()> {
+        let cluster_size = self.cluster_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let cluster = *chain
+                .get((pos / cluster_size) as usize)
+                .ok_or(FsError::Io)?;
+            let within = pos % cluster_size;
+            let len = (buf.len() - done).min((cluster_size - within) as usize);
+            self.write(
+                self.cluster_offset(cluster) + within,
+                &buf[done..done + len],
+            )?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Grows or shrinks the file of `node` to `size` bytes. Its entry isn't updated
+    fn resize(&self, state: &mut State, node: &mut NodeState, size: u32) -> Result<()> {
+        let cluster_size = self.cluster_size as u64;
+        let (old, new) = (u64::from(node.size), u64::from(size));
+        let mut chain = self.chain(node.first_cluster)?;
+        let needed = new.div_ceil(cluster_size) as usize;
+
+        if new > old {
+            // The rest of the last cluster may still hold data from before a truncation
+            let tail_end = new.min(old.next_multiple_of(cluster_size));
+            if tail_end > old {
+                self.write_chain(&chain, old, &vec![0; (tail_end - old) as usize])?;
+            }
+            let kept = chain.len();
+            while chain.len() < needed {
+                match self.allocate(state, chain.last().copied()) {
+                    Ok(cluster) => chain.push(cluster),
+                    Err(err) => {
+                        // Give back the clusters allocated so far
+                        if kept < chain.len() {
+                            if kept > 0 {
+                                self.set_fat_entry(chain[kept - 1], self.fat_type.end_marker())?;
+                            }
+                            self.free_chain(state, chain[kept])?;
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+            if node.first_cluster == FREE {
+                node.first_cluster = chain.first().copied().unwrap_or(FREE);
+            }
+        } else if new < old {
+            if needed == 0 {
+                self.free_chain(state, node.first_cluster)?;
+                node.first_cluster = FREE;
+            } else if let Some(&rest) = chain.get(needed) {
+                self.set_fat_entry(chain[needed - 1], self.fat_type.end_marker())?;
+                self.free_chain(state, rest)?;
+            }
+        }
+        node.size = size;
+        Ok(())
+    }
+
+    /// Every entry slot of a directory, with its offset
+    fn read_slots(&self, dir: DirLocation) -> Result<Vec<(u64, [u8; DIR_ENTRY_SIZE])>> {
+        let regions: Vec<(u64, usize)> = match dir {
+            DirLocation::FixedRoot => {
+                let (offset, entries) = self.root_dir;
+                vec![(offset, entries * DIR_ENTRY_SIZE)]
+            }
+            DirLocation::Clusters(first) => self
+                .chain(first)?
+                .into_iter()
+                .map(|cluster| (self.cluster_offset(cluster), self.cluster_size))
+                .collect(),
+        };
+
+        let mut slots = Vec::new();
+        let mut buf = Vec::new();
+        for (offset, len) in regions {
+            buf.resize(len, 0);
+            self.read(offset, &mut buf)?;
+            for (i, raw) in buf.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                let offset = offset + (i * DIR_ENTRY_SIZE) as u64;
+                slots.push((offset, raw.try_into().unwrap()));
+            }
+        }
+        Ok(slots)
+    }
+
+    /// The entries of a directory, without `.` and `..`
+    fn entries(&self, dir: DirLocation) -> Result<Vec<RawEntry>> {
+        let mut entries = parse_entries(&self.read_slots(dir)?);
+        if self.fat_type != FatType::Fat32 {
+            // The upper half of the first cluster is only used by FAT32, and may hold
+            // something else
+            for entry in &mut entries {
+                entry.first_cluster &= 0xffff;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Finds `count` consecutive free slots in a directory, growing it if needed
+    fn reserve_slots(&self, state: &mut State, dir: DirLocation, count: usize) -> Result<Vec<u64>> {
+        let slots = self.read_slots(dir)?;
+        // The slots from the end marker on are free, like the deleted ones
+        let end = slots.iter().position(|(_, raw)| raw[0] == ENTRY_END);
+        let mut run = Vec::new();
+        let mut last = None;
+        for (i, (offset, raw)) in slots.iter().enumerate() {
+            if end.is_some_and(|end| i >= end) || raw[0] == ENTRY_DELETED {
+                run.push(*offset);
+                if run.len() == count {
+                    last = Some(i);
+                    break;
+                }
+            } else {
+                run.clear();
+            }
+        }
+
+        match last {
+            Some(last) => {
+                // Slots after the end marker may hold garbage, which must stay hidden
+                if end.is_some_and(|end| last >= end) {
+                    if let Some((offset, raw)) = slots.get(last + 1) {
+                        if raw[0] != ENTRY_END {
+                            self.write(*offset, &[ENTRY_END])?;
+                        }
+                    }
+                }
+            }
+            None => {
+                let DirLocation::Clusters(first) = dir else {
+                    // The FAT12 and FAT16 root directory can't grow
+                    return Err(FsError::NoSpace);
+                };
+                // New clusters are zeroed, so all their slots are free
+                let mut cluster = *self.chain(first)?.last().ok_or(FsError::Io)?;
+                while run.len() < count {
+                    cluster = self.allocate(state, Some(cluster))?;
+                    let offset = self.cluster_offset(cluster);
+                    run.extend(
+                        (0..self.cluster_size as u64)
+                            .step_by(DIR_ENTRY_SIZE)
+                            .map(|i| offset + i),
+                    );
+                }
+                run.truncate(count);
+            }
+        }
+        Ok(run)
+    }
+
+    /// The inode of the file of `entry`, shared with the other users of the file
+    fn inode(self: &Arc<Self>, state: &mut State, entry: &RawEntry) -> Arc<FatInode> {
+        let number = entry.offset();
+        if let Some(inode) = state.inodes.get(&number).and_then(Weak::upgrade) {
+            return inode;
+        }
+        state.inodes.retain(|_, inode| inode.strong_count() > 0);
+        let inode = Arc::new(FatInode {
+            volume: self.clone(),
+            inode: number,
+            file_type: entry.file_type(),
+            node: Mutex::new(NodeState {
+                entry: Some(number),
+                first_cluster: entry.first_cluster,
+                size: entry.size,
+                removed: false,
+            }),
+        });
+        state.inodes.insert(number, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Writes the first cluster and size of `node` to its entry, updating its modification
+    /// time
+    fn update_entry(&self, node: &NodeState) -> Result<()> {
+        let Some(offset) = node.entry else {
+            return Ok(());
+        };
+        let mut raw = [0; DIR_ENTRY_SIZE];
+        self.read(offset, &mut raw)?;
+        set_first_cluster(&mut raw, node.first_cluster);
+        if raw[11] & ATTR_DIRECTORY == 0 {
+            raw[28..32].copy_from_slice(&node.size.to_le_bytes());
+        }
+        let (time, date) = timestamp();
+        raw[18..20].copy_from_slice(&date.to_le_bytes());
+        raw[22..24].copy_from_slice(&time.to_le_bytes());
+        raw[24..26].copy_from_slice(&date.to_le_bytes());
+        self.write(offset, &raw)
+    }
+
+    /// The number of free clusters, counting them if they aren't known
+    fn free_clusters(&self, state: &mut State) -> Result<u32> {
+        if let Some(free) = state.free_count {
+            return Ok(free);
+        }
+        let mut free = 0;
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_entry(cluster)? == FREE {
+                free += 1;
+            }
+        }
+        state.free_count = Some(free);
+        state.fs_info_dirty = true;
+        Ok(free)
+    }
+
+    /// Writes the FSInfo sector if it changed, and flushes the device
+    fn sync(&self, state: &mut State) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        if let (Some(offset), true) = (self.fs_info, state.fs_info_dirty) {
+            let mut info = [0; 8];
+            info[..4].copy_from_slice(&state.free_count.unwrap_or(FSINFO_UNKNOWN).to_le_bytes());
+            info[4..].copy_from_slice(&state.next_free.to_le_bytes());
+            self.write(offset + FSINFO_FREE_COUNT, &info)?;
+            state.fs_info_dirty = false;
+        }
+        Ok(self.device.flush()?)
+    }
+}
+
+impl Drop for Volume {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        if let Err(err) = self.sync(&mut state) {
+            log::warn!("fat: failed to sync the volume: {err:?}");
+        }
+    }
+}
+
+/// Where the entries of a directory are
+#[derive(Debug, Clone, Copy)]
+enum DirLocation {
+    /// The root directory of FAT12 and FAT16, before the clusters
+    FixedRoot,
+    Clusters(u32),
+}
+
+/// A short entry, with the long name before it if it has one
+struct RawEntry {
+    name: String,
+    short_name: [u8; 11],
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+    /// The slots of the long name, then of the short entry
+    slots: Vec<u64>,
+}
+
+impl RawEntry {
+    /// The offset of the short entry, which identifies the file
+    fn offset(&self) -> u64 {
+        *self.slots.last().unwrap()
+    }
+
+    fn file_type(&self) -> FileType {
+        if self.attr & ATTR_DIRECTORY != 0 {
+            FileType::Directory
+        } else {
+            FileType::Regular
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || short_name_to_string(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+/// A long name being put together from its parts, which come last to first
+struct LongName {
+    units: Vec<u16>,
+    slots: Vec<u64>,
+    /// The sequence number of the next part, where 0 means complete
+    next: u8,
+    checksum: u8,
+}
+
+fn parse_entries(slots: &[(u64, [u8; DIR_ENTRY_SIZE])]) -> Vec<RawEntry> {
+    let mut entries = Vec::new();
+    let mut long: Option<LongName> = None;
+    for (offset, raw) in slots {
+        match raw[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        if raw[11] & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME {
+            let sequence = raw[0] & LFN_SEQUENCE_MASK;
+            if raw[0] & LFN_LAST != 0 {
+                long = Some(LongName {
+                    units: vec![0; usize::from(sequence) * LFN_OFFSETS.len()],
+                    slots: Vec::new(),
+                    next: sequence,
+                    checksum: raw[13],
+                });
+            }
+            // A part out of order or of another name invalidates the whole name
+            long = long
+                .filter(|long| sequence != 0 && long.next == sequence && long.checksum == raw[13]);
+            if let Some(long) = &mut long {
+                let start = usize::from(sequence - 1) * LFN_OFFSETS.len();
+                for (i, &at) in LFN_OFFSETS.iter().enumerate() {
+                    long.units[start + i] = le16(raw, at);
+                }
+                long.slots.push(*offset);
+                long.next -= 1;
+            }
+            continue;
+        }
+
+        let long = long.take();
+        let short_name: [u8; 11] = raw[..11].try_into().unwrap();
+        if raw[11] & ATTR_VOLUME_ID != 0 || short_name == DOT || short_name == DOT_DOT {
+            continue;
+        }
+        let long = long.filter(|long| long.next == 0 && long.checksum == checksum(&short_name));
+        let (name, mut slots) = match long {
+            Some(long) => {
+                let len = long
+                    .units
+                    .iter()
+                    .position(|&unit| unit == 0)
+                    .unwrap_or(long.units.len());
+                let name = char::decode_utf16(long.units[..len].iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (name, long.slots)
+            }
+            None => (short_name_to_string(&short_name, raw[12]), Vec::new()),
+        };
+        slots.push(*offset);
+        entries.push(RawEntry {
+            name,
+            short_name,
+            attr: raw[11],
+            first_cluster: u32::from(le16(raw, 20)) << 16 | u32::from(le16(raw, 26)),
+            size: le32(raw, 28),
+            slots,
+        });
+    }
+    entries
+}
+
+/// The checksum of a short name, stored in the entries of its long name
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// The name of a short entry, with the case given by the reserved byte
+fn short_name_to_string(short_name: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let mut part: String = bytes
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| match (i, c) {
+                (0, ENTRY_E5) => 0xe5,
+                (_, c) if lower => c.to_ascii_lowercase(),
+                (_, c) => c,
+            })
+            // Bytes past ASCII are in the OEM code page, which is taken to be Latin-1
+            .map(char::from)
+            .collect();
+        part.truncate(part.trim_end_matches(' ').len());
+        part
+    };
+    let base = part(&short_name[..8], case & CASE_LOWER_BASE != 0);
+    let ext = part(&short_name[8..], case & CASE_LOWER_EXT != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{base}.{ext}")
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > MAX_NAME_LEN
+        || name.chars().any(|c| c < ' ' || INVALID_CHARS.contains(c))
+    {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL.contains(&c)
+}
+
+/// The short name and case flags of `name`, if it is a valid 8.3 name with each part in a
+/// single case, which doesn't need a long name
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || name.ends_with('.') {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    let mut case = 0;
+    for (part, start, lower_flag) in [(base, 0, CASE_LOWER_BASE), (ext, 8, CASE_LOWER_EXT)] {
+        let bytes = part.as_bytes();
+        if !bytes.iter().all(|&c| is_short_name_char(c)) {
+            return None;
+        }
+        let lower = bytes.iter().any(u8::is_ascii_lowercase);
+        if lower && bytes.iter().any(u8::is_ascii_uppercase) {
+            return None;
+        }
+        if lower {
+            case |= lower_flag;
+        }
+        for (i, c) in bytes.iter().enumerate() {
+            short_name[start + i] = c.to_ascii_uppercase();
+        }
+    }
+    Some((short_name, case))
+}
+
+/// The short name generated for the long name `name`, with a `~n` tail
+fn generated_short_name(name: &str, n: u32) -> [u8; 11] {
+    let convert = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match u8::try_from(c) {
+                Ok(c) if is_short_name_char(c) => c.to_ascii_uppercase(),
+                _ => b'_',
+            })
+            .collect()
+    };
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) => (convert(base), convert(ext)),
+        None => (convert(name), Vec::new()),
+    };
+
+    let tail = format!("~{n}");
+    let base_len = base.len().min(8 - tail.len());
+    let mut short_name = [b' '; 11];
+    short_name[..base_len].copy_from_slice(&base[..base_len]);
+    short_name[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+    let ext_len = ext.len().min(3);
+    short_name[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    short_name
+}
+
+/// The long name entries of `name`, in the order they are stored
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    // The name is terminated unless it fills the last entry, which is then padded
+    if !units.len().is_multiple_of(LFN_OFFSETS.len()) {
+        units.push(0);
+        units.resize(units.len().next_multiple_of(LFN_OFFSETS.len()), 0xffff);
+    }
+    let count = units.len() / LFN_OFFSETS.len();
+    (1..=count)
+        .rev()
+        .map(|sequence| {
+            let mut raw = [0; DIR_ENTRY_SIZE];
+            raw[0] = sequence as u8 | if sequence == count { LFN_LAST } else { 0 };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = checksum;
+            let units = &units[(sequence - 1) * LFN_OFFSETS.len()..][..LFN_OFFSETS.len()];
+            for (&at, unit) in LFN_OFFSETS.iter().zip(units) {
+                raw[at..at + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            raw
+        })
+        .collect()
+}
+
+fn set_first_cluster(raw: &mut [u8; DIR_ENTRY_SIZE], cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// The current time and date, in the format of entries
+fn timestamp() -> (u16, u16) {
+    let now = SystemTime::now().date_time();
+    if now.year < 1980 {
+        // The earliest date there is, 1980-01-01
+        return (0, 1 << 5 | 1);
+    }
+    let date = (now.year - 1980).min(127) << 9 | u16::from(now.month) << 5 | u16::from(now.day);
+    let time = u16::from(now.hour) << 11 | u16::from(now.minute) << 5 | u16::from(now.second / 2);
+    (time, date)
+}
+
+fn short_entry(short_name: &[u8; 11], case: u8, attr: u8, cluster: u32) -> [u8; DIR_ENTRY_SIZE] {
+    let mut raw = [0; DIR_ENTRY_SIZE];
+    raw[..11].copy_from_slice(short_name);
+    raw[11] = attr;
+    raw[12] = case;
+    let (time, date) = timestamp();
+    for (at, value) in [(14, time), (16, date), (18, date), (22, time), (24, date)] {
+        raw[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+    set_first_cluster(&mut raw, cluster);
+    raw
+}
+
+/// The parts of an inode which change
+#[derive(Debug, Clone, Copy)]
+struct NodeState {
+    /// The offset of the short entry of the file, `None` for the root
+    entry: Option<u64>,
+    first_cluster: u32,
+    /// The size of files, 0 for directories
+    size: u32,
+    /// Set once the file is deleted, after which it can't be used
+    removed: bool,
+}
+
+struct FatInode {
+    volume: Arc<Volume>,
+    inode: u64,
+    file_type: FileType,
+    node: Mutex<NodeState>,
+}
+
+impl FatInode {
+    fn node(&self) -> Result<NodeState> {
+        let node = *self.node.lock();
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+        Ok(node)
+    }
+
+    fn set_node(&self, node: NodeState) {
+        *self.node.lock() = node;
+    }
+
+    /// Checks that this is a directory, returning where its entries are
+    fn dir_location(&self) -> Result<DirLocation> {
+        if self.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let node = self.node()?;
+        Ok(match node.entry {
+            None if self.volume.fat_type != FatType::Fat32 => DirLocation::FixedRoot,
+            _ => DirLocation::Clusters(node.first_cluster),
+        })
+    }
+
+    fn check_file(&self) -> Result<()> {
+        if self.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.volume.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let size = match self.file_type {
+            FileType::Regular | FileType::Symlink => self.node.lock().size.into(),
+            FileType::Directory => self.read_dir().map_or(0, |entries| entries.len() as u64),
+        };
+        Metadata {
+            file_type: self.file_type,
+            size,
+            inode: self.inode,
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.check_file()?;
+        let _state = self.volume.state.lock();
+        let node = self.node()?;
+        let size = u64::from(node.size);
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let chain = self.volume.chain(node.first_cluster)?;
+        self.volume.read_chain(&chain, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        self.check_file()?;
+        self.check_writable()?;
+        let mut state = self.volume.state.lock();
+        let mut node = self.node()?;
+        // Sizes are 32 bits wide
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .and_then(|end| u32::try_from(end).ok())
+            .ok_or(FsError::NoSpace)?;
+        if end > node.size {
+            self.volume.resize(&mut state, &mut node, end)?;
+            self.set_node(node);
+        }
+        self.volume.update_entry(&node)?;
+        let chain = self.volume.chain(node.first_cluster)?;
+        self.volume.write_chain(&chain, offset, buf)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        self.check_file()?;
+        self.check_writable()?;
+        let size = u32::try_from(size).map_err(|_| FsError::NoSpace)?;
+        let mut state = self.volume.state.lock();
+        let mut node = self.node()?;
+        self.volume.resize(&mut state, &mut node, size)?;
+        self.set_node(node);
+        self.volume.update_entry(&node)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let mut state = self.volume.state.lock();
+        let entries = self.volume.entries(self.dir_location()?)?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(FsError::NotFound)?;
+        Ok(self.volume.inode(&mut state, entry))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        let dir = self.dir_location()?;
+        self.check_writable()?;
+        check_name(name)?;
+        let mut state = self.volume.state.lock();
+        let parent = self.node()?;
+        let entries = self.volume.entries(dir)?;
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short_name, case, long) = match exact_short_name(name) {
+            Some((short_name, case)) => (short_name, case, false),
+            None => {
+                let short_name = (1..)
+                    .map(|n| generated_short_name(name, n))
+                    .find(|short_name| entries.iter().all(|entry| entry.short_name != *short_name))
+                    .unwrap();
+                (short_name, 0, true)
+            }
+        };
+        let mut raws = if long {
+            long_name_entries(name, checksum(&short_name))
+        } else {
+            Vec::new()
+        };
+
+        // Directories get their first cluster now, for their `.` and `..` entries
+        let (attr, first_cluster) = match file_type {
+            FileType::Regular => (ATTR_ARCHIVE, FREE),
+            // FAT has no symbolic links
+            FileType::Symlink => return Err(FsError::InvalidArgument),
+            FileType::Directory => {
+                let cluster = self.volume.allocate(&mut state, None)?;
+                // The root is cluster 0 for `..`, even on FAT32
+                let parent_cluster = match parent.entry {
+                    Some(_) => parent.first_cluster,
+                    None => FREE,
+                };
+                let mut dots = [0; 2 * DIR_ENTRY_SIZE];
+                dots[..DIR_ENTRY_SIZE].copy_from_slice(&short_entry(
+                    &DOT,
+                    0,
+                    ATTR_DIRECTORY,
+                    cluster,
+                ));
+                dots[DIR_ENTRY_SIZE..].copy_from_slice(&short_entry(
+                    &DOT_DOT,
+                    0,
+                    ATTR_DIRECTORY,
+                    parent_cluster,
+                ));
+                self.volume
+                    .write(self.volume.cluster_offset(cluster), &dots)?;
+                (ATTR_DIRECTORY, cluster)
+            }
+        };
+        raws.push(short_entry(&short_name, case, attr, first_cluster));
+
+        let slots = match self.volume.reserve_slots(&mut state, dir, raws.len()) {
+            Ok(slots) => slots,
+            Err(err) => {
+                if first_cluster != FREE {
+                    self.volume.free_chain(&mut state, first_cluster)?;
+                }
+                return Err(err);
+            }
+        };
+        for (offset, raw) in slots.iter().zip(&raws) {
+            self.volume.write(*offset, raw)?;
+        }
+        let entry = RawEntry {
+            name: name.into(),
+            short_name,
+            attr,
+            first_cluster,
+            size: 0,
+            slots,
+        };
+        Ok(self.volume.inode(&mut state, &entry))
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let dir = self.dir_location()?;
+        self.check_writable()?;
+        let mut state = self.volume.state.lock();
+        let entries = self.volume.entries(dir)?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(FsError::NotFound)?;
+        if entry.file_type() == FileType::Directory
+            && !self
+                .volume
+                .entries(DirLocation::Clusters(entry.first_cluster))?
+                .is_empty()
+        {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        for &slot in &entry.slots {
+            self.volume.write(slot, &[ENTRY_DELETED])?;
+        }
+        self.volume.free_chain(&mut state, entry.first_cluster)?;
+        // The entry's slot, and so its inode number, can be reused from now on
+        if let Some(inode) = state
+            .inodes
+            .remove(&entry.offset())
+            .and_then(|inode| inode.upgrade())
+        {
+            inode.node.lock().removed = true;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let _state = self.volume.state.lock();
+        let entries = self.volume.entries(self.dir_location()?)?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| DirEntry {
+                file_type: entry.file_type(),
+                name: entry.name,
+            })
+            .collect())
+    }
+}
+
+/// A FAT volume
+pub struct FatFs {
+    volume: Arc<Volume>,
+    root: Arc<FatInode>,
+}
+
+impl FatFs {
+    /// Reads the boot sector of the volume on `device`, which is read-only if the device is
+    pub fn new(device: Arc<dyn BlockDevice>) -> core::result::Result<Self, FatError> {
+        let volume = Arc::new(Volume::new(device)?);
+        let root = Arc::new(FatInode {
+            volume: volume.clone(),
+            inode: ROOT_INODE,
+            file_type: FileType::Directory,
+            node: Mutex::new(NodeState {
+                entry: None,
+                first_cluster: volume.root_cluster,
+                size: 0,
+                removed: false,
+            }),
+        });
+        Ok(Self { volume, root })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.volume.fat_type
+    }
+
+    /// The size of a cluster in bytes, which files are allocated in
+    pub fn cluster_size(&self) -> usize {
+        self.volume.cluster_size
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.volume.cluster_count
+    }
+
+    /// The number of free clusters, counted the first time unless the FSInfo sector has it
+    pub fn free_clusters(&self) -> Result<u32> {
+        self.volume.free_clusters(&mut self.volume.state.lock())
+    }
+
+    /// Writes the FSInfo sector of FAT32 volumes and flushes the device. This is also done
+    /// once the volume and its files are dropped
+    pub fn sync(&self) -> Result<()> {
+        self.volume.sync(&mut self.volume.state.lock())
+    }
+}
+
+impl FileSystem for FatFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
@@ -0,0 +1,315 @@
+//! The initial ramdisk: a read-only filesystem unpacked from a USTAR or newc cpio archive.
+//!
+//! The archive is taken from the `opt/ros/initrd` fw_cfg file when the host provides one
+//! (`-fw_cfg name=opt/ros/initrd,file=...`) and it is valid, and is otherwise the one embedded
+//! in the kernel image, which the build script packs from the `initrd` directory.
+//!
+//! Only regular files and directories are kept; other entries, such as links and devices,
+//! are skipped. Directories missing from the archive are implied by the paths of their
+//! entries.
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata, Result};
+use crate::fw_cfg;
+
+/// The archive embedded in the kernel image
+pub static EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+/// The fw_cfg file the host can pass an archive in, replacing the embedded one
+pub const FW_CFG_FILE: &str = "opt/ros/initrd";
+
+/// Inode numbers are unique across all initrd instances
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveError {
+    /// The archive is neither USTAR nor newc cpio
+    UnknownFormat,
+    /// An entry extends past the end of the archive
+    Truncated,
+    /// The header at this offset is malformed
+    BadHeader(usize),
+    /// The header at this offset doesn't match its checksum
+    BadChecksum(usize),
+    /// An entry is inside something which isn't a directory
+    NotADirectory,
+}
+
+/// A read-only filesystem over the contents of an archive
+pub struct Initrd {
+    root: Arc<InitrdInode>,
+}
+
+impl Initrd {
+    /// Parses a USTAR or newc cpio archive, telling them apart by their magic
+    pub fn parse(archive: &'static [u8]) -> core::result::Result<Self, ArchiveError> {
+        let mut root = Node::Directory(BTreeMap::new());
+        if archive.starts_with(b"070701") || archive.starts_with(b"070702") {
+            parse_cpio(archive, &mut root)?;
+        } else if archive.get(257..262) == Some(b"ustar") {
+            parse_tar(archive, &mut root)?;
+        } else {
+            return Err(ArchiveError::UnknownFormat);
+        }
+        Ok(Self {
+            root: InitrdInode::freeze(root),
+        })
+    }
+}
+
+impl FileSystem for Initrd {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Mounts the initrd as the root filesystem, preferring the archive passed by the host over
+/// the embedded one unless it is invalid
+pub fn init() -> core::result::Result<(), ArchiveError> {
+    let from_host = fw_cfg::read_file(FW_CFG_FILE).and_then(|archive| {
+        log::info!("initrd: {} bytes from fw_cfg", archive.len());
+        Initrd::parse(Vec::leak(archive))
+            .inspect_err(|err| log::warn!("initrd: ignoring the archive from fw_cfg: {err:?}"))
+            .ok()
+    });
+    let initrd = match from_host {
+        Some(initrd) => initrd,
+        None => Initrd::parse(EMBEDDED)?,
+    };
+    if let Err(err) = super::mount("/", Arc::new(initrd)) {
+        log::warn!("initrd: failed to mount: {err:?}");
+    }
+    Ok(())
+}
+
+/// An entry being unpacked
+enum Node {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, Node>),
+}
+
+impl Node {
+    /// Adds an entry at `path`, creating the directories leading to it
+    fn insert(&mut self, path: &str, entry: Node) -> core::result::Result<(), ArchiveError> {
+        let mut components = path.split('/').filter(|c| !c.is_empty() && *c != ".");
+        let Some(mut name) = components.next() else {
+            // The root itself, as `.` or `./`
+            return Ok(());
+        };
+
+        let mut dir = self;
+        for next in components {
+            let Node::Directory(entries) = dir else {
+                return Err(ArchiveError::NotADirectory);
+            };
+            dir = entries
+                .entry(String::from(name))
+                .or_insert_with(|| Node::Directory(BTreeMap::new()));
+            name = next;
+        }
+
+        let Node::Directory(entries) = dir else {
+            return Err(ArchiveError::NotADirectory);
+        };
+        match (entries.get_mut(name), entry) {
+            // A directory may be listed after entries inside it
+            (Some(Node::Directory(_)), Node::Directory(_)) => {}
+            (_, entry) => {
+                entries.insert(String::from(name), entry);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn slice(
+    archive: &'static [u8],
+    start: usize,
+    len: usize,
+) -> core::result::Result<&'static [u8], ArchiveError> {
+    start
+        .checked_add(len)
+        .and_then(|end| archive.get(start..end))
+        .ok_or(ArchiveError::Truncated)
+}
+
+/// Parses a number made of `digits` in the given radix, ignoring null and space padding
+fn parse_number(
+    digits: &[u8],
+    radix: u32,
+    offset: usize,
+) -> core::result::Result<usize, ArchiveError> {
+    let digits = core::str::from_utf8(digits).map_err(|_| ArchiveError::BadHeader(offset))?;
+    let digits = digits.trim_matches(|c| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(digits, radix).map_err(|_| ArchiveError::BadHeader(offset))
+}
+
+/// A null terminated string, as used for names in both formats
+fn parse_name(bytes: &[u8], offset: usize) -> core::result::Result<&str, ArchiveError> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).map_err(|_| ArchiveError::BadHeader(offset))
+}
+
+const TAR_BLOCK: usize = 512;
+
+fn parse_tar(archive: &'static [u8], root: &mut Node) -> core::result::Result<(), ArchiveError> {
+    let mut offset = 0;
+    loop {
+        let header = slice(archive, offset, TAR_BLOCK)?;
+        // The archive ends with zero blocks
+        if header.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+
+        // The checksum is computed with its own field set to spaces
+        let checksum = parse_number(&header[148..156], 8, offset)?;
+        let sum: usize = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b })
+            .map(usize::from)
+            .sum();
+        if sum != checksum {
+            return Err(ArchiveError::BadChecksum(offset));
+        }
+
+        let size = parse_number(&header[124..136], 8, offset)?;
+        let prefix = parse_name(&header[345..500], offset)?;
+        let name = parse_name(&header[0..100], offset)?;
+        let data = slice(archive, offset + TAR_BLOCK, size)?;
+
+        let entry = match header[156] {
+            b'0' | b'\0' => Some(Node::File(data)),
+            b'5' => Some(Node::Directory(BTreeMap::new())),
+            _ => None,
+        };
+        if let Some(entry) = entry {
+            if prefix.is_empty() {
+                root.insert(name, entry)?;
+            } else {
+                root.insert(&alloc::format!("{prefix}/{name}"), entry)?;
+            }
+        }
+
+        offset += TAR_BLOCK + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+    }
+}
+
+const CPIO_HEADER: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const MODE_TYPE_MASK: usize = 0o170000;
+const MODE_DIRECTORY: usize = 0o040000;
+const MODE_REGULAR: usize = 0o100000;
+
+fn parse_cpio(archive: &'static [u8], root: &mut Node) -> core::result::Result<(), ArchiveError> {
+    let mut offset = 0;
+    loop {
+        let header = slice(archive, offset, CPIO_HEADER)?;
+        if !header.starts_with(b"070701") && !header.starts_with(b"070702") {
+            return Err(ArchiveError::BadHeader(offset));
+        }
+        // After the magic come 13 fields of 8 hex digits
+        let field = |i: usize| parse_number(&header[6 + i * 8..14 + i * 8], 16, offset);
+        let mode = field(1)?;
+        let size = field(6)?;
+        let name_size = field(11)?;
+
+        let name = parse_name(slice(archive, offset + CPIO_HEADER, name_size)?, offset)?;
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+        // The name and the data are both padded to 4 bytes
+        let data_offset = (offset + CPIO_HEADER + name_size).next_multiple_of(4);
+        let data = slice(archive, data_offset, size)?;
+
+        match mode & MODE_TYPE_MASK {
+            MODE_REGULAR => root.insert(name, Node::File(data))?,
+            MODE_DIRECTORY => root.insert(name, Node::Directory(BTreeMap::new()))?,
+            _ => {}
+        }
+
+        offset = (data_offset + size).next_multiple_of(4);
+    }
+}
+
+enum Contents {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, Arc<InitrdInode>>),
+}
+
+struct InitrdInode {
+    inode: u64,
+    contents: Contents,
+}
+
+impl InitrdInode {
+    fn freeze(node: Node) -> Arc<Self> {
+        let contents = match node {
+            Node::File(data) => Contents::File(data),
+            Node::Directory(entries) => Contents::Directory(
+                entries
+                    .into_iter()
+                    .map(|(name, node)| (name, Self::freeze(node)))
+                    .collect(),
+            ),
+        };
+        Arc::new(Self {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            contents,
+        })
+    }
+}
+
+impl Inode for InitrdInode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, size) = match &self.contents {
+            Contents::File(data) => (FileType::Regular, data.len()),
+            Contents::Directory(entries) => (FileType::Directory, entries.len()),
+        };
+        Metadata {
+            file_type,
+            size: size as u64,
+            inode: self.inode,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let Contents::File(data) = self.contents else {
+            return Err(FsError::IsADirectory);
+        };
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let Contents::Directory(entries) = &self.contents else {
+            return Err(FsError::NotADirectory);
+        };
+        match entries.get(name) {
+            Some(inode) => Ok(inode.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let Contents::Directory(entries) = &self.contents else {
+            return Err(FsError::NotADirectory);
+        };
+        Ok(entries
+            .iter()
+            .map(|(name, inode)| DirEntry {
+                name: name.clone(),
+                file_type: inode.metadata().file_type,
+            })
+            .collect())
+    }
+}
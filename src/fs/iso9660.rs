@@ -0,0 +1,582 @@
+//! ISO 9660 filesystems, as found on CD-ROMs, with the Rock Ridge and Joliet extensions.
+//!
+//! A volume starts with its volume descriptors, from sector 16 on: the primary one gives the
+//! root directory and the size of the logical blocks everything is addressed in. Directories
+//! are lists of records, which never cross a 2048 byte sector, each giving the extent of a
+//! file: the blocks it takes, one after the other. Files of 4GiB or more are split into
+//! several records with the same name.
+//!
+//! Plain ISO 9660 names are short, in upper case and carry a version, which is dropped here;
+//! they are looked up ignoring ASCII case. Rock Ridge adds entries to the system use area
+//! which ends each record, with POSIX names, file types and symbolic links, and relocates the
+//! directories nested too deep. Joliet adds a second tree of directories, found through a
+//! supplementary volume descriptor, with the same files under UCS-2 names. Rock Ridge names
+//! are used when the volume has them, then Joliet ones.
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata, Result};
+use crate::block::{self, BlockDevice, BlockError};
+
+const SECTOR_SIZE: usize = 2048;
+const FIRST_DESCRIPTOR: u64 = 16;
+/// Descriptors are read until the terminator, or this many
+const MAX_DESCRIPTORS: u64 = 32;
+const STANDARD_ID: &[u8] = b"CD001";
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+/// The escape sequences of Joliet supplementary descriptors, for UCS-2 levels 1 to 3
+const JOLIET_ESCAPES: [&[u8]; 3] = [b"%/@", b"%/C", b"%/E"];
+const ROOT_RECORD: usize = 156;
+
+const RECORD_HEADER: usize = 33;
+const FLAG_DIRECTORY: u8 = 0x02;
+/// The record isn't the last extent of its file
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// Continuation areas followed for the system use area of a record
+const MAX_CONTINUATIONS: usize = 8;
+const SUSP_CHECK: [u8; 2] = [0xbe, 0xef];
+/// The extension identifiers of Rock Ridge, in the `ER` entry of the root
+const ROCK_RIDGE_IDS: [&[u8]; 3] = [b"RRIP_1991A", b"IEEE_P1282", b"IEEE_1282"];
+const NM_CURRENT: u8 = 0x02;
+const NM_PARENT: u8 = 0x04;
+const SL_CONTINUE: u8 = 0x01;
+const SL_CURRENT: u8 = 0x02;
+const SL_PARENT: u8 = 0x04;
+const SL_ROOT: u8 = 0x08;
+
+const MODE_TYPE_MASK: u32 = 0xf000;
+const MODE_SYMLINK: u32 = 0xa000;
+
+/// Where the names of files come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Names {
+    /// The names of the ISO 9660 records
+    Plain,
+    /// The UCS-2 names of the Joliet tree
+    Joliet,
+    /// The `NM` entries of Rock Ridge
+    RockRidge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsoError {
+    /// Reading the device failed
+    Block(BlockError),
+    /// The volume has no valid primary volume descriptor
+    BadVolumeDescriptor,
+    /// The volume doesn't have the names asked for
+    MissingNames(Names),
+}
+
+impl From<BlockError> for IsoError {
+    fn from(err: BlockError) -> Self {
+        IsoError::Block(err)
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// What the system use area of a record says
+#[derive(Default)]
+struct SystemUse {
+    name: Option<String>,
+    link: Option<String>,
+    mode: Option<u32>,
+    /// The location of the directory this record stands for, if it was relocated
+    child_link: Option<u32>,
+    /// Whether this is a relocated directory, in its new parent
+    relocated: bool,
+    /// Whether an `ER` entry names Rock Ridge
+    rock_ridge: bool,
+    /// The `SP` entry, with the number of bytes to skip in every other area
+    sharing: Option<usize>,
+}
+
+/// A file or directory, as given by its records
+#[derive(Clone)]
+struct Node {
+    /// The byte offset of its first record, used as inode number
+    position: u64,
+    file_type: FileType,
+    /// The byte offsets and lengths of its extents
+    extents: Vec<(u64, u64)>,
+    link: Option<String>,
+}
+
+impl Node {
+    fn size(&self) -> u64 {
+        self.extents.iter().map(|&(_, len)| len).sum()
+    }
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    names: Names,
+    /// The bytes the system use area of records start with, before their entries
+    susp_skip: usize,
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        if offset + buf.len() as u64 > self.device.size() {
+            return Err(FsError::Io);
+        }
+        Ok(block::read_bytes(self.device.as_ref(), offset, buf)?)
+    }
+
+    /// Reads the data of `node` from `offset`, which must be before its end
+    fn read_data(&self, node: &Node, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut start = 0;
+        let mut done = 0;
+        for &(position, len) in &node.extents {
+            let end = start + len;
+            let from = offset + done as u64;
+            if from < end && done < buf.len() {
+                let chunk = (buf.len() - done).min((end - from) as usize);
+                self.read(position + from - start, &mut buf[done..done + chunk])?;
+                done += chunk;
+            }
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Parses the record at the start of `data`, at byte `position` of the volume
+    fn node(&self, data: &[u8], position: u64) -> Node {
+        let extent = u64::from(le32(data, 2)) + u64::from(data[1]);
+        Node {
+            position,
+            file_type: if data[25] & FLAG_DIRECTORY != 0 {
+                FileType::Directory
+            } else {
+                FileType::Regular
+            },
+            extents: vec![(extent * self.block_size, u64::from(le32(data, 10)))],
+            link: None,
+        }
+    }
+
+    /// Parses the entries of a system use area, following its continuation areas
+    fn system_use(&self, mut area: Vec<u8>, info: &mut SystemUse) -> Result<()> {
+        let mut link: Vec<String> = Vec::new();
+        let mut absolute = false;
+        let mut continues = false;
+        let mut continuations = 0;
+        loop {
+            let mut next = None;
+            let mut i = 0;
+            while i + 4 <= area.len() {
+                let len = usize::from(area[i + 2]);
+                if len < 4 || i + len > area.len() {
+                    break;
+                }
+                let data = &area[i + 4..i + len];
+                match &area[i..i + 2] {
+                    b"SP" if data.len() >= 3 && data[..2] == SUSP_CHECK => {
+                        info.sharing = Some(usize::from(data[2]));
+                    }
+                    b"ER" if data.len() >= 4 => {
+                        let id_len = usize::from(data[0]);
+                        let id = &data[4..data.len().min(4 + id_len)];
+                        info.rock_ridge |= ROCK_RIDGE_IDS.contains(&id);
+                    }
+                    b"CE" if data.len() >= 20 => {
+                        let block = u64::from(le32(data, 0));
+                        let offset = u64::from(le32(data, 8));
+                        let len = le32(data, 16);
+                        // Continuation areas are within a block
+                        if u64::from(len) > self.block_size {
+                            return Err(FsError::Io);
+                        }
+                        next = Some((block * self.block_size + offset, len));
+                    }
+                    b"NM" if !data.is_empty() && data[0] & (NM_CURRENT | NM_PARENT) == 0 => {
+                        let name = info.name.get_or_insert_with(String::new);
+                        name.push_str(&String::from_utf8_lossy(&data[1..]));
+                    }
+                    b"SL" if !data.is_empty() => {
+                        let mut j = 1;
+                        while j + 2 <= data.len() {
+                            let flags = data[j];
+                            let len = usize::from(data[j + 1]);
+                            let content = data.get(j + 2..j + 2 + len).unwrap_or_default();
+                            j += 2 + len;
+                            if flags & SL_ROOT != 0 {
+                                link.clear();
+                                absolute = true;
+                                continue;
+                            }
+                            let content = match flags {
+                                _ if flags & SL_CURRENT != 0 => ".".into(),
+                                _ if flags & SL_PARENT != 0 => "..".into(),
+                                _ => String::from_utf8_lossy(content),
+                            };
+                            match link.last_mut() {
+                                Some(last) if continues => last.push_str(&content),
+                                _ => link.push(content.into_owned()),
+                            }
+                            continues = flags & SL_CONTINUE != 0;
+                        }
+                        info.link = Some(String::new());
+                    }
+                    b"PX" if data.len() >= 4 => info.mode = Some(le32(data, 0)),
+                    b"CL" if data.len() >= 4 => info.child_link = Some(le32(data, 0)),
+                    b"RE" => info.relocated = true,
+                    b"ST" => break,
+                    _ => {}
+                }
+                i += len;
+            }
+
+            match next {
+                Some((offset, len)) if continuations < MAX_CONTINUATIONS => {
+                    continuations += 1;
+                    area = vec![0; len as usize];
+                    self.read(offset, &mut area)?;
+                }
+                _ => break,
+            }
+        }
+
+        if info.link.is_some() {
+            let mut target = String::new();
+            if absolute {
+                target.push('/');
+            }
+            target.push_str(&link.join("/"));
+            info.link = Some(target);
+        }
+        Ok(())
+    }
+
+    /// The entries of `dir`, without `.` and `..`
+    fn entries(&self, dir: &Node) -> Result<Vec<(String, Node)>> {
+        let mut entries: Vec<(String, Node)> = Vec::new();
+        // Whether the last entry has more extents to come
+        let mut open = false;
+        // A sector at a time, since records don't cross sectors and the length of the extent
+        // comes from the volume
+        let mut sector = [0; SECTOR_SIZE];
+        for &(start, len) in &dir.extents {
+            for sector_start in (0..len).step_by(SECTOR_SIZE) {
+                let data = &mut sector[..(len - sector_start).min(SECTOR_SIZE as u64) as usize];
+                self.read(start + sector_start, data)?;
+
+                let mut offset = 0;
+                while offset < data.len() {
+                    let len = usize::from(data[offset]);
+                    // Sectors end with zeroes after their last record
+                    if len == 0 {
+                        break;
+                    }
+                    if len < RECORD_HEADER + 1 || offset + len > data.len() {
+                        return Err(FsError::Io);
+                    }
+                    let record = &data[offset..offset + len];
+                    let name_len = usize::from(record[32]);
+                    if RECORD_HEADER + name_len > len {
+                        return Err(FsError::Io);
+                    }
+                    let position = start + sector_start + offset as u64;
+                    offset += len;
+
+                    let identifier = &record[RECORD_HEADER..RECORD_HEADER + name_len];
+                    if identifier == [0] || identifier == [1] {
+                        continue;
+                    }
+                    let mut node = self.node(record, position);
+                    let more = record[25] & FLAG_MULTI_EXTENT != 0;
+                    if open {
+                        if let Some((_, last)) = entries.last_mut() {
+                            last.extents.extend(node.extents);
+                            open = more;
+                            continue;
+                        }
+                    }
+                    open = more;
+
+                    let name = match self.names {
+                        Names::Plain => plain_name(identifier),
+                        Names::Joliet => joliet_name(identifier),
+                        Names::RockRidge => {
+                            // Names of even length are followed by a padding byte, which keeps
+                            // the system use area at an even offset
+                            let area =
+                                RECORD_HEADER + name_len + (1 - name_len % 2) + self.susp_skip;
+                            let mut info = SystemUse::default();
+                            self.system_use(record[area.min(len)..].into(), &mut info)?;
+                            if info.relocated {
+                                continue;
+                            }
+                            if let Some(child) = info.child_link {
+                                // The `.` record of the directory gives its size
+                                let mut dot = [0; RECORD_HEADER + 1];
+                                let location = u64::from(child) * self.block_size;
+                                self.read(location, &mut dot)?;
+                                node.file_type = FileType::Directory;
+                                node.extents = vec![(location, u64::from(le32(&dot, 10)))];
+                            } else if node.file_type != FileType::Directory
+                                && (info.link.is_some()
+                                    || info.mode.map(|mode| mode & MODE_TYPE_MASK)
+                                        == Some(MODE_SYMLINK))
+                            {
+                                node.file_type = FileType::Symlink;
+                                node.link = info.link;
+                            }
+                            info.name.unwrap_or_else(|| plain_name(identifier))
+                        }
+                    };
+                    entries.push((name, node));
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// The name of a plain record, without its version and the dot of names with no extension
+fn plain_name(identifier: &[u8]) -> String {
+    let name = String::from_utf8_lossy(identifier);
+    let name = name.split(';').next().unwrap_or_default();
+    String::from(name.strip_suffix('.').unwrap_or(name))
+}
+
+fn joliet_name(identifier: &[u8]) -> String {
+    let units = identifier
+        .chunks_exact(2)
+        .map(|unit| u16::from_be_bytes([unit[0], unit[1]]));
+    let name: String = char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+    match name.rsplit_once(';') {
+        Some((name, _)) => String::from(name),
+        None => name,
+    }
+}
+
+struct IsoInode {
+    volume: Arc<Volume>,
+    node: Node,
+}
+
+impl Inode for IsoInode {
+    fn metadata(&self) -> Metadata {
+        let size = match self.node.file_type {
+            FileType::Directory => self.read_dir().map_or(0, |entries| entries.len() as u64),
+            _ => self.node.size(),
+        };
+        Metadata {
+            file_type: self.node.file_type,
+            size,
+            inode: self.node.position,
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        self.node.file_type
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        match self.node.file_type {
+            FileType::Regular => {}
+            FileType::Directory => return Err(FsError::IsADirectory),
+            FileType::Symlink => return Err(FsError::InvalidArgument),
+        }
+        let size = self.node.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        self.volume.read_data(&self.node, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        if self.node.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let (_, node) = self
+            .volume
+            .entries(&self.node)?
+            .into_iter()
+            .find(|(entry, _)| match self.volume.names {
+                Names::Plain => entry.eq_ignore_ascii_case(name),
+                _ => entry == name,
+            })
+            .ok_or(FsError::NotFound)?;
+        Ok(Arc::new(IsoInode {
+            volume: self.volume.clone(),
+            node,
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        if self.node.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(self
+            .volume
+            .entries(&self.node)?
+            .into_iter()
+            .map(|(name, node)| DirEntry {
+                name,
+                file_type: node.file_type,
+            })
+            .collect())
+    }
+
+    fn read_link(&self) -> Result<String> {
+        self.node.link.clone().ok_or(FsError::InvalidArgument)
+    }
+}
+
+/// An ISO 9660 volume
+pub struct IsoFs {
+    root: Arc<IsoInode>,
+    volume_id: String,
+}
+
+impl IsoFs {
+    /// Reads the volume descriptors of the volume on `device`, taking the names of files from
+    /// Rock Ridge if the volume has them, and otherwise from Joliet
+    pub fn new(device: Arc<dyn BlockDevice>) -> core::result::Result<Self, IsoError> {
+        Self::open(device, None)
+    }
+
+    /// Like `new`, with the names from `names`
+    pub fn with_names(
+        device: Arc<dyn BlockDevice>,
+        names: Names,
+    ) -> core::result::Result<Self, IsoError> {
+        Self::open(device, Some(names))
+    }
+
+    fn open(
+        device: Arc<dyn BlockDevice>,
+        names: Option<Names>,
+    ) -> core::result::Result<Self, IsoError> {
+        // The root record and volume identifier of the primary and Joliet descriptors, with
+        // the position of the record
+        let mut primary = None;
+        let mut joliet = None;
+        let mut block_size = 0;
+        let mut descriptor = [0; SECTOR_SIZE];
+        for sector in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+            let offset = sector * SECTOR_SIZE as u64;
+            if offset + SECTOR_SIZE as u64 > device.size() {
+                break;
+            }
+            block::read_bytes(device.as_ref(), offset, &mut descriptor)?;
+            if &descriptor[1..6] != STANDARD_ID {
+                break;
+            }
+            let position = offset + ROOT_RECORD as u64;
+            let root: [u8; 34] = descriptor[ROOT_RECORD..ROOT_RECORD + 34]
+                .try_into()
+                .unwrap();
+            let id = &descriptor[40..72];
+            match descriptor[0] {
+                DESCRIPTOR_PRIMARY if primary.is_none() => {
+                    block_size = u64::from(le16(&descriptor, 128));
+                    let id = String::from_utf8_lossy(id);
+                    primary = Some((
+                        root,
+                        position,
+                        String::from(id.trim_end_matches([' ', '\0'])),
+                    ));
+                }
+                DESCRIPTOR_SUPPLEMENTARY if JOLIET_ESCAPES.contains(&&descriptor[88..91]) => {
+                    let id = joliet_name(id);
+                    joliet = Some((
+                        root,
+                        position,
+                        String::from(id.trim_end_matches([' ', '\0'])),
+                    ));
+                }
+                DESCRIPTOR_TERMINATOR => break,
+                _ => {}
+            }
+        }
+        let (root, position, volume_id) = primary.ok_or(IsoError::BadVolumeDescriptor)?;
+        if ![512, 1024, 2048].contains(&block_size) || root[25] & FLAG_DIRECTORY == 0 {
+            return Err(IsoError::BadVolumeDescriptor);
+        }
+
+        let mut volume = Volume {
+            device,
+            block_size,
+            names: Names::Plain,
+            susp_skip: 0,
+        };
+        let root = volume.node(&root, position);
+
+        // Rock Ridge is announced by the system use area of the `.` record of the root
+        let mut dot = [0; 255];
+        let start = root.extents[0].0;
+        volume
+            .read(start, &mut dot[..1])
+            .map_err(|_| IsoError::BadVolumeDescriptor)?;
+        let len = usize::from(dot[0]);
+        let mut info = SystemUse::default();
+        if len > RECORD_HEADER + 1 {
+            volume
+                .read(start, &mut dot[..len])
+                .map_err(|_| IsoError::BadVolumeDescriptor)?;
+            volume
+                .system_use(dot[RECORD_HEADER + 1..len].into(), &mut info)
+                .map_err(|_| IsoError::BadVolumeDescriptor)?;
+        }
+        let rock_ridge = info.sharing.is_some() && info.rock_ridge;
+        volume.susp_skip = info.sharing.unwrap_or(0);
+
+        let names = match names {
+            Some(names) => names,
+            None if rock_ridge => Names::RockRidge,
+            None if joliet.is_some() => Names::Joliet,
+            None => Names::Plain,
+        };
+        let (root, volume_id) = match names {
+            Names::Plain => (root, volume_id),
+            Names::RockRidge if rock_ridge => (root, volume_id),
+            Names::Joliet => match joliet {
+                Some((root, position, id)) => (volume.node(&root, position), id),
+                None => return Err(IsoError::MissingNames(names)),
+            },
+            Names::RockRidge => return Err(IsoError::MissingNames(names)),
+        };
+        volume.names = names;
+
+        Ok(Self {
+            root: Arc::new(IsoInode {
+                volume: Arc::new(volume),
+                node: root,
+            }),
+            volume_id,
+        })
+    }
+
+    pub fn names(&self) -> Names {
+        self.root.volume.names
+    }
+
+    /// The identifier of the volume, from the descriptor its names come from
+    pub fn volume_id(&self) -> &str {
+        &self.volume_id
+    }
+}
+
+impl FileSystem for IsoFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
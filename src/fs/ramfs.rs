@@ -0,0 +1,177 @@
+//! A filesystem keeping its files on the heap
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata, Result};
+use crate::sync::Mutex;
+
+/// Inode numbers are unique across all ramfs instances
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+impl RamFs {
+    /// An empty filesystem
+    pub fn new() -> Self {
+        Self {
+            root: RamInode::new(FileType::Directory),
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<RamInode>>),
+}
+
+struct RamInode {
+    inode: u64,
+    node: Mutex<Node>,
+}
+
+impl RamInode {
+    fn new(file_type: FileType) -> Arc<Self> {
+        Arc::new(Self {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            node: Mutex::new(match file_type {
+                FileType::Directory => Node::Directory(BTreeMap::new()),
+                _ => Node::File(Vec::new()),
+            }),
+        })
+    }
+}
+
+/// Converts a file offset or size to an index into memory
+fn to_index(offset: u64) -> Result<usize> {
+    usize::try_from(offset).map_err(|_| FsError::NoSpace)
+}
+
+/// Resizes `data`, failing instead of aborting if memory runs out
+fn resize(data: &mut Vec<u8>, len: usize) -> Result<()> {
+    if len > data.len() {
+        data.try_reserve(len - data.len())
+            .map_err(|_| FsError::NoSpace)?;
+    }
+    data.resize(len, 0);
+    Ok(())
+}
+
+impl Inode for RamInode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, size) = match &*self.node.lock() {
+            Node::File(data) => (FileType::Regular, data.len()),
+            Node::Directory(entries) => (FileType::Directory, entries.len()),
+        };
+        Metadata {
+            file_type,
+            size: size as u64,
+            inode: self.inode,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let Node::File(data) = &*self.node.lock() else {
+            return Err(FsError::IsADirectory);
+        };
+        let start = to_index(offset).unwrap_or(usize::MAX).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let Node::File(data) = &mut *self.node.lock() else {
+            return Err(FsError::IsADirectory);
+        };
+        let start = to_index(offset)?;
+        let end = start.checked_add(buf.len()).ok_or(FsError::NoSpace)?;
+        if end > data.len() {
+            resize(data, end)?;
+        }
+        data[start..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let Node::File(data) = &mut *self.node.lock() else {
+            return Err(FsError::IsADirectory);
+        };
+        resize(data, to_index(size)?)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let Node::Directory(entries) = &*self.node.lock() else {
+            return Err(FsError::NotADirectory);
+        };
+        match entries.get(name) {
+            Some(inode) => Ok(inode.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        let Node::Directory(entries) = &mut *self.node.lock() else {
+            return Err(FsError::NotADirectory);
+        };
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+        // Symbolic links are made with a target, which `create` has no room for
+        if file_type == FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = RamInode::new(file_type);
+        entries.insert(name.to_string(), inode.clone());
+        Ok(inode)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let Node::Directory(entries) = &mut *self.node.lock() else {
+            return Err(FsError::NotADirectory);
+        };
+        let inode = entries.get(name).ok_or(FsError::NotFound)?;
+        if let Node::Directory(children) = &*inode.node.lock() {
+            if !children.is_empty() {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let Node::Directory(entries) = &*self.node.lock() else {
+            return Err(FsError::NotADirectory);
+        };
+        Ok(entries
+            .iter()
+            .map(|(name, inode)| DirEntry {
+                name: name.clone(),
+                file_type: inode.metadata().file_type,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,294 @@
+//! Driver for QEMU's fw_cfg device, through which the host passes files to the kernel with
+//! `-fw_cfg name=opt/...,file=...` (or `string=...` instead of `file=...`).
+//!
+//! An item is selected by writing its key to the selector port, and its contents are then
+//! read sequentially from the data port, a byte at a time. When QEMU supports it, larger
+//! reads go through the DMA interface instead, which needs the physical addresses of the
+//! buffers and so is only used after `memory::init`.
+
+use alloc::vec::Vec;
+use core::{
+    cell::UnsafeCell,
+    fmt, ptr,
+    sync::atomic::{self, Ordering},
+};
+
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+use crate::{
+    memory,
+    sync::{LockClass, Mutex},
+};
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+/// Takes the physical address of a `DmaAccess` as two big-endian halves, and starts the
+/// transfer once the low half is written
+const DMA_PORT_HIGH: u16 = 0x514;
+const DMA_PORT_LOW: u16 = 0x518;
+
+const KEY_SIGNATURE: u16 = 0x0000;
+const KEY_FEATURES: u16 = 0x0001;
+const KEY_FILE_DIR: u16 = 0x0019;
+
+const SIGNATURE: [u8; 4] = *b"QEMU";
+const FEATURE_TRADITIONAL: u32 = 1 << 0;
+const FEATURE_DMA: u32 = 1 << 1;
+
+const DMA_CONTROL_ERROR: u32 = 1 << 0;
+const DMA_CONTROL_READ: u32 = 1 << 1;
+
+/// The maximum length of a file name, including the terminating null byte
+pub const NAME_LEN: usize = 56;
+
+const PAGE_SIZE: usize = 4096;
+
+static DEVICE: Mutex<FwCfg> = Mutex::with_class(LockClass::new("FW_CFG"), FwCfg::new());
+
+/// An entry of the file directory
+#[derive(Clone, Copy)]
+pub struct File {
+    size: u32,
+    select: u16,
+    name: [u8; NAME_LEN],
+}
+
+impl File {
+    /// Parses an entry of the directory: a big-endian size and selector key, two reserved
+    /// bytes and the null terminated name
+    fn from_entry(entry: &[u8; 64]) -> Self {
+        let mut name = [0; NAME_LEN];
+        name.copy_from_slice(&entry[8..]);
+        Self {
+            size: u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]),
+            select: u16::from_be_bytes([entry[4], entry[5]]),
+            name,
+        }
+    }
+
+    /// The name of the file, such as `opt/ros/cmdline`
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("File")
+            .field("name", &self.name())
+            .field("size", &self.size)
+            .field("select", &self.select)
+            .finish()
+    }
+}
+
+/// The request read by the device for a DMA transfer. All fields are big-endian
+#[repr(C, align(16))]
+struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+struct FwCfg {
+    selector: Port<u16>,
+    data: Port<u8>,
+    dma_high: Port<u32>,
+    dma_low: Port<u32>,
+    /// The feature bits, read on first use. No bits are set if the device is missing
+    features: Option<u32>,
+}
+
+impl FwCfg {
+    const fn new() -> Self {
+        Self {
+            selector: Port::new(SELECTOR_PORT),
+            data: Port::new(DATA_PORT),
+            dma_high: Port::new(DMA_PORT_HIGH),
+            dma_low: Port::new(DMA_PORT_LOW),
+            features: None,
+        }
+    }
+
+    fn features(&mut self) -> u32 {
+        if let Some(features) = self.features {
+            return features;
+        }
+
+        self.select(KEY_SIGNATURE);
+        let features = if self.read_array() == SIGNATURE {
+            self.select(KEY_FEATURES);
+            // Unlike everything else, the feature bits are little-endian
+            u32::from_le_bytes(self.read_array())
+        } else {
+            0
+        };
+        self.features = Some(features);
+        features
+    }
+
+    fn is_present(&mut self) -> bool {
+        self.features() & FEATURE_TRADITIONAL != 0
+    }
+
+    fn select(&mut self, key: u16) {
+        unsafe { self.selector.write(key) }
+    }
+
+    /// Reads the next bytes of the selected item through the data port
+    fn read_array<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        self.read_pio(&mut bytes);
+        bytes
+    }
+
+    fn read_pio(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = unsafe { self.data.read() };
+        }
+    }
+
+    /// Fills `buf` with the next bytes of the selected item, returning how many were read
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        // Without physical memory mapped, buffers can't be translated for DMA
+        let dma_usable = memory::phys_to_virt(PhysAddr::zero()).is_some();
+        if self.features() & FEATURE_DMA == 0 || !dma_usable {
+            self.read_pio(buf);
+            return buf.len();
+        }
+
+        // Heap buffers are only contiguous in virtual memory, so every transfer stays
+        // within a single page
+        let mut done = 0;
+        while done < buf.len() {
+            let virt = VirtAddr::from_ptr(buf[done..].as_ptr());
+            let len =
+                (PAGE_SIZE - usize::from(u16::from(virt.page_offset()))).min(buf.len() - done);
+            match memory::virt_to_phys(virt) {
+                Some(phys) if self.dma_transfer(DMA_CONTROL_READ, phys, len) => done += len,
+                _ => break,
+            }
+        }
+        done
+    }
+
+    /// Runs a single DMA transfer of `len` bytes at `address`, waiting for it to complete
+    fn dma_transfer(&mut self, control: u32, address: PhysAddr, len: usize) -> bool {
+        // The device writes its status back into the request, behind the compiler's back
+        let access = UnsafeCell::new(DmaAccess {
+            control: control.to_be(),
+            length: (len as u32).to_be(),
+            address: address.as_u64().to_be(),
+        });
+        // Being aligned to its size, the request never crosses a page boundary
+        let Some(access_phys) = memory::virt_to_phys(VirtAddr::from_ptr(access.get())) else {
+            return false;
+        };
+
+        // The request must be in memory before the device is told about it
+        atomic::fence(Ordering::SeqCst);
+        unsafe {
+            self.dma_high
+                .write(((access_phys.as_u64() >> 32) as u32).to_be());
+            self.dma_low.write((access_phys.as_u64() as u32).to_be());
+        }
+
+        // The device clears the control field once done, or sets the error bit
+        let succeeded = loop {
+            let control =
+                u32::from_be(unsafe { ptr::read_volatile(ptr::addr_of!((*access.get()).control)) });
+            if control & DMA_CONTROL_ERROR != 0 {
+                break false;
+            }
+            if control == 0 {
+                break true;
+            }
+            core::hint::spin_loop();
+        };
+        atomic::fence(Ordering::SeqCst);
+        succeeded
+    }
+
+    /// The entries of the file directory
+    fn directory(&mut self) -> impl Iterator<Item = File> + '_ {
+        let count = if self.is_present() {
+            self.select(KEY_FILE_DIR);
+            u32::from_be_bytes(self.read_array())
+        } else {
+            0
+        };
+        (0..count).map(move |_| File::from_entry(&self.read_array()))
+    }
+}
+
+/// Whether QEMU's fw_cfg device is present
+pub fn is_present() -> bool {
+    DEVICE.lock().is_present()
+}
+
+/// Whether the device supports the DMA interface, used for reads once physical memory is
+/// mapped
+pub fn supports_dma() -> bool {
+    DEVICE.lock().features() & FEATURE_DMA != 0
+}
+
+/// Looks up the file called `name`. Unlike `files`, this doesn't need the heap
+pub fn find(name: &str) -> Option<File> {
+    DEVICE.lock().directory().find(|file| file.name() == name)
+}
+
+/// All the files passed by the host
+pub fn files() -> Vec<File> {
+    DEVICE.lock().directory().collect()
+}
+
+/// Reads the start of `file` into `buf`, returning the number of bytes read, which is less
+/// than the size of the file if `buf` is too small
+pub fn read_into(file: &File, buf: &mut [u8]) -> usize {
+    let len = file.size().min(buf.len());
+    let mut device = DEVICE.lock();
+    device.select(file.select);
+    device.read(&mut buf[..len])
+}
+
+/// Reads the whole file called `name` into a new buffer
+pub fn read_file(name: &str) -> Option<Vec<u8>> {
+    let file = find(name)?;
+    let mut contents = alloc::vec![0; file.size()];
+    let len = read_into(&file, &mut contents);
+    (len == contents.len()).then_some(contents)
+}
+
+/// Passed by `-fw_cfg` in the test arguments
+#[cfg(test)]
+const TEST_GREETING: &str = "opt/ros/test/greeting";
+
+#[test_case]
+fn test_fw_cfg_is_present() {
+    assert!(is_present());
+}
+
+#[test_case]
+fn test_fw_cfg_read_string() {
+    let file = find(TEST_GREETING).expect("test file missing");
+    assert_eq!(file.name(), TEST_GREETING);
+
+    let mut buf = [0; 64];
+    let len = read_into(&file, &mut buf);
+    assert_eq!(&buf[..len], b"hello from the host");
+
+    // A short buffer only gets the start of the file
+    let mut short = [0; 5];
+    assert_eq!(read_into(&file, &mut short), 5);
+    assert_eq!(&short, b"hello");
+}
+
+#[test_case]
+fn test_fw_cfg_missing_file() {
+    assert!(find("opt/ros/test/missing").is_none());
+}
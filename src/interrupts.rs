@@ -1,24 +1,31 @@
-use pc_keyboard::{DecodedKey, KeyCode, Keyboard, ScancodeSet1};
 use pic8259::ChainedPics;
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 use x86_64::{
-    instructions::port::PortReadOnly,
+    instructions::port::Port,
+    set_general_handler,
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
 };
 
-use crate::{gdt, halt_loop, vga_buffer::VgaWriter, vga_print, vga_println};
+use crate::{
+    gdt, halt_loop,
+    irq::{self, dispatch},
+    panic, serial_println,
+    sync::{IrqSpinLock, LockClass, RawIrqSpinLock},
+    vga_println,
+};
 
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
     idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
     unsafe {
         idt.double_fault
             .set_handler_fn(double_fault_handler)
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
     }
     idt.page_fault.set_handler_fn(page_fault_handler);
-    idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
-    idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
+    // Hardware interrupts are routed to the handlers registered through `irq`
+    set_general_handler!(&mut idt, dispatch, irq::FIRST_VECTOR..=255);
     idt
 });
 
@@ -32,6 +39,14 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     vga_println!("EXCEPTION: Breakpoint\n{stack_frame:#?}");
 }
 
+/// Another CPU sends an NMI when it panics, to freeze this one
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    if panic::is_panicking() {
+        panic::halt_forever();
+    }
+    serial_println!("NMI\n{stack_frame:#?}");
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _err_code: u64,
@@ -52,86 +67,111 @@ extern "x86-interrupt" fn page_fault_handler(
     halt_loop();
 }
 
-// External Interrupts
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // vga_print!(".");
+static PICS: IrqSpinLock<ChainedPics> =
+    IrqSpinLock::from_raw(RawIrqSpinLock::with_class(LockClass::new("PICS")), unsafe {
+        ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
+    });
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8())
-    }
+pub fn init_pics() {
+    unsafe { PICS.lock().initialize() }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    static KEYBOARD: Mutex<Keyboard<pc_keyboard::layouts::Us104Key, ScancodeSet1>> =
-        Mutex::new(Keyboard::new(
-            ScancodeSet1::new(),
-            pc_keyboard::layouts::Us104Key,
-            pc_keyboard::HandleControl::Ignore,
-        ));
-
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = PortReadOnly::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => {
-                    vga_print!("{}", character);
-                }
-                DecodedKey::RawKey(key) => match key {
-                    KeyCode::LShift | KeyCode::RShift => (),
-                    _ => vga_print!("{key:?}"),
-                },
-            }
+/// The IRQ line the cascaded secondary PIC is connected to
+const CASCADE_IRQ: u8 = 2;
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+/// OCW3 command making the next read of the command port return the In-Service Register
+const READ_ISR: u8 = 0x0b;
+/// OCW2 command ending the highest priority interrupt in service
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// Enables the given IRQ line (0-15) on the PICs, including the cascade line if needed
+pub fn unmask_irq(line: u8) {
+    update_masks(|primary, secondary| {
+        if line < 8 {
+            *primary &= !(1 << line);
+        } else {
+            *primary &= !(1 << CASCADE_IRQ);
+            *secondary &= !(1 << (line - 8));
         }
-    }
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8())
-    }
+    })
 }
 
-pub const PIC_1_OFFSET: u8 = 32;
-pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-
-static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
-
-pub fn init_pics() {
-    unsafe { PICS.lock().initialize() }
+/// Disables the given IRQ line (0-15) on the PICs
+pub fn mask_irq(line: u8) {
+    update_masks(|primary, secondary| {
+        if line < 8 {
+            *primary |= 1 << line;
+        } else {
+            *secondary |= 1 << (line - 8);
+        }
+    })
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard = PIC_1_OFFSET + 1,
+fn update_masks(f: impl FnOnce(&mut u8, &mut u8)) {
+    let mut pics = PICS.lock();
+    let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+    f(&mut primary, &mut secondary);
+    unsafe { pics.write_masks(primary, secondary) }
 }
 
-impl InterruptIndex {
-    #[allow(unused)]
-    fn as_u8(self) -> u8 {
-        self.into()
+/// Checks whether an interrupt on `line` is spurious, acknowledging it if needed.
+///
+/// The PICs raise IRQ7 (or IRQ15 for the secondary PIC) when an interrupt goes away before
+/// it could be delivered. A spurious interrupt doesn't show up in the In-Service Register and
+/// must not be acknowledged, except on the primary PIC for a spurious IRQ15, since the
+/// primary did see a genuine interrupt on the cascade line
+pub(crate) fn is_spurious(line: u8) -> bool {
+    if line != 7 && line != 15 {
+        return false;
     }
 
-    #[allow(unused)]
-    fn as_usize(self) -> usize {
-        self.into()
+    let mut pics = PICS.lock();
+    let mut command = Port::<u8>::new(if line == 7 {
+        PIC_1_COMMAND
+    } else {
+        PIC_2_COMMAND
+    });
+    let isr = unsafe {
+        command.write(READ_ISR);
+        command.read()
+    };
+
+    // Both lines are the last input of their PIC
+    let spurious = isr & (1 << 7) == 0;
+    if spurious && line == 15 {
+        unsafe { pics.notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ) }
     }
+    spurious
 }
 
-impl From<InterruptIndex> for u8 {
-    fn from(value: InterruptIndex) -> Self {
-        value as u8
+/// Acknowledges every interrupt the PICs still have in service, after a panic abandoned the
+/// handlers which would have
+///
+/// # Safety
+/// Those handlers must never run again
+pub(crate) unsafe fn end_abandoned_interrupts() {
+    // The panic may have come with the lock held, too
+    unsafe { PICS.raw().force_release() };
+    let _pics = PICS.lock();
+    // The secondary PIC first, since its interrupts are in service on the cascade line as well
+    for command in [PIC_2_COMMAND, PIC_1_COMMAND] {
+        let mut command = Port::<u8>::new(command);
+        unsafe {
+            command.write(READ_ISR);
+            // Each non-specific EOI ends the highest priority interrupt in service
+            for _ in 0..command.read().count_ones() {
+                command.write(END_OF_INTERRUPT);
+            }
+        }
     }
 }
 
-impl From<InterruptIndex> for usize {
-    fn from(value: InterruptIndex) -> Self {
-        usize::from(u8::from(value))
-    }
+/// Acknowledges the interrupt delivered on `vector` to the PICs
+pub(crate) fn end_of_interrupt(vector: u8) {
+    unsafe { PICS.lock().notify_end_of_interrupt(vector) }
 }
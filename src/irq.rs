@@ -0,0 +1,235 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, structures::idt::InterruptStackFrame};
+
+use crate::{
+    deferred,
+    interrupts::{self, PIC_1_OFFSET},
+};
+
+/// The first vector available to hardware interrupts, right after the CPU exceptions
+pub const FIRST_VECTOR: u8 = 32;
+/// Number of legacy IRQ lines provided by the chained PICs
+pub const PIC_LINES: u8 = 16;
+
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const RTC_IRQ: u8 = 8;
+
+/// Maximum number of handlers sharing a single vector
+const MAX_SHARED: usize = 4;
+
+/// Whether a handler recognized the interrupt as coming from its device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    NotMine,
+}
+
+/// An interrupt handler, called from interrupt context with interrupts disabled
+pub type Handler = fn() -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The line or vector can't be used for hardware interrupts
+    InvalidLine(u8),
+    /// The handler is already registered on this line
+    AlreadyRegistered,
+    /// Too many handlers are already sharing this line
+    LineFull,
+    /// The handler wasn't registered on this line
+    NotRegistered,
+}
+
+static HANDLERS: [Mutex<[Option<Handler>; MAX_SHARED]>; 256] =
+    [const { Mutex::new([None; MAX_SHARED]) }; 256];
+
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+static UNHANDLED: AtomicU64 = AtomicU64::new(0);
+/// Number of interrupt handlers currently running, nested ones included
+static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// The vector a legacy IRQ line is delivered on
+pub fn line_to_vector(line: u8) -> Option<u8> {
+    (line < PIC_LINES).then(|| PIC_1_OFFSET + line)
+}
+
+/// The legacy IRQ line delivered on `vector`, if any
+pub fn vector_to_line(vector: u8) -> Option<u8> {
+    vector
+        .checked_sub(PIC_1_OFFSET)
+        .filter(|&line| line < PIC_LINES)
+}
+
+/// Registers `handler` for the legacy IRQ line `line` (0-15) and unmasks the line.
+///
+/// Lines may be shared, in which case every handler is called on each interrupt, and
+/// handlers must return `IrqReturn::NotMine` if their device didn't raise it. A driver
+/// registers one handler for all its devices, which goes through every device it found
+pub fn register(line: u8, handler: Handler) -> Result<(), IrqError> {
+    let vector = line_to_vector(line).ok_or(IrqError::InvalidLine(line))?;
+    register_vector(vector, handler)?;
+    interrupts::unmask_irq(line);
+    Ok(())
+}
+
+/// Registers `handler` for `vector`, which must not be a CPU exception
+pub fn register_vector(vector: u8, handler: Handler) -> Result<(), IrqError> {
+    if vector < FIRST_VECTOR {
+        return Err(IrqError::InvalidLine(vector));
+    }
+
+    without_interrupts(|| {
+        let mut handlers = HANDLERS[vector as usize].lock();
+        if handlers
+            .iter()
+            .flatten()
+            .any(|&h| ptr::fn_addr_eq(h, handler))
+        {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        let slot = handlers
+            .iter_mut()
+            .find(|h| h.is_none())
+            .ok_or(IrqError::LineFull)?;
+        *slot = Some(handler);
+        Ok(())
+    })
+}
+
+/// Removes `handler` from the legacy IRQ line `line`, masking the line if it was the last one
+pub fn unregister(line: u8, handler: Handler) -> Result<(), IrqError> {
+    let vector = line_to_vector(line).ok_or(IrqError::InvalidLine(line))?;
+    unregister_vector(vector, handler)?;
+    if !has_handlers(vector) {
+        interrupts::mask_irq(line);
+    }
+    Ok(())
+}
+
+/// Removes `handler` from `vector`
+pub fn unregister_vector(vector: u8, handler: Handler) -> Result<(), IrqError> {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS[vector as usize].lock();
+        let slot = handlers
+            .iter_mut()
+            .find(|h| h.is_some_and(|h| ptr::fn_addr_eq(h, handler)))
+            .ok_or(IrqError::NotRegistered)?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+fn has_handlers(vector: u8) -> bool {
+    without_interrupts(|| HANDLERS[vector as usize].lock().iter().any(Option::is_some))
+}
+
+/// Number of interrupts received on `vector`, including spurious ones
+pub fn count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Number of spurious IRQ7/IRQ15 interrupts
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Number of interrupts no registered handler claimed
+pub fn unhandled_count() -> u64 {
+    UNHANDLED.load(Ordering::Relaxed)
+}
+
+/// Forgets about the interrupt handlers which were running, after a panic abandoned them, and
+/// acknowledges the interrupts they were handling
+///
+/// # Safety
+/// Those handlers must never run again
+pub(crate) unsafe fn forget_running_handlers() {
+    IRQ_DEPTH.store(0, Ordering::Relaxed);
+    unsafe { interrupts::end_abandoned_interrupts() }
+}
+
+/// Whether the CPU is running an interrupt handler. Deferred work doesn't count
+pub fn in_interrupt() -> bool {
+    IRQ_DEPTH.load(Ordering::Relaxed) != 0
+}
+
+/// The entry point of every hardware interrupt vector (32-255), installed in the IDT
+/// through `set_general_handler!`
+pub(crate) fn dispatch(_stack_frame: InterruptStackFrame, vector: u8, _error_code: Option<u64>) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+
+    let line = vector_to_line(vector);
+    if let Some(line) = line {
+        if interrupts::is_spurious(line) {
+            SPURIOUS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+
+    IRQ_DEPTH.fetch_add(1, Ordering::Relaxed);
+    let handlers = *HANDLERS[vector as usize].lock();
+    let handled = handlers.iter().flatten().fold(false, |handled, handler| {
+        (handler() == IrqReturn::Handled) | handled
+    });
+    IRQ_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    if !handled {
+        UNHANDLED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if line.is_some() {
+        interrupts::end_of_interrupt(vector);
+    }
+
+    deferred::run_after_interrupt();
+}
+
+#[test_case]
+fn test_registered_handlers_are_called() {
+    use core::sync::atomic::AtomicUsize;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    const VECTOR: u8 = 200;
+
+    fn not_mine() -> IrqReturn {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::NotMine
+    }
+    fn mine() -> IrqReturn {
+        CALLS.fetch_add(10, Ordering::Relaxed);
+        IrqReturn::Handled
+    }
+
+    // Handlers after one which handled the interrupt are still called
+    register_vector(VECTOR, mine).unwrap();
+    register_vector(VECTOR, not_mine).unwrap();
+    assert_eq!(
+        register_vector(VECTOR, mine),
+        Err(IrqError::AlreadyRegistered)
+    );
+
+    let before = count(VECTOR);
+    unsafe { x86_64::instructions::interrupts::software_interrupt::<VECTOR>() };
+    assert_eq!(count(VECTOR), before + 1);
+    assert_eq!(CALLS.load(Ordering::Relaxed), 11);
+
+    unregister_vector(VECTOR, not_mine).unwrap();
+    unregister_vector(VECTOR, mine).unwrap();
+    let unhandled = unhandled_count();
+    unsafe { x86_64::instructions::interrupts::software_interrupt::<VECTOR>() };
+    assert_eq!(unhandled_count(), unhandled + 1);
+    assert_eq!(CALLS.load(Ordering::Relaxed), 11);
+}
+
+#[test_case]
+fn test_timer_interrupts_are_counted() {
+    let vector = line_to_vector(TIMER_IRQ).unwrap();
+    let before = count(vector);
+    crate::time::busy_wait(core::time::Duration::from_millis(10));
+    assert!(count(vector) > before);
+}
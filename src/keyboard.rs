@@ -0,0 +1,51 @@
+use pc_keyboard::{DecodedKey, KeyCode, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use x86_64::instructions::port::PortReadOnly;
+
+use crate::{
+    deferred,
+    irq::{self, IrqReturn},
+    vga_print,
+};
+
+const DATA_PORT: u16 = 0x60;
+
+pub fn init() {
+    irq::register(irq::KEYBOARD_IRQ, keyboard_interrupt)
+        .expect("failed to register the keyboard interrupt");
+}
+
+/// Reads the scancode, leaving the decoding and printing to a bottom half
+fn keyboard_interrupt() -> IrqReturn {
+    let mut port = PortReadOnly::new(DATA_PORT);
+    let scancode: u8 = unsafe { port.read() };
+
+    // Dropping a key press under heavy load is preferable to blocking in the interrupt
+    let _ = deferred::defer(process_scancode, scancode.into());
+
+    IrqReturn::Handled
+}
+
+fn process_scancode(scancode: usize) {
+    static KEYBOARD: Mutex<Keyboard<pc_keyboard::layouts::Us104Key, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(
+            ScancodeSet1::new(),
+            pc_keyboard::layouts::Us104Key,
+            pc_keyboard::HandleControl::Ignore,
+        ));
+
+    let mut keyboard = KEYBOARD.lock();
+    if let Ok(Some(key_event)) = keyboard.add_byte(scancode as u8) {
+        if let Some(key) = keyboard.process_keyevent(key_event) {
+            match key {
+                DecodedKey::Unicode(character) => {
+                    vga_print!("{}", character);
+                }
+                DecodedKey::RawKey(key) => match key {
+                    KeyCode::LShift | KeyCode::RShift => (),
+                    _ => vga_print!("{key:?}"),
+                },
+            }
+        }
+    }
+}
@@ -11,34 +11,35 @@
 extern crate alloc;
 
 use bootloader::BootInfo;
-use core::panic::PanicInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod backtrace;
+pub mod block;
+pub mod cmdline;
+pub mod config;
+pub mod deferred;
+pub mod dma;
+pub mod fs;
+pub mod fw_cfg;
 pub mod gdt;
 pub mod interrupts;
+pub mod irq;
+pub mod keyboard;
+pub mod logger;
 pub mod memory;
+pub mod net;
+pub mod panic;
+pub mod pci;
+pub mod rtc;
 pub mod serial;
+pub mod sync;
+pub mod testing;
+pub mod time;
 pub mod vga_buffer;
+pub mod virtio;
 
-pub trait TestCase {
-    fn run(&self);
-}
-
-impl<T: Fn()> TestCase for T {
-    fn run(&self) {
-        serial_print!("{}...", core::any::type_name::<Self>());
-        self();
-        serial_println!("[ok]");
-    }
-}
-
-pub fn test_runner(tests: &[&dyn TestCase]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
-    }
-    exit_qemu(QemuExitCode::Success);
-}
+pub use testing::{test_panic_handler, test_runner, TestCase};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
@@ -56,17 +57,9 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
-pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-
-    halt_loop();
-}
-
 #[cfg(test)]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
@@ -74,6 +67,9 @@ pub fn init() {
     gdt::init();
     interrupts::init_idt();
     interrupts::init_pics();
+    time::init(time::DEFAULT_TICK_FREQUENCY);
+    rtc::init(None);
+    keyboard::init();
 
     x86_64::instructions::interrupts::enable();
 }
@@ -105,4 +101,57 @@ mod tests {
     fn test_breakpoint_exception() {
         x86_64::instructions::interrupts::int3();
     }
+
+    crate::kernel_test! {
+        #[should_panic]
+        fn test_should_panic_continues_run() {
+            panic!("this panic is expected");
+        }
+    }
+
+    /// Panics the first time it runs, in the middle of printing
+    fn panicking_timer_handler() -> crate::irq::IrqReturn {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        struct Panics;
+        impl core::fmt::Display for Panics {
+            fn fmt(&self, _: &mut core::fmt::Formatter) -> core::fmt::Result {
+                panic!("this panic in an interrupt handler is expected");
+            }
+        }
+
+        static PANICKED: AtomicBool = AtomicBool::new(false);
+        if !PANICKED.swap(true, Ordering::Relaxed) {
+            crate::serial_println!("{}", Panics);
+        }
+        crate::irq::IrqReturn::NotMine
+    }
+
+    crate::kernel_test! {
+        #[should_panic]
+        fn test_should_panic_in_interrupt_handler() {
+            crate::irq::register(crate::irq::TIMER_IRQ, panicking_timer_handler).unwrap();
+            loop {
+                x86_64::instructions::hlt();
+            }
+        }
+    }
+
+    /// The panic above left an interrupt in service and the serial port locked
+    #[test_case]
+    fn test_run_continues_after_panic_in_interrupt_handler() {
+        crate::irq::unregister(crate::irq::TIMER_IRQ, panicking_timer_handler).unwrap();
+        let start = crate::time::ticks();
+        while crate::time::ticks() < start + 2 {
+            x86_64::instructions::hlt();
+        }
+        crate::serial_println!("the serial port is still usable");
+    }
+
+    crate::kernel_test! {
+        #[ignore]
+        fn test_ignored_is_skipped() {
+            panic!("ignored tests don't run by default");
+        }
+    }
 }
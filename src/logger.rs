@@ -0,0 +1,109 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{
+    serial_println,
+    vga_buffer::{Color, VgaWriter},
+};
+
+/// Where log records are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Vga,
+    Serial,
+    Both,
+}
+
+impl Console {
+    const fn to_u8(self) -> u8 {
+        match self {
+            Console::Vga => 0,
+            Console::Serial => 1,
+            Console::Both => 2,
+        }
+    }
+
+    const fn from_u8(console: u8) -> Self {
+        match console {
+            0 => Console::Vga,
+            1 => Console::Serial,
+            _ => Console::Both,
+        }
+    }
+
+    fn has_vga(self) -> bool {
+        matches!(self, Console::Vga | Console::Both)
+    }
+
+    fn has_serial(self) -> bool {
+        matches!(self, Console::Serial | Console::Both)
+    }
+}
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+static CONSOLE: AtomicU8 = AtomicU8::new(Console::Both.to_u8());
+
+/// Installs the kernel logger as the `log` crate's logger.
+///
+/// Can be called again to change the level and console
+pub fn init(level: LevelFilter, console: Console) {
+    // Only fails if the logger is already installed
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+    set_console(console);
+}
+
+pub fn set_console(console: Console) {
+    CONSOLE.store(console.to_u8(), Ordering::Relaxed);
+}
+
+pub fn console() -> Console {
+    Console::from_u8(CONSOLE.load(Ordering::Relaxed))
+}
+
+fn level_color(level: Level) -> Color {
+    match level {
+        Level::Error => Color::LightRed,
+        Level::Warn => Color::Yellow,
+        Level::Info => Color::White,
+        Level::Debug => Color::LightGray,
+        Level::Trace => Color::DarkGray,
+    }
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let console = console();
+        if console.has_serial() {
+            serial_println!(
+                "[{:>5}] {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+        if console.has_vga() {
+            let mut vga = VgaWriter::lock();
+            vga.set_colors(level_color(record.level()), Color::Black);
+            let _ = write!(vga, "[{:>5}] ", record.level());
+            vga.set_colors(Color::White, Color::Black);
+            let _ = writeln!(vga, "{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
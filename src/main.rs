@@ -9,7 +9,10 @@ extern crate alloc;
 use alloc::{boxed::Box, string::ToString, vec};
 use bootloader::BootInfo;
 use core::panic::PanicInfo;
-use ros::{allocator, halt_loop, memory, serial_println, vga_print, vga_println};
+use ros::{
+    allocator, block, config, deferred, dma, fs, logger, memory, panic, pci, rtc, serial_println,
+    vga_print, vga_println,
+};
 use x86_64::{
     registers,
     structures::paging::{Page, PageTable, Translate},
@@ -19,6 +22,10 @@ use x86_64::{
 bootloader::entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let config = config::get();
+    logger::init(config.log_level, config.console);
+    panic::set_policy(config.panic_policy);
+
     vga_println!("Hello VGA!");
     serial_println!("Hello Serial!");
 
@@ -31,20 +38,38 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     ros::init();
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    log::info!("heap: {} KiB", allocator::heap_size() / 1024);
+    if let Err(err) = dma::init(&mut frame_allocator) {
+        log::warn!("dma: no memory for the pool: {err:?}");
+    }
+    if let Err(err) = fs::initrd::init() {
+        log::warn!("initrd: invalid archive: {err:?}");
+    }
+    fs::init();
+    pci::init();
+    block::ata::init();
+    block::ahci::init();
+    block::nvme::init();
+    block::virtio::init();
+    block::partition::scan_all();
+
+    for arg in config.invalid.iter() {
+        log::warn!("ignoring invalid boot argument `{arg}`");
+    }
+
+    vga_println!("It is {}", rtc::SystemTime::now());
 
     #[cfg(test)]
     test_main();
 
-    halt_loop();
+    deferred::worker_loop();
 }
 
 /// This function is called on panic.
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    vga_println!("{}", info);
-
-    halt_loop();
+    ros::panic::handle_panic(info)
 }
 
 #[cfg(test)]
@@ -1,4 +1,5 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Once;
 use x86_64::{
     registers,
     structures::paging::{
@@ -8,6 +9,8 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -15,12 +18,59 @@ use x86_64::{
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
     unsafe {
         let level_4_table = active_lvl_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
     }
 }
 
+/// Returns the virtual address at which the physical address `phys` is mapped by the
+/// bootloader's complete physical memory mapping.
+///
+/// Returns `None` if `init` has not been called yet
+pub fn phys_to_virt(phys: PhysAddr) -> Option<VirtAddr> {
+    PHYSICAL_MEMORY_OFFSET
+        .get()
+        .map(|&offset| offset + phys.as_u64())
+}
+
+/// Translates `virt` to the physical address it is mapped to, by walking the active page
+/// tables.
+///
+/// Returns `None` if `virt` isn't mapped or `init` has not been called yet
+pub fn virt_to_phys(virt: VirtAddr) -> Option<PhysAddr> {
+    let offset = *PHYSICAL_MEMORY_OFFSET.get()?;
+    let (lvl4_table_frame, _) = registers::control::Cr3::read();
+
+    let mut table_phys = lvl4_table_frame.start_address();
+    let indexes = [
+        virt.p4_index(),
+        virt.p3_index(),
+        virt.p2_index(),
+        virt.p1_index(),
+    ];
+    for (level, index) in indexes.into_iter().enumerate() {
+        let table = unsafe { &*(offset + table_phys.as_u64()).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        match entry.frame() {
+            Ok(frame) => table_phys = frame.start_address(),
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => {
+                // Huge pages are 1GiB in the level 3 table and 2MiB in the level 2 table
+                let page_size: u64 = match level {
+                    1 => 1 << 30,
+                    2 => 1 << 21,
+                    _ => return None,
+                };
+                return Some(entry.addr() + (virt.as_u64() & (page_size - 1)));
+            }
+        }
+    }
+
+    Some(table_phys + u64::from(virt.page_offset()))
+}
+
 /// Returns a mutable reference to the active level 4 page table
 unsafe fn active_lvl_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     let (lvl4_table_frame, _) = registers::control::Cr3::read();
@@ -0,0 +1,93 @@
+//! Network devices, and the registry drivers add the interfaces they find to.
+//!
+//! A network device sends and receives Ethernet frames, from the destination address to the
+//! end of the payload: the frame check sequence is added and checked by the device. Drivers
+//! register each interface, which is named `eth0`, `eth1` and so on in the order they are
+//! found.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::fmt;
+
+use crate::{
+    sync::{LockClass, Mutex},
+    time::Deadline,
+};
+
+pub mod e1000;
+
+/// The destination and source addresses, and the EtherType
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+/// The largest frame, carrying 1500 bytes of payload
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: Self = Self([0xff; 6]);
+
+    /// Whether frames to this address go to a group of interfaces, broadcast included
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// The frame is shorter than an Ethernet header or longer than `MAX_FRAME_SIZE`
+    BadFrame,
+    /// There was no memory left for the buffers of the device
+    NoMemory,
+    /// The device didn't take the frame or complete the command in time
+    Timeout,
+    /// The device reported an error
+    Device,
+}
+
+pub trait NetDevice: Send + Sync {
+    fn mac_address(&self) -> MacAddress;
+
+    fn is_link_up(&self) -> bool;
+
+    /// Queues `frame` for sending. Frames shorter than the Ethernet minimum are padded
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// The oldest frame received and not taken yet, if any
+    fn receive(&self) -> Option<Vec<u8>>;
+
+    /// Like `receive`, waiting for a frame until `deadline`
+    fn receive_until(&self, deadline: Deadline) -> Option<Vec<u8>>;
+}
+
+static DEVICES: Mutex<Vec<(String, Arc<dyn NetDevice>)>> =
+    Mutex::with_class(LockClass::new("NET_DEVICES"), Vec::new());
+
+/// Adds `device` to the registry under the next free `eth<n>` name, which is returned
+pub fn register(device: Arc<dyn NetDevice>) -> String {
+    let mut devices = DEVICES.lock();
+    let name = format!("eth{}", devices.len());
+    log::info!("net: {name}: {}", device.mac_address());
+    devices.push((name.clone(), device));
+    name
+}
+
+/// The device registered under `name`
+pub fn get(name: &str) -> Option<Arc<dyn NetDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(other, _)| other == name)
+        .map(|(_, device)| device.clone())
+}
+
+/// Every registered device, in the order they were registered
+pub fn devices() -> Vec<(String, Arc<dyn NetDevice>)> {
+    DEVICES.lock().clone()
+}
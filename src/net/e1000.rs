@@ -0,0 +1,543 @@
+//! Intel 8254x gigabit Ethernet controllers, the e1000 family QEMU emulates by default.
+//!
+//! Frames go through two rings of 16 byte descriptors in DMA memory, each pointing to a
+//! buffer of its own. The driver hands receive descriptors to the controller by moving the
+//! receive tail past them, and the controller writes each frame it accepts to the next one,
+//! setting its descriptor done bit. Frames to send are queued by filling the next transmit
+//! descriptor and moving the transmit tail past it, and the controller sets the done bit once
+//! it is through with the buffer.
+//!
+//! The MAC address comes from the EEPROM, and the controller only accepts frames to it and to
+//! the broadcast address. Interrupts announce received frames, sent frames and link status
+//! changes, after which the link status is read from the controller.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    ptr,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use x86_64::VirtAddr;
+
+use super::{MacAddress, NetDevice, NetError, ETHERNET_HEADER_SIZE, MAX_FRAME_SIZE};
+use crate::{
+    dma::DmaBuffer,
+    irq::{self, IrqReturn},
+    memory,
+    pci::{self, Bar, DeviceId, PciDevice},
+    sync::{IrqSpinLock, LockClass, RawIrqSpinLock, WaitQueue},
+    time::{self, Deadline},
+};
+
+// Controller registers
+const REG_CTRL: usize = 0x0000;
+const REG_STATUS: usize = 0x0008;
+const REG_EERD: usize = 0x0014;
+const REG_MDIC: usize = 0x0020;
+const REG_ICR: usize = 0x00c0;
+const REG_IMS: usize = 0x00d0;
+const REG_IMC: usize = 0x00d8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+/// The multicast table, 128 registers of 32 bits
+const REG_MTA: usize = 0x5200;
+const MTA_ENTRIES: usize = 128;
+/// The first receive address, its low 32 bits then its high 16 bits
+const REG_RAL: usize = 0x5400;
+const REG_RAH: usize = 0x5404;
+
+/// Auto-speed detection
+const CTRL_ASDE: u32 = 1 << 5;
+/// Set link up
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+const EERD_ADDRESS_SHIFT: u32 = 8;
+const EERD_DATA_SHIFT: u32 = 16;
+
+/// The internal PHY is at address 1
+const MDIC_PHY: u32 = 1 << 21;
+const MDIC_OP_WRITE: u32 = 1 << 26;
+const MDIC_OP_READ: u32 = 2 << 26;
+const MDIC_READY: u32 = 1 << 28;
+const MDIC_ERROR: u32 = 1 << 30;
+
+/// The PHY control register, and its bits
+const PHY_CONTROL: u32 = 0;
+const PHY_AN_RESTART: u16 = 1 << 9;
+const PHY_AN_ENABLE: u16 = 1 << 12;
+const PHY_LOOPBACK: u16 = 1 << 14;
+
+/// The address valid bit of RAH
+const RAH_AV: u32 = 1 << 31;
+
+// Interrupt causes
+const INT_TXDW: u32 = 1 << 0;
+const INT_LSC: u32 = 1 << 2;
+/// The receive ring is running low
+const INT_RXDMT0: u32 = 1 << 4;
+/// The receive ring overran, with frames lost
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+const INT_RECEIVED: u32 = INT_RXT0 | INT_RXDMT0 | INT_RXO;
+
+const RCTL_EN: u32 = 1 << 1;
+/// Broadcast accept mode
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the frame check sequence
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+/// Pad short packets
+const TCTL_PSP: u32 = 1 << 3;
+/// The collision threshold and distance, at the values the manual recommends
+const TCTL_CT: u32 = 0x0f << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// The inter packet gaps the manual recommends for copper
+const TIPG: u32 = 10 | 8 << 10 | 6 << 20;
+
+const DESCRIPTOR_SIZE: usize = 16;
+/// A multiple of 8, since ring lengths are a multiple of 128 bytes
+const RING_SIZE: usize = 32;
+/// The buffer size of receive descriptors after reset
+const BUFFER_SIZE: usize = 2048;
+
+// Fields of the descriptors, besides the buffer address
+const DESC_LENGTH: usize = 8;
+/// The command of transmit descriptors
+const DESC_CMD: usize = 11;
+const DESC_STATUS: usize = 12;
+/// The errors of receive descriptors
+const DESC_ERRORS: usize = 13;
+
+/// Descriptor done, in the status of both kinds of descriptors
+const STATUS_DD: u8 = 1 << 0;
+/// End of packet, in the status of receive descriptors
+const STATUS_EOP: u8 = 1 << 1;
+const CMD_EOP: u8 = 1 << 0;
+/// Insert the frame check sequence
+const CMD_IFCS: u8 = 1 << 1;
+/// Report status, having the controller set the done bit
+const CMD_RS: u8 = 1 << 3;
+
+/// The shortest frame, without its frame check sequence
+const MIN_FRAME_SIZE: usize = 60;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static DRIVER: pci::Driver = pci::Driver {
+    name: "e1000",
+    ids: &[
+        // 82540EM, which QEMU emulates
+        DeviceId::new(0x8086, 0x100e),
+        // 82545EM
+        DeviceId::new(0x8086, 0x100f),
+    ],
+    probe,
+};
+
+/// The controllers found, whose interrupt causes the handler reads, which clears them
+static NICS: IrqSpinLock<Vec<Arc<E1000>>> = IrqSpinLock::from_raw(
+    RawIrqSpinLock::with_class(LockClass::new("E1000_NICS")),
+    Vec::new(),
+);
+
+/// Registers the driver for e1000 controllers
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+/// Every e1000 controller found, for PHY loopback and link negotiation
+pub fn devices() -> Vec<Arc<E1000>> {
+    NICS.lock().clone()
+}
+
+fn interrupt() -> IrqReturn {
+    let mut result = IrqReturn::NotMine;
+    for nic in NICS.lock().iter() {
+        // Reading the causes acknowledges them
+        let causes = nic.read32(REG_ICR);
+        if causes == 0 {
+            continue;
+        }
+        result = IrqReturn::Handled;
+        if causes & INT_LSC != 0 {
+            nic.link_changes.fetch_add(1, Ordering::Relaxed);
+            nic.link.notify_all();
+        }
+        if causes & INT_RECEIVED != 0 {
+            nic.received.notify_all();
+        }
+        if causes & INT_TXDW != 0 {
+            nic.sent.notify_all();
+        }
+    }
+    result
+}
+
+/// A ring of descriptors, and the buffers they point to
+struct Ring {
+    descriptors: DmaBuffer,
+    buffers: DmaBuffer,
+    /// The next descriptor the controller completes
+    next: usize,
+}
+
+impl Ring {
+    fn new() -> Result<Self, NetError> {
+        let descriptors =
+            DmaBuffer::new(RING_SIZE * DESCRIPTOR_SIZE).map_err(|_| NetError::NoMemory)?;
+        let buffers = DmaBuffer::new(RING_SIZE * BUFFER_SIZE).map_err(|_| NetError::NoMemory)?;
+        let mut ring = Self {
+            descriptors,
+            buffers,
+            next: 0,
+        };
+        // Each descriptor keeps its buffer, so only the other fields change later
+        for i in 0..RING_SIZE {
+            let address = ring.buffers.phys_at(i * BUFFER_SIZE).as_u64();
+            let offset = i * DESCRIPTOR_SIZE;
+            ring.descriptors[offset..offset + 8].copy_from_slice(&address.to_le_bytes());
+        }
+        Ok(ring)
+    }
+
+    fn read8(&self, index: usize, field: usize) -> u8 {
+        unsafe { ptr::read_volatile(&self.descriptors[index * DESCRIPTOR_SIZE + field]) }
+    }
+
+    fn write8(&mut self, index: usize, field: usize, value: u8) {
+        unsafe {
+            ptr::write_volatile(
+                &mut self.descriptors[index * DESCRIPTOR_SIZE + field],
+                value,
+            )
+        }
+    }
+
+    fn length(&self, index: usize) -> usize {
+        let offset = index * DESCRIPTOR_SIZE + DESC_LENGTH;
+        let length: u16 = unsafe { ptr::read_volatile(self.descriptors[offset..].as_ptr().cast()) };
+        usize::from(length)
+    }
+
+    fn set_length(&mut self, index: usize, length: usize) {
+        let offset = index * DESCRIPTOR_SIZE + DESC_LENGTH;
+        self.descriptors[offset..offset + 2].copy_from_slice(&(length as u16).to_le_bytes());
+    }
+
+    fn buffer(&mut self, index: usize) -> &mut [u8] {
+        &mut self.buffers[index * BUFFER_SIZE..(index + 1) * BUFFER_SIZE]
+    }
+
+    /// Whether the controller is done with transmit descriptor `index`, or never had it
+    fn is_sent(&self, index: usize) -> bool {
+        self.read8(index, DESC_CMD) == 0 || self.read8(index, DESC_STATUS) & STATUS_DD != 0
+    }
+
+    fn address(&self) -> u64 {
+        self.descriptors.phys().as_u64()
+    }
+}
+
+/// An e1000 controller, which is registered as a `NetDevice`
+pub struct E1000 {
+    registers: VirtAddr,
+    mac: MacAddress,
+    rx: IrqSpinLock<Ring>,
+    tx: IrqSpinLock<Ring>,
+    /// Notified as frames are received
+    received: WaitQueue,
+    /// Notified as transmit descriptors are done with
+    sent: WaitQueue,
+    /// Notified as the link status changes
+    link: WaitQueue,
+    link_changes: AtomicUsize,
+}
+
+impl E1000 {
+    fn read32(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.registers + register as u64).as_ptr()) }
+    }
+
+    fn write32(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.registers + register as u64).as_mut_ptr(), value) }
+    }
+
+    /// Resets the controller, reads its MAC address and starts receiving and sending
+    fn new(registers: VirtAddr) -> Result<Self, NetError> {
+        let mut nic = Self {
+            registers,
+            mac: MacAddress::default(),
+            rx: IrqSpinLock::from_raw(
+                RawIrqSpinLock::with_class(LockClass::new("E1000_RX")),
+                Ring::new()?,
+            ),
+            tx: IrqSpinLock::from_raw(
+                RawIrqSpinLock::with_class(LockClass::new("E1000_TX")),
+                Ring::new()?,
+            ),
+            received: WaitQueue::new(),
+            sent: WaitQueue::new(),
+            link: WaitQueue::new(),
+            link_changes: AtomicUsize::new(0),
+        };
+
+        nic.write32(REG_IMC, u32::MAX);
+        nic.write32(REG_CTRL, nic.read32(REG_CTRL) | CTRL_RST);
+        // The manual asks for a microsecond before the registers are accessed again
+        time::busy_wait(Duration::from_micros(10));
+        let deadline = Deadline::after(TIMEOUT);
+        while nic.read32(REG_CTRL) & CTRL_RST != 0 {
+            if deadline.has_passed() {
+                return Err(NetError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        // Resetting may have raised interrupts, which are masked again and cleared
+        nic.write32(REG_IMC, u32::MAX);
+        nic.read32(REG_ICR);
+        nic.write32(REG_CTRL, nic.read32(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        nic.mac = nic.read_mac();
+        let [a, b, c, d, e, f] = nic.mac.0;
+        nic.write32(REG_RAL, u32::from_le_bytes([a, b, c, d]));
+        nic.write32(REG_RAH, u32::from_le_bytes([e, f, 0, 0]) | RAH_AV);
+        for i in 0..MTA_ENTRIES {
+            nic.write32(REG_MTA + 4 * i, 0);
+        }
+
+        let ring_length = (RING_SIZE * DESCRIPTOR_SIZE) as u32;
+        let rx = nic.rx.lock().address();
+        nic.write32(REG_RDBAL, rx as u32);
+        nic.write32(REG_RDBAH, (rx >> 32) as u32);
+        nic.write32(REG_RDLEN, ring_length);
+        nic.write32(REG_RDH, 0);
+        // Every descriptor but the last goes to the controller, which stops short of the tail
+        nic.write32(REG_RDT, RING_SIZE as u32 - 1);
+        // 2KiB buffers are the default, left as 0
+        nic.write32(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        let tx = nic.tx.lock().address();
+        nic.write32(REG_TDBAL, tx as u32);
+        nic.write32(REG_TDBAH, (tx >> 32) as u32);
+        nic.write32(REG_TDLEN, ring_length);
+        nic.write32(REG_TDH, 0);
+        nic.write32(REG_TDT, 0);
+        nic.write32(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        nic.write32(REG_TIPG, TIPG);
+        Ok(nic)
+    }
+
+    /// Reads `word` of the EEPROM
+    fn read_eeprom(&self, word: u8) -> Option<u16> {
+        self.write32(REG_EERD, u32::from(word) << EERD_ADDRESS_SHIFT | EERD_START);
+        let deadline = Deadline::after(Duration::from_millis(10));
+        loop {
+            let value = self.read32(REG_EERD);
+            if value & EERD_DONE != 0 {
+                return Some((value >> EERD_DATA_SHIFT) as u16);
+            }
+            if deadline.has_passed() {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// The address in the first three words of the EEPROM, or else the one the controller
+    /// loaded in the first receive address at reset
+    fn read_mac(&self) -> MacAddress {
+        let mut mac = [0; 6];
+        let words = (0..3).map(|word| self.read_eeprom(word));
+        for (bytes, word) in mac.chunks_exact_mut(2).zip(words) {
+            match word {
+                Some(word) => bytes.copy_from_slice(&word.to_le_bytes()),
+                None => {
+                    let low = self.read32(REG_RAL).to_le_bytes();
+                    let high = self.read32(REG_RAH).to_le_bytes();
+                    return MacAddress([low[0], low[1], low[2], low[3], high[0], high[1]]);
+                }
+            }
+        }
+        MacAddress(mac)
+    }
+
+    /// Waits for the PHY to complete the MDI command in progress, returning the MDIC register
+    fn wait_mdic(&self) -> Result<u32, NetError> {
+        let deadline = Deadline::after(Duration::from_millis(10));
+        loop {
+            let value = self.read32(REG_MDIC);
+            if value & MDIC_ERROR != 0 {
+                return Err(NetError::Device);
+            }
+            if value & MDIC_READY != 0 {
+                return Ok(value);
+            }
+            if deadline.has_passed() {
+                return Err(NetError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn read_phy(&self, register: u32) -> Result<u16, NetError> {
+        self.write32(REG_MDIC, register << 16 | MDIC_PHY | MDIC_OP_READ);
+        Ok(self.wait_mdic()? as u16)
+    }
+
+    fn write_phy(&self, register: u32, value: u16) -> Result<(), NetError> {
+        self.write32(
+            REG_MDIC,
+            u32::from(value) | register << 16 | MDIC_PHY | MDIC_OP_WRITE,
+        );
+        self.wait_mdic().map(|_| ())
+    }
+
+    /// Has the PHY send frames straight back to the controller instead of out on the wire,
+    /// or stop doing so
+    pub fn set_loopback(&self, enabled: bool) -> Result<(), NetError> {
+        let control = self.read_phy(PHY_CONTROL)? & !(PHY_AN_RESTART | PHY_LOOPBACK);
+        let loopback = if enabled { PHY_LOOPBACK } else { 0 };
+        self.write_phy(PHY_CONTROL, control | loopback)
+    }
+
+    /// Has the PHY negotiate the link again, taking it down until negotiation completes
+    pub fn restart_autonegotiation(&self) -> Result<(), NetError> {
+        let control = self.read_phy(PHY_CONTROL)?;
+        self.write_phy(PHY_CONTROL, control | PHY_AN_ENABLE | PHY_AN_RESTART)
+    }
+
+    /// How many link status changes the controller reported
+    pub fn link_changes(&self) -> usize {
+        self.link_changes.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the link is up or `deadline` has passed, returning whether it is up
+    pub fn wait_for_link(&self, deadline: Deadline) -> bool {
+        self.link
+            .wait_until_deadline(deadline, || self.is_link_up().then_some(()))
+            .is_some()
+    }
+}
+
+impl NetDevice for E1000 {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn is_link_up(&self) -> bool {
+        self.read32(REG_STATUS) & STATUS_LU != 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        if !(ETHERNET_HEADER_SIZE..=MAX_FRAME_SIZE).contains(&frame.len()) {
+            return Err(NetError::BadFrame);
+        }
+        self.sent
+            .wait_until_deadline(Deadline::after(TIMEOUT), || {
+                let mut tx = self.tx.lock();
+                let index = tx.next;
+                // One descriptor stays free, since a full ring would look like an empty one
+                if !tx.is_sent(index) || !tx.is_sent((index + 1) % RING_SIZE) {
+                    return None;
+                }
+
+                let length = frame.len().max(MIN_FRAME_SIZE);
+                let buffer = tx.buffer(index);
+                buffer[..frame.len()].copy_from_slice(frame);
+                buffer[frame.len()..length].fill(0);
+                tx.set_length(index, length);
+                tx.write8(index, DESC_STATUS, 0);
+                tx.write8(index, DESC_CMD, CMD_EOP | CMD_IFCS | CMD_RS);
+
+                // The controller must see the descriptor before the new tail
+                fence(Ordering::Release);
+                tx.next = (index + 1) % RING_SIZE;
+                self.write32(REG_TDT, tx.next as u32);
+                Some(())
+            })
+            .ok_or(NetError::Timeout)
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut rx = self.rx.lock();
+        loop {
+            let index = rx.next;
+            let status = rx.read8(index, DESC_STATUS);
+            if status & STATUS_DD == 0 {
+                return None;
+            }
+            // The rest of the descriptor and the frame must be read after the status
+            fence(Ordering::Acquire);
+
+            // Frames with errors, or too long for a single buffer, are dropped
+            let errors = rx.read8(index, DESC_ERRORS);
+            let frame = (status & STATUS_EOP != 0 && errors == 0).then(|| {
+                let length = rx.length(index).min(BUFFER_SIZE);
+                rx.buffer(index)[..length].to_vec()
+            });
+
+            // The descriptor goes back to the controller, as the new tail
+            rx.write8(index, DESC_STATUS, 0);
+            fence(Ordering::Release);
+            rx.next = (index + 1) % RING_SIZE;
+            self.write32(REG_RDT, index as u32);
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+
+    fn receive_until(&self, deadline: Deadline) -> Option<Vec<u8>> {
+        self.received
+            .wait_until_deadline(deadline, || self.receive())
+    }
+}
+
+fn probe(device: &PciDevice) -> bool {
+    let Some(Bar::Memory { address, .. }) = device.bars[0] else {
+        return false;
+    };
+    let Some(registers) = memory::phys_to_virt(address) else {
+        return false;
+    };
+    device.enable();
+    device.set_command(device.command() & !pci::COMMAND_INTX_DISABLE);
+
+    let line = device.interrupt_line;
+    match irq::register(line, interrupt) {
+        Ok(()) | Err(irq::IrqError::AlreadyRegistered) => {}
+        Err(err) => log::warn!("e1000: can't use IRQ {line}: {err:?}"),
+    }
+    let nic = match E1000::new(registers) {
+        Ok(nic) => Arc::new(nic),
+        Err(err) => {
+            log::warn!("e1000: can't use {}: {err:?}", device.address);
+            return false;
+        }
+    };
+    NICS.lock().push(nic.clone());
+    // Only once the interrupt handler knows the controller, which could otherwise keep the
+    // line raised
+    nic.write32(REG_IMS, INT_LSC | INT_RECEIVED | INT_TXDW);
+
+    let name = super::register(nic);
+    log::info!("e1000: {} is {name}", device.address);
+    true
+}
@@ -0,0 +1,164 @@
+//! The kernel panic path.
+//!
+//! Panics can happen while any lock is held, including the console locks, so nothing here
+//! takes a lock: the panic screen is drawn straight into the VGA buffer and the report is
+//! written straight to COM1.
+
+use core::{
+    fmt::Write,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use x86_64::{
+    instructions::{interrupts, port::Port, tables::lidt},
+    registers::model_specific::Msr,
+    structures::DescriptorTablePointer,
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    backtrace::Backtrace,
+    exit_qemu, memory, serial,
+    vga_buffer::{Color, VgaWriter},
+    QemuExitCode,
+};
+
+/// What the kernel does once a panic has been reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Halt forever, leaving the panic screen up
+    Halt,
+    /// Reset the machine
+    Reboot,
+    /// Exit QEMU through the `isa-debug-exit` device
+    ExitQemu(QemuExitCode),
+}
+
+impl PanicPolicy {
+    const fn to_u8(self) -> u8 {
+        match self {
+            PanicPolicy::Halt => 0,
+            PanicPolicy::Reboot => 1,
+            PanicPolicy::ExitQemu(QemuExitCode::Success) => 2,
+            PanicPolicy::ExitQemu(QemuExitCode::Failed) => 3,
+        }
+    }
+
+    const fn from_u8(policy: u8) -> Self {
+        match policy {
+            1 => PanicPolicy::Reboot,
+            2 => PanicPolicy::ExitQemu(QemuExitCode::Success),
+            3 => PanicPolicy::ExitQemu(QemuExitCode::Failed),
+            _ => PanicPolicy::Halt,
+        }
+    }
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Halt.to_u8());
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// The local APIC register used to send inter-processor interrupts
+const APIC_ICR_LOW: u64 = 0x300;
+const APIC_ICR_HIGH: u64 = 0x310;
+const IA32_APIC_BASE: u32 = 0x1b;
+
+pub fn set_policy(policy: PanicPolicy) {
+    POLICY.store(policy.to_u8(), Ordering::Relaxed);
+}
+
+pub fn policy() -> PanicPolicy {
+    PanicPolicy::from_u8(POLICY.load(Ordering::Relaxed))
+}
+
+/// Whether the kernel has panicked
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+/// Reports a panic on the screen and the serial port, then applies the panic policy
+pub fn handle_panic(info: &PanicInfo) -> ! {
+    interrupts::disable();
+
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        // Panicked while reporting a panic: keep it short, the screen may be what's broken
+        serial::_print_unlocked(format_args!("\nnested panic: {}\n", info));
+        apply(policy());
+    }
+
+    freeze_other_cpus();
+
+    let backtrace = Backtrace::capture();
+    serial::_print_unlocked(format_args!(
+        "\nKERNEL PANIC: {}\nbacktrace:\n{}",
+        info, backtrace
+    ));
+    draw_panic_screen(info, &backtrace);
+
+    apply(policy())
+}
+
+fn draw_panic_screen(info: &PanicInfo, backtrace: &Backtrace) {
+    // Safety: other CPUs are frozen and interrupts are disabled, so nothing else can write
+    // to the screen
+    let mut vga = unsafe { VgaWriter::steal() };
+    vga.disable_cursor();
+    vga.set_colors(Color::White, Color::Red);
+    vga.clear();
+
+    let _ = writeln!(vga, "KERNEL PANIC\n\n{}\n\nbacktrace:\n{}", info, backtrace);
+}
+
+/// Stops every other CPU by sending them an NMI, which they handle by halting once they see
+/// the kernel is panicking
+fn freeze_other_cpus() {
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    // Bit 11 is the global enable of the local APIC
+    if apic_base & (1 << 11) == 0 {
+        return;
+    }
+    let Some(apic) = memory::phys_to_virt(PhysAddr::new(apic_base & !0xfff)) else {
+        return;
+    };
+
+    // Destination shorthand "all excluding self", level assert, NMI delivery mode
+    let command: u32 = (0b11 << 18) | (1 << 14) | (0b100 << 8);
+    unsafe {
+        (apic + APIC_ICR_HIGH).as_mut_ptr::<u32>().write_volatile(0);
+        (apic + APIC_ICR_LOW)
+            .as_mut_ptr::<u32>()
+            .write_volatile(command);
+    }
+}
+
+fn apply(policy: PanicPolicy) -> ! {
+    match policy {
+        PanicPolicy::Halt => {}
+        PanicPolicy::Reboot => reboot(),
+        PanicPolicy::ExitQemu(code) => exit_qemu(code),
+    }
+    halt_forever()
+}
+
+/// Halts with interrupts disabled, so only an NMI can wake the CPU up again
+pub fn halt_forever() -> ! {
+    loop {
+        interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+fn reboot() {
+    // Pulse the CPU reset line through the keyboard controller
+    unsafe { Port::<u8>::new(0x64).write(0xfe) };
+
+    // If that didn't work, triple fault by raising an exception without an IDT
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        lidt(&empty);
+        interrupts::int3();
+    }
+}
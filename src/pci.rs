@@ -0,0 +1,627 @@
+//! PCI bus enumeration and the registry of PCI drivers.
+//!
+//! The configuration space is accessed through the memory mapped ECAM region when the ACPI
+//! `MCFG` table reports one, and through the legacy `0xcf8`/`0xcfc` ports otherwise. `init`
+//! scans every bus once, after which drivers registered with `register_driver` are probed
+//! for the devices matching their IDs.
+
+use alloc::vec::Vec;
+use core::{fmt, ptr};
+
+use spin::Once;
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+use crate::{
+    acpi, memory,
+    sync::{IrqSpinLock, LockClass, Mutex, RawIrqSpinLock},
+};
+
+pub mod capability;
+
+pub use capability::{Capability, Msi, MsiX};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// Offsets into the configuration space header
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION: u16 = 0x08;
+const PROG_IF: u16 = 0x09;
+const SUBCLASS: u16 = 0x0a;
+const CLASS: u16 = 0x0b;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const SECONDARY_BUS: u16 = 0x19;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3c;
+const INTERRUPT_PIN: u16 = 0x3d;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_TYPE_MULTIFUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// Offset of the first allocation entry in the ACPI `MCFG` table
+const MCFG_ENTRIES_OFFSET: usize = 44;
+const MCFG_ENTRY_SIZE: usize = 16;
+
+/// How the configuration space is reached
+enum Access {
+    Legacy,
+    /// Memory mapped configuration space for the buses `start_bus..=end_bus` of segment 0
+    Ecam {
+        base: VirtAddr,
+        start_bus: u8,
+        end_bus: u8,
+    },
+}
+
+static ACCESS: Once<Access> = Once::new();
+
+/// Serializes the two-step accesses through the legacy ports, which interrupt handlers may
+/// use as well
+static LEGACY_PORTS: IrqSpinLock<(Port<u32>, Port<u32>)> = IrqSpinLock::from_raw(
+    RawIrqSpinLock::with_class(LockClass::new("PCI_CONFIG")),
+    (Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)),
+);
+
+/// Finds the ECAM region of segment 0 in the ACPI `MCFG` table
+fn ecam_from_acpi() -> Option<Access> {
+    let table = acpi::find_table(b"MCFG")?;
+    let entries =
+        (table.header.length as usize).saturating_sub(MCFG_ENTRIES_OFFSET) / MCFG_ENTRY_SIZE;
+    (0..entries)
+        .map(|i| MCFG_ENTRIES_OFFSET + i * MCFG_ENTRY_SIZE)
+        .find(|&entry| table.read::<u16>(entry + 8) == 0)
+        .and_then(|entry| {
+            let start_bus: u8 = table.read(entry + 10);
+            // The base address is the one of bus 0, even if the region starts further
+            let base = memory::phys_to_virt(PhysAddr::new(table.read(entry)))?;
+            Some(Access::Ecam {
+                base,
+                start_bus,
+                end_bus: table.read(entry + 11),
+            })
+        })
+}
+
+/// The location of a function on the PCI bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        assert!(device < 32 && function < 8);
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Reads the aligned dword of the configuration space containing `offset`
+    pub fn read_u32(self, offset: u16) -> u32 {
+        let offset = offset & !0b11;
+        match ACCESS.get() {
+            Some(&Access::Ecam {
+                base,
+                start_bus,
+                end_bus,
+            }) if (start_bus..=end_bus).contains(&self.bus) => unsafe {
+                ptr::read_volatile(self.ecam_address(base, offset).as_ptr())
+            },
+            _ => {
+                if offset >= 0x100 {
+                    // Extended configuration space is only reachable through ECAM
+                    return u32::MAX;
+                }
+                let mut ports = LEGACY_PORTS.lock();
+                unsafe {
+                    ports.0.write(self.legacy_address(offset));
+                    ports.1.read()
+                }
+            }
+        }
+    }
+
+    /// Writes the aligned dword of the configuration space containing `offset`
+    pub fn write_u32(self, offset: u16, value: u32) {
+        let offset = offset & !0b11;
+        match ACCESS.get() {
+            Some(&Access::Ecam {
+                base,
+                start_bus,
+                end_bus,
+            }) if (start_bus..=end_bus).contains(&self.bus) => unsafe {
+                ptr::write_volatile(self.ecam_address(base, offset).as_mut_ptr(), value)
+            },
+            _ => {
+                if offset >= 0x100 {
+                    return;
+                }
+                let mut ports = LEGACY_PORTS.lock();
+                unsafe {
+                    ports.0.write(self.legacy_address(offset));
+                    ports.1.write(value);
+                }
+            }
+        }
+    }
+
+    pub fn read_u16(self, offset: u16) -> u16 {
+        (self.read_u32(offset) >> ((offset & 0b10) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u16) -> u8 {
+        (self.read_u32(offset) >> ((offset & 0b11) * 8)) as u8
+    }
+
+    /// Writes a word, leaving the other half of its dword unchanged
+    pub fn write_u16(self, offset: u16, value: u16) {
+        let shift = (offset & 0b10) * 8;
+        let dword = self.read_u32(offset) & !(0xffff << shift);
+        self.write_u32(offset, dword | u32::from(value) << shift);
+    }
+
+    fn legacy_address(self, offset: u16) -> u32 {
+        1 << 31
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset)
+    }
+
+    fn ecam_address(self, base: VirtAddr, offset: u16) -> VirtAddr {
+        base + (u64::from(self.bus) << 20
+            | u64::from(self.device) << 15
+            | u64::from(self.function) << 12
+            | u64::from(offset))
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A Base Address Register, decoded and sized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: PhysAddr,
+        size: u64,
+        prefetchable: bool,
+        /// Whether the BAR takes the next slot as well for the upper half of the address
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+impl Bar {
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptPin {
+    A,
+    B,
+    C,
+    D,
+}
+
+/// A function found on the PCI bus
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// The layout of the header, without the multifunction bit: 0 for devices and 1 for
+    /// PCI-to-PCI bridges
+    pub header_type: u8,
+    /// The legacy IRQ line the firmware routed `interrupt_pin` to
+    pub interrupt_line: u8,
+    pub interrupt_pin: Option<InterruptPin>,
+    pub bars: [Option<Bar>; 6],
+    pub msi: Option<Msi>,
+    pub msix: Option<MsiX>,
+}
+
+impl PciDevice {
+    /// Reads the header of the function at `address`, or `None` if there is none
+    pub fn probe(address: PciAddress) -> Option<Self> {
+        let vendor_id = address.read_u16(VENDOR_ID);
+        if vendor_id == 0xffff {
+            return None;
+        }
+
+        let header_type = address.read_u8(HEADER_TYPE) & !HEADER_TYPE_MULTIFUNCTION;
+        let mut device = Self {
+            address,
+            vendor_id,
+            device_id: address.read_u16(DEVICE_ID),
+            class: address.read_u8(CLASS),
+            subclass: address.read_u8(SUBCLASS),
+            prog_if: address.read_u8(PROG_IF),
+            revision: address.read_u8(REVISION),
+            header_type,
+            interrupt_line: address.read_u8(INTERRUPT_LINE),
+            interrupt_pin: match address.read_u8(INTERRUPT_PIN) {
+                1 => Some(InterruptPin::A),
+                2 => Some(InterruptPin::B),
+                3 => Some(InterruptPin::C),
+                4 => Some(InterruptPin::D),
+                _ => None,
+            },
+            bars: [None; 6],
+            msi: None,
+            msix: None,
+        };
+        device.bars = device.read_bars();
+        device.msi = device.capabilities().find_map(|c| Msi::parse(address, c));
+        device.msix = device.capabilities().find_map(|c| MsiX::parse(address, c));
+        Some(device)
+    }
+
+    /// Decodes and sizes the BARs, with decoding disabled while they are probed
+    fn read_bars(&self) -> [Option<Bar>; 6] {
+        let mut bars = [None; 6];
+        let count = match self.header_type {
+            0 => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+
+        let command = self.command();
+        self.set_command(command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+        let mut i = 0;
+        while i < count {
+            let offset = BAR0 + 4 * i as u16;
+            let (value, mask) = self.size_register(offset);
+            if value & 1 == 1 {
+                let mask = mask & !0b11 & 0xffff;
+                if mask != 0 {
+                    bars[i] = Some(Bar::Io {
+                        port: (value & !0b11) as u16,
+                        size: (!mask + 1) as u16,
+                    });
+                }
+                i += 1;
+                continue;
+            }
+
+            let is_64bit = (value >> 1) & 0b11 == 0b10;
+            let (mut address, mut mask) = (u64::from(value & !0xf), u64::from(mask & !0xf));
+            if is_64bit && i + 1 < count {
+                let (high, high_mask) = self.size_register(offset + 4);
+                address |= u64::from(high) << 32;
+                mask |= u64::from(high_mask) << 32;
+            } else if mask != 0 {
+                // The size is computed on 64 bits either way
+                mask |= 0xffff_ffff << 32;
+            }
+            if mask != 0 {
+                bars[i] = Some(Bar::Memory {
+                    address: PhysAddr::new(address),
+                    size: !mask + 1,
+                    prefetchable: value & (1 << 3) != 0,
+                    is_64bit,
+                });
+            }
+            i += if is_64bit { 2 } else { 1 };
+        }
+
+        self.set_command(command);
+        bars
+    }
+
+    /// Returns the value of a BAR register, and the mask of its writable bits
+    fn size_register(&self, offset: u16) -> (u32, u32) {
+        let value = self.address.read_u32(offset);
+        self.address.write_u32(offset, u32::MAX);
+        let mask = self.address.read_u32(offset);
+        self.address.write_u32(offset, value);
+        (value, mask)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.address.read_u16(COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        self.address.write_u16(COMMAND, command)
+    }
+
+    /// Enables decoding of the BARs and lets the device master the bus, for DMA
+    pub fn enable(&self) {
+        self.set_command(
+            self.command() | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+
+    /// The secondary bus of a PCI-to-PCI bridge
+    pub fn secondary_bus(&self) -> Option<u8> {
+        (self.header_type == HEADER_TYPE_BRIDGE).then(|| self.address.read_u8(SECONDARY_BUS))
+    }
+
+    /// The capabilities list of the function
+    pub fn capabilities(&self) -> impl Iterator<Item = Capability> {
+        let address = self.address;
+        let first = if address.read_u16(STATUS) & STATUS_CAPABILITIES != 0 {
+            address.read_u8(CAPABILITIES_POINTER)
+        } else {
+            0
+        };
+        capability::walk(address, first)
+    }
+
+    /// A short description of the class, as shown by `lspci`
+    pub fn description(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE interface",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "Non-Volatile memory controller",
+            (0x01, _) => "Mass storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "Network controller",
+            (0x03, 0x00) => "VGA compatible controller",
+            (0x03, _) => "Display controller",
+            (0x04, _) => "Multimedia controller",
+            (0x05, _) => "Memory controller",
+            (0x06, 0x00) => "Host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "Bridge",
+            (0x0c, 0x03) => "USB controller",
+            (0x0c, 0x05) => "SMBus",
+            (0x0c, _) => "Serial bus controller",
+            (0xff, _) => "Unassigned class",
+            _ => "Unclassified device",
+        }
+    }
+}
+
+/// One line in the format of `lspci -nn`, such as
+/// `00:02.0 VGA compatible controller [0300]: [1234:1111] (rev 02)`
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: [{:04x}:{:04x}] (rev {:02x})",
+            self.address,
+            self.description(),
+            self.class,
+            self.subclass,
+            self.vendor_id,
+            self.device_id,
+            self.revision
+        )
+    }
+}
+
+/// A pattern matching devices, by vendor and device ID or by class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId {
+    vendor_id: Option<u16>,
+    device_id: Option<u16>,
+    class: Option<u8>,
+    subclass: Option<u8>,
+    prog_if: Option<u8>,
+}
+
+impl DeviceId {
+    const ANY: Self = Self {
+        vendor_id: None,
+        device_id: None,
+        class: None,
+        subclass: None,
+        prog_if: None,
+    };
+
+    /// Matches a single device
+    pub const fn new(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            ..Self::ANY
+        }
+    }
+
+    /// Matches every device of a class, such as `(0x01, 0x06)` for SATA controllers
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        Self {
+            class: Some(class),
+            subclass: Some(subclass),
+            ..Self::ANY
+        }
+    }
+
+    /// Narrows a class match down to a programming interface, such as `0x01` for AHCI
+    pub const fn prog_if(self, prog_if: u8) -> Self {
+        Self {
+            prog_if: Some(prog_if),
+            ..self
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        fn matches<T: PartialEq>(pattern: Option<T>, value: T) -> bool {
+            pattern.is_none_or(|pattern| pattern == value)
+        }
+        matches(self.vendor_id, device.vendor_id)
+            && matches(self.device_id, device.device_id)
+            && matches(self.class, device.class)
+            && matches(self.subclass, device.subclass)
+            && matches(self.prog_if, device.prog_if)
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub ids: &'static [DeviceId],
+    /// Called for each unclaimed device matching `ids`, returning whether the driver took
+    /// the device
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+struct Entry {
+    device: PciDevice,
+    driver: Option<&'static Driver>,
+}
+
+struct Registry {
+    devices: Vec<Entry>,
+    drivers: Vec<&'static Driver>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::with_class(
+    LockClass::new("PCI_REGISTRY"),
+    Registry {
+        devices: Vec::new(),
+        drivers: Vec::new(),
+    },
+);
+
+/// Scans every bus, then probes the drivers registered so far.
+///
+/// Needs the heap, and the memory module for ECAM
+pub fn init() {
+    let access = ACCESS.call_once(|| ecam_from_acpi().unwrap_or(Access::Legacy));
+    if let Access::Ecam { base, .. } = access {
+        log::debug!("pci: ECAM at {:?}", base);
+    }
+
+    let devices = scan();
+    for device in &devices {
+        log::debug!("pci: {device}");
+    }
+    REGISTRY.lock().devices = devices
+        .into_iter()
+        .map(|device| Entry {
+            device,
+            driver: None,
+        })
+        .collect();
+
+    let drivers = REGISTRY.lock().drivers.clone();
+    for driver in drivers {
+        probe(driver);
+    }
+}
+
+/// Enumerates every function, by brute force over all the buses
+fn scan() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let Some(first) = PciDevice::probe(PciAddress::new(bus, device, 0)) else {
+                continue;
+            };
+            let multifunction = first.address.read_u8(HEADER_TYPE) & HEADER_TYPE_MULTIFUNCTION != 0;
+            devices.push(first);
+            if multifunction {
+                devices.extend((1..8).filter_map(|function| {
+                    PciDevice::probe(PciAddress::new(bus, device, function))
+                }));
+            }
+        }
+    }
+    devices
+}
+
+/// Probes `driver` for every unclaimed device it matches
+fn probe(driver: &'static Driver) {
+    let candidates: Vec<PciDevice> = REGISTRY
+        .lock()
+        .devices
+        .iter()
+        .filter(|entry| entry.driver.is_none())
+        .filter(|entry| driver.ids.iter().any(|id| id.matches(&entry.device)))
+        .map(|entry| entry.device.clone())
+        .collect();
+
+    // The lock isn't held while probing, so drivers can look at the other devices
+    for device in candidates {
+        if (driver.probe)(&device) {
+            log::info!("pci: {} bound to {}", driver.name, device.address);
+            let mut registry = REGISTRY.lock();
+            if let Some(entry) = registry
+                .devices
+                .iter_mut()
+                .find(|entry| entry.device.address == device.address)
+            {
+                entry.driver = Some(driver);
+            }
+        }
+    }
+}
+
+/// Registers `driver`, probing it right away for the devices found by `init`
+pub fn register_driver(driver: &'static Driver) {
+    REGISTRY.lock().drivers.push(driver);
+    probe(driver);
+}
+
+/// Every device found by `init`, ordered by address
+pub fn devices() -> Vec<PciDevice> {
+    REGISTRY
+        .lock()
+        .devices
+        .iter()
+        .map(|entry| entry.device.clone())
+        .collect()
+}
+
+/// The first device matching `id`
+pub fn find(id: DeviceId) -> Option<PciDevice> {
+    REGISTRY
+        .lock()
+        .devices
+        .iter()
+        .find(|entry| id.matches(&entry.device))
+        .map(|entry| entry.device.clone())
+}
+
+/// The name of the driver bound to the device at `address`
+pub fn driver_of(address: PciAddress) -> Option<&'static str> {
+    REGISTRY
+        .lock()
+        .devices
+        .iter()
+        .find(|entry| entry.device.address == address)
+        .and_then(|entry| entry.driver)
+        .map(|driver| driver.name)
+}
+
+#[test_case]
+fn test_host_bridge_through_legacy_ports() {
+    // The i440FX host bridge QEMU emulates by default
+    let host_bridge = PciDevice::probe(PciAddress::new(0, 0, 0)).expect("no host bridge");
+    assert_eq!((host_bridge.class, host_bridge.subclass), (0x06, 0x00));
+    assert_eq!(host_bridge.description(), "Host bridge");
+    assert!(PciDevice::probe(PciAddress::new(0, 31, 7)).is_none());
+}
@@ -0,0 +1,102 @@
+//! The capabilities list of PCI functions, and the MSI and MSI-X capabilities
+
+use super::PciAddress;
+
+pub const ID_POWER_MANAGEMENT: u8 = 0x01;
+pub const ID_MSI: u8 = 0x05;
+pub const ID_VENDOR_SPECIFIC: u8 = 0x09;
+pub const ID_PCI_EXPRESS: u8 = 0x10;
+pub const ID_MSIX: u8 = 0x11;
+
+/// Bounds the walk, in case the list loops
+const MAX_CAPABILITIES: usize = 48;
+
+/// An entry of the capabilities list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset of the capability in the configuration space
+    pub offset: u8,
+}
+
+/// Iterates over the list starting at offset `first`, where 0 ends the list
+pub(super) fn walk(address: PciAddress, first: u8) -> impl Iterator<Item = Capability> {
+    let mut next = first & !0b11;
+    core::iter::from_fn(move || {
+        if next == 0 {
+            return None;
+        }
+        let offset = next;
+        let header = address.read_u16(offset.into());
+        next = (header >> 8) as u8 & !0b11;
+        Some(Capability {
+            id: header as u8,
+            offset,
+        })
+    })
+    .take(MAX_CAPABILITIES)
+}
+
+/// Message Signaled Interrupts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msi {
+    pub offset: u8,
+    /// Whether the message address can be above 4GiB
+    pub is_64bit: bool,
+    /// How many vectors the function can use, a power of two up to 32
+    pub max_vectors: u8,
+    pub per_vector_masking: bool,
+    pub enabled: bool,
+}
+
+impl Msi {
+    pub(super) fn parse(address: PciAddress, capability: Capability) -> Option<Self> {
+        if capability.id != ID_MSI {
+            return None;
+        }
+        let control = address.read_u16(u16::from(capability.offset) + 2);
+        Some(Self {
+            offset: capability.offset,
+            is_64bit: control & (1 << 7) != 0,
+            max_vectors: 1 << ((control >> 1) & 0b111).min(5),
+            per_vector_masking: control & (1 << 8) != 0,
+            enabled: control & 1 != 0,
+        })
+    }
+}
+
+/// Extended Message Signaled Interrupts, configured through a table in one of the BARs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiX {
+    pub offset: u8,
+    /// The number of entries of the table
+    pub table_size: u16,
+    /// The BAR holding the table, and the offset of the table in it
+    pub table_bar: u8,
+    pub table_offset: u32,
+    /// The BAR holding the Pending Bit Array, and the offset of the array in it
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+    pub enabled: bool,
+}
+
+impl MsiX {
+    pub(super) fn parse(address: PciAddress, capability: Capability) -> Option<Self> {
+        if capability.id != ID_MSIX {
+            return None;
+        }
+        let offset = u16::from(capability.offset);
+        let control = address.read_u16(offset + 2);
+        let table = address.read_u32(offset + 4);
+        let pba = address.read_u32(offset + 8);
+        Some(Self {
+            offset: capability.offset,
+            table_size: (control & 0x7ff) + 1,
+            table_bar: (table & 0b111) as u8,
+            table_offset: table & !0b111,
+            pba_bar: (pba & 0b111) as u8,
+            pba_offset: pba & !0b111,
+            enabled: control & (1 << 15) != 0,
+        })
+    }
+}
@@ -0,0 +1,434 @@
+use core::{
+    fmt,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{
+    acpi,
+    irq::{self, IrqReturn},
+    time::Instant,
+};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+/// Where QEMU and most firmware keep the century, if the FADT doesn't say otherwise
+const DEFAULT_REG_CENTURY: u8 = 0x32;
+
+/// Offset of the century register index in the ACPI FADT
+const FADT_CENTURY_OFFSET: usize = 108;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_UPDATE_ENDED_INTERRUPT: u8 = 1 << 4;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_C_UPDATE_ENDED: u8 = 1 << 4;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+
+const HOUR_PM: u8 = 1 << 7;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Guards the CMOS index register, since every access is a two-step process
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos::new());
+
+/// Nanoseconds between the UNIX epoch and `Instant::ZERO`
+static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+/// Whether `BOOT_UNIX_NANOS` has been aligned to an RTC update
+static SYNCED: AtomicBool = AtomicBool::new(false);
+
+static UPDATE_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+struct Cmos {
+    address: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    const fn new() -> Self {
+        Self {
+            address: Port::new(CMOS_ADDRESS),
+            data: Port::new(CMOS_DATA),
+        }
+    }
+
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.address.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.address.write(register);
+            self.data.write(value);
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+
+    /// Reads the raw clock registers once no update is in progress
+    fn read_raw(&mut self, century_register: u8) -> [u8; 7] {
+        while self.update_in_progress() {
+            core::hint::spin_loop();
+        }
+        [
+            self.read(REG_SECONDS),
+            self.read(REG_MINUTES),
+            self.read(REG_HOURS),
+            self.read(REG_DAY),
+            self.read(REG_MONTH),
+            self.read(REG_YEAR),
+            self.read(century_register),
+        ]
+    }
+
+    /// Reads the current date and time.
+    ///
+    /// The registers are read until two consecutive reads agree, so an update happening
+    /// halfway through can't produce an inconsistent result
+    fn read_date_time(&mut self) -> DateTime {
+        let century_register = century_register();
+        let mut raw = self.read_raw(century_register);
+        loop {
+            let again = self.read_raw(century_register);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        let [second, minute, hour, day, month, year, century] = raw;
+
+        let status_b = self.read(REG_STATUS_B);
+        let decode = |value: u8| {
+            if status_b & STATUS_B_BINARY != 0 {
+                value
+            } else {
+                (value & 0x0f) + (value >> 4) * 10
+            }
+        };
+
+        let hour = if status_b & STATUS_B_24_HOUR != 0 {
+            decode(hour)
+        } else {
+            // 12-hour mode counts 12, 1, 2, ..., 11 and flags PM in the top bit
+            let pm = hour & HOUR_PM != 0;
+            let hour = decode(hour & !HOUR_PM) % 12;
+            if pm {
+                hour + 12
+            } else {
+                hour
+            }
+        };
+
+        let century = match decode(century) {
+            // The century register isn't implemented everywhere
+            0 => 20,
+            century => century,
+        };
+
+        DateTime {
+            year: u16::from(century) * 100 + u16::from(decode(year)),
+            month: decode(month),
+            day: decode(day),
+            hour,
+            minute: decode(minute),
+            second: decode(second),
+        }
+    }
+}
+
+/// The CMOS register holding the century, as reported by the FADT
+fn century_register() -> u8 {
+    static CENTURY_REGISTER: Once<u8> = Once::new();
+
+    *CENTURY_REGISTER.call_once(|| {
+        acpi::find_table(b"FACP")
+            .filter(|fadt| fadt.header.length as usize > FADT_CENTURY_OFFSET)
+            .map(|fadt| fadt.read::<u8>(FADT_CENTURY_OFFSET))
+            .filter(|&register| register != 0)
+            .unwrap_or(DEFAULT_REG_CENTURY)
+    })
+}
+
+/// Reads the current date and time from the CMOS clock
+pub fn read() -> DateTime {
+    interrupts::without_interrupts(|| CMOS.lock().read_date_time())
+}
+
+/// Anchors the wall clock to the current RTC time
+fn sync_boot_time() {
+    let now = Instant::now();
+    let unix_nanos = read().to_unix_timestamp() * NANOS_PER_SEC;
+    BOOT_UNIX_NANOS.store(
+        unix_nanos.saturating_sub(now.since_boot().as_nanos() as u64),
+        Ordering::Relaxed,
+    );
+}
+
+/// Reads the RTC to establish the wall clock, and enables the update-ended interrupt
+/// (and the periodic interrupt, if `periodic_rate` is given) on IRQ8.
+///
+/// `periodic_rate` is the RTC rate selector: the periodic interrupt fires at
+/// `32768 >> (rate - 1)` Hz, with `rate` between 3 and 15.
+///
+/// Since the RTC only has a resolution of one second, the wall clock is re-anchored at
+/// the first update-ended interrupt, which marks the exact start of a second
+pub fn init(periodic_rate: Option<u8>) {
+    sync_boot_time();
+
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+
+        if let Some(rate) = periodic_rate {
+            let rate = rate.clamp(3, 15);
+            let status_a = cmos.read(REG_STATUS_A);
+            cmos.write(REG_STATUS_A, (status_a & 0xf0) | rate);
+        }
+
+        let mut status_b = cmos.read(REG_STATUS_B) | STATUS_B_UPDATE_ENDED_INTERRUPT;
+        if periodic_rate.is_some() {
+            status_b |= STATUS_B_PERIODIC_INTERRUPT;
+        }
+        cmos.write(REG_STATUS_B, status_b);
+
+        // Acknowledge anything pending, otherwise no further interrupt will be raised
+        cmos.read(REG_STATUS_C);
+    });
+
+    irq::register(irq::RTC_IRQ, rtc_interrupt).expect("failed to register the RTC interrupt");
+}
+
+fn rtc_interrupt() -> IrqReturn {
+    let status_c = CMOS.lock().read(REG_STATUS_C);
+
+    if status_c & STATUS_C_UPDATE_ENDED != 0 {
+        UPDATE_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        if !SYNCED.swap(true, Ordering::Relaxed) {
+            sync_boot_time();
+        }
+    }
+    if status_c & STATUS_C_PERIODIC != 0 {
+        PERIODIC_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    IrqReturn::Handled
+}
+
+/// Number of update-ended interrupts received, which happen once per second
+pub fn update_interrupts() -> u64 {
+    UPDATE_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Number of periodic interrupts received
+pub fn periodic_interrupts() -> u64 {
+    PERIODIC_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// A calendar date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the UNIX epoch, 1970-01-01T00:00:00Z
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(
+            i64::from(self.year),
+            u32::from(self.month),
+            u32::from(self.day),
+        );
+        let seconds =
+            u64::from(self.hour) * 3600 + u64::from(self.minute) * 60 + u64::from(self.second);
+        (days.max(0) as u64) * SECONDS_PER_DAY + seconds
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let (year, month, day) = civil_from_days((timestamp / SECONDS_PER_DAY) as i64);
+        let seconds = timestamp % SECONDS_PER_DAY;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Days since 1970-01-01 of the given proleptic Gregorian date.
+///
+/// From http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The proleptic Gregorian date (year, month, day) of the given number of days since 1970-01-01.
+///
+/// From http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// A point in wall-clock time, measured from the UNIX epoch.
+///
+/// Unlike `time::Instant` this isn't guaranteed to be monotonic, since the wall clock may
+/// be re-anchored to the RTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime {
+    unix_nanos: u64,
+}
+
+impl SystemTime {
+    pub const UNIX_EPOCH: Self = Self { unix_nanos: 0 };
+
+    /// The current wall-clock time, combining the RTC with the monotonic clock
+    pub fn now() -> Self {
+        Self::from_instant(Instant::now())
+    }
+
+    /// The wall-clock time corresponding to a monotonic clock reading
+    pub fn from_instant(instant: Instant) -> Self {
+        Self {
+            unix_nanos: BOOT_UNIX_NANOS.load(Ordering::Relaxed)
+                + instant.since_boot().as_nanos() as u64,
+        }
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        Self {
+            unix_nanos: timestamp * NANOS_PER_SEC,
+        }
+    }
+
+    /// Whole seconds since the UNIX epoch
+    pub fn unix_timestamp(&self) -> u64 {
+        self.unix_nanos / NANOS_PER_SEC
+    }
+
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix_timestamp(self.unix_timestamp())
+    }
+
+    /// Returns `Err` with the difference if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, Duration> {
+        match self.unix_nanos.checked_sub(earlier.unix_nanos) {
+            Some(nanos) => Ok(Duration::from_nanos(nanos)),
+            None => Err(Duration::from_nanos(earlier.unix_nanos - self.unix_nanos)),
+        }
+    }
+
+    pub fn elapsed(&self) -> Result<Duration, Duration> {
+        Self::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, rhs: Duration) -> SystemTime {
+        Self {
+            unix_nanos: self.unix_nanos + rhs.as_nanos() as u64,
+        }
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, rhs: Duration) -> SystemTime {
+        Self {
+            unix_nanos: self.unix_nanos - rhs.as_nanos() as u64,
+        }
+    }
+}
+
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.date_time().fmt(f)
+    }
+}
+
+#[test_case]
+fn test_unix_timestamp_round_trip() {
+    let date_time = |year, month, day, hour, minute, second| DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    };
+    let cases = [
+        (0, date_time(1970, 1, 1, 0, 0, 0)),
+        (951_782_400, date_time(2000, 2, 29, 0, 0, 0)),
+        (1_700_000_000, date_time(2023, 11, 14, 22, 13, 20)),
+        (4_107_542_399, date_time(2100, 2, 28, 23, 59, 59)),
+    ];
+    for (timestamp, expected) in cases {
+        assert_eq!(DateTime::from_unix_timestamp(timestamp), expected);
+        assert_eq!(expected.to_unix_timestamp(), timestamp);
+    }
+}
+
+#[test_case]
+fn test_rtc_update_interrupt() {
+    let before = update_interrupts();
+    crate::time::busy_wait(Duration::from_millis(2100));
+    assert!(update_interrupts() > before);
+    assert!(SystemTime::now().date_time().year >= 2024);
+}
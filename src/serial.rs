@@ -1,22 +1,38 @@
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 use uart_16550::SerialPort;
-use x86_64::instructions::interrupts;
 
-static SERIAL1: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
+use crate::sync::{IrqSpinLock, LockClass, RawIrqSpinLock};
+
+static SERIAL1: Lazy<IrqSpinLock<SerialPort>> = Lazy::new(|| {
     let mut s = unsafe { SerialPort::new(0x3f8) };
     s.init();
-    Mutex::new(s)
+    IrqSpinLock::from_raw(RawIrqSpinLock::with_class(LockClass::new("SERIAL1")), s)
 });
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    })
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+/// Prints without taking the `SERIAL1` lock, for reporting errors from places where it may
+/// already be held. The output may interleave with other prints
+#[doc(hidden)]
+pub fn _print_unlocked(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    let mut port = unsafe { SerialPort::new(0x3f8) };
+    let _ = port.write_fmt(args);
+}
+
+/// Releases the `SERIAL1` lock, which a panic may have abandoned while it was held
+///
+/// # Safety
+/// Whoever held the lock must never run again
+pub(crate) unsafe fn force_unlock() {
+    unsafe { SERIAL1.raw().force_release() }
 }
 
 /// Prints to the host through the serial interface.
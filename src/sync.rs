@@ -0,0 +1,25 @@
+//! Synchronization primitives.
+//!
+//! `IrqSpinLock` is for data shared with interrupt handlers: it disables interrupts for as
+//! long as it is held, so an interrupt can never spin on a lock its own CPU already holds.
+//!
+//! The other primitives block instead of spinning, by waiting on a `WaitQueue`. They must not
+//! be used from interrupt context, since an interrupt handler can't block.
+//!
+//! Locks created with a `LockClass` are checked by the `lockdep` validator in debug builds.
+
+pub mod condvar;
+pub mod irq_spin_lock;
+pub mod lockdep;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod wait_queue;
+
+pub use condvar::Condvar;
+pub use irq_spin_lock::{IrqSpinLock, IrqSpinLockGuard, RawIrqSpinLock};
+pub use lockdep::LockClass;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
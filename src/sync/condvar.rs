@@ -0,0 +1,87 @@
+use core::time::Duration;
+
+use super::{Mutex, MutexGuard, WaitQueue};
+use crate::time::Deadline;
+
+/// A condition variable, used together with a `Mutex`.
+///
+/// As with any condition variable, wake-ups may be spurious, so the condition must be
+/// re-checked after `wait` returns (or use `wait_while`)
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Releases the lock, blocks until notified, then re-acquires the lock
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_inner(guard, None).0
+    }
+
+    /// Like `wait`, but also returns `true` if the timeout elapsed before a notification
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, bool) {
+        self.wait_inner(guard, Some(Deadline::after(timeout)))
+    }
+
+    /// Blocks until `condition` returns `false`
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    fn wait_inner<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        deadline: Option<Deadline>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mutex: &'a Mutex<T> = MutexGuard::mutex(&guard);
+        // Observe the generation before unlocking, so a notification sent right after the
+        // unlock isn't missed
+        let generation = self.waiters.generation();
+        drop(guard);
+
+        let notified = self.waiters.wait_for_notification(generation, deadline);
+        (mutex.lock(), !notified)
+    }
+
+    /// Safe to call from interrupt context
+    pub fn notify_one(&self) {
+        self.waiters.notify_one();
+    }
+
+    /// Safe to call from interrupt context
+    pub fn notify_all(&self) {
+        self.waiters.notify_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_condvar_timeout() {
+    let mutex = Mutex::new(false);
+    let condvar = Condvar::new();
+
+    let (guard, timed_out) = condvar.wait_timeout(mutex.lock(), Duration::from_millis(5));
+    assert!(timed_out);
+    assert!(!*guard);
+}
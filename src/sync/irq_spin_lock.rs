@@ -0,0 +1,130 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use super::lockdep::{self, LockClass, LockKind};
+
+/// A spinlock which disables interrupts while it is held, restoring the previous interrupt
+/// state once it is released.
+///
+/// Nested locks must be released in the reverse order they were acquired, otherwise
+/// interrupts may be re-enabled while an inner lock is still held
+pub struct RawIrqSpinLock {
+    locked: AtomicBool,
+    /// Only accessed by the lock holder
+    interrupts_were_enabled: AtomicBool,
+    class: Option<LockClass>,
+}
+
+impl RawIrqSpinLock {
+    /// A lock checked by the lock validator as part of `class`
+    pub const fn with_class(class: LockClass) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            interrupts_were_enabled: AtomicBool::new(false),
+            class: Some(class),
+        }
+    }
+
+    /// Releases the lock whoever holds it, leaving the interrupt state and the lock validator
+    /// alone. For recovering locks held by code a panic abandoned
+    ///
+    /// # Safety
+    /// The holder must never run again
+    pub(crate) unsafe fn force_release(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawIrqSpinLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        locked: AtomicBool::new(false),
+        interrupts_were_enabled: AtomicBool::new(false),
+        class: None,
+    };
+
+    // Interrupts must be restored on the CPU that disabled them
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock(&self) {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if let Some(class) = self.class {
+            lockdep::acquire(class, LockKind::IrqSafe, false);
+        }
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        self.interrupts_were_enabled
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    fn try_lock(&self) -> bool {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.interrupts_were_enabled
+                .store(enabled, Ordering::Relaxed);
+            if let Some(class) = self.class {
+                lockdep::acquire(class, LockKind::IrqSafe, true);
+            }
+            true
+        } else {
+            if enabled {
+                interrupts::enable();
+            }
+            false
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        if let Some(class) = self.class {
+            lockdep::release(class);
+        }
+        let enabled = self.interrupts_were_enabled.load(Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+        if enabled {
+            interrupts::enable();
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+/// A spinlock safe to share with interrupt handlers, see `RawIrqSpinLock`
+pub type IrqSpinLock<T> = lock_api::Mutex<RawIrqSpinLock, T>;
+pub type IrqSpinLockGuard<'a, T> = lock_api::MutexGuard<'a, RawIrqSpinLock, T>;
+
+#[test_case]
+fn test_irq_spin_lock_restores_interrupts() {
+    static LOCK: IrqSpinLock<u32> = IrqSpinLock::new(0);
+
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = LOCK.lock();
+        assert!(!interrupts::are_enabled());
+        *guard += 1;
+        assert!(LOCK.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+
+    interrupts::without_interrupts(|| {
+        drop(LOCK.lock());
+        assert!(!interrupts::are_enabled());
+    });
+    assert_eq!(*LOCK.lock(), 1);
+}
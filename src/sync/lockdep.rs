@@ -0,0 +1,301 @@
+//! A lock validator for debug builds, in the style of Linux's lockdep.
+//!
+//! Locks belonging to a `LockClass` are tracked: every time a lock is acquired while others are
+//! held, the order is recorded in a graph of lock classes. Acquisitions which would close a
+//! cycle in that graph (a possible ABBA deadlock), which re-acquire a class already held, or
+//! which take a sleeping lock from interrupt context are reported on the serial port, along
+//! with a backtrace. The report doesn't take any lock, so it gets out even when the console
+//! locks are the ones involved.
+//!
+//! In release builds the validator does nothing.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{backtrace::Backtrace, irq, serial};
+
+const MAX_CLASSES: usize = 64;
+/// Maximum depth of nested locks
+const MAX_HELD: usize = 32;
+
+/// A class of locks, identified by its name. Locks with the same name share a class, so the
+/// ordering rules learnt from one apply to all of them
+#[derive(Debug, Clone, Copy)]
+pub struct LockClass {
+    name: &'static str,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> Self {
+        Self { name }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockKind {
+    /// Disables interrupts while held, so it may be taken from interrupt context
+    IrqSafe,
+    /// Blocks while contended, so it must never be taken from interrupt context
+    Sleeping,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Violation {
+    InterruptContext,
+    Recursive,
+    /// The lock is acquired while holding the class `held`, but the class `held` was previously
+    /// acquired (perhaps indirectly) while holding it
+    Inversion {
+        held: usize,
+    },
+}
+
+struct Validator {
+    classes: [Option<LockClass>; MAX_CLASSES],
+    class_count: usize,
+    /// Bit `j` of `after[i]` is set if class `j` was acquired while holding class `i`
+    after: [u64; MAX_CLASSES],
+    /// Classes of the locks currently held, in acquisition order.
+    /// There is a single CPU for now, so interrupt handlers simply stack on top
+    held: [usize; MAX_HELD],
+    depth: usize,
+}
+
+impl Validator {
+    const fn new() -> Self {
+        Self {
+            classes: [None; MAX_CLASSES],
+            class_count: 0,
+            after: [0; MAX_CLASSES],
+            held: [0; MAX_HELD],
+            depth: 0,
+        }
+    }
+
+    /// The index of `class`, registering it on first use.
+    /// Returns `None` once the class table is full, leaving the new class untracked
+    fn class_id(&mut self, class: LockClass) -> Option<usize> {
+        let known = self.classes[..self.class_count]
+            .iter()
+            .position(|c| c.is_some_and(|c| c.name == class.name));
+        if known.is_some() || self.class_count == MAX_CLASSES {
+            return known;
+        }
+
+        self.classes[self.class_count] = Some(class);
+        self.class_count += 1;
+        Some(self.class_count - 1)
+    }
+
+    fn held(&self) -> &[usize] {
+        &self.held[..self.depth.min(MAX_HELD)]
+    }
+
+    /// Whether `to` was ever acquired, directly or indirectly, while holding `from`
+    fn depends_on(&self, from: usize, to: usize) -> bool {
+        let mut seen = 0u64;
+        let mut frontier = self.after[from];
+        while frontier != 0 {
+            let class = frontier.trailing_zeros() as usize;
+            if class == to {
+                return true;
+            }
+            seen |= 1 << class;
+            frontier = (frontier | self.after[class]) & !seen;
+        }
+        false
+    }
+
+    fn check(&self, id: usize, kind: LockKind, trylock: bool) -> Option<Violation> {
+        if kind == LockKind::Sleeping && irq::in_interrupt() {
+            return Some(Violation::InterruptContext);
+        }
+        // A failed trylock can't deadlock, so a successful one doesn't constrain the order
+        if trylock {
+            return None;
+        }
+        if self.held().contains(&id) {
+            return Some(Violation::Recursive);
+        }
+        self.held()
+            .iter()
+            .find(|&&held| self.depends_on(id, held))
+            .map(|&held| Violation::Inversion { held })
+    }
+
+    fn acquire(&mut self, id: usize, kind: LockKind, trylock: bool) -> Option<Violation> {
+        let violation = self.check(id, kind, trylock);
+        // Recording the order of an inversion would make the graph cyclic
+        if violation.is_none() && !trylock {
+            for i in 0..self.held().len() {
+                self.after[self.held[i]] |= 1 << id;
+            }
+        }
+
+        if self.depth < MAX_HELD {
+            self.held[self.depth] = id;
+        }
+        self.depth += 1;
+        violation
+    }
+
+    fn release(&mut self, id: usize) {
+        if self.depth > MAX_HELD {
+            self.depth -= 1;
+            return;
+        }
+        // Locks aren't always released in the reverse order they were taken
+        if let Some(i) = self.held().iter().rposition(|&held| held == id) {
+            self.held.copy_within(i + 1..self.depth, i);
+            self.depth -= 1;
+        }
+    }
+
+    fn name(&self, id: usize) -> &'static str {
+        self.classes[id].map_or("<unknown>", |c| c.name)
+    }
+
+    fn report(&self, class: LockClass, violation: Violation) {
+        macro_rules! report {
+            ($($arg:tt)*) => {
+                serial::_print_unlocked(format_args!($($arg)*))
+            };
+        }
+
+        report!("\n=============================================\n");
+        match violation {
+            Violation::InterruptContext => {
+                report!("WARNING: sleeping lock taken in interrupt context\n");
+                report!("acquiring {} from an interrupt handler\n", class.name);
+            }
+            Violation::Recursive => {
+                report!("WARNING: possible recursive locking detected\n");
+                report!("acquiring {}, which is already held\n", class.name);
+            }
+            Violation::Inversion { held } => {
+                report!("WARNING: possible circular locking dependency detected\n");
+                report!(
+                    "acquiring {} while holding {}, but {} was previously acquired while holding {}\n",
+                    class.name,
+                    self.name(held),
+                    self.name(held),
+                    class.name
+                );
+            }
+        }
+
+        report!("held locks:\n");
+        for (i, &held) in self.held().iter().enumerate() {
+            report!("  #{i}: {}\n", self.name(held));
+        }
+        report!("backtrace:\n{}", Backtrace::capture());
+        report!("=============================================\n");
+    }
+}
+
+static VALIDATOR: Mutex<Validator> = Mutex::new(Validator::new());
+static VIOLATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Validates the acquisition of a lock of `class`, which must be reported before spinning or
+/// blocking on the lock so a deadlock gets reported too.
+///
+/// Successful trylocks are reported with `trylock` set
+pub(crate) fn acquire(class: LockClass, kind: LockKind, trylock: bool) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut validator = VALIDATOR.lock();
+        let Some(id) = validator.class_id(class) else {
+            return;
+        };
+        if let Some(violation) = validator.acquire(id, kind, trylock) {
+            VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+            validator.report(class, violation);
+        }
+    })
+}
+
+/// Records the release of a lock of `class`
+pub(crate) fn release(class: LockClass) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut validator = VALIDATOR.lock();
+        if let Some(id) = validator.class_id(class) {
+            validator.release(id);
+        }
+    })
+}
+
+/// Forgets about every lock currently held, after a panic abandoned them
+pub(crate) fn forget_held_locks() {
+    interrupts::without_interrupts(|| VALIDATOR.lock().depth = 0)
+}
+
+/// Number of locking violations reported so far. Always zero in release builds
+pub fn violation_count() -> u64 {
+    VIOLATIONS.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_lockdep_reports_inversion() {
+    use super::{IrqSpinLock, RawIrqSpinLock};
+
+    static A: IrqSpinLock<()> = IrqSpinLock::from_raw(
+        RawIrqSpinLock::with_class(LockClass::new("lockdep test A")),
+        (),
+    );
+    static B: IrqSpinLock<()> = IrqSpinLock::from_raw(
+        RawIrqSpinLock::with_class(LockClass::new("lockdep test B")),
+        (),
+    );
+
+    let before = violation_count();
+    {
+        let _a = A.lock();
+        let _b = B.lock();
+    }
+    assert_eq!(violation_count(), before);
+    {
+        let _b = B.lock();
+        let _a = A.lock();
+    }
+    assert_eq!(
+        violation_count(),
+        before + u64::from(cfg!(debug_assertions))
+    );
+}
+
+#[test_case]
+fn test_lockdep_reports_sleeping_lock_in_interrupt() {
+    use super::Mutex;
+    use crate::irq::IrqReturn;
+
+    static LOCK: Mutex<()> = Mutex::with_class(LockClass::new("lockdep test sleeping"), ());
+    const VECTOR: u8 = 201;
+
+    fn handler() -> IrqReturn {
+        drop(LOCK.lock());
+        IrqReturn::Handled
+    }
+
+    drop(LOCK.lock());
+    let before = violation_count();
+    irq::register_vector(VECTOR, handler).unwrap();
+    unsafe { x86_64::instructions::interrupts::software_interrupt::<VECTOR>() };
+    irq::unregister_vector(VECTOR, handler).unwrap();
+    assert_eq!(
+        violation_count(),
+        before + u64::from(cfg!(debug_assertions))
+    );
+}
@@ -0,0 +1,160 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use super::{
+    lockdep::{self, LockClass, LockKind},
+    WaitQueue,
+};
+use crate::time::Deadline;
+
+/// A mutual exclusion lock which blocks on a `WaitQueue` while it is contended.
+///
+/// Must not be used from interrupt context, use `IrqSpinLock` for data shared with
+/// interrupt handlers
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    class: Option<LockClass>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            class: None,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// A mutex checked by the lock validator as part of `class`
+    pub const fn with_class(class: LockClass, data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            class: Some(class),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if let Some(class) = self.class {
+            lockdep::acquire(class, LockKind::Sleeping, false);
+        }
+        self.waiters.wait_until(|| self.try_acquire())
+    }
+
+    /// Gives up and returns `None` if the lock couldn't be acquired within `timeout`
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        self.waiters
+            .wait_until_deadline(Deadline::after(timeout), || self.try_lock())
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let guard = self.try_acquire()?;
+        if let Some(class) = self.class {
+            lockdep::acquire(class, LockKind::Sleeping, true);
+        }
+        Some(guard)
+    }
+
+    fn try_acquire(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Releases the lock without a guard.
+    ///
+    /// # Safety
+    /// The lock must be held, and its guard must not be used afterwards
+    pub unsafe fn force_unlock(&self) {
+        if let Some(class) = self.class {
+            lockdep::release(class);
+        }
+        self.locked.store(false, Ordering::Release);
+        self.waiters.notify_one();
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// The mutex this guard belongs to
+    pub fn mutex(guard: &Self) -> &'a Mutex<T> {
+        guard.mutex
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.mutex.force_unlock() }
+    }
+}
+
+#[test_case]
+fn test_mutex_lock_timeout() {
+    let mutex = Mutex::new(5);
+    {
+        let mut guard = mutex.lock();
+        *guard += 1;
+        assert!(mutex.try_lock().is_none());
+        assert!(mutex.lock_timeout(Duration::from_millis(5)).is_none());
+    }
+    assert_eq!(*mutex.lock_timeout(Duration::from_millis(5)).unwrap(), 6);
+}
@@ -0,0 +1,76 @@
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use super::WaitQueue;
+use crate::time::Deadline;
+
+/// A counting semaphore which blocks on a `WaitQueue` while no permits are available.
+///
+/// `release` may be called from interrupt context, but `acquire` must not be
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire().then_some(()))
+    }
+
+    /// Returns `false` if no permit became available within `timeout`
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.waiters
+            .wait_until_deadline(Deadline::after(timeout), || {
+                self.try_acquire().then_some(())
+            })
+            .is_some()
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+#[test_case]
+fn test_semaphore_released_from_interrupt() {
+    use crate::irq::{self, IrqReturn};
+
+    static SEMAPHORE: Semaphore = Semaphore::new(1);
+
+    fn on_timer() -> IrqReturn {
+        if SEMAPHORE.available_permits() == 0 {
+            SEMAPHORE.release();
+        }
+        IrqReturn::NotMine
+    }
+
+    SEMAPHORE.acquire();
+    assert!(!SEMAPHORE.try_acquire());
+    assert!(!SEMAPHORE.acquire_timeout(Duration::from_millis(5)));
+
+    irq::register(irq::TIMER_IRQ, on_timer).unwrap();
+    SEMAPHORE.acquire();
+    irq::unregister(irq::TIMER_IRQ, on_timer).unwrap();
+}
@@ -0,0 +1,139 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::time::Deadline;
+
+/// A queue of waiters blocked until some condition changes.
+///
+/// There is no scheduler yet, so blocking means halting the CPU until the next interrupt,
+/// which is the only thing that can make progress on someone else's behalf. Once there is a
+/// scheduler, this is where waiters get put to sleep instead.
+///
+/// Waiters aren't tracked individually: every notification wakes all of them, and each
+/// re-checks its condition
+pub struct WaitQueue {
+    generation: AtomicU64,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Blocks until `condition` returns `Some`, re-checking it each time the queue is
+    /// notified and after any other interrupt. A condition that polls the device itself still
+    /// makes progress if the interrupt is lost.
+    ///
+    /// Panics if called with interrupts disabled, since nothing could ever notify the queue
+    pub fn wait_until<T>(&self, mut condition: impl FnMut() -> Option<T>) -> T {
+        loop {
+            let generation = self.generation.load(Ordering::Acquire);
+            if let Some(value) = condition() {
+                return value;
+            }
+            self.block(generation);
+        }
+    }
+
+    /// Like `wait_until`, but gives up and returns `None` once `deadline` has passed
+    pub fn wait_until_deadline<T>(
+        &self,
+        deadline: Deadline,
+        mut condition: impl FnMut() -> Option<T>,
+    ) -> Option<T> {
+        loop {
+            let generation = self.generation.load(Ordering::Acquire);
+            if let Some(value) = condition() {
+                return Some(value);
+            }
+            if deadline.has_passed() {
+                return None;
+            }
+            self.block(generation);
+        }
+    }
+
+    /// Blocks until the queue is notified after `generation` was observed, or until the next
+    /// interrupt, whichever comes first
+    fn block(&self, generation: u64) {
+        assert!(
+            interrupts::are_enabled(),
+            "blocking with interrupts disabled would never wake up"
+        );
+
+        // Checking and halting with interrupts disabled makes sure a notification can't slip in
+        // between the check and the `hlt`
+        interrupts::disable();
+        if self.generation.load(Ordering::Acquire) == generation {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+
+    /// The current generation, which changes on every notification
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Blocks until the queue has been notified since `generation` was observed.
+    ///
+    /// Returns `false` if `deadline` passed first
+    pub fn wait_for_notification(&self, generation: u64, deadline: Option<Deadline>) -> bool {
+        let notified = || (self.generation() != generation).then_some(());
+        match deadline {
+            Some(deadline) => self.wait_until_deadline(deadline, notified).is_some(),
+            None => {
+                self.wait_until(notified);
+                true
+            }
+        }
+    }
+
+    /// Wakes a waiter. Safe to call from interrupt context
+    pub fn notify_one(&self) {
+        self.notify_all()
+    }
+
+    /// Wakes every waiter. Safe to call from interrupt context
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_wait_queue_woken_from_interrupt() {
+    use crate::irq::{self, IrqReturn};
+    use core::sync::atomic::AtomicBool;
+
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static READY: AtomicBool = AtomicBool::new(false);
+
+    fn on_timer() -> IrqReturn {
+        READY.store(true, Ordering::Relaxed);
+        QUEUE.notify_all();
+        IrqReturn::NotMine
+    }
+
+    irq::register(irq::TIMER_IRQ, on_timer).unwrap();
+    QUEUE.wait_until(|| READY.load(Ordering::Relaxed).then_some(()));
+    irq::unregister(irq::TIMER_IRQ, on_timer).unwrap();
+}
+
+#[test_case]
+fn test_wait_queue_deadline() {
+    static QUEUE: WaitQueue = WaitQueue::new();
+
+    let deadline = Deadline::after(core::time::Duration::from_millis(5));
+    assert_eq!(QUEUE.wait_until_deadline(deadline, || None::<()>), None);
+    assert!(deadline.has_passed());
+}
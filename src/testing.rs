@@ -0,0 +1,353 @@
+//! The in-kernel test framework used by the library and every integration test.
+//!
+//! Tests run one after the other. When a test panics, the panic handler records the failure
+//! (or the success of a `should_panic` test) and restarts the runner at the next test, on a
+//! fresh stack. The panicking test never unwinds, so locks it held stay locked, except those of
+//! the console, and interrupts it was handling are acknowledged.
+//!
+//! The boot configuration (see `config`) selects tests:
+//! - `test.filter=<pattern>` only runs tests whose name contains a pattern (may be repeated)
+//! - `test.skip=<pattern>` skips tests whose name contains a pattern (may be repeated)
+//! - `test.ignored` also runs tests marked as ignored
+//!
+//! - `test.format=<pretty|tap|junit>` selects the output format, see `report`
+
+use core::{
+    arch::asm,
+    panic::PanicInfo,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+use self::report::{Format, Outcome, Summary};
+use crate::{
+    config, exit_qemu, halt_loop, irq, panic, serial, sync::lockdep, time::Instant,
+    vga_buffer::VgaWriter, QemuExitCode,
+};
+
+pub mod report;
+
+/// A test, as collected by `#[test_case]`
+pub trait TestCase: Sync {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+
+    /// Whether the test passes by panicking
+    fn should_panic(&self) -> bool {
+        false
+    }
+
+    /// Whether the test only runs when asked for with `test.ignored`
+    fn ignore(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Fn() + Sync> TestCase for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<Self>()
+    }
+
+    fn run(&self) {
+        self()
+    }
+}
+
+/// A test with metadata, usually declared through `kernel_test!`
+#[derive(Debug, Clone, Copy)]
+pub struct Test {
+    name: &'static str,
+    test: fn(),
+    should_panic: bool,
+    ignore: bool,
+}
+
+impl Test {
+    pub const fn new(name: &'static str, test: fn()) -> Self {
+        Self {
+            name,
+            test,
+            should_panic: false,
+            ignore: false,
+        }
+    }
+
+    pub const fn should_panic(self) -> Self {
+        Self {
+            should_panic: true,
+            ..self
+        }
+    }
+
+    pub const fn ignore(self) -> Self {
+        Self {
+            ignore: true,
+            ..self
+        }
+    }
+}
+
+impl TestCase for Test {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        (self.test)()
+    }
+
+    fn should_panic(&self) -> bool {
+        self.should_panic
+    }
+
+    fn ignore(&self) -> bool {
+        self.ignore
+    }
+}
+
+/// Declares a test with attributes, which can be `#[should_panic]` and `#[ignore]`
+///
+/// ```ignore
+/// ros::kernel_test! {
+///     #[should_panic]
+///     fn test_overflow_panics() {
+///         let _ = u8::MAX + core::hint::black_box(1);
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! kernel_test {
+    ($(#[$attr:ident])* fn $name:ident() $body:block) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static $name: $crate::testing::Test = {
+            fn $name() $body
+            $crate::testing::Test::new(concat!(module_path!(), "::", stringify!($name)), $name)
+                $(.$attr())*
+        };
+    };
+}
+
+struct TestRun {
+    tests: &'static [&'static dyn TestCase],
+    start: Instant,
+}
+
+static RUN: Once<TestRun> = Once::new();
+/// The name of the test of a kernel without the test harness, see `begin_single`
+static SINGLE: Once<&'static str> = Once::new();
+/// Index of the test currently running
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+/// Whether a test is running, as opposed to the runner itself
+static IN_TEST: AtomicBool = AtomicBool::new(false);
+/// When the current test started, in nanoseconds since boot
+static TEST_START: AtomicU64 = AtomicU64::new(0);
+
+static PASSED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+static IGNORED: AtomicUsize = AtomicUsize::new(0);
+static FILTERED: AtomicUsize = AtomicUsize::new(0);
+
+/// The stack the runner restarts on after a test panicked
+const RESTART_STACK_SIZE: usize = 4096 * 16;
+static mut RESTART_STACK: [u8; RESTART_STACK_SIZE] = [0; RESTART_STACK_SIZE];
+
+pub fn test_runner(tests: &[&dyn TestCase]) {
+    // Safety: `test_main` never returns once the runner has started, since the runner exits
+    // QEMU, and restarting after a panic switches to `RESTART_STACK`, so the frame holding
+    // the test array is never popped nor overwritten
+    let tests: &'static [&'static dyn TestCase] = unsafe { core::mem::transmute(tests) };
+    let run = RUN.call_once(|| TestRun {
+        tests,
+        start: Instant::now(),
+    });
+
+    let suite = run
+        .tests
+        .first()
+        .map_or("", |test| report::suite_name(test.name()));
+    report::start(format(), suite, run.tests.len());
+    run_from(0)
+}
+
+fn run_from(index: usize) -> ! {
+    let run = RUN.get().expect("no test run in progress");
+    for (i, test) in run.tests.iter().enumerate().skip(index) {
+        CURRENT.store(i, Ordering::Relaxed);
+        run_test(*test);
+    }
+
+    let summary = summary();
+    report::finish(format(), summary, run.start.elapsed());
+    exit_qemu(if summary.failed == 0 {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::Failed
+    });
+    halt_loop()
+}
+
+fn summary() -> Summary {
+    Summary {
+        passed: PASSED.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+        ignored: IGNORED.load(Ordering::Relaxed),
+        filtered: FILTERED.load(Ordering::Relaxed),
+    }
+}
+
+fn format() -> Format {
+    config::get().test.format
+}
+
+fn run_test(test: &dyn TestCase) {
+    if !config::get().test.is_selected(test.name()) {
+        record(test.name(), Outcome::Filtered);
+        return;
+    }
+    if test.ignore() && !config::get().test.run_ignored {
+        record(test.name(), Outcome::Ignored);
+        return;
+    }
+
+    report::begin(format(), test.name());
+    TEST_START.store(
+        Instant::now().since_boot().as_nanos() as u64,
+        Ordering::Relaxed,
+    );
+    IN_TEST.store(true, Ordering::Relaxed);
+    test.run();
+    IN_TEST.store(false, Ordering::Relaxed);
+
+    if test.should_panic() {
+        record(
+            test.name(),
+            Outcome::Failed {
+                message: &"test did not panic as expected",
+                location: None,
+            },
+        );
+    } else {
+        record(test.name(), Outcome::Passed);
+    }
+}
+
+/// Counts and reports the outcome of the current test
+fn record(name: &str, outcome: Outcome) {
+    let counter = match outcome {
+        Outcome::Passed => &PASSED,
+        Outcome::Failed { .. } => &FAILED,
+        Outcome::Ignored => &IGNORED,
+        Outcome::Filtered => &FILTERED,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+
+    let elapsed = match outcome {
+        Outcome::Passed | Outcome::Failed { .. } => elapsed_since_start(),
+        Outcome::Ignored | Outcome::Filtered => Duration::ZERO,
+    };
+    let number = CURRENT.load(Ordering::Relaxed) + 1;
+    report::result(format(), number, name, &outcome, elapsed);
+}
+
+/// Starts the single test of a test kernel which doesn't use the test harness, such as one
+/// expecting a double fault. Its result is reported by `end_single`, or by
+/// `test_panic_handler` if it panics
+pub fn begin_single(name: &'static str) {
+    SINGLE.call_once(|| name);
+    report::start(format(), report::suite_name(name), 1);
+    report::begin(format(), name);
+    TEST_START.store(
+        Instant::now().since_boot().as_nanos() as u64,
+        Ordering::Relaxed,
+    );
+}
+
+/// Reports the result of the test started by `begin_single`. Exiting QEMU is up to the caller
+pub fn end_single(result: Result<(), &PanicInfo>) {
+    let name = SINGLE.get().expect("no single test in progress");
+    match result {
+        Ok(()) => record(name, Outcome::Passed),
+        Err(info) => record(
+            name,
+            Outcome::Failed {
+                message: &info.message(),
+                location: info.location(),
+            },
+        ),
+    }
+    report::finish(format(), summary(), elapsed_since_start());
+}
+
+fn elapsed_since_start() -> Duration {
+    Instant::now().since_boot() - Duration::from_nanos(TEST_START.load(Ordering::Relaxed))
+}
+
+/// The panic handler of test kernels.
+///
+/// A panic inside a test fails it (or passes it, for `should_panic` tests) and the run goes
+/// on with the next test. Any other panic ends the run
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    interrupts::disable();
+
+    if let (Some(run), true) = (RUN.get(), IN_TEST.swap(false, Ordering::Relaxed)) {
+        let index = CURRENT.load(Ordering::Relaxed);
+        let test = run.tests[index];
+        if test.should_panic() {
+            record(test.name(), Outcome::Passed);
+        } else {
+            record(
+                test.name(),
+                Outcome::Failed {
+                    message: &info.message(),
+                    location: info.location(),
+                },
+            );
+        }
+        restart_at(index + 1);
+    }
+
+    if SINGLE.is_completed() {
+        end_single(Err(info));
+    } else {
+        serial::_print_unlocked(format_args!("[failed]\n\nError: {}\n\n", info));
+    }
+    panic::set_policy(panic::PanicPolicy::ExitQemu(QemuExitCode::Failed));
+    panic::handle_panic(info)
+}
+
+/// Resumes the runner at the test `index`, discarding the current stack
+fn restart_at(index: usize) -> ! {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn resume() -> ! {
+        // The panicking test may have been interrupted anywhere, including in an interrupt
+        // handler or with locks held. The next tests still need the console and interrupts
+        lockdep::forget_held_locks();
+        unsafe {
+            irq::forget_running_handlers();
+            serial::force_unlock();
+            VgaWriter::force_unlock();
+        }
+        interrupts::enable();
+        run_from(NEXT.load(Ordering::Relaxed))
+    }
+
+    NEXT.store(index, Ordering::Relaxed);
+    let stack_top = (addr_of!(RESTART_STACK) as u64 + RESTART_STACK_SIZE as u64) & !0xf;
+    unsafe {
+        asm!(
+            "mov rsp, {stack_top}",
+            // Ends backtraces here
+            "xor ebp, ebp",
+            "call {resume}",
+            stack_top = in(reg) stack_top,
+            resume = sym resume,
+            options(noreturn),
+        )
+    }
+}
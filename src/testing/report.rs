@@ -0,0 +1,244 @@
+//! The output formats of test results, selected with `test.format=<pretty|tap|junit>` on the
+//! kernel command line (see `config`).
+//!
+//! Every record is written on lines of its own, so host tools can pick them out of whatever
+//! else the tests print over serial.
+
+use core::{
+    fmt::{self, Display, Write},
+    panic::Location,
+    time::Duration,
+};
+
+use crate::serial;
+
+/// Prints over serial without taking the serial port's lock, which a panicking test may hold
+macro_rules! out {
+    ($($arg:tt)*) => {
+        serial::_print_unlocked(format_args!($($arg)*))
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `<name>... [ok] (<elapsed>)` lines and a summary
+    Pretty,
+    /// Test Anything Protocol, version 13
+    Tap,
+    /// A JUnit XML test suite
+    Junit,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "pretty" => Some(Format::Pretty),
+            "tap" => Some(Format::Tap),
+            "junit" => Some(Format::Junit),
+            _ => None,
+        }
+    }
+}
+
+pub enum Outcome<'a> {
+    Passed,
+    Failed {
+        message: &'a dyn Display,
+        location: Option<&'a Location<'a>>,
+    },
+    Ignored,
+    Filtered,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    pub filtered: usize,
+}
+
+/// The crate a test belongs to, from its full name
+pub fn suite_name(test_name: &str) -> &str {
+    test_name.split("::").next().unwrap_or(test_name)
+}
+
+pub fn start(format: Format, suite: &str, count: usize) {
+    match format {
+        Format::Pretty => out!("Running {} tests\n", count),
+        Format::Tap => out!("TAP version 13\n1..{}\n", count),
+        Format::Junit => out!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuite name=\"{}\" tests=\"{}\">\n",
+            Escaped(Escape::Xml, suite),
+            count
+        ),
+    }
+}
+
+/// Called right before a test runs, so a hanging test can be identified
+pub fn begin(format: Format, name: &str) {
+    if format == Format::Pretty {
+        out!("{}... ", name);
+    }
+}
+
+/// Reports the outcome of the `number`th test, counting from 1
+pub fn result(format: Format, number: usize, name: &str, outcome: &Outcome, elapsed: Duration) {
+    match format {
+        Format::Pretty => pretty_result(name, outcome, elapsed),
+        Format::Tap => tap_result(number, name, outcome, elapsed),
+        Format::Junit => junit_result(name, outcome, elapsed),
+    }
+}
+
+pub fn finish(format: Format, summary: Summary, elapsed: Duration) {
+    match format {
+        Format::Pretty => out!(
+            "\ntest result: {}. {} passed; {} failed; {} ignored; {} filtered out; finished in {:?}\n",
+            if summary.failed == 0 { "ok" } else { "FAILED" },
+            summary.passed,
+            summary.failed,
+            summary.ignored,
+            summary.filtered,
+            elapsed,
+        ),
+        Format::Tap => out!(
+            "# passed {}\n# failed {}\n# ignored {}\n# filtered out {}\n",
+            summary.passed,
+            summary.failed,
+            summary.ignored,
+            summary.filtered
+        ),
+        Format::Junit => out!("</testsuite>\n"),
+    }
+}
+
+fn pretty_result(name: &str, outcome: &Outcome, elapsed: Duration) {
+    match outcome {
+        Outcome::Passed => out!("[ok] ({:?})\n", elapsed),
+        Outcome::Failed {
+            message,
+            location: Some(location),
+        } => out!(
+            "[failed] ({:?})\n\nError: panicked at {}:\n{}\n\n",
+            elapsed,
+            location,
+            message
+        ),
+        Outcome::Failed {
+            message,
+            location: None,
+        } => out!("[failed] ({:?})\n\nError: {}\n\n", elapsed, message),
+        Outcome::Ignored => out!("{}... [ignored]\n", name),
+        Outcome::Filtered => {}
+    }
+}
+
+fn tap_result(number: usize, name: &str, outcome: &Outcome, elapsed: Duration) {
+    let name = Escaped(Escape::TapDescription, name);
+    let millis = Millis(elapsed);
+    match outcome {
+        Outcome::Passed => out!(
+            "ok {} - {}\n  ---\n  duration_ms: {}\n  ...\n",
+            number,
+            name,
+            millis
+        ),
+        Outcome::Failed { message, location } => {
+            out!(
+                "not ok {} - {}\n  ---\n  duration_ms: {}\n  message: \"{}\"\n",
+                number,
+                name,
+                millis,
+                Escaped(Escape::Yaml, message)
+            );
+            if let Some(location) = location {
+                out!("  location: \"{}\"\n", Escaped(Escape::Yaml, location));
+            }
+            out!("  ...\n");
+        }
+        Outcome::Ignored => out!("ok {} - {} # SKIP ignored\n", number, name),
+        Outcome::Filtered => out!("ok {} - {} # SKIP filtered out\n", number, name),
+    }
+}
+
+fn junit_result(name: &str, outcome: &Outcome, elapsed: Duration) {
+    let (class, test) = name.rsplit_once("::").unwrap_or(("", name));
+    out!(
+        "  <testcase classname=\"{}\" name=\"{}\" time=\"{}.{:06}\"",
+        Escaped(Escape::Xml, class),
+        Escaped(Escape::Xml, test),
+        elapsed.as_secs(),
+        elapsed.subsec_micros()
+    );
+    match outcome {
+        Outcome::Passed => out!("/>\n"),
+        Outcome::Failed { message, location } => {
+            out!(
+                "><failure type=\"panic\" message=\"{}\">",
+                Escaped(Escape::Xml, message)
+            );
+            if let Some(location) = location {
+                out!("at {}", Escaped(Escape::Xml, location));
+            }
+            out!("</failure></testcase>\n");
+        }
+        Outcome::Ignored => out!("><skipped message=\"ignored\"/></testcase>\n"),
+        Outcome::Filtered => out!("><skipped message=\"filtered out\"/></testcase>\n"),
+    }
+}
+
+/// A duration in milliseconds, with microsecond precision
+struct Millis(Duration);
+
+impl Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let micros = self.0.as_micros();
+        write!(f, "{}.{:03}", micros / 1000, micros % 1000)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    /// XML attribute values and text
+    Xml,
+    /// Double-quoted YAML strings
+    Yaml,
+    /// TAP test descriptions, where `#` starts a directive
+    TapDescription,
+}
+
+/// Displays a value with the characters special to the output format escaped
+struct Escaped<T>(Escape, T);
+
+impl<T: Display> Display for Escaped<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Escaper<'a, 'b> {
+            escape: Escape,
+            f: &'a mut fmt::Formatter<'b>,
+        }
+
+        impl Write for Escaper<'_, '_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for c in s.chars() {
+                    match (self.escape, c) {
+                        (Escape::Xml, '&') => self.f.write_str("&amp;")?,
+                        (Escape::Xml, '<') => self.f.write_str("&lt;")?,
+                        (Escape::Xml, '>') => self.f.write_str("&gt;")?,
+                        (Escape::Xml, '"') => self.f.write_str("&quot;")?,
+                        (Escape::Xml, '\n') => self.f.write_str("&#10;")?,
+                        (Escape::Yaml, '"') => self.f.write_str("\\\"")?,
+                        (Escape::Yaml, '\\') => self.f.write_str("\\\\")?,
+                        (Escape::Yaml, '\n') => self.f.write_str("\\n")?,
+                        (Escape::TapDescription, '#') => self.f.write_str("\\#")?,
+                        (Escape::TapDescription, '\n') => self.f.write_char(' ')?,
+                        (_, c) => self.f.write_char(c)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        write!(Escaper { escape: self.0, f }, "{}", self.1)
+    }
+}
@@ -0,0 +1,241 @@
+use core::{
+    ops::{Add, AddAssign, Sub, SubAssign},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::{
+    irq::{self, IrqReturn},
+    memory,
+};
+
+pub mod hpet;
+pub mod pit;
+pub mod tsc;
+pub mod wheel;
+
+pub use wheel::{sleep, timeout, Deadline};
+
+/// The default frequency of the timer interrupt, in Hz
+pub const DEFAULT_TICK_FREQUENCY: u32 = 1000;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Number of timer interrupts since `init`
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// The frequency the PIT was actually programmed to
+static TICK_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+/// The calibrated TSC frequency, or zero if the TSC isn't used as a clock source
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// The TSC value corresponding to `Instant` zero
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+static HPET: Once<Option<hpet::Hpet>> = Once::new();
+
+/// Programs the PIT to fire at roughly `tick_frequency` Hz and calibrates the TSC.
+///
+/// The TSC is calibrated against the HPET if ACPI reports one (which requires the memory
+/// module to be initialized beforehand), and against PIT channel 2 otherwise
+pub fn init(tick_frequency: u32) {
+    TICK_FREQUENCY.store(pit::set_frequency(tick_frequency), Ordering::Relaxed);
+
+    let tsc_frequency = match hpet() {
+        Some(hpet) => tsc::calibrate_with_hpet(hpet),
+        None => tsc::calibrate_with_pit(),
+    };
+    TSC_START.store(tsc::read(), Ordering::Relaxed);
+    TSC_FREQUENCY.store(tsc_frequency, Ordering::Relaxed);
+
+    irq::register(irq::TIMER_IRQ, timer_interrupt).expect("failed to register the timer interrupt");
+}
+
+fn timer_interrupt() -> IrqReturn {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    wheel::run(now);
+    IrqReturn::Handled
+}
+
+/// Number of timer interrupts since `init`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// The frequency of the timer interrupt, in Hz
+pub fn tick_frequency() -> u32 {
+    TICK_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// The calibrated TSC frequency in Hz, or `None` if the TSC hasn't been calibrated
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// The HPET, if ACPI reports one.
+///
+/// Returns `None` until the memory module is initialized, and looks again once it is
+pub fn hpet() -> Option<&'static hpet::Hpet> {
+    // Don't cache a failed lookup just because physical memory isn't accessible yet
+    memory::phys_to_virt(PhysAddr::new(0))?;
+    HPET.call_once(hpet::Hpet::from_acpi).as_ref()
+}
+
+/// Converts a number of timer ticks into a `Duration`
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    match tick_frequency() {
+        0 => Duration::ZERO,
+        hz => Duration::from_nanos(mul_div(ticks, NANOS_PER_SEC, u64::from(hz))),
+    }
+}
+
+/// Converts a `Duration` into a number of timer ticks, rounding up
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = u128::from(tick_frequency()) * duration.as_nanos();
+    nanos.div_ceil(u128::from(NANOS_PER_SEC)) as u64
+}
+
+/// Computes `a * b / c` without intermediate overflow
+fn mul_div(a: u64, b: u64, c: u64) -> u64 {
+    (u128::from(a) * u128::from(b) / u128::from(c)) as u64
+}
+
+/// A measurement of the monotonic clock, with nanosecond resolution.
+///
+/// Backed by the TSC once it has been calibrated, and by the timer tick counter otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    /// The instant at which `init` completed
+    pub const ZERO: Self = Self { nanos: 0 };
+
+    pub fn now() -> Self {
+        let nanos = match tsc_frequency() {
+            Some(hz) => {
+                let elapsed = tsc::read().saturating_sub(TSC_START.load(Ordering::Relaxed));
+                mul_div(elapsed, NANOS_PER_SEC, hz)
+            }
+            None => ticks_to_duration(ticks()).as_nanos() as u64,
+        };
+        Self { nanos }
+    }
+
+    /// Time elapsed since `init`
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().saturating_duration_since(*self)
+    }
+
+    /// Panics if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier)
+            .expect("supplied instant is later than self")
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.nanos
+            .checked_sub(earlier.nanos)
+            .map(Duration::from_nanos)
+    }
+
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_add(nanos).map(|nanos| Self { nanos })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_sub(nanos).map(|nanos| Self { nanos })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Time elapsed since `init`
+pub fn uptime() -> Duration {
+    Instant::now().since_boot()
+}
+
+/// Spins until `duration` has passed
+pub fn busy_wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn test_instant_monotonic() {
+    let mut last = Instant::now();
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn test_tsc_busy_wait_matches_pit_ticks() {
+    for millis in [10, 50, 100] {
+        let start = ticks();
+        busy_wait(Duration::from_millis(millis));
+        let elapsed = ticks() - start;
+
+        let expected = millis * u64::from(tick_frequency()) / 1000;
+        let tolerance = expected / 10 + 2;
+        assert!(
+            elapsed.abs_diff(expected) <= tolerance,
+            "busy-waited {millis}ms but {elapsed} ticks passed (expected {expected})"
+        );
+    }
+}
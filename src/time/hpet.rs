@@ -0,0 +1,60 @@
+use core::ptr;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{acpi, memory};
+
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0f0;
+
+/// Offset of the base address (within its Generic Address Structure) in the ACPI `HPET` table
+const ACPI_BASE_ADDRESS_OFFSET: usize = 44;
+
+/// The High Precision Event Timer's main counter
+#[derive(Debug)]
+pub struct Hpet {
+    base: VirtAddr,
+    /// The counter's tick period in femtoseconds
+    period_fs: u64,
+}
+
+impl Hpet {
+    /// Locates the HPET through the ACPI tables and enables its main counter.
+    ///
+    /// Returns `None` if ACPI doesn't report an HPET (or the memory module isn't initialized)
+    pub fn from_acpi() -> Option<Self> {
+        let table = acpi::find_table(b"HPET")?;
+        let phys: u64 = table.read(ACPI_BASE_ADDRESS_OFFSET);
+        let base = memory::phys_to_virt(PhysAddr::new(phys))?;
+
+        let mut hpet = Self { base, period_fs: 0 };
+        hpet.period_fs = hpet.read(GENERAL_CAPABILITIES) >> 32;
+        if hpet.period_fs == 0 {
+            return None;
+        }
+
+        let config = hpet.read(GENERAL_CONFIGURATION);
+        hpet.write(GENERAL_CONFIGURATION, config | 1);
+
+        Some(hpet)
+    }
+
+    fn read(&self, register: u64) -> u64 {
+        unsafe { ptr::read_volatile((self.base + register).as_ptr()) }
+    }
+
+    fn write(&mut self, register: u64, value: u64) {
+        unsafe { ptr::write_volatile((self.base + register).as_mut_ptr(), value) }
+    }
+
+    /// The frequency of the main counter, in Hz
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    /// The current value of the main counter
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+}
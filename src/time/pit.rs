@@ -0,0 +1,66 @@
+use x86_64::instructions::port::Port;
+
+/// The frequency of the oscillator driving every PIT channel, in Hz
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Controls the channel 2 gate (bit 0) and the speaker (bit 1), and reports the channel 2
+/// output (bit 5)
+const CHANNEL_2_GATE: u16 = 0x61;
+
+/// Converts a frequency into the closest reload value the PIT supports
+fn divisor_for(frequency: u32) -> u16 {
+    let divisor = (BASE_FREQUENCY + frequency / 2) / frequency.max(1);
+    divisor.clamp(1, u16::MAX as u32) as u16
+}
+
+/// Programs channel 0 (wired to IRQ0) as a rate generator firing at roughly `frequency` Hz.
+///
+/// Returns the exact frequency that was achieved
+pub fn set_frequency(frequency: u32) -> u32 {
+    let divisor = divisor_for(frequency);
+
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut channel_0 = Port::<u8>::new(CHANNEL_0);
+    unsafe {
+        // Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary
+        command.write(0x34);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+
+    BASE_FREQUENCY / divisor as u32
+}
+
+/// Busy-waits for `count` PIT cycles using channel 2, without relying on interrupts.
+///
+/// `f` is called right as the countdown starts, and its result is passed to `then`
+/// right after it finishes. This is used to calibrate other clocks against the PIT
+pub fn measure<T, R>(count: u16, f: impl FnOnce() -> T, then: impl FnOnce(T) -> R) -> R {
+    let mut gate = Port::<u8>::new(CHANNEL_2_GATE);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut channel_2 = Port::<u8>::new(CHANNEL_2);
+
+    unsafe {
+        // Disable the gate and the speaker while the counter is programmed
+        let initial = gate.read();
+        gate.write(initial & !0b11);
+
+        // Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary
+        command.write(0xb0);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        let start = f();
+        gate.write((initial & !0b10) | 0b01);
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let result = then(start);
+
+        gate.write(initial);
+        result
+    }
+}
@@ -0,0 +1,51 @@
+use core::arch::asm;
+
+use super::{hpet::Hpet, pit};
+
+/// Number of PIT cycles in each calibration window, roughly 10ms
+const PIT_CALIBRATION_CYCLES: u16 = 11_932;
+const CALIBRATION_RUNS: usize = 3;
+
+/// Reads the time stamp counter
+#[inline]
+pub fn read() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+/// Measures the TSC frequency in Hz using PIT channel 2 as the reference.
+///
+/// The fastest of several runs is used, since an interruption can only make a run longer
+pub fn calibrate_with_pit() -> u64 {
+    let elapsed = (0..CALIBRATION_RUNS)
+        .map(|_| pit::measure(PIT_CALIBRATION_CYCLES, read, |start| read() - start))
+        .min()
+        .unwrap_or(0);
+
+    elapsed * u64::from(pit::BASE_FREQUENCY) / u64::from(PIT_CALIBRATION_CYCLES)
+}
+
+/// Measures the TSC frequency in Hz using the HPET main counter as the reference
+pub fn calibrate_with_hpet(hpet: &Hpet) -> u64 {
+    // Roughly 10ms
+    let window = hpet.frequency() / 100;
+
+    (0..CALIBRATION_RUNS)
+        .map(|_| {
+            let hpet_start = hpet.counter();
+            let tsc_start = read();
+            while hpet.counter().wrapping_sub(hpet_start) < window {
+                core::hint::spin_loop();
+            }
+            let tsc_elapsed = read() - tsc_start;
+            let hpet_elapsed = hpet.counter().wrapping_sub(hpet_start);
+
+            (u128::from(tsc_elapsed) * u128::from(hpet.frequency()) / u128::from(hpet_elapsed))
+                as u64
+        })
+        .min()
+        .unwrap_or(0)
+}
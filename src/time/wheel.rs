@@ -0,0 +1,444 @@
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{duration_to_ticks, ticks};
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+/// With 6 bits per level, 4 levels cover 2^24 ticks (over 4 hours at 1kHz).
+/// Anything further away waits in the overflow list
+const LEVELS: usize = 4;
+
+/// The slot index of the tick `tick` in the given level of the wheel
+fn slot_index(tick: u64, level: usize) -> usize {
+    ((tick >> (SLOT_BITS * level as u32)) as usize) & (SLOTS - 1)
+}
+
+/// What happens when a timer expires
+pub enum Action {
+    Callback(Box<dyn FnMut() + Send>),
+    Wake(Waker),
+}
+
+/// A pending timer, owned by a `TimerWheel`
+pub struct Timer {
+    deadline: u64,
+    period: Option<u64>,
+    action: Action,
+    cancelled: Arc<AtomicBool>,
+    next: Option<Box<Timer>>,
+}
+
+impl Timer {
+    /// A timer expiring at the tick `deadline`
+    pub fn new(deadline: u64, action: Action) -> Box<Self> {
+        Box::new(Self {
+            deadline,
+            period: None,
+            action,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            next: None,
+        })
+    }
+
+    /// Makes the timer re-arm itself every `period` ticks after it expires
+    pub fn periodic(mut self: Box<Self>, period: u64) -> Box<Self> {
+        self.period = Some(period.max(1));
+        self
+    }
+
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    pub fn period(&self) -> Option<u64> {
+        self.period
+    }
+
+    /// A handle which can cancel this timer
+    pub fn handle(&self) -> TimerHandle {
+        TimerHandle {
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Runs the timer's action
+    pub fn fire(&mut self) {
+        match &mut self.action {
+            Action::Callback(f) => f(),
+            Action::Wake(waker) => waker.wake_by_ref(),
+        }
+    }
+}
+
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Timer")
+            .field("deadline", &self.deadline)
+            .field("period", &self.period)
+            .field("cancelled", &self.is_cancelled())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Cancels a timer, whether it is still pending or not.
+///
+/// Cancelled timers are dropped lazily, when the wheel reaches them
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TimerHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// An intrusive singly linked list of timers.
+///
+/// Moving timers between lists never allocates, which keeps cascading safe inside the
+/// timer interrupt
+pub struct TimerList {
+    head: Option<Box<Timer>>,
+}
+
+impl TimerList {
+    pub const fn new() -> Self {
+        Self { head: None }
+    }
+
+    fn push_front(&mut self, mut timer: Box<Timer>) {
+        timer.next = self.head.take();
+        self.head = Some(timer);
+    }
+
+    pub fn pop_front(&mut self) -> Option<Box<Timer>> {
+        let mut timer = self.head.take()?;
+        self.head = timer.next.take();
+        Some(timer)
+    }
+
+    fn take_all(&mut self) -> Self {
+        Self {
+            head: self.head.take(),
+        }
+    }
+
+    fn reverse(&mut self) {
+        let mut reversed = Self::new();
+        while let Some(timer) = self.pop_front() {
+            reversed.push_front(timer);
+        }
+        *self = reversed;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+}
+
+impl Default for TimerList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iterator for TimerList {
+    type Item = Box<Timer>;
+
+    fn next(&mut self) -> Option<Box<Timer>> {
+        self.pop_front()
+    }
+}
+
+impl Drop for TimerList {
+    fn drop(&mut self) {
+        // Avoid a recursive drop of the whole chain
+        while self.pop_front().is_some() {}
+    }
+}
+
+/// A hierarchical timing wheel, in the style of the classic Linux timer wheel.
+///
+/// Level `n` has 64 slots each spanning `64^n` ticks. A timer is placed in the lowest level
+/// where its deadline and the current tick only differ within that level's slot index, and is
+/// cascaded down one level at a time as the wheel turns, until it expires from level 0
+pub struct TimerWheel {
+    now: u64,
+    levels: [[TimerList; SLOTS]; LEVELS],
+    overflow: TimerList,
+    len: usize,
+}
+
+impl TimerWheel {
+    pub const fn new(now: u64) -> Self {
+        Self {
+            now,
+            levels: [const { [const { TimerList::new() }; SLOTS] }; LEVELS],
+            overflow: TimerList::new(),
+            len: 0,
+        }
+    }
+
+    /// The last tick the wheel was advanced to
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Number of timers in the wheel, including cancelled ones which haven't been reached yet
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds a timer to the wheel.
+    ///
+    /// Timers whose deadline has already passed expire on the next tick
+    pub fn insert(&mut self, mut timer: Box<Timer>) {
+        timer.deadline = timer.deadline.max(self.now + 1);
+        self.place(timer);
+    }
+
+    /// Puts a timer in the slot for its deadline, which mustn't be before the current tick.
+    ///
+    /// Unlike `insert`, a deadline on the current tick is kept: cascading runs before level 0
+    /// of that tick is emptied, so such timers still expire on time
+    fn place(&mut self, timer: Box<Timer>) {
+        let diff = timer.deadline ^ self.now;
+        let level = (0..LEVELS).find(|&level| diff >> (SLOT_BITS * (level as u32 + 1)) == 0);
+        let list = match level {
+            Some(level) => &mut self.levels[level][slot_index(timer.deadline, level)],
+            None => &mut self.overflow,
+        };
+        list.push_front(timer);
+        self.len += 1;
+    }
+
+    fn reinsert(&mut self, list: TimerList) {
+        for timer in list {
+            self.len -= 1;
+            self.place(timer);
+        }
+    }
+
+    /// Advances the wheel up to the tick `now`, returning the expired timers ordered by deadline
+    pub fn advance(&mut self, now: u64) -> TimerList {
+        let mut expired = TimerList::new();
+
+        while self.now < now {
+            self.now += 1;
+            let tick = self.now;
+
+            if tick & ((1 << (SLOT_BITS * LEVELS as u32)) - 1) == 0 {
+                let overflow = self.overflow.take_all();
+                self.reinsert(overflow);
+            }
+            for level in (1..LEVELS).rev() {
+                if tick & ((1 << (SLOT_BITS * level as u32)) - 1) == 0 {
+                    let slot = self.levels[level][slot_index(tick, level)].take_all();
+                    self.reinsert(slot);
+                }
+            }
+
+            for timer in self.levels[0][slot_index(tick, 0)].take_all() {
+                self.len -= 1;
+                expired.push_front(timer);
+            }
+        }
+
+        expired.reverse();
+        expired
+    }
+}
+
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new(0));
+
+/// Advances the kernel's timer wheel to the tick `now`, running every expired timer.
+///
+/// Called from the timer interrupt handler
+pub(crate) fn run(now: u64) {
+    let expired = WHEEL.lock().advance(now);
+
+    for mut timer in expired {
+        if timer.is_cancelled() {
+            continue;
+        }
+        timer.fire();
+        if let Some(period) = timer.period {
+            timer.deadline += period;
+            WHEEL.lock().insert(timer);
+        }
+    }
+}
+
+/// Adds a timer to the kernel's timer wheel
+pub fn schedule(timer: Box<Timer>) -> TimerHandle {
+    let handle = timer.handle();
+    interrupts::without_interrupts(|| WHEEL.lock().insert(timer));
+    handle
+}
+
+/// Calls `f` once, from the timer interrupt, `delay` ticks from now
+pub fn after_ticks(delay: u64, f: impl FnOnce() + Send + 'static) -> TimerHandle {
+    let mut f = Some(f);
+    let callback = move || {
+        if let Some(f) = f.take() {
+            f()
+        }
+    };
+    schedule(Timer::new(
+        ticks() + delay,
+        Action::Callback(Box::new(callback)),
+    ))
+}
+
+/// Calls `f` from the timer interrupt every `period` ticks
+pub fn every_ticks(period: u64, f: impl FnMut() + Send + 'static) -> TimerHandle {
+    schedule(Timer::new(ticks() + period, Action::Callback(Box::new(f))).periodic(period))
+}
+
+/// Calls `f` once, from the timer interrupt, after `delay`
+pub fn after(delay: Duration, f: impl FnOnce() + Send + 'static) -> TimerHandle {
+    after_ticks(duration_to_ticks(delay), f)
+}
+
+/// Calls `f` from the timer interrupt every `period`
+pub fn every(period: Duration, f: impl FnMut() + Send + 'static) -> TimerHandle {
+    every_ticks(duration_to_ticks(period), f)
+}
+
+/// A point in the future, in ticks, used to bound blocking operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline {
+    tick: u64,
+}
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Self::at_tick(ticks() + duration_to_ticks(timeout))
+    }
+
+    pub fn at_tick(tick: u64) -> Self {
+        Self { tick }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn has_passed(&self) -> bool {
+        ticks() >= self.tick
+    }
+}
+
+/// A future which completes once a deadline has passed
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Deadline,
+    timer: Option<TimerHandle>,
+}
+
+impl Sleep {
+    pub fn until(deadline: Deadline) -> Self {
+        Self {
+            deadline,
+            timer: None,
+        }
+    }
+
+    pub fn deadline(&self) -> Deadline {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.deadline.has_passed() {
+            return Poll::Ready(());
+        }
+
+        if let Some(timer) = self.timer.take() {
+            timer.cancel();
+        }
+        let timer = Timer::new(self.deadline.tick, Action::Wake(cx.waker().clone()));
+        self.timer = Some(schedule(timer));
+
+        // The deadline may have passed while the timer was being registered
+        if self.deadline.has_passed() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = &self.timer {
+            timer.cancel();
+        }
+    }
+}
+
+/// Waits for `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::until(Deadline::after(duration))
+}
+
+/// The error returned by `Timeout` when its deadline passes first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// A future which fails with `Elapsed` if the inner future doesn't complete before a deadline
+#[derive(Debug)]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `future` is structurally pinned and never moved out of `self`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// Bounds `future` to complete within `duration`
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
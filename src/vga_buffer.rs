@@ -1,11 +1,17 @@
 use core::fmt::Write;
 use core::{fmt, ops::DerefMut};
 
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 use volatile::Volatile;
-use x86_64::instructions::interrupts;
 use x86_64::instructions::port::Port;
 
+use crate::sync::{IrqSpinLock, LockClass, RawIrqSpinLock};
+
+pub mod snapshot;
+
+#[cfg(test)]
+use snapshot::Golden;
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -52,20 +58,20 @@ struct ScreenChar {
 }
 
 impl ScreenChar {
-    /// A space with default coloring
-    fn blank() -> Self {
+    /// A space, which shows the background color
+    fn blank(color_code: ColorCode) -> Self {
         Self {
             ascii_character: b' ',
-            color_code: ColorCode::default(),
+            color_code,
         }
     }
-    /// A null character with default coloring, used to denote the end of a line
+    /// A null character, used to denote the end of a line
     ///
     /// If you are not trying to denote the end of a line, consider using `blank`
-    fn null() -> Self {
+    fn null(color_code: ColorCode) -> Self {
         Self {
             ascii_character: b'\0',
-            color_code: ColorCode::default(),
+            color_code,
         }
     }
 }
@@ -85,20 +91,42 @@ pub struct VgaWriter {
     buffer: &'static mut VgaBuffer,
 }
 
+static VGA_WRITER: Lazy<IrqSpinLock<VgaWriter>> = Lazy::new(|| {
+    let mut w = VgaWriter {
+        column_position: 0,
+        color_code: ColorCode::default(),
+        buffer: unsafe { &mut *(0xb8000 as *mut VgaBuffer) },
+    };
+    w.clear();
+    IrqSpinLock::from_raw(RawIrqSpinLock::with_class(LockClass::new("VGA_WRITER")), w)
+});
+
 impl VgaWriter {
     pub fn lock() -> impl DerefMut<Target = Self> {
-        static VGA_WRITER: Lazy<Mutex<VgaWriter>> = Lazy::new(|| {
-            let mut w = VgaWriter {
-                column_position: 0,
-                color_code: ColorCode::default(),
-                buffer: unsafe { &mut *(0xb8000 as *mut VgaBuffer) },
-            };
-            w.clear();
-            Mutex::new(w)
-        });
-
         VGA_WRITER.lock()
     }
+
+    /// Releases the lock of the global writer, which a panic may have abandoned while it was
+    /// held
+    ///
+    /// # Safety
+    /// Whoever held the lock must never run again
+    pub(crate) unsafe fn force_unlock() {
+        unsafe { VGA_WRITER.raw().force_release() }
+    }
+
+    /// A writer which bypasses the lock of the global writer, for printing from the panic
+    /// handler even if the lock is held.
+    ///
+    /// # Safety
+    /// Nothing else may write to the screen while the returned writer is in use
+    pub unsafe fn steal() -> Self {
+        VgaWriter {
+            column_position: 0,
+            color_code: ColorCode::default(),
+            buffer: unsafe { &mut *(0xb8000 as *mut VgaBuffer) },
+        }
+    }
     pub fn set_colors(&mut self, foreground: Color, background: Color) {
         self.color_code = ColorCode::new(foreground, background);
     }
@@ -128,7 +156,8 @@ impl VgaWriter {
 
     fn new_line(&mut self) {
         if self.column_position < BUFFER_WIDTH {
-            self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(ScreenChar::null());
+            self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position]
+                .write(ScreenChar::null(self.color_code));
         }
         self.scroll(-1);
         self.column_position = 0;
@@ -177,12 +206,12 @@ impl VgaWriter {
         for x in 0..BUFFER_WIDTH {
             self.clear_char(row, x);
         }
-        self.set_char(row, 0, ScreenChar::null());
+        self.set_char(row, 0, ScreenChar::null(self.color_code));
     }
 
     #[inline]
     pub fn clear_char(&mut self, row: usize, col: usize) {
-        self.set_char(row, col, ScreenChar::blank())
+        self.set_char(row, col, ScreenChar::blank(self.color_code))
     }
 
     #[inline]
@@ -203,7 +232,7 @@ impl VgaWriter {
                 .unwrap_or(BUFFER_WIDTH);
         } else {
             self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position - 1]
-                .write(ScreenChar::blank());
+                .write(ScreenChar::blank(self.color_code));
             self.column_position -= 1;
         }
     }
@@ -280,9 +309,7 @@ macro_rules! vga_println {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    interrupts::without_interrupts(|| {
-        VgaWriter::lock().write_fmt(args).unwrap();
-    })
+    VgaWriter::lock().write_fmt(args).unwrap();
 }
 
 #[test_case]
@@ -296,13 +323,54 @@ fn test_vga_println() {
 fn test_vga_println_output() {
     let s = "Some test string that fits on a single line";
 
-    interrupts::without_interrupts(|| {
-        // Keep the writer locked to avoid an interrupt deadlock
-        let mut writer = VgaWriter::lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
-    });
+    // Keep the writer locked so no interrupt handler prints in between
+    let mut writer = VgaWriter::lock();
+    writeln!(writer, "\n{}", s).expect("writeln failed");
+    writer.snapshot().assert_matches(&Golden::bottom(&[s, ""]));
+}
+
+#[test_case]
+fn test_vga_scrolling() {
+    let mut writer = VgaWriter::lock();
+    writer.clear();
+    write!(writer, "\none\ntwo\nthree").unwrap();
+    writer
+        .snapshot()
+        .assert_matches(&Golden::bottom(&["", "one", "two", "three"]));
+
+    writer.scroll(-2);
+    writer
+        .snapshot()
+        .assert_matches(&Golden::bottom(&["two", "three", "", ""]));
+}
+
+#[test_case]
+fn test_vga_backspace_across_lines() {
+    let mut writer = VgaWriter::lock();
+    writer.clear();
+    // The third backspace goes back to the end of the previous line
+    write!(writer, "\nab\ncd\x08\x08\x08X").unwrap();
+    writer
+        .snapshot()
+        .assert_matches(&Golden::bottom(&["", "abX"]));
+}
+
+#[test_case]
+fn test_vga_colors() {
+    let mut writer = VgaWriter::lock();
+    writeln!(writer).unwrap();
+    writer.set_colors(Color::Yellow, Color::Blue);
+    write!(writer, "warn").unwrap();
+    writer.set_colors(Color::White, Color::Black);
+    write!(writer, " ok").unwrap();
+
+    writer
+        .snapshot()
+        .assert_matches(&Golden::bottom(&["warn ok"]).with_colors(
+            &["yyyywww"],
+            &[
+                ('y', Color::Yellow, Color::Blue),
+                ('w', Color::White, Color::Black),
+            ],
+        ));
 }
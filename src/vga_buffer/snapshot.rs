@@ -0,0 +1,244 @@
+//! Helpers for asserting on the contents of the screen in tests.
+//!
+//! A `Snapshot` is a plain copy of the VGA buffer, which is compared against a `Golden`: the
+//! expected text of a range of rows, optionally with the expected colors of each cell. On a
+//! mismatch, a row by row diff is printed over serial before panicking.
+
+use core::fmt;
+
+use super::{Color, ColorCode, VgaWriter, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::serial_println;
+
+const COLORS: [Color; 16] = [
+    Color::Black,
+    Color::Blue,
+    Color::Green,
+    Color::Cyan,
+    Color::Red,
+    Color::Magenta,
+    Color::Brown,
+    Color::LightGray,
+    Color::DarkGray,
+    Color::LightBlue,
+    Color::LightGreen,
+    Color::LightCyan,
+    Color::LightRed,
+    Color::Pink,
+    Color::Yellow,
+    Color::White,
+];
+
+impl ColorCode {
+    fn colors(self) -> (Color, Color) {
+        (
+            COLORS[(self.0 & 0xf) as usize],
+            COLORS[(self.0 >> 4) as usize],
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub character: char,
+    pub foreground: Color,
+    pub background: Color,
+}
+
+/// A copy of the whole screen
+#[derive(Clone)]
+pub struct Snapshot {
+    cells: [[Cell; BUFFER_WIDTH]; BUFFER_HEIGHT],
+}
+
+impl VgaWriter {
+    pub fn snapshot(&self) -> Snapshot {
+        let blank = Cell {
+            character: ' ',
+            foreground: Color::White,
+            background: Color::Black,
+        };
+        let mut snapshot = Snapshot {
+            cells: [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        };
+        for (row, chars) in self.buffer.chars.iter().enumerate() {
+            for (col, c) in chars.iter().enumerate() {
+                let c = c.read();
+                let (foreground, background) = c.color_code.colors();
+                snapshot.cells[row][col] = Cell {
+                    // The null character marks the end of a line, but shows as a blank
+                    character: match c.ascii_character {
+                        b'\0' => ' ',
+                        byte => char::from(byte),
+                    },
+                    foreground,
+                    background,
+                };
+            }
+        }
+        snapshot
+    }
+}
+
+impl Snapshot {
+    pub const HEIGHT: usize = BUFFER_HEIGHT;
+    pub const WIDTH: usize = BUFFER_WIDTH;
+
+    pub fn cell(&self, row: usize, col: usize) -> Cell {
+        self.cells[row][col]
+    }
+
+    /// The text of `row`, without trailing blanks
+    pub fn row_text(&self, row: usize) -> RowText<'_> {
+        let cells = &self.cells[row];
+        let len = cells
+            .iter()
+            .rposition(|c| c.character != ' ')
+            .map_or(0, |last| last + 1);
+        RowText(&cells[..len])
+    }
+
+    pub fn matches(&self, golden: &Golden) -> bool {
+        (0..golden.rows.len()).all(|i| self.row_matches(golden, i))
+    }
+
+    /// Panics if the screen doesn't match `golden`, after printing a diff over serial
+    #[track_caller]
+    pub fn assert_matches(&self, golden: &Golden) {
+        if self.matches(golden) {
+            return;
+        }
+
+        serial_println!("\nscreen doesn't match (! marks differing rows):");
+        serial_println!("    row | expected / actual");
+        for i in 0..golden.rows.len() {
+            let row = golden.first_row + i;
+            let marker = if self.row_matches(golden, i) {
+                ' '
+            } else {
+                '!'
+            };
+            serial_println!("  {} {:>3} | {:?}", marker, row, golden.rows[i]);
+            serial_println!("        | {:?}", self.row_text(row));
+            if let Some(colors) = golden.colors.get(i) {
+                serial_println!("        | colors {:?}", colors);
+                serial_println!("        | colors {:?}", ColorRow(self, row, golden.legend));
+            }
+        }
+        panic!("screen doesn't match the expected contents");
+    }
+
+    fn row_matches(&self, golden: &Golden, i: usize) -> bool {
+        let row = golden.first_row + i;
+        let text_matches = {
+            let mut actual = self.row_text(row).0.iter().map(|c| c.character);
+            let mut expected = golden.rows[i].chars();
+            loop {
+                match (actual.next(), expected.next()) {
+                    (None, None) => break true,
+                    // Trailing blanks in the expected text don't matter
+                    (None, Some(e)) => break e == ' ' && expected.all(|e| e == ' '),
+                    (Some(a), e) if Some(a) != e => break false,
+                    _ => {}
+                }
+            }
+        };
+
+        let colors_match = golden.colors.get(i).is_none_or(|colors| {
+            colors.chars().enumerate().all(|(col, annotation)| {
+                let cell = self.cells[row][col];
+                match golden.color(annotation) {
+                    Some(expected) => expected == (cell.foreground, cell.background),
+                    // Unknown annotations, such as spaces and dots, match any colors
+                    None => true,
+                }
+            })
+        });
+
+        text_matches && colors_match
+    }
+}
+
+/// The text of a row
+pub struct RowText<'a>(&'a [Cell]);
+
+impl fmt::Display for RowText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|c| fmt::Write::write_char(f, c.character))
+    }
+}
+
+impl fmt::Debug for RowText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// The colors of a row, annotated through a legend
+struct ColorRow<'a>(&'a Snapshot, usize, &'a [(char, Color, Color)]);
+
+impl fmt::Debug for ColorRow<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ColorRow(snapshot, row, legend) = *self;
+        let len = snapshot.row_text(row).0.len();
+        fmt::Write::write_char(f, '"')?;
+        for cell in &snapshot.cells[row][..len] {
+            let annotation = legend
+                .iter()
+                .find(|&&(_, fg, bg)| (fg, bg) == (cell.foreground, cell.background))
+                .map_or('?', |&(annotation, _, _)| annotation);
+            fmt::Write::write_char(f, annotation)?;
+        }
+        fmt::Write::write_char(f, '"')
+    }
+}
+
+/// The expected contents of consecutive rows of the screen
+#[derive(Debug, Clone, Copy)]
+pub struct Golden<'a> {
+    first_row: usize,
+    rows: &'a [&'a str],
+    colors: &'a [&'a str],
+    legend: &'a [(char, Color, Color)],
+}
+
+impl<'a> Golden<'a> {
+    /// The expected text of the rows starting at `first_row`. Trailing blanks don't matter
+    pub const fn new(first_row: usize, rows: &'a [&'a str]) -> Self {
+        assert!(first_row + rows.len() <= BUFFER_HEIGHT);
+        Self {
+            first_row,
+            rows,
+            colors: &[],
+            legend: &[],
+        }
+    }
+
+    /// The expected text of the rows at the bottom of the screen
+    pub const fn bottom(rows: &'a [&'a str]) -> Self {
+        Self::new(BUFFER_HEIGHT - rows.len(), rows)
+    }
+
+    /// Adds the expected colors, as one annotation character per cell for each row.
+    /// `legend` maps annotations to a foreground and background color, and cells annotated
+    /// with any other character (such as a space or a dot) may have any colors
+    pub const fn with_colors(
+        self,
+        colors: &'a [&'a str],
+        legend: &'a [(char, Color, Color)],
+    ) -> Self {
+        Self {
+            colors,
+            legend,
+            ..self
+        }
+    }
+
+    fn color(&self, annotation: char) -> Option<(Color, Color)> {
+        self.legend
+            .iter()
+            .find(|&&(a, _, _)| a == annotation)
+            .map(|&(_, fg, bg)| (fg, bg))
+    }
+}
@@ -0,0 +1,377 @@
+//! The virtio PCI transport, shared by the virtio device drivers.
+//!
+//! Virtio devices come in two flavours: legacy devices, with all their registers in an I/O
+//! BAR, and modern (virtio 1.0) devices, which describe where their register blocks are in
+//! vendor specific PCI capabilities. QEMU's transitional devices offer both, in which case
+//! the modern interface is used. Either way, the driver negotiates features, sets up its
+//! virtqueues and reports itself ready through the device status.
+//!
+//! MSI-X isn't used, so devices interrupt through their legacy line, and the ISR status
+//! register tells whether they did.
+
+use core::ptr;
+
+use x86_64::{instructions::port::Port, VirtAddr};
+
+use crate::{
+    dma::DmaError,
+    memory,
+    pci::{capability::ID_VENDOR_SPECIFIC, Bar, DeviceId, PciDevice},
+};
+
+pub mod queue;
+
+pub use queue::{Buffer, VirtQueue};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+/// The ids of the legacy and modern devices of each type: transitional devices use
+/// `0x1000 + n`, modern only ones `0x1040 + type`
+pub const fn device_ids(legacy: u16, device_type: u16) -> [DeviceId; 2] {
+    [
+        DeviceId::new(VENDOR_ID, legacy),
+        DeviceId::new(VENDOR_ID, 0x1040 + device_type),
+    ]
+}
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+/// The device conforms to virtio 1.0, which modern devices require the driver to accept
+pub const F_VERSION_1: u64 = 1 << 32;
+
+/// Set in the ISR status when a virtqueue was used
+pub const ISR_QUEUE: u8 = 1 << 0;
+/// Set in the ISR status when the device configuration changed
+pub const ISR_CONFIG: u8 = 1 << 1;
+
+// Legacy registers, as offsets in the I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0;
+const LEGACY_DRIVER_FEATURES: u16 = 4;
+const LEGACY_QUEUE_PFN: u16 = 8;
+const LEGACY_QUEUE_SIZE: u16 = 12;
+const LEGACY_QUEUE_SELECT: u16 = 14;
+const LEGACY_QUEUE_NOTIFY: u16 = 16;
+const LEGACY_STATUS: u16 = 18;
+const LEGACY_ISR: u16 = 19;
+/// Where the device specific configuration starts, without MSI-X
+const LEGACY_DEVICE_CONFIG: u16 = 20;
+
+// The configuration structure types of modern devices
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_ISR: u8 = 3;
+const CFG_DEVICE: u8 = 4;
+
+// Fields of the common configuration structure
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0;
+const COMMON_DEVICE_FEATURE: usize = 4;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 8;
+const COMMON_DRIVER_FEATURE: usize = 12;
+const COMMON_STATUS: usize = 20;
+const COMMON_QUEUE_SELECT: usize = 22;
+const COMMON_QUEUE_SIZE: usize = 24;
+const COMMON_QUEUE_ENABLE: usize = 28;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 30;
+const COMMON_QUEUE_DESC: usize = 32;
+const COMMON_QUEUE_DRIVER: usize = 40;
+const COMMON_QUEUE_DEVICE: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// Neither a usable I/O BAR nor the modern capabilities were found
+    NoTransport,
+    /// The device didn't accept the features the driver selected
+    FeaturesRejected,
+    /// The device doesn't have the requested virtqueue
+    NoQueue(u16),
+    /// The device has no device specific configuration
+    NoDeviceConfig,
+    /// There was no memory for a virtqueue
+    Dma(DmaError),
+}
+
+impl From<DmaError> for VirtioError {
+    fn from(err: DmaError) -> Self {
+        VirtioError::Dma(err)
+    }
+}
+
+/// How the registers of a device are reached
+#[derive(Debug)]
+pub enum Transport {
+    Legacy {
+        io: u16,
+    },
+    Modern {
+        common: VirtAddr,
+        notify: VirtAddr,
+        notify_multiplier: u32,
+        isr: VirtAddr,
+        /// Missing for devices without device specific configuration
+        device: Option<VirtAddr>,
+    },
+}
+
+impl Transport {
+    /// Finds the registers of `device`, preferring the modern interface, and enables it for
+    /// DMA.
+    ///
+    /// Needs the memory module, to reach memory BARs
+    pub fn new(device: &PciDevice) -> Result<Self, VirtioError> {
+        device.enable();
+        Self::modern(device)
+            .or_else(|| match device.bars[0] {
+                // Only transitional devices have the legacy interface
+                Some(Bar::Io { port, .. }) if device.device_id < 0x1040 => {
+                    Some(Transport::Legacy { io: port })
+                }
+                _ => None,
+            })
+            .ok_or(VirtioError::NoTransport)
+    }
+
+    fn modern(device: &PciDevice) -> Option<Self> {
+        let (mut common, mut notify, mut isr, mut config) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        for capability in device.capabilities() {
+            if capability.id != ID_VENDOR_SPECIFIC {
+                continue;
+            }
+            let offset = u16::from(capability.offset);
+            let cfg_type = device.address.read_u8(offset + 3);
+            let bar = device.address.read_u8(offset + 4);
+            let bar_offset = device.address.read_u32(offset + 8);
+            let address = match device.bars.get(usize::from(bar)).copied().flatten() {
+                Some(Bar::Memory { address, .. }) => {
+                    memory::phys_to_virt(address + u64::from(bar_offset))
+                }
+                _ => None,
+            };
+            // The first structure of each type is the preferred one
+            match cfg_type {
+                CFG_COMMON => common = common.or(address),
+                CFG_NOTIFY if notify.is_none() => {
+                    notify = address;
+                    notify_multiplier = device.address.read_u32(offset + 16);
+                }
+                CFG_ISR => isr = isr.or(address),
+                CFG_DEVICE => config = config.or(address),
+                _ => {}
+            }
+        }
+        Some(Transport::Modern {
+            common: common?,
+            notify: notify?,
+            notify_multiplier,
+            isr: isr?,
+            device: config,
+        })
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Transport::Legacy { .. })
+    }
+
+    fn io<T>(io: u16, register: u16) -> Port<T> {
+        Port::new(io + register)
+    }
+
+    fn common<T: Copy>(common: VirtAddr, field: usize) -> *mut T {
+        (common + field as u64).as_mut_ptr()
+    }
+
+    pub fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io } => unsafe { Self::io(io, LEGACY_STATUS).read() },
+            Transport::Modern { common, .. } => unsafe {
+                ptr::read_volatile(Self::common(common, COMMON_STATUS))
+            },
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { io } => unsafe { Self::io(io, LEGACY_STATUS).write(status) },
+            Transport::Modern { common, .. } => unsafe {
+                ptr::write_volatile(Self::common(common, COMMON_STATUS), status)
+            },
+        }
+    }
+
+    /// Resets the device, then acknowledges it and announces the driver
+    pub fn reset(&self) {
+        self.set_status(0);
+        // Modern devices finish resetting when the status reads back as 0
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    }
+
+    pub fn device_features(&self) -> u64 {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                u64::from(Self::io::<u32>(io, LEGACY_DEVICE_FEATURES).read())
+            },
+            Transport::Modern { common, .. } => (0..2).fold(0, |features, half| unsafe {
+                ptr::write_volatile(Self::common(common, COMMON_DEVICE_FEATURE_SELECT), half);
+                let bits: u32 = ptr::read_volatile(Self::common(common, COMMON_DEVICE_FEATURE));
+                features | u64::from(bits) << (32 * half)
+            }),
+        }
+    }
+
+    /// Accepts the features in `wanted` the device offers, returning them.
+    ///
+    /// Modern devices also get `F_VERSION_1`, and must accept the selection
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError> {
+        let features = match *self {
+            Transport::Legacy { io } => {
+                let features = self.device_features() & wanted & u64::from(u32::MAX);
+                unsafe { Self::io(io, LEGACY_DRIVER_FEATURES).write(features as u32) };
+                features
+            }
+            Transport::Modern { common, .. } => {
+                let features = self.device_features() & (wanted | F_VERSION_1);
+                for half in 0..2 {
+                    unsafe {
+                        ptr::write_volatile(
+                            Self::common(common, COMMON_DRIVER_FEATURE_SELECT),
+                            half,
+                        );
+                        ptr::write_volatile(
+                            Self::common(common, COMMON_DRIVER_FEATURE),
+                            (features >> (32 * half)) as u32,
+                        );
+                    }
+                }
+                features
+            }
+        };
+
+        if !self.is_legacy() {
+            self.set_status(self.status() | STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.set_status(self.status() | STATUS_FAILED);
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Creates virtqueue `index`, with at most `max_size` entries, and hands it to the device
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<VirtQueue, VirtioError> {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                Self::io(io, LEGACY_QUEUE_SELECT).write(index);
+                // The size of legacy queues is set by the device
+                let size: u16 = Self::io(io, LEGACY_QUEUE_SIZE).read();
+                if size == 0 {
+                    return Err(VirtioError::NoQueue(index));
+                }
+                let queue = VirtQueue::new(index, size, true, Self::io(io, LEGACY_QUEUE_NOTIFY))?;
+                let pfn = queue.descriptors().as_u64() >> 12;
+                Self::io::<u32>(io, LEGACY_QUEUE_PFN).write(pfn as u32);
+                Ok(queue)
+            },
+            Transport::Modern {
+                common,
+                notify,
+                notify_multiplier,
+                ..
+            } => unsafe {
+                ptr::write_volatile(Self::common(common, COMMON_QUEUE_SELECT), index);
+                let size: u16 = ptr::read_volatile(Self::common(common, COMMON_QUEUE_SIZE));
+                if size == 0 {
+                    return Err(VirtioError::NoQueue(index));
+                }
+                let size = size.min(max_size);
+                let notify_off: u16 =
+                    ptr::read_volatile(Self::common(common, COMMON_QUEUE_NOTIFY_OFF));
+                let doorbell = notify + u64::from(notify_off) * u64::from(notify_multiplier);
+                let queue = VirtQueue::new(index, size, false, doorbell)?;
+
+                ptr::write_volatile(Self::common(common, COMMON_QUEUE_SIZE), size);
+                let addresses = [
+                    (COMMON_QUEUE_DESC, queue.descriptors()),
+                    (COMMON_QUEUE_DRIVER, queue.available()),
+                    (COMMON_QUEUE_DEVICE, queue.used()),
+                ];
+                for (field, address) in addresses {
+                    ptr::write_volatile(Self::common::<u64>(common, field), address.as_u64());
+                }
+                ptr::write_volatile(Self::common(common, COMMON_QUEUE_ENABLE), 1u16);
+                Ok(queue)
+            },
+        }
+    }
+
+    /// Tells the device the driver is ready, after its queues are set up
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Reads and acknowledges the ISR status, which is 0 if the device didn't interrupt
+    pub fn isr_status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io } => unsafe { Self::io(io, LEGACY_ISR).read() },
+            Transport::Modern { isr, .. } => unsafe { ptr::read_volatile(isr.as_ptr()) },
+        }
+    }
+
+    /// Reads the field at `offset` in the device specific configuration
+    pub fn read_config<T: Copy>(&self, offset: usize) -> Result<T, VirtioError> {
+        match *self {
+            // The legacy configuration is read a byte at a time, since fields needn't be aligned
+            Transport::Legacy { io } => {
+                let mut value = core::mem::MaybeUninit::<T>::uninit();
+                let bytes = value.as_mut_ptr() as *mut u8;
+                for i in 0..core::mem::size_of::<T>() {
+                    let port = io + LEGACY_DEVICE_CONFIG + (offset + i) as u16;
+                    unsafe { bytes.add(i).write(Port::<u8>::new(port).read()) };
+                }
+                Ok(unsafe { value.assume_init() })
+            }
+            Transport::Modern { device, .. } => {
+                let device = device.ok_or(VirtioError::NoDeviceConfig)?;
+                Ok(unsafe { ptr::read_volatile((device + offset as u64).as_ptr()) })
+            }
+        }
+    }
+}
+
+/// Where the device is notified of new buffers in a queue
+#[derive(Debug)]
+pub enum Doorbell {
+    Port(Port<u16>),
+    Mmio(VirtAddr),
+}
+
+impl From<Port<u16>> for Doorbell {
+    fn from(port: Port<u16>) -> Self {
+        Doorbell::Port(port)
+    }
+}
+
+impl From<VirtAddr> for Doorbell {
+    fn from(address: VirtAddr) -> Self {
+        Doorbell::Mmio(address)
+    }
+}
+
+impl Doorbell {
+    fn ring(&mut self, queue: u16) {
+        match self {
+            Doorbell::Port(port) => unsafe { port.write(queue) },
+            Doorbell::Mmio(address) => unsafe {
+                ptr::write_volatile(address.as_mut_ptr::<u16>(), queue)
+            },
+        }
+    }
+}
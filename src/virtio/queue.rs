@@ -0,0 +1,253 @@
+//! Split virtqueues, through which drivers hand buffers to virtio devices.
+//!
+//! A split queue is three arrays in memory shared with the device: the descriptor table,
+//! where chains of descriptors each describe a request's buffers, the available ring, where
+//! the driver puts the heads of chains for the device, and the used ring, where the device
+//! puts them back once it is done with them, along with how much it wrote.
+//!
+//! The arrays live in a `DmaBuffer`, and so do the buffers the descriptors point to, since
+//! the device only sees physical addresses. Queues aren't locked: drivers wrap them in an
+//! `IrqSpinLock` when their interrupt handler pops used chains.
+
+use core::{
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use x86_64::PhysAddr;
+
+use super::Doorbell;
+use crate::dma::{DmaBuffer, DmaError, PAGE_SIZE};
+
+/// Continues the chain in `next`
+const DESC_F_NEXT: u16 = 1;
+/// The device writes to the buffer, instead of reading it
+const DESC_F_WRITE: u16 = 2;
+
+const DESCRIPTOR_SIZE: usize = 16;
+/// The flags and index before the entries of both rings
+const RING_HEADER_SIZE: usize = 4;
+
+/// A buffer in a chain handed to the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub len: u32,
+    /// Whether the device writes to the buffer, instead of reading it
+    pub device_writable: bool,
+}
+
+impl Buffer {
+    /// A buffer for the device to read from
+    pub fn readable(address: PhysAddr, len: u32) -> Self {
+        Self {
+            address,
+            len,
+            device_writable: false,
+        }
+    }
+
+    /// A buffer for the device to write to
+    pub fn writable(address: PhysAddr, len: u32) -> Self {
+        Self {
+            address,
+            len,
+            device_writable: true,
+        }
+    }
+}
+
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    available_offset: usize,
+    used_offset: usize,
+    /// The first descriptor of the free list, which is chained through `next`
+    free_head: u16,
+    free_count: u16,
+    /// Where the driver puts the next chain in the available ring
+    available_index: u16,
+    /// The entry of the used ring after the last one popped
+    last_used: u16,
+    doorbell: Doorbell,
+}
+
+impl VirtQueue {
+    /// Allocates queue `index` with `size` entries, which must be a power of 2.
+    ///
+    /// Legacy devices expect the used ring on the page after the available ring
+    pub(super) fn new(
+        index: u16,
+        size: u16,
+        legacy: bool,
+        doorbell: impl Into<Doorbell>,
+    ) -> Result<Self, DmaError> {
+        let entries = usize::from(size);
+        let available_offset = DESCRIPTOR_SIZE * entries;
+        let available_end = available_offset + RING_HEADER_SIZE + 2 * entries + 2;
+        let used_offset = available_end.next_multiple_of(if legacy { PAGE_SIZE } else { 4 });
+        let used_end = used_offset + RING_HEADER_SIZE + 8 * entries + 2;
+
+        let mut queue = Self {
+            index,
+            size,
+            memory: DmaBuffer::new(used_end)?,
+            available_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            available_index: 0,
+            last_used: 0,
+            doorbell: doorbell.into(),
+        };
+        for descriptor in 0..size {
+            queue.set_next(descriptor, descriptor.wrapping_add(1));
+        }
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// How many descriptors are free, which bounds the length of the next chain
+    pub fn free_count(&self) -> u16 {
+        self.free_count
+    }
+
+    /// The physical address of the descriptor table
+    pub fn descriptors(&self) -> PhysAddr {
+        self.memory.phys()
+    }
+
+    /// The physical address of the available ring
+    pub fn available(&self) -> PhysAddr {
+        self.memory.phys_at(self.available_offset)
+    }
+
+    /// The physical address of the used ring
+    pub fn used(&self) -> PhysAddr {
+        self.memory.phys_at(self.used_offset)
+    }
+
+    fn field<T>(&self, offset: usize) -> *mut T {
+        // The memory is shared with the device, so it is only ever accessed with volatile
+        // reads and writes through pointers
+        unsafe { self.memory.as_mut_ptr().add(offset).cast() }
+    }
+
+    fn descriptor<T>(&self, descriptor: u16, field: usize) -> *mut T {
+        self.field(usize::from(descriptor) * DESCRIPTOR_SIZE + field)
+    }
+
+    fn next(&self, descriptor: u16) -> u16 {
+        unsafe { ptr::read_volatile(self.descriptor(descriptor, 14)) }
+    }
+
+    fn set_next(&mut self, descriptor: u16, next: u16) {
+        unsafe { ptr::write_volatile(self.descriptor(descriptor, 14), next) }
+    }
+
+    fn flags(&self, descriptor: u16) -> u16 {
+        unsafe { ptr::read_volatile(self.descriptor(descriptor, 12)) }
+    }
+
+    /// Makes `buffers` available to the device as a chain, returning the chain's head, which
+    /// `pop_used` returns once the device is done with it.
+    ///
+    /// Returns `None` if there aren't enough free descriptors. The device only looks at the
+    /// chain once `notify` is called
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut descriptor = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut flags = if buffer.device_writable {
+                DESC_F_WRITE
+            } else {
+                0
+            };
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            let next = self.next(descriptor);
+            unsafe {
+                ptr::write_volatile(self.descriptor(descriptor, 0), buffer.address.as_u64());
+                ptr::write_volatile(self.descriptor(descriptor, 8), buffer.len);
+                ptr::write_volatile(self.descriptor(descriptor, 12), flags);
+            }
+            if i + 1 < buffers.len() {
+                descriptor = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = usize::from(self.available_index % self.size);
+        unsafe {
+            ptr::write_volatile(
+                self.field(self.available_offset + RING_HEADER_SIZE + 2 * slot),
+                head,
+            );
+        }
+        // The device must see the descriptors and the ring entry before the new index
+        fence(Ordering::Release);
+        self.available_index = self.available_index.wrapping_add(1);
+        unsafe {
+            ptr::write_volatile(self.field(self.available_offset + 2), self.available_index);
+        }
+        Some(head)
+    }
+
+    /// Tells the device there are new chains in the available ring
+    pub fn notify(&mut self) {
+        fence(Ordering::SeqCst);
+        self.doorbell.ring(self.index);
+    }
+
+    /// Takes the next chain the device is done with, returning its head and how many bytes
+    /// the device wrote to it.
+    ///
+    /// The chain's descriptors stay reserved until it is passed to `free`, so the head keeps
+    /// identifying the request until the driver is done with it
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index: u16 = unsafe { ptr::read_volatile(self.field(self.used_offset + 2)) };
+        if used_index == self.last_used {
+            return None;
+        }
+        // The entry must be read after the index
+        fence(Ordering::Acquire);
+
+        let slot = usize::from(self.last_used % self.size);
+        let entry = self.used_offset + RING_HEADER_SIZE + 8 * slot;
+        let (head, len) = unsafe {
+            let head: u32 = ptr::read_volatile(self.field(entry));
+            let len: u32 = ptr::read_volatile(self.field(entry + 4));
+            (head as u16, len)
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((head, len))
+    }
+
+    /// Returns the descriptors of the used chain starting at `head` to the free list
+    pub fn free(&mut self, head: u16) {
+        let mut last = head;
+        let mut count = 1;
+        while self.flags(last) & DESC_F_NEXT != 0 {
+            last = self.next(last);
+            count += 1;
+        }
+        self.set_next(last, self.free_head);
+        self.free_head = head;
+        self.free_count += count;
+    }
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ros::{
+    allocator,
+    block::{self, testing},
+    dma,
+    memory::{self, BootInfoFrameAllocator},
+    pci,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    dma::init(&mut frame_alloc).expect("DMA pool initialization failed");
+    pci::init();
+    block::ahci::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+#[test_case]
+fn finds_drives() {
+    let controller = pci::find(pci::DeviceId::class(0x01, 0x06)).expect("no AHCI controller");
+    // The ICH9 controller, the one built into QEMU's q35 machine
+    assert_eq!(
+        (controller.vendor_id, controller.device_id),
+        (0x8086, 0x2922)
+    );
+    assert_eq!(pci::driver_of(controller.address), Some("ahci"));
+    // A drive on each of the first three ports, named in port order
+    for name in ["sda", "sdb", "sdc"] {
+        assert!(block::get(name).is_some(), "{name} missing");
+    }
+}
+
+/// tests/data/ata.img, attached to the first port
+#[test_case]
+fn test_disk() {
+    testing::check_test_disk("sda");
+}
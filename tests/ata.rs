@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ros::{
+    allocator,
+    block::{self, testing, BlockError},
+    memory::{self, BootInfoFrameAllocator},
+    pci,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    pci::init();
+    block::ata::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+#[test_case]
+fn finds_drives() {
+    // The boot disk is the primary master
+    assert!(block::get("ata0").is_some());
+}
+
+/// tests/data/ata.img, attached as the primary slave
+#[test_case]
+fn test_disk() {
+    testing::check_test_disk("ata1");
+}
+
+#[test_case]
+fn empty_cdrom_drive() {
+    // The secondary master is an ATAPI drive without a disc
+    let cdrom = block::get("ata2").expect("cdrom drive missing");
+    assert_eq!(cdrom.block_size(), 2048);
+    assert!(cdrom.is_read_only());
+    let mut buf = vec![0; 2048];
+    assert_eq!(cdrom.read_blocks(0, &mut buf), Err(BlockError::NoMedium));
+    assert_eq!(cdrom.write_blocks(0, &buf), Err(BlockError::ReadOnly));
+}
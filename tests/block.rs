@@ -0,0 +1,338 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use ros::{
+    allocator,
+    block::{
+        self,
+        cache::BlockCache,
+        partition::{self, Guid, PartitionError, PartitionKind},
+        ramdisk::RamDisk,
+        BlockDevice, BlockError,
+    },
+    memory::{self, BootInfoFrameAllocator},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// A ramdisk counting the accesses which reach it
+struct Counting {
+    disk: RamDisk,
+    reads: AtomicU64,
+    writes: AtomicU64,
+}
+
+impl Counting {
+    fn new(block_count: u64) -> Arc<Self> {
+        let disk = RamDisk::new(512, block_count);
+        for lba in 0..block_count {
+            disk.write_blocks(lba, &[lba as u8; 512]).unwrap();
+        }
+        Arc::new(Self {
+            disk,
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+        })
+    }
+}
+
+impl BlockDevice for Counting {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk.block_count()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.disk.read_blocks(lba, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.disk.write_blocks(lba, buf)
+    }
+}
+
+#[test_case]
+fn ramdisk_reads_and_writes() {
+    let disk = RamDisk::new(512, 8);
+    assert_eq!(disk.block_count(), 8);
+    disk.write_blocks(2, &[7; 1024]).unwrap();
+    let mut buf = [0; 512];
+    disk.read_blocks(3, &mut buf).unwrap();
+    assert_eq!(buf, [7; 512]);
+    assert_eq!(disk.read_blocks(8, &mut buf), Err(BlockError::OutOfRange));
+
+    let disk = RamDisk::from_vec(512, vec![1; 100]).read_only();
+    assert_eq!(disk.block_count(), 1);
+    assert_eq!(disk.write_blocks(0, &buf), Err(BlockError::ReadOnly));
+}
+
+#[test_case]
+fn names_disks_past_z() {
+    let names: Vec<_> = [0, 25, 26, 27, 51, 701, 702]
+        .into_iter()
+        .map(|index| block::disk_name("sd", index))
+        .collect();
+    assert_eq!(
+        names,
+        ["sda", "sdz", "sdaa", "sdab", "sdaz", "sdzz", "sdaaa"]
+    );
+}
+
+#[test_case]
+fn cache_hits_and_evicts_least_recently_used() {
+    let disk = Counting::new(16);
+    let cache = BlockCache::new(disk.clone(), 4);
+    let mut buf = [0; 512];
+
+    for lba in 0..4 {
+        cache.read_blocks(lba, &mut buf).unwrap();
+        assert_eq!(buf, [lba as u8; 512]);
+    }
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 4);
+    cache.read_blocks(0, &mut buf).unwrap();
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 4);
+
+    // Block 1 is now the least recently used, so it makes room for block 4
+    cache.read_blocks(4, &mut buf).unwrap();
+    cache.read_blocks(0, &mut buf).unwrap();
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 5);
+    cache.read_blocks(1, &mut buf).unwrap();
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 6);
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (2, 6));
+}
+
+#[test_case]
+fn cache_writes_back() {
+    let disk = Counting::new(16);
+    let cache = BlockCache::new(disk.clone(), 4);
+    cache.write_blocks(10, &[0xaa; 1024]).unwrap();
+    assert_eq!(disk.writes.load(Ordering::Relaxed), 0);
+    assert_eq!(cache.dirty_count(), 2);
+
+    // Dirty blocks are read back from the cache
+    let mut buf = [0; 512];
+    cache.read_blocks(11, &mut buf).unwrap();
+    assert_eq!(buf, [0xaa; 512]);
+    assert_eq!(disk.reads.load(Ordering::Relaxed), 0);
+
+    // Evicting a dirty block writes it
+    for lba in 0..3 {
+        cache.read_blocks(lba, &mut buf).unwrap();
+    }
+    assert_eq!(disk.writes.load(Ordering::Relaxed), 1);
+    disk.disk.read_blocks(10, &mut buf).unwrap();
+    assert_eq!(buf, [0xaa; 512]);
+
+    cache.flush().unwrap();
+    assert_eq!(cache.dirty_count(), 0);
+    disk.disk.read_blocks(11, &mut buf).unwrap();
+    assert_eq!(buf, [0xaa; 512]);
+
+    // And so does dropping the cache
+    cache.write_blocks(5, &[0x55; 512]).unwrap();
+    drop(cache);
+    disk.disk.read_blocks(5, &mut buf).unwrap();
+    assert_eq!(buf, [0x55; 512]);
+}
+
+fn mbr_entry(block: &mut [u8], index: usize, kind: u8, start: u32, count: u32) {
+    let entry = &mut block[446 + 16 * index..446 + 16 * (index + 1)];
+    entry[4] = kind;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&count.to_le_bytes());
+}
+
+fn sign(block: &mut [u8]) {
+    block[510] = 0x55;
+    block[511] = 0xaa;
+}
+
+#[test_case]
+fn parses_mbr_with_logical_partitions() {
+    let mut image = vec![0; 512 * 128];
+    mbr_entry(&mut image, 0, 0x0c, 8, 16);
+    mbr_entry(&mut image, 1, 0x05, 32, 64);
+    image[446] = 0x80;
+    sign(&mut image[..512]);
+    // Two logical partitions, each after its EBR
+    let ebr = &mut image[32 * 512..33 * 512];
+    mbr_entry(ebr, 0, 0x83, 1, 10);
+    mbr_entry(ebr, 1, 0x05, 20, 30);
+    sign(ebr);
+    let ebr = &mut image[52 * 512..53 * 512];
+    mbr_entry(ebr, 0, 0x83, 2, 8);
+    sign(ebr);
+    image[60 * 512..61 * 512].fill(0x60);
+
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_vec(512, image));
+    let partitions = partition::parse(&disk).unwrap();
+    let layout: Vec<_> = partitions
+        .iter()
+        .map(|p| (p.number, p.start, p.block_count()))
+        .collect();
+    assert_eq!(layout, [(1, 8, 16), (5, 33, 10), (6, 54, 8)]);
+    assert_eq!(
+        partitions[0].kind,
+        PartitionKind::Mbr {
+            kind: 0x0c,
+            bootable: true
+        }
+    );
+
+    // Partitions are block devices of their own: block 60 is the 7th of the last one
+    let mut buf = [0; 512];
+    partitions[2].read_blocks(6, &mut buf).unwrap();
+    assert_eq!(buf, [0x60; 512]);
+    assert_eq!(
+        partitions[2].read_blocks(8, &mut buf),
+        Err(BlockError::OutOfRange)
+    );
+}
+
+fn gpt_image() -> Vec<u8> {
+    let mut image = vec![0; 512 * 64];
+    mbr_entry(&mut image, 0, 0xee, 1, 63);
+    sign(&mut image[..512]);
+
+    let entries = &mut image[2 * 512..6 * 512];
+    let entry = &mut entries[..128];
+    entry[0..16].copy_from_slice(&Guid::BASIC_DATA.0);
+    entry[16..32].copy_from_slice(&[0x11; 16]);
+    entry[32..40].copy_from_slice(&10u64.to_le_bytes());
+    entry[40..48].copy_from_slice(&19u64.to_le_bytes());
+    for (i, c) in "data".encode_utf16().enumerate() {
+        entry[56 + 2 * i..58 + 2 * i].copy_from_slice(&c.to_le_bytes());
+    }
+    // The third entry, leaving the second one unused
+    let entry = &mut entries[256..384];
+    entry[0..16].copy_from_slice(&Guid::LINUX_DATA.0);
+    entry[32..40].copy_from_slice(&30u64.to_le_bytes());
+    entry[40..48].copy_from_slice(&39u64.to_le_bytes());
+    let entries_crc = partition::crc32(&entries[..16 * 128]);
+
+    let header = &mut image[512..1024];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&1u64.to_le_bytes());
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&16u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let header_crc = partition::crc32(&header[..92]);
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+    image
+}
+
+#[test_case]
+fn parses_gpt() {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_vec(512, gpt_image()));
+    let partitions = partition::parse(&disk).unwrap();
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(
+        (
+            partitions[0].number,
+            partitions[0].start,
+            partitions[0].block_count()
+        ),
+        (1, 10, 10)
+    );
+    assert_eq!(
+        partitions[0].kind,
+        PartitionKind::Gpt {
+            kind: Guid::BASIC_DATA,
+            guid: Guid([0x11; 16]),
+            name: "data".into()
+        }
+    );
+    assert_eq!(partitions[1].number, 3);
+    assert_eq!(
+        alloc::format!("{}", Guid::LINUX_DATA),
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+    );
+}
+
+#[test_case]
+fn rejects_bad_tables() {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(512, 64));
+    assert_eq!(partition::parse(&disk).err(), Some(PartitionError::NoTable));
+
+    let mut image = gpt_image();
+    image[2 * 512 + 60] ^= 1;
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_vec(512, image));
+    assert_eq!(
+        partition::parse(&disk).err(),
+        Some(PartitionError::BadGptEntries)
+    );
+
+    let mut image = gpt_image();
+    image[512 + 40] ^= 1;
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_vec(512, image));
+    assert_eq!(
+        partition::parse(&disk).err(),
+        Some(PartitionError::BadGptHeader)
+    );
+
+    // Entry sizes which aren't 128 bytes times a power of 2, or are larger than a block, are
+    // rejected before the entries are read, even with a valid header CRC
+    for entry_size in [129u32, 1024, 0x1000_0000] {
+        let mut image = gpt_image();
+        let header = &mut image[512..1024];
+        header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+        header[16..20].fill(0);
+        let header_crc = partition::crc32(&header[..92]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_vec(512, image));
+        assert_eq!(
+            partition::parse(&disk).err(),
+            Some(PartitionError::BadGptHeader),
+            "entry size {entry_size}"
+        );
+    }
+}
+
+#[test_case]
+fn scan_registers_partitions() {
+    block::register("rd9", Arc::new(RamDisk::from_vec(512, gpt_image())));
+    assert_eq!(partition::scan("rd9"), Ok(2));
+    let data = block::get("rd9p1").expect("partition not registered");
+    assert_eq!(data.block_count(), 10);
+    assert!(block::get("rd9p3").is_some());
+    assert_eq!(partition::partition_name("vda", 2), "vda2");
+}
@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, time::Duration};
+use ros::{
+    allocator, dma,
+    memory::{self, BootInfoFrameAllocator},
+    net::{self, e1000, MacAddress, NetDevice, NetError},
+    pci,
+    time::Deadline,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    dma::init(&mut frame_alloc).expect("DMA pool initialization failed");
+    pci::init();
+    e1000::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// Two e1000 controllers on the same QEMU hub, so whatever one sends reaches the other
+const MAC0: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+const MAC1: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x57]);
+
+/// A local experimental EtherType
+const ETHER_TYPE: [u8; 2] = [0x88, 0xb5];
+
+fn nics() -> (Arc<dyn NetDevice>, Arc<dyn NetDevice>) {
+    let eth0 = net::get("eth0").expect("eth0 missing");
+    let eth1 = net::get("eth1").expect("eth1 missing");
+    // Leftovers of earlier tests
+    while eth0.receive().is_some() || eth1.receive().is_some() {}
+    (eth0, eth1)
+}
+
+fn frame(to: MacAddress, from: MacAddress, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::from(to.0);
+    frame.extend_from_slice(&from.0);
+    frame.extend_from_slice(&ETHER_TYPE);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn soon() -> Deadline {
+    Deadline::after(Duration::from_secs(2))
+}
+
+#[test_case]
+fn finds_nics() {
+    let controller = pci::find(pci::DeviceId::new(0x8086, 0x100e)).expect("no e1000 controller");
+    assert_eq!(pci::driver_of(controller.address), Some("e1000"));
+    assert_eq!(e1000::devices().len(), 2);
+
+    let names: Vec<_> = net::devices().into_iter().map(|(name, _)| name).collect();
+    assert_eq!(names, ["eth0", "eth1"]);
+    let (eth0, eth1) = nics();
+    assert_eq!(eth0.mac_address(), MAC0);
+    assert_eq!(eth1.mac_address(), MAC1);
+    assert_eq!(alloc::format!("{MAC0}"), "52:54:00:12:34:56");
+    assert!(eth0.is_link_up());
+    assert!(eth1.is_link_up());
+}
+
+#[test_case]
+fn sends_and_receives() {
+    let (eth0, eth1) = nics();
+    let payload: Vec<u8> = (0..1000).map(|i| (i * 3) as u8).collect();
+    let sent = frame(MAC1, MAC0, &payload);
+    eth0.send(&sent).unwrap();
+    assert_eq!(eth1.receive_until(soon()), Some(sent));
+
+    // More frames than the rings hold, going the other way
+    for i in 0..100u8 {
+        let sent = frame(MAC0, MAC1, &[i; 100]);
+        eth1.send(&sent).unwrap();
+        assert_eq!(eth0.receive_until(soon()), Some(sent), "frame {i}");
+    }
+    assert_eq!(eth0.receive(), None);
+    assert_eq!(eth1.receive(), None);
+}
+
+#[test_case]
+fn receives_broadcasts() {
+    let (eth0, eth1) = nics();
+    let sent = frame(MacAddress::BROADCAST, MAC0, b"anyone there?");
+    eth0.send(&sent).unwrap();
+    // Short frames are padded with zeros
+    let received = eth1.receive_until(soon()).unwrap();
+    assert_eq!(received.len(), 60);
+    assert_eq!(&received[..sent.len()], sent);
+    assert!(received[sent.len()..].iter().all(|&byte| byte == 0));
+}
+
+#[test_case]
+fn filters_other_addresses() {
+    let (eth0, eth1) = nics();
+    let stranger = MacAddress([0x52, 0x54, 0x00, 0xaa, 0xbb, 0xcc]);
+    eth0.send(&frame(stranger, MAC0, &[1; 64])).unwrap();
+    let sent = frame(MAC1, MAC0, &[2; 64]);
+    eth0.send(&sent).unwrap();
+    // Frames arrive in order, so the first one would have come before
+    assert_eq!(eth1.receive_until(soon()), Some(sent));
+    assert_eq!(eth1.receive(), None);
+}
+
+#[test_case]
+fn loops_frames_back() {
+    let (eth0, eth1) = nics();
+    let nic = &e1000::devices()[0];
+    nic.set_loopback(true).unwrap();
+    let sent = frame(MAC0, MAC0, &[7; 200]);
+    eth0.send(&sent).unwrap();
+    assert_eq!(eth0.receive_until(soon()), Some(sent));
+    let missed = frame(MAC1, MAC0, &[8; 200]);
+    eth0.send(&missed).unwrap();
+    assert_eq!(
+        eth1.receive_until(Deadline::after(Duration::from_millis(100))),
+        None
+    );
+
+    nic.set_loopback(false).unwrap();
+    let sent = frame(MAC1, MAC0, &[9; 200]);
+    eth0.send(&sent).unwrap();
+    assert_eq!(eth1.receive_until(soon()), Some(sent));
+}
+
+#[test_case]
+fn reports_link_changes() {
+    let nic = &e1000::devices()[1];
+    let changes = nic.link_changes();
+    nic.restart_autonegotiation().unwrap();
+    // QEMU takes the link down, and brings it back up half a second later
+    assert!(!nic.is_link_up());
+    assert!(nic.wait_for_link(Deadline::after(Duration::from_secs(5))));
+    // The interrupt may come just after the status changes
+    let deadline = soon();
+    while nic.link_changes() == changes && !deadline.has_passed() {
+        core::hint::spin_loop();
+    }
+    assert!(nic.link_changes() > changes);
+
+    let (eth0, eth1) = nics();
+    let sent = frame(MAC1, MAC0, b"back up");
+    eth0.send(&sent).unwrap();
+    assert_eq!(&eth1.receive_until(soon()).unwrap()[..sent.len()], sent);
+}
+
+#[test_case]
+fn rejects_bad_frames() {
+    let (eth0, _) = nics();
+    assert_eq!(eth0.send(&[0; 13]), Err(NetError::BadFrame));
+    assert_eq!(eth0.send(&[0; 1515]), Err(NetError::BadFrame));
+    assert_eq!(eth0.send(&frame(MAC1, MAC0, &[0; 1500])), Ok(()));
+}
@@ -0,0 +1,273 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ros::{
+    allocator,
+    block::{self, cache::BlockCache, ramdisk::RamDisk, BlockDevice},
+    dma,
+    fs::{
+        self,
+        ext2::{Ext2Error, Ext2Fs},
+        FileSystem, FileType, FsError, OpenOptions,
+    },
+    memory::{self, BootInfoFrameAllocator},
+    pci,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    dma::init(&mut frame_alloc).expect("DMA pool initialization failed");
+    fs::init();
+    pci::init();
+    block::ahci::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// tests/data/ext2.img: a 64KiB ext2 volume with 1KiB blocks, holding
+/// - `hello.txt`
+/// - `indirect.bin`, 14336 bytes of `i * 3 + 1`, going past the direct blocks
+/// - `sparse.bin`, 274KiB with data in its first block and in block 273, reached through the
+///   double indirect block, and holes everywhere else
+/// - `link.txt` linking to `hello.txt`, `long_link` to a path too long to fit in the inode,
+///   and `loop` to itself
+/// - `dir/nested/deep.txt`, `dir/up.txt` linking to `../hello.txt` and `dir/parent` to `..`
+static EXT2_IMAGE: &[u8] = include_bytes!("data/ext2.img");
+
+/// The same image, attached to the third port of the AHCI controller
+const EXT2_DISK: &str = "sdc";
+
+const HELLO: &[u8] = b"hello from ext2\n";
+
+fn ramdisk() -> Arc<dyn BlockDevice> {
+    Arc::new(RamDisk::from_vec(512, EXT2_IMAGE.into()))
+}
+
+fn mount(path: &str, device: Arc<dyn BlockDevice>) {
+    fs::create_dir(path).unwrap();
+    fs::mount(path, Arc::new(Ext2Fs::new(device).unwrap())).unwrap();
+}
+
+fn unmount(path: &str) {
+    fs::unmount(path).unwrap();
+    fs::remove(path).unwrap();
+}
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn reads_files() {
+    let ext2 = Ext2Fs::new(ramdisk()).unwrap();
+    assert_eq!(ext2.volume_name(), "rosext2");
+    assert_eq!(ext2.block_size(), 1024);
+    fs::create_dir("/ext2").unwrap();
+    fs::mount("/ext2", Arc::new(ext2)).unwrap();
+
+    assert_eq!(
+        names("/ext2"),
+        [
+            "lost+found",
+            "dir",
+            "hello.txt",
+            "indirect.bin",
+            "link.txt",
+            "long_link",
+            "loop",
+            "sparse.bin"
+        ]
+    );
+    assert_eq!(fs::read_to_vec("/ext2/hello.txt").unwrap(), HELLO);
+    assert_eq!(
+        fs::read_to_vec("/ext2/dir/nested/deep.txt").unwrap(),
+        b"deep inside\n"
+    );
+    assert_eq!(fs::metadata("/ext2/dir").unwrap().size, 3);
+
+    // Read a block at a time, since the image already takes most of the heap
+    let fd = fs::open("/ext2/indirect.bin", OpenOptions::new()).unwrap();
+    let mut block = [0; 1024];
+    let mut offset = 0;
+    loop {
+        let len = fs::read(fd, &mut block).unwrap();
+        if len == 0 {
+            break;
+        }
+        for (i, &byte) in block[..len].iter().enumerate() {
+            let expected = ((offset + i) * 3 + 1) as u8;
+            assert_eq!(byte, expected, "wrong byte at offset {}", offset + i);
+        }
+        offset += len;
+    }
+    assert_eq!(offset, 14336);
+    fs::close(fd).unwrap();
+
+    unmount("/ext2");
+}
+
+#[test_case]
+fn reads_sparse_files() {
+    let ext2 = Ext2Fs::new(ramdisk()).unwrap();
+    let sparse = ext2.root().lookup("sparse.bin").unwrap();
+    assert_eq!(sparse.metadata().size, 280576);
+
+    let mut block = vec![0; 1024];
+    assert_eq!(sparse.read_at(0, &mut block).unwrap(), 1024);
+    assert!(block.starts_with(b"start"));
+    assert!(block[5..].iter().all(|&byte| byte == b'.'));
+    // Holes in the direct, indirect and double indirect ranges
+    for offset in [5 * 1024, 100 * 1024, 200 * 1024] {
+        block.fill(0xff);
+        sparse.read_at(offset, &mut block).unwrap();
+        assert!(block.iter().all(|&byte| byte == 0), "no hole at {offset}");
+    }
+    assert_eq!(sparse.read_at(273 * 1024, &mut block).unwrap(), 1024);
+    assert!(block.starts_with(b"double indirect"));
+    assert!(block[15..].iter().all(|&byte| byte == b'!'));
+    assert_eq!(sparse.read_at(274 * 1024, &mut block).unwrap(), 0);
+}
+
+#[test_case]
+fn follows_symlinks() {
+    mount("/links", ramdisk());
+
+    assert_eq!(fs::read_link("/links/link.txt").unwrap(), "hello.txt");
+    assert_eq!(
+        fs::symlink_metadata("/links/link.txt").unwrap().file_type,
+        FileType::Symlink
+    );
+    assert_eq!(
+        fs::metadata("/links/link.txt").unwrap().file_type,
+        FileType::Regular
+    );
+    assert_eq!(fs::read_to_vec("/links/link.txt").unwrap(), HELLO);
+    assert_eq!(
+        fs::read_to_vec("/links/long_link").unwrap(),
+        b"deep inside\n"
+    );
+    // Relative targets start from the directory of the link, even in the middle of a path
+    assert_eq!(fs::read_to_vec("/links/dir/up.txt").unwrap(), HELLO);
+    assert_eq!(
+        fs::read_to_vec("/links/dir/parent/dir/parent/hello.txt").unwrap(),
+        HELLO
+    );
+    assert!(names("/links/dir/parent").contains(&String::from("sparse.bin")));
+    assert_eq!(fs::metadata("/links/loop"), Err(FsError::SymlinkLoop));
+    // The mount point itself is the root of the volume, not the directory it covers
+    let root = fs::symlink_metadata("/links").unwrap();
+    assert_eq!((root.inode, root.size), (2, 8));
+    assert_eq!(root, fs::metadata("/links").unwrap());
+    assert_eq!(
+        fs::read_link("/links/hello.txt"),
+        Err(FsError::InvalidArgument)
+    );
+
+    unmount("/links");
+}
+
+#[test_case]
+fn is_read_only() {
+    mount("/ro", ramdisk());
+
+    assert_eq!(
+        fs::write_file("/ro/hello.txt", b"changed"),
+        Err(FsError::ReadOnly)
+    );
+    assert_eq!(
+        fs::open("/ro/new.txt", OpenOptions::new().write(true).create(true)),
+        Err(FsError::ReadOnly)
+    );
+    assert_eq!(fs::remove("/ro/hello.txt"), Err(FsError::ReadOnly));
+    assert_eq!(fs::read_to_vec("/ro/hello.txt").unwrap(), HELLO);
+
+    unmount("/ro");
+}
+
+#[test_case]
+fn reads_sata_disk() {
+    let disk = block::get(EXT2_DISK).expect("ext2 disk missing");
+    mount("/sata", Arc::new(BlockCache::new(disk, 16)));
+
+    assert_eq!(fs::read_to_vec("/sata/dir/up.txt").unwrap(), HELLO);
+    assert_eq!(fs::read_to_vec("/sata/indirect.bin").unwrap().len(), 14336);
+
+    unmount("/sata");
+}
+
+/// Where the size of `inode` is in an image with a single group of 1KiB blocks
+fn inode_size_offset(image: &[u8], inode: u64) -> usize {
+    let le32 = |offset: usize| u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap());
+    let inode_size = u16::from_le_bytes(image[1024 + 88..1024 + 90].try_into().unwrap());
+    // The inode table, in the first group descriptor
+    let table = le32(2048 + 8) as usize;
+    table * 1024 + (inode as usize - 1) * usize::from(inode_size) + 4
+}
+
+/// A copy of the image with the size of `inode` far past what the inode has
+fn with_corrupt_size(inode: u64) -> Arc<dyn BlockDevice> {
+    let mut image = Vec::from(EXT2_IMAGE);
+    let offset = inode_size_offset(&image, inode);
+    image[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    Arc::new(RamDisk::from_vec(512, image))
+}
+
+#[test_case]
+fn rejects_corrupt_sizes() {
+    // Neither size is trusted for an allocation. Each image is dropped before the next one
+    // is made, to keep them within the heap
+    {
+        let root = Ext2Fs::new(with_corrupt_size(2)).unwrap().root();
+        assert_eq!(root.read_dir().err(), Some(FsError::Io));
+        assert_eq!(root.lookup("hello.txt").err(), Some(FsError::Io));
+    }
+
+    let long_link = {
+        let root = Ext2Fs::new(ramdisk()).unwrap().root();
+        root.lookup("long_link").unwrap().metadata().inode
+    };
+    let root = Ext2Fs::new(with_corrupt_size(long_link)).unwrap().root();
+    let link = root.lookup("long_link").unwrap();
+    assert_eq!(link.read_link(), Err(FsError::Io));
+}
+
+#[test_case]
+fn rejects_bad_volumes() {
+    let blank: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(512, 128));
+    assert_eq!(Ext2Fs::new(blank).err(), Some(Ext2Error::BadSuperblock));
+
+    // Extents, which ext4 uses for its block lists
+    let mut image = Vec::from(EXT2_IMAGE);
+    image[1024 + 96] |= 0x40;
+    let extents: Arc<dyn BlockDevice> = Arc::new(RamDisk::from_vec(512, image));
+    assert_eq!(
+        Ext2Fs::new(extents).err(),
+        Some(Ext2Error::Unsupported(0x40))
+    );
+}
@@ -0,0 +1,268 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ros::{
+    allocator,
+    block::{self, cache::BlockCache, partition, ramdisk::RamDisk, BlockDevice},
+    dma,
+    fs::{
+        self,
+        fat::{FatError, FatFs, FatType},
+        FileSystem, FileType, FsError, OpenOptions, SeekFrom,
+    },
+    memory::{self, BootInfoFrameAllocator},
+    pci,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    dma::init(&mut frame_alloc).expect("DMA pool initialization failed");
+    fs::init();
+    pci::init();
+    block::ahci::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// tests/data/fat32.img, attached to the second port of the AHCI controller: an MBR with a
+/// single FAT32 partition, holding
+/// - `HELLO.TXT`, `readme.md` and `A long file name.txt`
+/// - `docs/big.bin`, 5000 bytes counting up modulo 251 in clusters which aren't contiguous
+/// - `docs/Empty File`
+///
+/// and a deleted `OLD.TXT`
+const FAT32_DISK: &str = "sdb";
+
+/// tests/data/fat12.img: a 32KiB FAT12 volume with `HELLO.TXT` and `docs/big.bin`
+static FAT12_IMAGE: &[u8] = include_bytes!("data/fat12.img");
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+fn check_big(contents: &[u8]) {
+    assert_eq!(contents.len(), 5000);
+    for (i, &byte) in contents.iter().enumerate() {
+        assert_eq!(byte, (i % 251) as u8, "wrong byte at offset {i}");
+    }
+}
+
+/// The FAT32 partition, behind a cache
+fn fat32_partition() -> Arc<dyn BlockDevice> {
+    let name = partition::partition_name(FAT32_DISK, 1);
+    if block::get(&name).is_none() {
+        partition::scan(FAT32_DISK).expect("no partition table");
+    }
+    let partition = block::get(&name).expect("FAT32 partition missing");
+    Arc::new(BlockCache::new(partition, 32))
+}
+
+fn fat12_ramdisk() -> Arc<dyn BlockDevice> {
+    Arc::new(RamDisk::from_vec(512, FAT12_IMAGE.into()))
+}
+
+#[test_case]
+fn reads_host_image() {
+    let fat = FatFs::new(fat32_partition()).unwrap();
+    assert_eq!(fat.fat_type(), FatType::Fat32);
+    fs::create_dir("/host").unwrap();
+    fs::mount("/host", Arc::new(fat)).unwrap();
+
+    assert_eq!(
+        names("/host"),
+        ["HELLO.TXT", "readme.md", "A long file name.txt", "docs"]
+    );
+    assert_eq!(
+        fs::read_to_vec("/host/HELLO.TXT").unwrap(),
+        b"Hello from the host!\n"
+    );
+    assert_eq!(fs::read_to_vec("/host/readme.md").unwrap(), b"# ros\n");
+    // Names are looked up ignoring case, and files with a long name by their short one too
+    assert_eq!(
+        fs::read_to_vec("/host/a LONG file NAME.txt").unwrap(),
+        b"long names work\n"
+    );
+    assert_eq!(
+        fs::read_to_vec("/host/ALONGF~1.TXT").unwrap(),
+        b"long names work\n"
+    );
+    assert_eq!(fs::metadata("/host/OLD.TXT"), Err(FsError::NotFound));
+
+    assert!(fs::metadata("/host/docs").unwrap().is_dir());
+    assert_eq!(names("/host/docs"), ["big.bin", "Empty File"]);
+    check_big(&fs::read_to_vec("/host/docs/big.bin").unwrap());
+    assert_eq!(fs::metadata("/host/docs/Empty File").unwrap().size, 0);
+
+    fs::unmount("/host").unwrap();
+    fs::remove("/host").unwrap();
+}
+
+#[test_case]
+fn changes_persist() {
+    fs::create_dir("/rw").unwrap();
+    fs::mount("/rw", Arc::new(FatFs::new(fat32_partition()).unwrap())).unwrap();
+
+    fs::write_file("/rw/A new file with a long name.txt", b"first").unwrap();
+    let fd = fs::open(
+        "/rw/a new file with a long name.txt",
+        OpenOptions::new().append(true),
+    )
+    .unwrap();
+    fs::write(fd, b", second").unwrap();
+    fs::close(fd).unwrap();
+    fs::create_dir("/rw/docs/nested").unwrap();
+    fs::write_file("/rw/docs/nested/data.bin", &[0x5a; 3000]).unwrap();
+    fs::remove("/rw/HELLO.TXT").unwrap();
+    let fd = fs::open("/rw/readme.md", OpenOptions::new().write(true)).unwrap();
+    fs::seek(fd, SeekFrom::End(0)).unwrap();
+    fs::write(fd, b"more\n").unwrap();
+    fs::close(fd).unwrap();
+
+    // Dropping the filesystem writes everything back, for a new mount to find
+    drop(fs::unmount("/rw").unwrap());
+    let fat = FatFs::new(fat32_partition()).unwrap();
+    let root = fat.root();
+    let names: Vec<String> = root
+        .read_dir()
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert!(!names.iter().any(|name| name == "HELLO.TXT"));
+    assert!(names
+        .iter()
+        .any(|name| name == "A new file with a long name.txt"));
+    let file = root.lookup("A new file with a long name.txt").unwrap();
+    let mut buf = [0; 32];
+    let len = file.read_at(0, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"first, second");
+
+    let data = root
+        .lookup("docs")
+        .and_then(|docs| docs.lookup("nested"))
+        .and_then(|nested| nested.lookup("data.bin"))
+        .unwrap();
+    let mut contents = vec![0; 3000];
+    assert_eq!(data.read_at(0, &mut contents).unwrap(), 3000);
+    assert!(contents.iter().all(|&byte| byte == 0x5a));
+    let readme = root.lookup("README.MD").unwrap();
+    let len = readme.read_at(0, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"# ros\nmore\n");
+    fs::remove("/rw").unwrap();
+}
+
+#[test_case]
+fn fat12_from_ramdisk() {
+    let fat = FatFs::new(fat12_ramdisk()).unwrap();
+    assert_eq!(fat.fat_type(), FatType::Fat12);
+    let root = fat.root();
+    assert_eq!(
+        root.read_dir().unwrap().len(),
+        2,
+        "HELLO.TXT and docs expected"
+    );
+    let big = root.lookup("DOCS").unwrap().lookup("BIG.BIN").unwrap();
+    let mut contents = vec![0; 5000];
+    assert_eq!(big.read_at(0, &mut contents).unwrap(), 5000);
+    check_big(&contents);
+}
+
+#[test_case]
+fn create_truncate_and_delete() {
+    let fat = FatFs::new(fat12_ramdisk()).unwrap();
+    let root = fat.root();
+    let free = fat.free_clusters().unwrap();
+
+    let file = root.create("Growing file.dat", FileType::Regular).unwrap();
+    file.write_at(0, &[1; 1000]).unwrap();
+    // Writing past the end leaves zeroes in between
+    file.write_at(2000, &[2; 10]).unwrap();
+    assert_eq!(file.metadata().size, 2010);
+    let mut buf = vec![0; 2010];
+    file.read_at(0, &mut buf).unwrap();
+    assert!(buf[..1000].iter().all(|&byte| byte == 1));
+    assert!(buf[1000..2000].iter().all(|&byte| byte == 0));
+
+    // Shrinking frees clusters, and growing again doesn't bring back old data
+    file.truncate(100).unwrap();
+    assert_eq!(
+        fat.free_clusters().unwrap(),
+        free - 1,
+        "one cluster is left"
+    );
+    file.truncate(1000).unwrap();
+    let len = file.read_at(0, &mut buf).unwrap();
+    assert_eq!(len, 1000);
+    assert!(buf[100..1000].iter().all(|&byte| byte == 0));
+
+    let dir = root.create("a directory", FileType::Directory).unwrap();
+    dir.create("inside", FileType::Regular).unwrap();
+    assert_eq!(
+        root.create("A DIRECTORY", FileType::Regular).err(),
+        Some(FsError::AlreadyExists)
+    );
+    assert_eq!(root.unlink("a directory"), Err(FsError::DirectoryNotEmpty));
+    dir.unlink("inside").unwrap();
+    root.unlink("a directory").unwrap();
+    root.unlink("Growing file.dat").unwrap();
+    assert_eq!(file.read_at(0, &mut buf), Err(FsError::NotFound));
+    assert_eq!(fat.free_clusters().unwrap(), free);
+}
+
+#[test_case]
+fn runs_out_of_space() {
+    let fat = FatFs::new(fat12_ramdisk()).unwrap();
+    let root = fat.root();
+    let free = fat.free_clusters().unwrap() as usize;
+    let file = root.create("full", FileType::Regular).unwrap();
+    let data = vec![3; (free + 1) * fat.cluster_size()];
+    assert_eq!(file.write_at(0, &data), Err(FsError::NoSpace));
+    // A failed write gives back the clusters it took
+    assert_eq!(fat.free_clusters().unwrap() as usize, free);
+    file.write_at(0, &data[..free * fat.cluster_size()])
+        .unwrap();
+    assert_eq!(fat.free_clusters().unwrap(), 0);
+
+    assert_eq!(
+        root.create("no room", FileType::Directory).err(),
+        Some(FsError::NoSpace)
+    );
+}
+
+#[test_case]
+fn rejects_bad_volumes() {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(512, 64));
+    assert_eq!(FatFs::new(disk).err(), Some(FatError::BadBootSector));
+
+    let fat = FatFs::new(fat12_ramdisk()).unwrap();
+    assert_eq!(
+        fat.root().create("bad:name", FileType::Regular).err(),
+        Some(FsError::InvalidPath)
+    );
+}
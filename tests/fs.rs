@@ -0,0 +1,182 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ros::{
+    allocator,
+    fs::{self, ramfs::RamFs, FileType, FsError, OpenOptions, SeekFrom},
+    memory::{self, BootInfoFrameAllocator},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    fs::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn write_and_read_file() {
+    fs::write_file("/hello.txt", b"hello world").unwrap();
+    assert_eq!(fs::read_to_vec("/hello.txt").unwrap(), b"hello world");
+
+    let metadata = fs::metadata("/hello.txt").unwrap();
+    assert_eq!(metadata.file_type, FileType::Regular);
+    assert_eq!(metadata.size, 11);
+
+    // Writing again replaces the contents
+    fs::write_file("/hello.txt", b"bye").unwrap();
+    assert_eq!(fs::read_to_vec("/hello.txt").unwrap(), b"bye");
+}
+
+#[test_case]
+fn directories() {
+    fs::create_dir("/dir").unwrap();
+    fs::create_dir("/dir/sub").unwrap();
+    fs::write_file("/dir/b", b"").unwrap();
+    fs::write_file("/dir/a", b"").unwrap();
+    assert_eq!(names("/dir"), ["a", "b", "sub"]);
+    assert!(fs::metadata("/dir/sub").unwrap().is_dir());
+
+    assert_eq!(fs::create_dir("/dir/a"), Err(FsError::AlreadyExists));
+    assert_eq!(fs::create_dir("/missing/x"), Err(FsError::NotFound));
+    assert_eq!(fs::create_dir("/dir/a/x"), Err(FsError::NotADirectory));
+    assert_eq!(fs::read_to_vec("/dir"), Err(FsError::IsADirectory));
+
+    fs::write_file("/dir/sub/c", b"").unwrap();
+    assert_eq!(fs::remove("/dir/sub"), Err(FsError::DirectoryNotEmpty));
+    fs::remove("/dir/sub/c").unwrap();
+    fs::remove("/dir/sub").unwrap();
+    assert_eq!(names("/dir"), ["a", "b"]);
+}
+
+#[test_case]
+fn path_resolution() {
+    fs::create_dir("/paths").unwrap();
+    fs::write_file("/paths/file", b"x").unwrap();
+    assert!(fs::metadata("/paths/./file").is_ok());
+    assert!(fs::metadata("//paths//file/").is_ok());
+    assert!(fs::metadata("/paths/../paths/file").is_ok());
+    // `..` stops at the root
+    assert!(fs::metadata("/../../paths/file").is_ok());
+    assert_eq!(fs::metadata("paths/file"), Err(FsError::InvalidPath));
+    assert_eq!(fs::remove("/"), Err(FsError::Busy));
+}
+
+#[test_case]
+fn file_descriptors() {
+    let fd = fs::open("/fd", OpenOptions::new().write(true).create(true)).unwrap();
+    assert_eq!(fs::write(fd, b"0123456789"), Ok(10));
+    assert_eq!(fs::seek(fd, SeekFrom::Start(2)), Ok(2));
+    let mut buf = [0; 4];
+    assert_eq!(fs::read(fd, &mut buf), Ok(4));
+    assert_eq!(&buf, b"2345");
+    assert_eq!(fs::seek(fd, SeekFrom::End(-1)), Ok(9));
+    assert_eq!(fs::read(fd, &mut buf), Ok(1));
+    assert_eq!(fs::read(fd, &mut buf), Ok(0));
+    assert_eq!(
+        fs::seek(fd, SeekFrom::Current(-20)),
+        Err(FsError::InvalidArgument)
+    );
+
+    // Writing past the end leaves a gap of zeroes
+    fs::seek(fd, SeekFrom::Start(12)).unwrap();
+    fs::write(fd, b"!").unwrap();
+    assert_eq!(fs::read_to_vec("/fd").unwrap(), b"0123456789\0\0!");
+    fs::close(fd).unwrap();
+
+    assert_eq!(fs::close(fd), Err(FsError::BadDescriptor));
+    assert_eq!(fs::read(fd, &mut buf), Err(FsError::BadDescriptor));
+
+    let fd = fs::open("/fd", OpenOptions::new()).unwrap();
+    assert_eq!(fs::write(fd, b"x"), Err(FsError::PermissionDenied));
+    fs::close(fd).unwrap();
+
+    assert_eq!(
+        fs::open("/missing", OpenOptions::new()),
+        Err(FsError::NotFound)
+    );
+}
+
+#[test_case]
+fn append_and_truncate() {
+    fs::write_file("/log", b"one").unwrap();
+    let fd = fs::open("/log", OpenOptions::new().append(true)).unwrap();
+    fs::write(fd, b" two").unwrap();
+    fs::close(fd).unwrap();
+    assert_eq!(fs::read_to_vec("/log").unwrap(), b"one two");
+
+    let fd = fs::open("/log", OpenOptions::new().write(true).truncate(true)).unwrap();
+    assert_eq!(fs::metadata("/log").unwrap().size, 0);
+    fs::close(fd).unwrap();
+}
+
+#[test_case]
+fn descriptor_limit() {
+    let fds: Vec<_> = (0..fs::MAX_OPEN_FILES)
+        .map_while(|_| fs::open("/", OpenOptions::new()).ok())
+        .collect();
+    assert_eq!(
+        fs::open("/", OpenOptions::new()),
+        Err(FsError::TooManyOpenFiles)
+    );
+    for fd in fds {
+        fs::close(fd).unwrap();
+    }
+    let fd = fs::open("/", OpenOptions::new()).unwrap();
+    fs::close(fd).unwrap();
+}
+
+#[test_case]
+fn mounts() {
+    fs::create_dir("/mnt").unwrap();
+    fs::write_file("/mnt/hidden", b"").unwrap();
+    fs::mount("/mnt", Arc::new(RamFs::new())).unwrap();
+
+    // The mounted filesystem hides the directory it is mounted on
+    assert!(names("/mnt").is_empty());
+    fs::write_file("/mnt/file", b"mounted").unwrap();
+    assert_eq!(fs::read_to_vec("/mnt/file").unwrap(), b"mounted");
+    assert_eq!(
+        fs::mount("/mnt", Arc::new(RamFs::new())),
+        Err(FsError::Busy)
+    );
+    assert_eq!(fs::remove("/mnt"), Err(FsError::Busy));
+    assert_eq!(
+        fs::mount("/mnt/file", Arc::new(RamFs::new())),
+        Err(FsError::NotADirectory)
+    );
+
+    fs::unmount("/mnt").unwrap();
+    assert_eq!(names("/mnt"), ["hidden"]);
+    assert_eq!(fs::unmount("/mnt").err(), Some(FsError::NotFound));
+}
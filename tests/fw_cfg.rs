@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ros::{
+    allocator, fw_cfg,
+    memory::{self, BootInfoFrameAllocator},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// Passed by `-fw_cfg` in the test arguments, from tests/data/fw_cfg_blob.bin
+const BLOB: &str = "opt/ros/test/blob";
+const BLOB_SIZE: usize = 12345;
+
+#[test_case]
+fn lists_files() {
+    let files = fw_cfg::files();
+    let blob = files
+        .iter()
+        .find(|file| file.name() == BLOB)
+        .expect("blob missing from the directory");
+    assert_eq!(blob.size(), BLOB_SIZE);
+    assert!(files
+        .iter()
+        .any(|file| file.name() == "opt/ros/test/greeting"));
+}
+
+#[test_case]
+fn reads_file_through_dma() {
+    // With memory initialized, reads spanning several pages go through DMA
+    assert!(fw_cfg::supports_dma());
+    let blob = fw_cfg::read_file(BLOB).expect("failed to read the blob");
+    assert_eq!(blob.len(), BLOB_SIZE);
+    for (i, &byte) in blob.iter().enumerate() {
+        assert_eq!(byte, (i % 251) as u8, "wrong byte at offset {i}");
+    }
+}
+
+#[test_case]
+fn missing_file() {
+    assert!(fw_cfg::read_file("opt/ros/test/missing").is_none());
+}
@@ -0,0 +1,137 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ros::{
+    allocator,
+    fs::{
+        self,
+        initrd::{ArchiveError, Initrd},
+        FileType, FsError, OpenOptions,
+    },
+    memory::{self, BootInfoFrameAllocator},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    fs::initrd::init().expect("failed to mount the initrd");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// The same sample tree, in both formats
+static SAMPLE_TAR: &[u8] = include_bytes!("data/initrd.tar");
+static SAMPLE_CPIO: &[u8] = include_bytes!("data/initrd.cpio");
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+/// Checks the sample tree mounted at `at`
+fn check_sample(at: &str) {
+    let path = |p: &str| alloc::format!("{at}{p}");
+
+    assert_eq!(names(at), ["a", "bin", "empty_dir", "etc"]);
+    assert_eq!(names(&path("/etc")), ["empty", "motd"]);
+    assert!(names(&path("/empty_dir")).is_empty());
+    // Directories without an entry of their own are implied
+    assert_eq!(names(&path("/a/b")), ["c"]);
+    assert_eq!(
+        fs::metadata(&path("/a/b")).unwrap().file_type,
+        FileType::Directory
+    );
+
+    assert_eq!(
+        fs::read_to_vec(&path("/bin/hello")).unwrap(),
+        b"hello from the initrd\n"
+    );
+    assert_eq!(fs::read_to_vec(&path("/etc/motd")).unwrap(), b"Welcome\n");
+    assert!(fs::read_to_vec(&path("/etc/empty")).unwrap().is_empty());
+
+    let deep = fs::read_to_vec(&path("/a/b/c/deep.txt")).unwrap();
+    assert_eq!(deep.len(), 1500);
+    assert!(deep.iter().enumerate().all(|(i, &b)| b == i as u8));
+}
+
+/// The host passes an invalid archive (see the test arguments), which is ignored
+#[test_case]
+fn embedded_initrd_is_root() {
+    assert_eq!(fs::read_to_vec("/etc/hostname").unwrap(), b"ros\n");
+    assert_eq!(fs::read_to_vec("/etc/motd").unwrap(), b"Welcome to ros!\n");
+}
+
+#[test_case]
+fn tar_archive() {
+    fs::mount("/etc", Arc::new(Initrd::parse(SAMPLE_TAR).unwrap())).unwrap();
+    check_sample("/etc");
+    fs::unmount("/etc").unwrap();
+}
+
+#[test_case]
+fn cpio_archive() {
+    fs::mount("/etc", Arc::new(Initrd::parse(SAMPLE_CPIO).unwrap())).unwrap();
+    check_sample("/etc");
+    fs::unmount("/etc").unwrap();
+}
+
+#[test_case]
+fn initrd_is_read_only() {
+    assert_eq!(fs::write_file("/etc/motd", b"x"), Err(FsError::ReadOnly));
+    assert_eq!(fs::create_dir("/new"), Err(FsError::ReadOnly));
+    assert_eq!(fs::remove("/etc/motd"), Err(FsError::ReadOnly));
+    assert_eq!(
+        fs::open("/etc/new", OpenOptions::new().write(true).create(true)),
+        Err(FsError::ReadOnly)
+    );
+}
+
+#[test_case]
+fn invalid_archives() {
+    assert_eq!(
+        Initrd::parse(b"not an archive").err(),
+        Some(ArchiveError::UnknownFormat)
+    );
+    assert_eq!(
+        Initrd::parse(&SAMPLE_CPIO[..200]).err(),
+        Some(ArchiveError::Truncated)
+    );
+    assert_eq!(
+        Initrd::parse(&SAMPLE_TAR[..1024]).err(),
+        Some(ArchiveError::Truncated)
+    );
+
+    static CORRUPTED: spin::Lazy<Vec<u8>> = spin::Lazy::new(|| {
+        let mut tar = SAMPLE_TAR.to_vec();
+        tar[0] ^= 1;
+        tar
+    });
+    assert_eq!(
+        Initrd::parse(&CORRUPTED).err(),
+        Some(ArchiveError::BadChecksum(0))
+    );
+}
@@ -0,0 +1,298 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ros::{
+    allocator,
+    block::{self, cache::BlockCache, ramdisk::RamDisk, BlockDevice},
+    fs::{
+        self,
+        iso9660::{IsoError, IsoFs, Names},
+        FileSystem, FileType, FsError, Inode,
+    },
+    memory::{self, BootInfoFrameAllocator},
+    pci,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    fs::init();
+    pci::init();
+    block::ata::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// tests/data/cdrom.iso, in the secondary slave drive: 44 sectors with Rock Ridge and Joliet
+/// names, holding
+/// - `readme.txt`, `A long file name.txt` and `big.bin`, 5000 bytes of `i * 7`
+/// - `link.txt` linking to `readme.txt`
+/// - `docs/nested/deep.txt`, `docs/up.txt` linking to `../readme.txt` and `docs/deep_link`
+///   to `nested/deep.txt`
+/// - `docs/moved/inside.txt`, with `docs/moved` relocated to `rr_moved/moved` by Rock Ridge
+/// - `many`, with 30 empty files and records in two sectors
+const CDROM: &str = "ata3";
+const SECTORS: u64 = 44;
+
+const README: &[u8] = b"hello from the cd\n";
+
+fn cdrom() -> Arc<dyn BlockDevice> {
+    let drive = block::get(CDROM).expect("cdrom drive missing");
+    Arc::new(BlockCache::new(drive, 8))
+}
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+fn inode_names(inode: &Arc<dyn Inode>) -> Vec<String> {
+    inode
+        .read_dir()
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn reads_cdrom_drive() {
+    let drive = block::get(CDROM).expect("cdrom drive missing");
+    assert_eq!(drive.block_size(), 2048);
+    assert_eq!(drive.block_count(), SECTORS);
+    assert!(drive.is_read_only());
+    let mut sector = vec![0; 2048];
+    drive.read_blocks(16, &mut sector).unwrap();
+    assert_eq!(&sector[..6], b"\x01CD001");
+}
+
+#[test_case]
+fn rock_ridge_names() {
+    let iso = IsoFs::new(cdrom()).unwrap();
+    assert_eq!(iso.names(), Names::RockRidge);
+    assert_eq!(iso.volume_id(), "ROS_TEST_CD");
+    fs::create_dir("/cd").unwrap();
+    fs::mount("/cd", Arc::new(iso)).unwrap();
+
+    assert_eq!(
+        names("/cd"),
+        [
+            "A long file name.txt",
+            "big.bin",
+            "docs",
+            "link.txt",
+            "many",
+            "readme.txt",
+            "rr_moved"
+        ]
+    );
+    assert_eq!(fs::read_to_vec("/cd/readme.txt").unwrap(), README);
+    assert_eq!(
+        fs::read_to_vec("/cd/A long file name.txt").unwrap(),
+        b"long names work\n"
+    );
+    let big = fs::read_to_vec("/cd/big.bin").unwrap();
+    assert_eq!(big.len(), 5000);
+    for (i, &byte) in big.iter().enumerate() {
+        assert_eq!(byte, (i * 7) as u8, "wrong byte at offset {i}");
+    }
+
+    // The relocated directory shows up where it belongs, and only there
+    assert_eq!(
+        names("/cd/docs"),
+        ["deep_link", "moved", "nested", "up.txt"]
+    );
+    assert_eq!(
+        fs::read_to_vec("/cd/docs/moved/inside.txt").unwrap(),
+        b"relocated\n"
+    );
+    assert!(names("/cd/rr_moved").is_empty());
+
+    let many = names("/cd/many");
+    assert_eq!(many.len(), 30);
+    assert_eq!(many[29], "File number 29.txt");
+    assert_eq!(fs::metadata("/cd/many/File number 29.txt").unwrap().size, 0);
+
+    assert_eq!(
+        fs::write_file("/cd/readme.txt", b"changed"),
+        Err(FsError::ReadOnly)
+    );
+    fs::unmount("/cd").unwrap();
+    fs::remove("/cd").unwrap();
+}
+
+#[test_case]
+fn follows_symlinks() {
+    fs::create_dir("/links").unwrap();
+    fs::mount("/links", Arc::new(IsoFs::new(cdrom()).unwrap())).unwrap();
+
+    assert_eq!(fs::read_link("/links/link.txt").unwrap(), "readme.txt");
+    assert_eq!(
+        fs::symlink_metadata("/links/link.txt").unwrap().file_type,
+        FileType::Symlink
+    );
+    assert_eq!(fs::read_to_vec("/links/link.txt").unwrap(), README);
+    assert_eq!(fs::read_to_vec("/links/docs/up.txt").unwrap(), README);
+    assert_eq!(
+        fs::read_link("/links/docs/deep_link").unwrap(),
+        "nested/deep.txt"
+    );
+    assert_eq!(
+        fs::read_to_vec("/links/docs/deep_link").unwrap(),
+        b"deep inside\n"
+    );
+
+    fs::unmount("/links").unwrap();
+    fs::remove("/links").unwrap();
+}
+
+#[test_case]
+fn joliet_names() {
+    let iso = IsoFs::with_names(cdrom(), Names::Joliet).unwrap();
+    assert_eq!(iso.volume_id(), "ros test cd");
+    let root = iso.root();
+    assert_eq!(
+        inode_names(&root),
+        [
+            "A long file name.txt",
+            "big.bin",
+            "docs",
+            "many",
+            "readme.txt"
+        ]
+    );
+    let inside = root
+        .lookup("docs")
+        .and_then(|docs| docs.lookup("moved"))
+        .and_then(|moved| moved.lookup("inside.txt"))
+        .unwrap();
+    let mut buf = [0; 32];
+    let len = inside.read_at(0, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"relocated\n");
+    assert_eq!(root.lookup("README.TXT").err(), Some(FsError::NotFound));
+    assert_eq!(inode_names(&root.lookup("many").unwrap()).len(), 30);
+}
+
+#[test_case]
+fn plain_names() {
+    let iso = IsoFs::with_names(cdrom(), Names::Plain).unwrap();
+    let root = iso.root();
+    assert_eq!(
+        inode_names(&root),
+        [
+            "ALONGFIL.TXT",
+            "BIG.BIN",
+            "DOCS",
+            "LINK.TXT",
+            "MANY",
+            "README.TXT",
+            "RR_MOVED"
+        ]
+    );
+    // Plain names are looked up ignoring case
+    let readme = root.lookup("readme.txt").unwrap();
+    let mut buf = [0; 32];
+    let len = readme.read_at(0, &mut buf).unwrap();
+    assert_eq!(&buf[..len], README);
+    // Without Rock Ridge, links are empty files
+    let link = root.lookup("LINK.TXT").unwrap().metadata();
+    assert_eq!((link.file_type, link.size), (FileType::Regular, 0));
+}
+
+/// A volume with a primary descriptor in sector 16 and an empty root directory in sector 18
+fn plain_volume() -> Arc<dyn BlockDevice> {
+    let disk = RamDisk::new(2048, 20);
+    let mut sector = vec![0; 2048];
+    sector[..7].copy_from_slice(b"\x01CD001\x01");
+    sector[40..46].copy_from_slice(b"PLAIN ");
+    sector[128..130].copy_from_slice(&2048u16.to_le_bytes());
+    let mut root = [0; 34];
+    root[0] = 34;
+    root[2..6].copy_from_slice(&18u32.to_le_bytes());
+    root[10..14].copy_from_slice(&2048u32.to_le_bytes());
+    root[25] = 0x02;
+    root[32] = 1;
+    sector[156..190].copy_from_slice(&root);
+    disk.write_blocks(16, &sector).unwrap();
+
+    sector.fill(0);
+    sector[..7].copy_from_slice(b"\xffCD001\x01");
+    disk.write_blocks(17, &sector).unwrap();
+
+    sector.fill(0);
+    sector[..34].copy_from_slice(&root);
+    root[33] = 1;
+    sector[34..68].copy_from_slice(&root);
+    disk.write_blocks(18, &sector).unwrap();
+    Arc::new(disk)
+}
+
+#[test_case]
+fn picks_available_names() {
+    let iso = IsoFs::new(plain_volume()).unwrap();
+    assert_eq!(iso.names(), Names::Plain);
+    assert_eq!(iso.volume_id(), "PLAIN");
+    assert!(iso.root().read_dir().unwrap().is_empty());
+    assert_eq!(
+        IsoFs::with_names(plain_volume(), Names::Joliet).err(),
+        Some(IsoError::MissingNames(Names::Joliet))
+    );
+    assert_eq!(
+        IsoFs::with_names(plain_volume(), Names::RockRidge).err(),
+        Some(IsoError::MissingNames(Names::RockRidge))
+    );
+
+    let blank: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(2048, 20));
+    assert_eq!(IsoFs::new(blank).err(), Some(IsoError::BadVolumeDescriptor));
+}
+
+#[test_case]
+fn rejects_corrupt_lengths() {
+    // A root directory far larger than the volume, which is read a sector at a time rather
+    // than allocated whole
+    let disk = plain_volume();
+    let mut sector = vec![0; 2048];
+    disk.read_blocks(16, &mut sector).unwrap();
+    sector[156 + 10..156 + 14].copy_from_slice(&u32::MAX.to_le_bytes());
+    disk.write_blocks(16, &sector).unwrap();
+    let iso = IsoFs::new(disk).unwrap();
+    assert_eq!(iso.root().read_dir().err(), Some(FsError::Io));
+
+    // A continuation area longer than a block, in the system use area of the root
+    let disk = plain_volume();
+    disk.read_blocks(18, &mut sector).unwrap();
+    let parent: [u8; 34] = sector[34..68].try_into().unwrap();
+    let mut entry = [0; 28];
+    entry[..4].copy_from_slice(b"CE\x1c\x01");
+    entry[4..8].copy_from_slice(&18u32.to_le_bytes());
+    entry[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+    sector[0] = 34 + 28;
+    sector[34..62].copy_from_slice(&entry);
+    sector[62..96].copy_from_slice(&parent);
+    disk.write_blocks(18, &sector).unwrap();
+    assert_eq!(IsoFs::new(disk).err(), Some(IsoError::BadVolumeDescriptor));
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ros::{
+    allocator,
+    block::{self, nvme, testing},
+    dma,
+    memory::{self, BootInfoFrameAllocator},
+    pci,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    dma::init(&mut frame_alloc).expect("DMA pool initialization failed");
+    pci::init();
+    block::nvme::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// tests/data/ata.img, attached to namespace 1 of the controller
+const DISK: &str = "nvme0n1";
+
+#[test_case]
+fn finds_controller() {
+    let controller = pci::find(pci::DeviceId::class(0x01, 0x08)).expect("no NVMe controller");
+    assert_eq!(pci::driver_of(controller.address), Some("nvme"));
+    let controllers = nvme::controllers();
+    assert_eq!(controllers.len(), 1);
+    assert_eq!(controllers[0].model(), "QEMU NVMe Ctrl");
+}
+
+#[test_case]
+fn test_disk() {
+    testing::check_test_disk(DISK);
+}
+
+/// More admin commands than the admin queue has entries, so its completion queue wraps and
+/// the phase of new completions flips
+#[test_case]
+fn wraps_admin_queue() {
+    let controller = &nvme::controllers()[0];
+    for _ in 0..40 {
+        assert_eq!(controller.namespaces(), [1]);
+    }
+}
+
+/// A command per sector, several times more than the I/O queue has entries
+#[test_case]
+fn wraps_io_queue() {
+    let disk = block::get(DISK).unwrap();
+    let mut sector = vec![0; testing::SECTOR_SIZE];
+    for lba in 0..testing::SECTORS {
+        disk.read_blocks(lba, &mut sector).unwrap();
+        testing::check_sector(lba, &sector);
+    }
+}
@@ -0,0 +1,33 @@
+//!
+//! A panic while the VGA writer is locked must still get reported, instead of deadlocking
+//!
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use ros::{
+    panic::{self, PanicPolicy},
+    testing,
+    vga_buffer::VgaWriter,
+    QemuExitCode,
+};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    testing::begin_single("panic_with_vga_locked::panic_with_vga_locked");
+
+    ros::init();
+
+    let _vga = VgaWriter::lock();
+    panic!("panicked with the VGA writer locked");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::end_single(Ok(()));
+    // Exits with success only if the panic report made it through
+    panic::set_policy(PanicPolicy::ExitQemu(QemuExitCode::Success));
+    panic::handle_panic(info)
+}
@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use ros::{
+    allocator,
+    memory::{self, BootInfoFrameAllocator},
+    pci::{self, Bar, DeviceId, Driver, PciAddress},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// QEMU's default `pc` machine: an i440FX host bridge and a PIIX3 south bridge
+#[test_case]
+fn finds_host_bridge() {
+    let host_bridge = pci::find(DeviceId::new(0x8086, 0x1237)).expect("no i440FX host bridge");
+    assert_eq!(host_bridge.address, PciAddress::new(0, 0, 0));
+    assert_eq!((host_bridge.class, host_bridge.subclass), (0x06, 0x00));
+}
+
+#[test_case]
+fn finds_vga() {
+    let vga = pci::find(DeviceId::class(0x03, 0x00)).expect("no VGA controller");
+    assert_eq!((vga.vendor_id, vga.device_id), (0x1234, 0x1111));
+
+    // The framebuffer of the standard VGA is 16MiB
+    match vga.bars[0] {
+        Some(Bar::Memory {
+            size, prefetchable, ..
+        }) => {
+            assert_eq!(size, 16 << 20);
+            assert!(prefetchable);
+        }
+        bar => panic!("unexpected framebuffer BAR {bar:?}"),
+    }
+    assert!(format!("{vga}").contains("VGA compatible controller [0300]: [1234:1111]"));
+}
+
+#[test_case]
+fn finds_functions_of_multifunction_device() {
+    // The PIIX3 IDE controller is function 1 of the ISA bridge
+    let ide = pci::find(DeviceId::class(0x01, 0x01)).expect("no IDE controller");
+    assert_eq!(ide.address, PciAddress::new(0, 1, 1));
+    // The bus master IDE registers
+    assert!(matches!(ide.bars[4], Some(Bar::Io { size: 16, .. })));
+}
+
+#[test_case]
+fn capabilities_of_every_device_terminate() {
+    for device in pci::devices() {
+        assert!(device.capabilities().count() < 48);
+    }
+}
+
+static PROBED: AtomicUsize = AtomicUsize::new(0);
+
+static VGA_DRIVER: Driver = Driver {
+    name: "test-vga",
+    ids: &[DeviceId::class(0x03, 0x00)],
+    probe: |device| {
+        PROBED.fetch_add(1, Ordering::Relaxed);
+        device.vendor_id == 0x1234
+    },
+};
+
+static SECOND_VGA_DRIVER: Driver = Driver {
+    name: "test-vga-2",
+    ids: &[DeviceId::new(0x1234, 0x1111)],
+    probe: |_| panic!("the device already has a driver"),
+};
+
+#[test_case]
+fn drivers_bind_by_id() {
+    pci::register_driver(&VGA_DRIVER);
+    assert_eq!(PROBED.load(Ordering::Relaxed), 1);
+    let vga = pci::find(DeviceId::class(0x03, 0x00)).unwrap();
+    assert_eq!(pci::driver_of(vga.address), Some("test-vga"));
+
+    // Devices with a driver aren't offered to others
+    pci::register_driver(&SECOND_VGA_DRIVER);
+    assert_eq!(pci::driver_of(vga.address), Some("test-vga"));
+    assert_eq!(pci::driver_of(PciAddress::new(0, 0, 0)), None);
+}
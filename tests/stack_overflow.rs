@@ -8,7 +8,7 @@
 
 use core::panic::PanicInfo;
 
-use ros::{exit_qemu, gdt, serial_print, serial_println, QemuExitCode};
+use ros::{exit_qemu, gdt, testing, QemuExitCode};
 use spin::Lazy;
 use volatile::Volatile;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
@@ -34,7 +34,7 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
+    testing::end_single(Ok(()));
     exit_qemu(QemuExitCode::Success);
     loop {}
 }
@@ -47,7 +47,7 @@ fn stack_overflow() {
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    serial_print!("stack_overflow::stack_overflow...  ");
+    testing::begin_single("stack_overflow::stack_overflow");
 
     ros::init();
     init_test_idt();
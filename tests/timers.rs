@@ -0,0 +1,217 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::{
+    future::Future,
+    pin::pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use ros::{
+    allocator,
+    memory::{self, BootInfoFrameAllocator},
+    time::{
+        self,
+        wheel::{self, Action, Elapsed, Timer, TimerWheel},
+    },
+};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+
+    test_main();
+    ros::halt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// Polls `future` to completion, halting between polls
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Spins (with interrupts enabled) until the timer tick counter reaches `tick`
+fn wait_for_tick(tick: u64) {
+    while time::ticks() < tick {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Every timer expires exactly on its deadline, in deadline order, across all wheel levels
+#[test_case]
+fn wheel_expires_on_deadline() {
+    let start = 1000;
+    let mut wheel = TimerWheel::new(start);
+    let delays = [
+        1, 2, 63, 64, 65, 127, 128, 4095, 4096, 4097, 70_000, 262_143, 262_144,
+    ];
+    for &delay in delays.iter().rev() {
+        wheel.insert(Timer::new(
+            start + delay,
+            Action::Callback(alloc::boxed::Box::new(|| ())),
+        ));
+    }
+    assert_eq!(wheel.len(), delays.len());
+
+    let mut fired = Vec::new();
+    for tick in start + 1..=start + 262_144 {
+        for timer in wheel.advance(tick) {
+            assert_eq!(timer.deadline(), tick);
+            fired.push(tick - start);
+        }
+    }
+    assert_eq!(fired, delays);
+    assert!(wheel.is_empty());
+}
+
+/// Deadlines on slot boundaries of the upper levels are reached by cascading on the very tick
+/// they expire, and must still expire on it
+#[test_case]
+fn wheel_expires_on_aligned_deadlines() {
+    let mut wheel = TimerWheel::new(0);
+    let deadlines = [64, 128, 4096, 8192, 262_144];
+    for &deadline in &deadlines {
+        wheel.insert(Timer::new(
+            deadline,
+            Action::Callback(alloc::boxed::Box::new(|| ())),
+        ));
+    }
+
+    let mut fired = Vec::new();
+    for tick in 1..=262_144 {
+        for timer in wheel.advance(tick) {
+            assert_eq!(timer.deadline(), tick);
+            fired.push(tick);
+        }
+    }
+    assert_eq!(fired, deadlines);
+    assert!(wheel.is_empty());
+}
+
+/// Advancing several ticks at once still yields the expired timers in deadline order
+#[test_case]
+fn wheel_orders_batched_expiry() {
+    let mut wheel = TimerWheel::new(0);
+    for deadline in [70, 5, 4096, 300, 64, 1] {
+        wheel.insert(Timer::new(
+            deadline,
+            Action::Callback(alloc::boxed::Box::new(|| ())),
+        ));
+    }
+    let deadlines: Vec<u64> = wheel.advance(5000).map(|t| t.deadline()).collect();
+    assert_eq!(deadlines, vec![1, 5, 64, 70, 300, 4096]);
+}
+
+#[test_case]
+fn callback_fires_on_its_tick() {
+    static FIRED_AT: AtomicU64 = AtomicU64::new(0);
+
+    let scheduled_at = time::ticks();
+    wheel::after_ticks(25, || FIRED_AT.store(time::ticks(), Ordering::Relaxed));
+    wait_for_tick(scheduled_at + 30);
+
+    let fired_at = FIRED_AT.load(Ordering::Relaxed);
+    assert!(fired_at >= scheduled_at + 25, "fired early at {fired_at}");
+    assert!(fired_at <= scheduled_at + 26, "fired late at {fired_at}");
+}
+
+#[test_case]
+fn callbacks_fire_in_order() {
+    let order = Arc::new(Mutex::new(Vec::new()));
+    for delay in [40, 10, 30, 20] {
+        let order = order.clone();
+        wheel::after_ticks(delay, move || order.lock().push(delay));
+    }
+    wait_for_tick(time::ticks() + 45);
+    assert_eq!(*order.lock(), vec![10, 20, 30, 40]);
+}
+
+#[test_case]
+fn periodic_timer_and_cancel() {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+
+    let handle = wheel::every_ticks(10, || {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+    });
+    wait_for_tick(time::ticks() + 55);
+    handle.cancel();
+    let count = COUNT.load(Ordering::Relaxed);
+    assert!((5..=6).contains(&count), "fired {count} times");
+
+    wait_for_tick(time::ticks() + 30);
+    assert_eq!(COUNT.load(Ordering::Relaxed), count);
+}
+
+#[test_case]
+fn cancelled_timer_never_fires() {
+    let fired = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let fired = fired.clone();
+        wheel::after_ticks(5, move || fired.store(true, Ordering::Relaxed))
+    };
+    handle.cancel();
+    wait_for_tick(time::ticks() + 10);
+    assert!(!fired.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn sleep_waits_for_duration() {
+    let start = time::ticks();
+    block_on(time::sleep(Duration::from_millis(20)));
+    let elapsed = time::ticks() - start;
+    let expected = time::duration_to_ticks(Duration::from_millis(20));
+    assert!(
+        elapsed >= expected && elapsed <= expected + 2,
+        "slept {elapsed} ticks"
+    );
+}
+
+#[test_case]
+fn timeout_elapses() {
+    let result = block_on(time::timeout(
+        Duration::from_millis(10),
+        core::future::pending::<()>(),
+    ));
+    assert_eq!(result, Err(Elapsed));
+
+    let result = block_on(time::timeout(Duration::from_millis(10), async { 7 }));
+    assert_eq!(result, Ok(7));
+}
+
+/// `ros::init` looks for the HPET before the memory module is up, which mustn't hide it later
+#[test_case]
+fn finds_hpet_once_memory_is_up() {
+    let hpet = time::hpet().expect("no HPET");
+    assert!(hpet.frequency() > 0);
+    let start = hpet.counter();
+    wait_for_tick(time::ticks() + 2);
+    assert!(hpet.counter() > start);
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ros::{
+    allocator,
+    block::{self, testing},
+    dma::{self, DmaBuffer},
+    memory::{self, BootInfoFrameAllocator},
+    pci,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ros::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_alloc = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_alloc).expect("Heap Initialization Failed");
+    dma::init(&mut frame_alloc).expect("DMA pool initialization failed");
+    pci::init();
+    block::virtio::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ros::test_panic_handler(info)
+}
+
+/// tests/data/ata.img, attached twice: as `vda` to a modern only device and as `vdb` to a
+/// legacy only one
+const DISKS: [&str; 2] = ["vda", "vdb"];
+
+#[test_case]
+fn finds_both_transports() {
+    let devices = block::virtio::devices();
+    assert_eq!(devices.len(), 2);
+    assert!(!devices[0].is_legacy());
+    assert!(devices[1].is_legacy());
+}
+
+#[test_case]
+fn test_disks() {
+    for name in DISKS {
+        testing::check_test_disk(name);
+    }
+}
+
+#[test_case]
+fn requests_complete_asynchronously() {
+    for device in block::virtio::devices() {
+        // Several requests in flight at once, completed in any order
+        let requests: alloc::vec::Vec<_> = (0..8)
+            .map(|i| device.start_read(i * 16, 16).unwrap())
+            .collect();
+        for (i, request) in requests.into_iter().enumerate().rev() {
+            let data = request.wait().unwrap().unwrap();
+            for (j, sector) in data[..16 * 512].chunks(512).enumerate() {
+                testing::check_sector((i * 16 + j) as u64, sector);
+            }
+        }
+
+        let mut data = DmaBuffer::new(512).unwrap();
+        data[..512].fill(0x5a);
+        let request = device.start_write(200, data, 1).unwrap();
+        assert!(request.wait().unwrap().is_some());
+        let data = device.start_read(200, 1).unwrap().wait().unwrap().unwrap();
+        assert!(data[..512].iter().all(|&byte| byte == 0x5a));
+    }
+}